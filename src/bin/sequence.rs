@@ -2,8 +2,13 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use argh::FromArgs;
-use strudel_of_lilypond::sequencer::lilypond::{lilypond_of_sequence, strudel_of_sequence};
-use strudel_of_lilypond::sequencer::model::BarSequence;
+use strudel_of_lilypond::sequencer::library::{cache_dir, check_fetched, fetch_libraries};
+use strudel_of_lilypond::sequencer::lilypond::{
+    arrange_sequence, lilypond_of_sequence, strudel_of_sequence, strudel_stems_of_sequence, SequenceRenderOptions,
+};
+use strudel_of_lilypond::sequencer::model::{BarSequence, Order};
+use strudel_of_lilypond::sequencer::setlist::{setlist_html, setlist_of_songs, Song};
+use strudel_of_lilypond::{Stamp, StampCache};
 
 /// Generate LilyPond and Strudel HTML files from a YAML bar sequence
 #[derive(FromArgs)]
@@ -12,34 +17,321 @@ struct Args {
     #[argh(option)]
     library: Vec<String>,
 
+    /// seed for sections with `order: shuffle` (defaults to a new seed on every run)
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// also write one HTML player per voice (<input stem>-<drums>.html)
+    #[argh(switch)]
+    stems: bool,
+
+    /// page title (defaults to the input file stem)
+    #[argh(option)]
+    title: Option<String>,
+
+    /// drum machine sample bank (e.g. RolandTR909)
+    #[argh(option)]
+    bank: Option<String>,
+
+    /// gain of every voice (e.g. 0.8)
+    #[argh(option)]
+    gain: Option<String>,
+
+    /// color the voices and show them as a punchcard
+    #[argh(switch)]
+    punchcard: bool,
+
+    /// HTML template file; {{title}} and {{code}} are replaced by the title and the Strudel code
+    #[argh(option)]
+    template: Option<String>,
+
+    /// do not write anything, report whether the outputs are up to date with their sources
+    /// (exit status 1 if any is stale)
+    #[argh(switch)]
+    check_stale: bool,
+
+    /// leave outputs that are up to date with their sources untouched; their stamps are cached
+    /// in .strudel-of-lilypond-cache
+    #[argh(switch)]
+    incremental: bool,
+
+    /// input YAML sequence files (`./library` for a file named like a command)
+    #[argh(positional)]
+    inputs: Vec<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Library(LibraryCommand),
+    Setlist(SetlistCommand),
+}
+
+/// Fetch the libraries a sequence file declares into .strudel-libraries next to it
+#[derive(FromArgs)]
+#[argh(subcommand, name = "library")]
+struct LibraryCommand {
+    #[argh(subcommand)]
+    action: LibraryAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum LibraryAction {
+    Fetch(FetchCommand),
+    Update(UpdateCommand),
+}
+
+/// Fetch the libraries missing from the cache
+#[derive(FromArgs)]
+#[argh(subcommand, name = "fetch")]
+struct FetchCommand {
     /// input YAML sequence file
     #[argh(positional)]
     input: String,
 }
 
-fn main() {
-    let args: Args = argh::from_env();
+/// Fetch all the libraries again
+#[derive(FromArgs)]
+#[argh(subcommand, name = "update")]
+struct UpdateCommand {
+    /// input YAML sequence file
+    #[argh(positional)]
+    input: String,
+}
+
+/// Report the tempo, bars and duration of several sequences played one after the other
+#[derive(FromArgs)]
+#[argh(subcommand, name = "setlist")]
+struct SetlistCommand {
+    /// path to a library root directory (can be repeated)
+    #[argh(option)]
+    library: Vec<String>,
+
+    /// seed for sections with `order: shuffle` (defaults to a new seed on every run)
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// page title (defaults to Setlist)
+    #[argh(option)]
+    title: Option<String>,
+
+    /// drum machine sample bank (e.g. RolandTR909)
+    #[argh(option)]
+    bank: Option<String>,
 
-    let input_path = &args.input;
-    let libraries: Vec<PathBuf> = args.library.iter().map(PathBuf::from).collect();
+    /// gain of every voice (e.g. 0.8)
+    #[argh(option)]
+    gain: Option<String>,
+
+    /// color the voices and show them as a punchcard
+    #[argh(switch)]
+    punchcard: bool,
+
+    /// also write a page with the report and one player per song
+    #[argh(option)]
+    setlist_html: Option<String>,
 
-    let content = match fs::read_to_string(input_path) {
+    /// input YAML sequence files, in playing order
+    #[argh(positional)]
+    inputs: Vec<String>,
+}
+
+fn read_sequence(path: &str) -> (BarSequence, String) {
+    let content = match fs::read_to_string(path) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Error reading {input_path}: {e}");
+            eprintln!("Error reading {path}: {e}");
             std::process::exit(1);
         }
     };
 
-    let sequence: BarSequence = match serde_yaml::from_str(&content) {
-        Ok(s) => s,
+    match serde_yaml::from_str(&content) {
+        Ok(s) => (s, content),
         Err(e) => {
             eprintln!("Error parsing YAML: {e}");
             std::process::exit(1);
         }
+    }
+}
+
+/// `library fetch <file>` and `library update <file>`
+fn library_command(command: &LibraryCommand) {
+    let (update, path) = match &command.action {
+        LibraryAction::Fetch(fetch) => (false, &fetch.input),
+        LibraryAction::Update(update) => (true, &update.input),
+    };
+    let (sequence, _) = read_sequence(path);
+    match fetch_libraries(&sequence.libraries, &cache_dir(Path::new(path)), update) {
+        Ok(fetched) if fetched.is_empty() => eprintln!("Libraries up to date"),
+        Ok(fetched) => {
+            for name in fetched {
+                eprintln!("Fetched {name}");
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The --library roots, then the cache of the libraries the sequence declares
+fn sequence_libraries(roots: &[String], path: &str, sequence: &BarSequence) -> Vec<PathBuf> {
+    let mut libraries: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+    if !sequence.libraries.is_empty() {
+        let cache = cache_dir(Path::new(path));
+        if let Err(e) = check_fetched(&sequence.libraries, &cache) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        libraries.push(cache);
+    }
+    libraries
+}
+
+/// Options of the stamp: the parsed options changing the outputs, in a fixed order and one
+/// spelling, however they were written on the command line
+fn stamp_options(args: &Args) -> String {
+    let values = [
+        ("library", (!args.library.is_empty()).then(|| args.library.join(","))),
+        ("seed", args.seed.map(|seed| seed.to_string())),
+        ("stems", args.stems.then(String::new)),
+        ("title", args.title.clone()),
+        ("bank", args.bank.clone()),
+        ("gain", args.gain.clone()),
+        ("punchcard", args.punchcard.then(String::new)),
+        ("template", args.template.clone()),
+    ];
+    values
+        .into_iter()
+        .filter_map(|(name, value)| match value? {
+            value if value.is_empty() => Some(format!("--{name}")),
+            value => Some(format!("--{name} {value}")),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn file_stem(path: &str) -> &str {
+    Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or("output")
+}
+
+/// The `--seed` given, or a new one
+fn seed_or_new(seed: Option<u64>) -> u64 {
+    seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    })
+}
+
+/// `setlist <file>...`: the songs in the given order, each named after its file
+fn setlist_command(args: &SetlistCommand) {
+    if args.inputs.is_empty() {
+        eprintln!("Usage: strudel-of-lilypond-sequence setlist <sequence file>...");
+        std::process::exit(1);
+    }
+    let seed = seed_or_new(args.seed);
+    let songs: Vec<Song> = args
+        .inputs
+        .iter()
+        .map(|path| {
+            let (sequence, _) = read_sequence(path);
+            let libraries = sequence_libraries(&args.library, path, &sequence);
+            Song { name: file_stem(path).to_string(), sequence: arrange_sequence(&sequence, seed), libraries }
+        })
+        .collect();
+
+    match setlist_of_songs(&songs) {
+        Ok(setlist) => println!("{setlist}"),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.setlist_html {
+        let options = SequenceRenderOptions {
+            title: args.title.clone().unwrap_or_else(|| "Setlist".to_string()),
+            bank: args.bank.clone(),
+            gain: args.gain.clone(),
+            punchcard: args.punchcard,
+            ..Default::default()
+        };
+        let written = setlist_html(&songs, &options).and_then(|html| fs::write(path, html).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => eprintln!("Wrote {path}"),
+            Err(e) => {
+                eprintln!("Error writing {path}: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Args = argh::from_env();
+    match (&args.command, args.inputs.as_slice()) {
+        (Some(Command::Library(command)), []) => return library_command(command),
+        (Some(Command::Setlist(command)), []) => return setlist_command(command),
+        (None, [_, ..]) => {}
+        (Some(_), inputs) => {
+            eprintln!("Unexpected arguments before the command: {}", inputs.join(" "));
+            std::process::exit(1);
+        }
+        (None, []) => {
+            eprintln!("Missing the input sequence file (see --help)");
+            std::process::exit(1);
+        }
+    }
+
+    let template = match &args.template {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(e) => {
+                eprintln!("Error reading {path}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
 
-    // Write .ly file
+    let seed = seed_or_new(args.seed);
+    let cache_path = Path::new(StampCache::FILE_NAME);
+    let mut cache = if args.incremental { StampCache::load(cache_path) } else { StampCache::default() };
+    let mut stale = false;
+    for input_path in &args.inputs {
+        stale |= generate(&args, input_path, seed, &template, &mut cache);
+    }
+
+    if args.incremental
+        && !args.check_stale
+        && let Err(e) = cache.save(cache_path)
+    {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+    if stale {
+        std::process::exit(1);
+    }
+}
+
+/// Write the LilyPond and HTML files of one sequence, exiting on errors; with --check-stale,
+/// report whether they are up to date and return whether any is stale
+fn generate(args: &Args, input_path: &str, seed: u64, template: &Option<String>, cache: &mut StampCache) -> bool {
+    let (sequence, content) = read_sequence(input_path);
+    let libraries = sequence_libraries(&args.library, input_path, &sequence);
+
+    if sequence.sequence.iter().any(|s| s.order == Order::Shuffle) {
+        eprintln!("Shuffle seed: {seed}");
+    }
+    let sequence = arrange_sequence(&sequence, seed);
+
     let output_path = Path::new(input_path).with_extension("ly");
     let output_dir = output_path.parent().unwrap_or(Path::new("."));
 
@@ -51,33 +343,86 @@ fn main() {
         }
     };
 
-    match fs::write(&output_path, &ly_output) {
-        Ok(_) => eprintln!("Wrote {}", output_path.display()),
-        Err(e) => {
-            eprintln!("Error writing {}: {e}", output_path.display());
-            std::process::exit(1);
-        }
+    // Library patterns count through the LilyPond they expand to
+    let stamp = Stamp::new(&[&content, &ly_output, template.as_deref().unwrap_or_default()], &stamp_options(args));
+    let cache_key = output_path.display().to_string();
+    if args.incremental && !args.check_stale && cache.is_fresh(&cache_key, &stamp) {
+        eprintln!("unchanged: {cache_key}");
+        return false;
     }
+    let mut outputs: Vec<(PathBuf, String)> = vec![(output_path, ly_output)];
 
     // Generate Strudel HTML
-    let stem = Path::new(input_path).file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("output");
+    let stem = file_stem(input_path);
+
+    let options = SequenceRenderOptions {
+        title: args.title.clone().unwrap_or_else(|| stem.to_string()),
+        bank: args.bank.clone(),
+        gain: args.gain.clone(),
+        punchcard: args.punchcard,
+        template: template.clone(),
+        ..Default::default()
+    };
 
-    let html = match strudel_of_sequence(&sequence, &libraries, stem) {
+    let html = match strudel_of_sequence(&sequence, &libraries, &options) {
         Ok(h) => h,
         Err(e) => {
             eprintln!("Error generating Strudel: {e}");
             std::process::exit(1);
         }
     };
+    outputs.push((Path::new(input_path).with_extension("html"), html));
 
-    let html_path = Path::new(input_path).with_extension("html");
-    match fs::write(&html_path, &html) {
-        Ok(_) => println!("{}", html_path.display()),
-        Err(e) => {
-            eprintln!("Error writing {}: {e}", html_path.display());
-            std::process::exit(1);
+    if args.stems {
+        let stems = match strudel_stems_of_sequence(&sequence, &libraries, &options) {
+            Ok(stems) => stems,
+            Err(e) => {
+                eprintln!("Error generating Strudel stems: {e}");
+                std::process::exit(1);
+            }
+        };
+        for (name, html) in stems {
+            outputs.push((Path::new(input_path).with_file_name(format!("{stem}-{name}.html")), html));
+        }
+    }
+
+    let stale_reason = |path: &Path| match fs::read_to_string(path) {
+        Ok(text) => match Stamp::read(&text) {
+            Some(found) => found.staleness(&stamp),
+            None => Some("no stamp".to_string()),
+        },
+        Err(_) => Some("missing".to_string()),
+    };
+
+    if args.check_stale {
+        let mut stale = false;
+        for (path, _) in &outputs {
+            let reason = stale_reason(path);
+            match &reason {
+                Some(reason) => println!("stale: {} ({reason})", path.display()),
+                None => println!("up to date: {}", path.display()),
+            }
+            stale |= reason.is_some();
+        }
+        return stale;
+    }
+
+    for (i, (path, text)) in outputs.iter().enumerate() {
+        if args.incremental && stale_reason(path).is_none() {
+            eprintln!("unchanged: {}", path.display());
+            continue;
+        }
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match fs::write(path, stamp.apply(text, extension)) {
+            // The .ly file is reported on stderr, the pages on stdout
+            Ok(_) if i == 0 => eprintln!("Wrote {}", path.display()),
+            Ok(_) => println!("{}", path.display()),
+            Err(e) => {
+                eprintln!("Error writing {}: {e}", path.display());
+                std::process::exit(1);
+            }
         }
     }
+    cache.record(&cache_key, &stamp, outputs.iter().map(|(path, _)| path.display().to_string()).collect());
+    false
 }
@@ -0,0 +1,92 @@
+//! Snapshot checks over a directory of LilyPond files, to pin conversions across upgrades
+
+use std::path::{Path, PathBuf};
+
+use crate::{expand_includes, GeneratorOptions, LilyPondParser, Stamp, StrudelGenerator};
+
+/// A file of the corpus whose conversion does not match its snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The LilyPond file
+    pub source: PathBuf,
+    pub kind: MismatchKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// No `.str` file next to the `.ly` one
+    MissingSnapshot,
+    /// The file could not be read or converted
+    Error(String),
+    /// First line (1-based, stamp left out) where the conversion and the snapshot differ
+    Differs { line: usize, expected: String, actual: String },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = self.source.display();
+        match &self.kind {
+            MismatchKind::MissingSnapshot => write!(f, "{}: no snapshot", source),
+            MismatchKind::Error(e) => write!(f, "{}: {}", source, e),
+            MismatchKind::Differs { line, expected, actual } => {
+                write!(f, "{}, line {}:\n  expected: {}\n  actual:   {}", source, line, expected, actual)
+            }
+        }
+    }
+}
+
+/// Convert every `.ly` file of `dir` (includes expanded) and compare the code with the `.str`
+/// snapshot of the same name, as written by `--also-str` (the stamp line is not compared).
+/// Files are checked in name order; an empty result means the whole corpus matches.
+///
+/// ```no_run
+/// use strudel_of_lilypond::{corpus::check_corpus, GeneratorOptions};
+///
+/// let mismatches = check_corpus("tests/grooves".as_ref(), &GeneratorOptions::default()).unwrap();
+/// let report: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+/// assert!(mismatches.is_empty(), "{}", report.join("\n"));
+/// ```
+pub fn check_corpus(dir: &Path, options: &GeneratorOptions) -> Result<Vec<Mismatch>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("Cannot read {}: {}", dir.display(), e))?;
+    let mut sources: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ly"))
+        .collect();
+    sources.sort();
+
+    let options = GeneratorOptions { emit_prologue: true, ..options.clone() };
+    let mismatches = sources
+        .into_iter()
+        .filter_map(|source| {
+            let kind = check_file(&source, &options)?;
+            Some(Mismatch { source, kind })
+        })
+        .collect();
+    Ok(mismatches)
+}
+
+fn check_file(source: &Path, options: &GeneratorOptions) -> Option<MismatchKind> {
+    let Ok(expected) = std::fs::read_to_string(source.with_extension("str")) else {
+        return Some(MismatchKind::MissingSnapshot);
+    };
+    let actual = match convert(source, options) {
+        Ok(actual) => actual,
+        Err(e) => return Some(MismatchKind::Error(e)),
+    };
+
+    let unstamped = |text: &str| -> Vec<String> {
+        text.lines().filter(|line| Stamp::read(line).is_none()).map(str::to_string).collect()
+    };
+    let (expected, actual) = (unstamped(&expected), unstamped(&actual));
+    let line = (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))?;
+    let at = |lines: &[String]| lines.get(line).cloned().unwrap_or_else(|| "(end of file)".to_string());
+    Some(MismatchKind::Differs { line: line + 1, expected: at(&expected), actual: at(&actual) })
+}
+
+fn convert(source: &Path, options: &GeneratorOptions) -> Result<String, String> {
+    let code = std::fs::read_to_string(source).map_err(|e| format!("Cannot read: {}", e))?;
+    let base_dir = source.parent().unwrap_or(Path::new("."));
+    let code = expand_includes(&code, base_dir)?;
+    let result = LilyPondParser::new().parse(&code)?;
+    Ok(StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, options))
+}
@@ -0,0 +1,233 @@
+//! Event-level comparison of two parsed scores, bar by bar and staff by staff
+
+use crate::{DrumEvent, Length, ParseResult, PitchedEvent, Staff, StaffContent, StrudelGenerator, GeneratorOptions};
+
+/// One musical difference between two scores
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Tempo { old: String, new: String },
+    /// A staff (or drum voice) present only in the new score
+    StaffAdded { staff: String },
+    /// A staff (or drum voice) present only in the old score
+    StaffRemoved { staff: String },
+    /// Events of a bar that differ. Bar numbers are 1-based and counted after unfolding repeats.
+    BarChanged {
+        staff: String,
+        old_bar: usize,
+        new_bar: usize,
+        removed: Vec<String>,
+        added: Vec<String>,
+    },
+    /// A bar present only in the new score, with its events
+    BarAdded { staff: String, bar: usize, added: Vec<String> },
+    /// A bar present only in the old score, with its events
+    BarRemoved { staff: String, bar: usize, removed: Vec<String> },
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Tempo { old, new } => write!(f, "tempo: {} -> {}", old, new),
+            Difference::StaffAdded { staff } => write!(f, "{}: staff added", staff),
+            Difference::StaffRemoved { staff } => write!(f, "{}: staff removed", staff),
+            Difference::BarChanged { staff, old_bar, new_bar, removed, added } => {
+                if old_bar == new_bar {
+                    write!(f, "{}, bar {}:", staff, new_bar)?;
+                } else {
+                    write!(f, "{}, bar {} (was {}):", staff, new_bar, old_bar)?;
+                }
+                write_events(f, removed, added)
+            }
+            Difference::BarAdded { staff, bar, added } => {
+                write!(f, "{}, bar {} added:", staff, bar)?;
+                write_events(f, &[], added)
+            }
+            Difference::BarRemoved { staff, bar, removed } => {
+                write!(f, "{}, bar {} removed:", staff, bar)?;
+                write_events(f, removed, &[])
+            }
+        }
+    }
+}
+
+fn write_events(f: &mut std::fmt::Formatter<'_>, removed: &[String], added: &[String]) -> std::fmt::Result {
+    for event in removed {
+        write!(f, " -{}", event)?;
+    }
+    for event in added {
+        write!(f, " +{}", event)?;
+    }
+    Ok(())
+}
+
+/// Compare two scores at the event level. Staves are paired in order (drum voices separately),
+/// repeats are unfolded, and events are written `pitch:length` with the length in quarter notes
+/// (`c4:1`, `e4:1/2`, `~:2` for a rest, `bd:1` for a drum hit).
+pub fn diff_scores(old: &ParseResult, new: &ParseResult) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    let tempo = |r: &ParseResult| format!("{} = {}", r.tempo.beat_unit, r.tempo.bpm);
+    if tempo(old) != tempo(new) {
+        differences.push(Difference::Tempo { old: tempo(old), new: tempo(new) });
+    }
+
+    let old_parts = score_parts(old);
+    let new_parts = score_parts(new);
+    for i in 0..old_parts.len().max(new_parts.len()) {
+        match (old_parts.get(i), new_parts.get(i)) {
+            (Some((_, old_bars)), Some((staff, new_bars))) => diff_bars(staff, old_bars, new_bars, &mut differences),
+            (Some((staff, _)), None) => differences.push(Difference::StaffRemoved { staff: staff.clone() }),
+            (None, Some((staff, _))) => differences.push(Difference::StaffAdded { staff: staff.clone() }),
+            (None, None) => {}
+        }
+    }
+
+    differences
+}
+
+type Bars = Vec<Vec<String>>;
+
+/// Label and bars of every staff, drum voices as parts of their own
+fn score_parts(result: &ParseResult) -> Vec<(String, Bars)> {
+    let mut parts = Vec::new();
+
+    for (index, staff) in result.staves.iter().enumerate() {
+        let mut staff: Staff = staff.clone();
+        staff.unfold_repeats();
+        let label = StrudelGenerator::staff_label(&staff, index);
+
+        match &staff.content {
+            StaffContent::Notes(events) => parts.push((label, pitched_bars(events))),
+            StaffContent::Drums(voices) => {
+                for (v, voice) in voices.iter().enumerate() {
+                    let voice_label = if voices.len() > 1 { format!("{} (voice {})", label, v + 1) } else { label.clone() };
+                    parts.push((voice_label, drum_bars(&voice.events)));
+                }
+            }
+        }
+    }
+
+    parts
+}
+
+fn format_length(length: Length) -> String {
+    if length.den == 1 { length.num.to_string() } else { format!("{}/{}", length.num, length.den) }
+}
+
+fn pitched_bars(events: &[PitchedEvent]) -> Bars {
+    let options = GeneratorOptions::default();
+    let mut bars: Bars = vec![Vec::new()];
+    for event in events {
+        let token = match event {
+            PitchedEvent::Note(n) => {
+                let mut pitches = vec![StrudelGenerator::format_note(n, &options)];
+                pitches.extend(n.chord_notes.iter().flatten().map(|c| StrudelGenerator::format_note(c, &options)));
+                let pitch = if pitches.len() > 1 { format!("<{}>", pitches.join(" ")) } else { pitches.remove(0) };
+                format!("{}:{}", pitch, format_length(n.quarters()))
+            }
+            PitchedEvent::Rest { duration, length } => {
+                format!("~:{}", format_length(length.unwrap_or_else(|| Length::of_duration(*duration, 0))))
+            }
+            PitchedEvent::BarLine => {
+                bars.push(Vec::new());
+                continue;
+            }
+            _ => continue,
+        };
+        bars.last_mut().unwrap().push(token);
+    }
+    bars.retain(|bar| !bar.is_empty());
+    bars
+}
+
+fn drum_bars(events: &[DrumEvent]) -> Bars {
+    let mut bars: Bars = vec![Vec::new()];
+    for event in events {
+        let token = match event {
+            DrumEvent::Hit(hit) => format!("{}:{}", hit.name, format_length(hit.quarters())),
+            DrumEvent::Rest { duration, length } => {
+                format!("~:{}", format_length(length.unwrap_or_else(|| Length::of_duration(*duration, 0))))
+            }
+            DrumEvent::BarLine => {
+                bars.push(Vec::new());
+                continue;
+            }
+            _ => continue,
+        };
+        bars.last_mut().unwrap().push(token);
+    }
+    bars.retain(|bar| !bar.is_empty());
+    bars
+}
+
+/// Align the bars of both versions, so an inserted bar does not show every later bar as changed
+fn diff_bars(staff: &str, old: &Bars, new: &Bars, differences: &mut Vec<Difference>) {
+    let mut matches = common_subsequence(old, new);
+    matches.push((old.len(), new.len()));
+
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in matches {
+        // Unmatched bars in between: pair them up as changed, the rest was removed or added
+        while i < next_i || j < next_j {
+            let staff = staff.to_string();
+            let (in_old, in_new) = (i < next_i, j < next_j);
+            differences.push(if in_old && in_new {
+                let kept = common_subsequence(&old[i], &new[j]);
+                Difference::BarChanged {
+                    staff,
+                    old_bar: i + 1,
+                    new_bar: j + 1,
+                    removed: unmatched(&old[i], kept.iter().map(|&(a, _)| a)),
+                    added: unmatched(&new[j], kept.iter().map(|&(_, b)| b)),
+                }
+            } else if in_old {
+                Difference::BarRemoved { staff, bar: i + 1, removed: old[i].clone() }
+            } else {
+                Difference::BarAdded { staff, bar: j + 1, added: new[j].clone() }
+            });
+            i += in_old as usize;
+            j += in_new as usize;
+        }
+        i = next_i + 1;
+        j = next_j + 1;
+    }
+}
+
+fn unmatched(events: &[String], matched: impl Iterator<Item = usize>) -> Vec<String> {
+    let matched: Vec<usize> = matched.collect();
+    events
+        .iter()
+        .enumerate()
+        .filter(|(k, _)| !matched.contains(k))
+        .map(|(_, e)| e.clone())
+        .collect()
+}
+
+/// Index pairs of a longest common subsequence of `a` and `b`
+fn common_subsequence<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
@@ -1,43 +1,309 @@
+pub mod corpus;
+pub mod diff;
 pub mod sequencer;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+/// Exact musical length in quarter notes, as a reduced fraction (`3/2` is a dotted quarter)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Length {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Length {
+    /// `num/den` reduced; a zero denominator counts as 1
+    pub fn new(num: u32, den: u32) -> Self {
+        Self::rounded(num.into(), den.into())
+    }
+
+    /// `num/den` reduced, `None` for a zero denominator or a fraction too fine for `u32` terms
+    fn checked(num: u128, den: u128) -> Option<Self> {
+        if den == 0 {
+            return None;
+        }
+        let divisor = gcd(num, den);
+        Some(Length { num: u32::try_from(num / divisor).ok()?, den: u32::try_from(den / divisor).ok()? })
+    }
+
+    /// `num/den` reduced, rounded to the nearest fraction with `u32` terms when too fine (and to
+    /// `u32::MAX` quarters when too long)
+    fn rounded(num: u128, den: u128) -> Self {
+        let den = den.max(1);
+        let max = u128::from(u32::MAX);
+        Self::checked(num, den).unwrap_or_else(|| {
+            let whole = num / den;
+            if whole >= max {
+                return Length { num: u32::MAX, den: 1 };
+            }
+            // The largest denominator that keeps the numerator in a `u32`
+            let rounded_den = max / (whole + 1);
+            let rounded_num = (num.saturating_mul(rounded_den) + den / 2) / den;
+            Self::checked(rounded_num.min(max), rounded_den).unwrap_or(Length { num: u32::MAX, den: 1 })
+        })
+    }
+
+    /// Length of a written note value (`4` = quarter, `8` = eighth) with augmentation dots
+    pub fn of_duration(duration: u32, dots: u32) -> Self {
+        // Each dot adds half of the previous value: base * (2 - 1/2^dots)
+        let factor = 1u128 << dots.min(8);
+        Self::rounded(4 * (2 * factor - 1), u128::from(duration.max(1)) * factor)
+    }
+
+    /// Sum, `None` when it is too fine to be exact (see [`Length::plus`])
+    pub fn checked_plus(self, other: Length) -> Option<Self> {
+        let (a, b) = self.cross(other);
+        Self::checked(a + b, u128::from(self.den) * u128::from(other.den))
+    }
+
+    /// Sum, rounded when too fine to be exact (deeply nested tuplets)
+    pub fn plus(self, other: Length) -> Self {
+        let (a, b) = self.cross(other);
+        Self::rounded(a + b, u128::from(self.den) * u128::from(other.den))
+    }
+
+    /// Product, `None` when it is too fine to be exact (see [`Length::times`])
+    pub fn checked_times(self, other: Length) -> Option<Self> {
+        Self::checked(u128::from(self.num) * u128::from(other.num), u128::from(self.den) * u128::from(other.den))
+    }
+
+    /// Product, rounded when too fine to be exact
+    pub fn times(self, other: Length) -> Self {
+        Self::rounded(u128::from(self.num) * u128::from(other.num), u128::from(self.den) * u128::from(other.den))
+    }
+
+    /// What is left after taking `other` away, `None` unless `other` is shorter
+    pub fn minus(self, other: Length) -> Option<Self> {
+        let (a, b) = self.cross(other);
+        (a > b).then(|| Self::rounded(a - b, u128::from(self.den) * u128::from(other.den)))
+    }
+
+    /// How far `self` is into the current `span`: what is left after taking out as many whole
+    /// `span`s as it holds
+    pub fn offset_in(self, span: Length) -> Self {
+        let (a, b) = self.cross(span);
+        Self::rounded(a % b.max(1), u128::from(self.den) * u128::from(span.den))
+    }
+
+    /// Both numerators over the common denominator `self.den * other.den`
+    fn cross(self, other: Length) -> (u128, u128) {
+        (u128::from(self.num) * u128::from(other.den), u128::from(other.num) * u128::from(self.den))
+    }
+
+    pub fn as_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Note {
     pub name: char,
     pub octave: i32,
     pub accidental: Option<String>,
+    /// Written note value (`4` = quarter), without dots
     pub duration: u32,
-    #[allow(dead_code)]
     pub midi: i32,
     /// Additional notes if this is a chord (first note is self)
     pub chord_notes: Option<Vec<Note>>,
+    /// Exact length when `duration` alone does not describe it (dots, merged ties)
+    pub length: Option<Length>,
+    /// Tied to the next note, when the tie could not be merged (e.g. across a bar line)
+    pub tied: bool,
+    /// Manual beam (`c8[ d e f]`) starting or ending on this note
+    pub beam: Option<Beam>,
+    /// Slur (`c( d e)`) starting or ending on this note: the notes it spans are played legato
+    pub slur: Option<Slur>,
+    /// Dynamic mark attached to the note (`ff`, `p`, ...)
+    pub dynamic: Option<String>,
+    /// Hairpin starting or ending on this note
+    pub hairpin: Option<Hairpin>,
+    /// Articulations attached to the note (`accent`, `staccato`, ...)
+    pub articulations: Vec<String>,
+    /// Grace note (`\grace`, `\acciaccatura`, `\appoggiatura`): its time is taken from a
+    /// neighbouring note when generating, see [`GraceTiming`]
+    pub grace: bool,
 }
 
-#[derive(Debug, Clone)]
+/// End of a manual beam, `[` or `]` after the duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beam {
+    Start,
+    End,
+}
+
+/// End of a slur, `(` or `)` after the duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slur {
+    Start,
+    End,
+}
+
+/// Hairpin mark after the duration: `\<`, `\>` or `\!`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hairpin {
+    Crescendo,
+    Decrescendo,
+    /// End of a hairpin without a dynamic mark
+    End,
+}
+
+impl Note {
+    /// Length in quarter notes
+    pub fn quarters(&self) -> Length {
+        self.length.unwrap_or_else(|| Length::of_duration(self.duration, 0))
+    }
+
+    /// Symbol of the chord (`Cmaj7`, `Am/C`) from its pitch classes, preferring the lowest note
+    /// as the root; `None` for single notes and chords of no known quality
+    pub fn chord_symbol(&self) -> Option<String> {
+        const QUALITIES: [(&str, &[i32]); 15] = [
+            ("", &[0, 4, 7]),
+            ("m", &[0, 3, 7]),
+            ("dim", &[0, 3, 6]),
+            ("aug", &[0, 4, 8]),
+            ("sus2", &[0, 2, 7]),
+            ("sus4", &[0, 5, 7]),
+            ("5", &[0, 7]),
+            ("6", &[0, 4, 7, 9]),
+            ("m6", &[0, 3, 7, 9]),
+            ("7", &[0, 4, 7, 10]),
+            ("maj7", &[0, 4, 7, 11]),
+            ("m7", &[0, 3, 7, 10]),
+            ("mMaj7", &[0, 3, 7, 11]),
+            ("m7b5", &[0, 3, 6, 10]),
+            ("dim7", &[0, 3, 6, 9]),
+        ];
+        let name = |n: &Note| {
+            let accidental = match n.accidental.as_deref() {
+                Some("is") => "#",
+                Some("es") => "b",
+                _ => "",
+            };
+            format!("{}{}", n.name.to_ascii_uppercase(), accidental)
+        };
+
+        let mut notes: Vec<&Note> = std::iter::once(self).chain(self.chord_notes.iter().flatten()).collect();
+        notes.sort_by_key(|n| n.midi);
+        for root in &notes {
+            let mut intervals: Vec<i32> = notes.iter().map(|n| (n.midi - root.midi).rem_euclid(12)).collect();
+            intervals.sort();
+            intervals.dedup();
+            if let Some((quality, _)) = QUALITIES.iter().find(|(_, q)| *q == intervals.as_slice()) {
+                let bass = if root.midi == notes[0].midi { String::new() } else { format!("/{}", name(notes[0])) };
+                return Some(format!("{}{}{}", name(root), quality, bass));
+            }
+        }
+        None
+    }
+
+    /// The note (and its chord notes) moved by `semitones`, spelled by the interval:
+    /// up 2 turns `f` into `g` and `b` into `cis`. Spellings that would need a double
+    /// accidental use the enharmonic with sharps going up, flats going down.
+    pub fn transpose(&self, semitones: i32) -> Note {
+        const LETTERS: [char; 7] = ['c', 'd', 'e', 'f', 'g', 'a', 'b'];
+        const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+        // Letter steps of the interval: minor/major second = 1, thirds = 2, fourth and tritone = 3, ...
+        const STEPS: [i32; 12] = [0, 1, 1, 2, 2, 3, 3, 4, 5, 5, 6, 6];
+
+        let midi = self.midi + semitones;
+        let letter = LETTERS.iter().position(|&l| l == self.name).unwrap_or(0) as i32;
+        let steps = STEPS[semitones.rem_euclid(12) as usize] + 7 * semitones.div_euclid(12);
+        let mut index = (letter + steps).rem_euclid(7) as usize;
+        let mut alteration = (midi - NATURALS[index]).rem_euclid(12);
+        if alteration > 6 {
+            alteration -= 12;
+        }
+        if alteration.abs() > 1 {
+            let pitch_class = midi.rem_euclid(12);
+            index = match NATURALS.iter().position(|&n| n == pitch_class) {
+                Some(natural) => natural,
+                None if semitones >= 0 => NATURALS.iter().position(|&n| n == pitch_class - 1).unwrap(),
+                None => NATURALS.iter().position(|&n| n == pitch_class + 1).unwrap(),
+            };
+            alteration = pitch_class - NATURALS[index];
+        }
+
+        Note {
+            name: LETTERS[index],
+            octave: (midi - NATURALS[index] - alteration).div_euclid(12) - 1,
+            accidental: match alteration {
+                1 => Some("is".to_string()),
+                -1 => Some("es".to_string()),
+                _ => None,
+            },
+            midi,
+            chord_notes: self.chord_notes.as_ref().map(|notes| notes.iter().map(|n| n.transpose(semitones)).collect()),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct DrumHit {
     pub name: String,
+    /// Written note value (`4` = quarter), without dots
     pub duration: u32,
+    /// Exact length when `duration` alone does not describe it (dots)
+    pub length: Option<Length>,
+    /// Articulations attached to the hit (`accent`, `staccato`, ...)
+    pub articulations: Vec<String>,
+    /// Dynamic mark attached to the hit (`ff`, `p`, ...)
+    pub dynamic: Option<String>,
+    /// Hairpin starting or ending on this hit
+    pub hairpin: Option<Hairpin>,
+    /// Manual beam (`hh8[ hh hh hh]`) starting or ending on this hit
+    pub beam: Option<Beam>,
+    /// Hand playing the hit, from a text script made of `R` and `L` (`^"R"`, `^"L"`, `^"RL"`)
+    pub sticking: Option<String>,
+    /// Grace hit (`\acciaccatura sn16`, a flam or drag): its time is taken from a neighbouring
+    /// hit when generating, as for [`Note::grace`]
+    pub grace: bool,
+}
+
+impl DrumHit {
+    /// Length in quarter notes
+    pub fn quarters(&self) -> Length {
+        self.length.unwrap_or_else(|| Length::of_duration(self.duration, 0))
+    }
+}
+
+/// Length of a rest event in quarter notes
+fn rest_quarters(duration: u32, length: &Option<Length>) -> Length {
+    length.unwrap_or_else(|| Length::of_duration(duration, 0))
 }
 
 #[derive(Debug, Clone)]
 pub enum PitchedEvent {
     Note(Note),
-    Rest { duration: u32 },
+    Rest { duration: u32, length: Option<Length> },
     BarLine,
     RepeatStart(u32),
     RepeatEnd,
+    /// Start of an `\alternative` ending, right after the `RepeatEnd` of its repeat or the
+    /// `AlternativeEnd` of the ending before
+    AlternativeStart,
+    AlternativeEnd,
+    /// Start of an unmetered passage (`\cadenzaOn`), played as one free bar
+    CadenzaOn,
+    CadenzaOff,
+    /// `\time 7/8`: the meter of the bars that follow
+    Time(TimeSignature),
     Comment(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum DrumEvent {
     Hit(DrumHit),
-    Rest { duration: u32 },
+    Rest { duration: u32, length: Option<Length> },
     BarLine,
     RepeatStart(u32),
     RepeatEnd,
+    AlternativeStart,
+    AlternativeEnd,
+    CadenzaOn,
+    CadenzaOff,
+    Time(TimeSignature),
     Comment(String),
 }
 
@@ -47,6 +313,29 @@ pub struct DrumVoiceData {
     pub punchcard_color: Option<String>,
     pub gain: Option<String>,
     pub pan: Option<String>,
+    pub speed: Option<Speed>,
+    /// Label of the voice (`kick`, `hats`), written as a comment above its part of the stack
+    pub name: Option<String>,
+}
+
+/// Playback speed change set with `% @strudel-of-lilypond@ slow 2` / `fast 2`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Speed {
+    /// `.slow(factor)`, e.g. a half-time feel
+    Slow(String),
+    /// `.fast(factor)`, e.g. double-time hats
+    Fast(String),
+}
+
+/// Tempo multiplier over a range of bars, from `% @strudel-of-lilypond@ rubato 5-8=0.8 9=0.5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rubato {
+    /// First bar of the range (1-based, counted after unfolding repeats)
+    pub first_bar: u32,
+    /// Last bar of the range, included
+    pub last_bar: u32,
+    /// Multiplier of the tempo, as written (`0.8` plays the bars 20% slower)
+    pub factor: String,
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +344,54 @@ pub struct Tempo {
     pub bpm: u32,
 }
 
+/// Time signature from `\time 3/4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    /// Beats per bar
+    pub count: u32,
+    /// Note value of a beat (`4` = quarter)
+    pub unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature { count: 4, unit: 4 }
+    }
+}
+
+impl TimeSignature {
+    /// Length of a bar in 4/4 bars (`7/8` is 7/8 of a 4/4 bar), the weight the generator gives it
+    pub fn bar_weight(self) -> Length {
+        Length::new(self.count, self.unit)
+    }
+
+    /// Length of a beat in quarters: a dotted note in compound meters (`6/8` has two dotted
+    /// quarters), the written unit otherwise, but at least a quarter
+    pub fn beat(self) -> Length {
+        if self.unit >= 8 && self.count.is_multiple_of(3) {
+            Length::new(12, self.unit)
+        } else {
+            Length::new(4, self.unit.min(4))
+        }
+    }
+}
+
+/// Key signature from `\key g \major`. It only affects the engraving: LilyPond note names are
+/// absolute (`f` is F natural in G major), so the pitches are not changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySignature {
+    /// Tonic as written (`g`, `bes`)
+    pub tonic: String,
+    /// `major`, `minor` or a church mode (`dorian`, ...)
+    pub mode: String,
+}
+
+impl std::fmt::Display for KeySignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.tonic, self.mode)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum StaffKind {
     Pitched,
@@ -75,6 +412,32 @@ pub struct Staff {
     pub punchcard_color: Option<String>,
     pub gain: Option<String>,
     pub pan: Option<String>,
+    /// Octaves added to every note at generation time (`% @strudel-of-lilypond@ octave -1`)
+    pub octave_shift: i32,
+    pub speed: Option<Speed>,
+    /// Context name from `\new Staff = "lead"`
+    pub name: Option<String>,
+    /// `instrumentName` from the `\with { ... }` block or a `\set Staff.instrumentName`
+    pub instrument: Option<String>,
+    /// Sampler playing a pitched staff instead of the piano (`% @strudel-of-lilypond@ sample name`)
+    pub sample: Option<Sample>,
+    /// How the repeats of this staff are written, over [`GeneratorOptions::repeat_style`]
+    /// (`% @strudel-of-lilypond@ repeats unrolled`)
+    pub repeat_style: Option<RepeatStyle>,
+    /// First key signature of a pitched staff
+    pub key: Option<KeySignature>,
+    /// Why the staff could not be parsed, in lenient mode (see [`LilyPondParser::lenient`]); it
+    /// has no content and is written as a comment
+    pub error: Option<String>,
+}
+
+/// Sample set with `% @strudel-of-lilypond@ sample mysample` (`.s("mysample")`), or
+/// `sample mysample n` to play the pitches as sample numbers: `n()` counts the semitones
+/// above the lowest note of the staff, for sliced or multi-sample instruments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub slices: bool,
 }
 
 impl Staff {
@@ -85,6 +448,14 @@ impl Staff {
             punchcard_color: None,
             gain: None,
             pan: None,
+            octave_shift: 0,
+            speed: None,
+            name: None,
+            instrument: None,
+            sample: None,
+            repeat_style: None,
+            key: None,
+            error: None,
         }
     }
 
@@ -100,6 +471,14 @@ impl Staff {
             punchcard_color,
             gain,
             pan,
+            octave_shift: 0,
+            speed: None,
+            name: None,
+            instrument: None,
+            sample: None,
+            repeat_style: None,
+            key: None,
+            error: None,
         }
     }
 
@@ -110,6 +489,14 @@ impl Staff {
             punchcard_color: None,
             gain: None,
             pan: None,
+            octave_shift: 0,
+            speed: None,
+            name: None,
+            instrument: None,
+            sample: None,
+            repeat_style: None,
+            key: None,
+            error: None,
         }
     }
 
@@ -126,167 +513,2233 @@ impl Staff {
             _ => None,
         }
     }
-}
-
-#[derive(Debug)]
-pub struct ParseResult {
-    pub staves: Vec<Staff>,
-    pub tempo: Tempo,
-}
 
-impl ParseResult {
-    /// For backwards compatibility: returns all notes flattened (excluding bar lines and repeats)
-    pub fn notes(&self) -> Vec<Note> {
-        self.staves
-            .iter()
-            .filter_map(|s| s.events())
-            .flat_map(|events| {
-                events.iter().filter_map(|e| match e {
-                    PitchedEvent::Note(n) => Some(n.clone()),
-                    _ => None,
+    /// Snap every note, rest and hit onto a grid of `grid` steps per whole note,
+    /// see [`quantize_pitched`]; returns the warnings of the dropped notes. A grid of 0 leaves
+    /// the staff unchanged.
+    pub fn quantize(&mut self, grid: u32) -> Vec<Warning> {
+        if grid == 0 {
+            return Vec::new();
+        }
+        match &mut self.content {
+            StaffContent::Notes(events) => {
+                let (quantized, warnings) = quantize_pitched(events, grid);
+                *events = quantized;
+                warnings
+            }
+            StaffContent::Drums(voices) => voices
+                .iter_mut()
+                .flat_map(|voice| {
+                    let (quantized, warnings) = quantize_drums(&voice.events, grid);
+                    voice.events = quantized;
+                    warnings
                 })
-            })
-            .collect()
+                .collect(),
+        }
     }
-}
-
-#[derive(Clone)]
-enum VariableKind {
-    Pitched(String),
-    Drums(String),
-}
 
-/// Expand `\include "file.ly"` directives by recursively inlining file contents.
-pub fn expand_includes(code: &str, base_dir: &Path) -> Result<String, String> {
-    let mut seen = HashSet::new();
-    expand_includes_recursive(code, base_dir, &mut seen)
-}
+    /// Move every note of a pitched staff by `semitones` (drum staves are left alone)
+    pub fn transpose(&mut self, semitones: i32) {
+        if let StaffContent::Notes(events) = &mut self.content {
+            for event in events.iter_mut() {
+                if let PitchedEvent::Note(n) = event {
+                    *n = n.transpose(semitones);
+                }
+            }
+        }
+    }
 
-fn expand_includes_recursive(
-    code: &str,
-    base_dir: &Path,
-    seen: &mut HashSet<PathBuf>,
-) -> Result<String, String> {
-    let re = regex::Regex::new(r#"\\include\s+"([^"]+)""#).unwrap();
-    let mut result = code.to_string();
+    /// Replace every repeat by its repeated content (like LilyPond's `\unfoldRepeats`)
+    pub fn unfold_repeats(&mut self) {
+        match &mut self.content {
+            StaffContent::Notes(events) => *events = unfold_pitched_repeats(events),
+            StaffContent::Drums(voices) => {
+                for voice in voices.iter_mut() {
+                    voice.events = unfold_drum_repeats(&voice.events);
+                }
+            }
+        }
+    }
 
-    loop {
-        let Some(caps) = re.captures(&result) else {
-            break;
-        };
+    /// Bar count of every drum voice (empty for a pitched staff)
+    pub fn voice_bars(&self) -> Vec<u32> {
+        self.drum_voices()
+            .map(|voices| voices.iter().map(|v| StrudelGenerator::count_bars(&v.events, &mut 0)).collect())
+            .unwrap_or_default()
+    }
 
-        let full_match = caps.get(0).unwrap();
-        let file_name = caps.get(1).unwrap().as_str();
-        let file_path = base_dir.join(file_name);
+    /// Append whole-bar rests to the drum voices shorter than the longest one, so that a stack
+    /// of voices stays aligned over the cycle
+    pub fn pad_short_voices(&mut self) {
+        let bars = self.voice_bars();
+        let longest = bars.iter().copied().max().unwrap_or(0);
+        if let StaffContent::Drums(voices) = &mut self.content {
+            for (voice, count) in voices.iter_mut().zip(bars) {
+                for _ in count..longest {
+                    voice.events.push(DrumEvent::BarLine);
+                    voice.events.push(DrumEvent::Rest { duration: 1, length: None });
+                }
+            }
+        }
+    }
 
-        let canonical = file_path
-            .canonicalize()
-            .map_err(|e| format!("Cannot resolve include \"{}\": {}", file_name, e))?;
+    /// Play the drum hits with other sounds: `map` goes from a LilyPond drum name (`sn`) or the
+    /// Strudel sound it plays (`sd`) to the sound to play instead (`cp`)
+    pub fn map_drums(&mut self, map: &BTreeMap<String, String>) {
+        let map: HashMap<String, &String> =
+            map.iter().map(|(from, to)| (LilyPondParser::lilypond_to_strudel_drum(from), to)).collect();
+        if let StaffContent::Drums(voices) = &mut self.content {
+            for event in voices.iter_mut().flat_map(|v| v.events.iter_mut()) {
+                if let DrumEvent::Hit(hit) = event
+                    && let Some(&to) = map.get(&hit.name)
+                {
+                    hit.name = to.clone();
+                }
+            }
+        }
+    }
 
-        if !seen.insert(canonical.clone()) {
-            return Err(format!("Circular include detected: \"{}\"", file_name));
+    /// Keep bars `start..end` (0-based, counted after unfolding repeats)
+    pub fn slice_bars(&mut self, start: usize, end: usize) {
+        // The meter in effect at `start` carries over into the slice
+        fn slice<E: Clone>(bars: Vec<Vec<E>>, start: usize, end: usize, bar_line: E, is_time: impl Fn(&E) -> bool) -> Vec<E> {
+            let end = end.min(bars.len());
+            let start = start.min(end);
+            let time = bars[..start].iter().flatten().filter(|e| is_time(e)).last().cloned();
+            time.into_iter().chain(bars[start..end].join(&bar_line)).collect()
         }
 
-        let content = std::fs::read_to_string(&canonical)
-            .map_err(|e| format!("Cannot read include \"{}\": {}", file_name, e))?;
+        self.unfold_repeats();
+        match &mut self.content {
+            StaffContent::Notes(events) => {
+                *events = slice(pitched_bars(events), start, end, PitchedEvent::BarLine, |e| {
+                    matches!(e, PitchedEvent::Time(_))
+                });
+            }
+            StaffContent::Drums(voices) => {
+                for voice in voices.iter_mut() {
+                    voice.events = slice(drum_bars(&voice.events), start, end, DrumEvent::BarLine, |e| {
+                        matches!(e, DrumEvent::Time(_))
+                    });
+                }
+            }
+        }
+    }
 
-        let child_base = canonical.parent().unwrap_or(base_dir);
-        let expanded = expand_includes_recursive(&content, child_base, seen)?;
+    /// The first `count` bars as one staff each, the same as [`slice_bars`](Self::slice_bars)
+    /// of every bar would give, unfolding and splitting the staff only once
+    fn bar_slices(&self, count: usize) -> Vec<Staff> {
+        fn slices<E: Clone>(bars: Vec<Vec<E>>, count: usize, is_time: impl Fn(&E) -> bool) -> Vec<Vec<E>> {
+            let mut time = None;
+            (0..count)
+                .map(|bar| {
+                    let bar = bars.get(bar).map_or(&[][..], Vec::as_slice);
+                    let slice = time.iter().chain(bar).cloned().collect();
+                    time = bar.iter().filter(|e| is_time(e)).last().cloned().or(time.take());
+                    slice
+                })
+                .collect()
+        }
 
-        result = format!(
-            "{}{}{}",
-            &result[..full_match.start()],
-            expanded,
-            &result[full_match.end()..]
-        );
+        let mut staff = self.clone();
+        staff.unfold_repeats();
+        match std::mem::replace(&mut staff.content, StaffContent::Notes(Vec::new())) {
+            StaffContent::Notes(events) => slices(pitched_bars(&events), count, |e| matches!(e, PitchedEvent::Time(_)))
+                .into_iter()
+                .map(|events| Staff { content: StaffContent::Notes(events), ..staff.clone() })
+                .collect(),
+            StaffContent::Drums(voices) => {
+                let mut bars: Vec<Vec<DrumVoiceData>> = vec![Vec::new(); count];
+                for voice in voices {
+                    let voice_slices = slices(drum_bars(&voice.events), count, |e| matches!(e, DrumEvent::Time(_)));
+                    for (bar, events) in bars.iter_mut().zip(voice_slices) {
+                        bar.push(DrumVoiceData { events, ..voice.clone() });
+                    }
+                }
+                bars.into_iter().map(|voices| Staff { content: StaffContent::Drums(voices), ..staff.clone() }).collect()
+            }
+        }
     }
 
-    Ok(result)
-}
+    /// Meter change (`\time`) of every bar, repeats unfolded; the longest voice of a drum staff
+    fn meter_changes(&self) -> Vec<Option<TimeSignature>> {
+        fn last_time<E>(bar: &[E], time: impl Fn(&E) -> Option<TimeSignature>) -> Option<TimeSignature> {
+            bar.iter().rev().find_map(time)
+        }
 
-pub struct LilyPondParser {
-    note_to_midi: HashMap<char, i32>,
-}
+        let mut staff = self.clone();
+        staff.unfold_repeats();
+        match &staff.content {
+            StaffContent::Notes(events) => pitched_bars(events)
+                .iter()
+                .map(|bar| last_time(bar, |e| match e {
+                    PitchedEvent::Time(t) => Some(*t),
+                    _ => None,
+                }))
+                .collect(),
+            StaffContent::Drums(voices) => voices
+                .iter()
+                .map(|voice| drum_bars(&voice.events))
+                .max_by_key(Vec::len)
+                .unwrap_or_default()
+                .iter()
+                .map(|bar| last_time(bar, |e| match e {
+                    DrumEvent::Time(t) => Some(*t),
+                    _ => None,
+                }))
+                .collect(),
+        }
+    }
 
-impl LilyPondParser {
-    pub fn new() -> Self {
-        let mut note_to_midi = HashMap::new();
-        note_to_midi.insert('c', 0);
-        note_to_midi.insert('d', 2);
-        note_to_midi.insert('e', 4);
-        note_to_midi.insert('f', 5);
-        note_to_midi.insert('g', 7);
-        note_to_midi.insert('a', 9);
-        note_to_midi.insert('b', 11);
+    /// Comments (section names, loop markers) with the bar they start, counted like
+    /// [`slice_bars`](Self::slice_bars)
+    fn comment_bars(&self) -> Vec<(usize, String)> {
+        let mut staff = self.clone();
+        staff.unfold_repeats();
+        match &staff.content {
+            StaffContent::Notes(events) => comment_bars(
+                events,
+                |e| matches!(e, PitchedEvent::BarLine),
+                |e| matches!(e, PitchedEvent::Note(_) | PitchedEvent::Rest { .. }),
+                |e| match e {
+                    PitchedEvent::Comment(text) => Some(text.as_str()),
+                    _ => None,
+                },
+            ),
+            StaffContent::Drums(voices) => voices
+                .iter()
+                .flat_map(|voice| {
+                    comment_bars(
+                        &voice.events,
+                        |e| matches!(e, DrumEvent::BarLine),
+                        |e| matches!(e, DrumEvent::Hit(_) | DrumEvent::Rest { .. }),
+                        |e| match e {
+                            DrumEvent::Comment(text) => Some(text.as_str()),
+                            _ => None,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
 
-        LilyPondParser { note_to_midi }
+    /// Drop the `loop start` / `loop end` markers
+    fn remove_loop_markers(&mut self) {
+        let is_marker = |text: &str| text == LOOP_START || text == LOOP_END;
+        match &mut self.content {
+            StaffContent::Notes(events) => {
+                events.retain(|e| !matches!(e, PitchedEvent::Comment(text) if is_marker(text)));
+            }
+            StaffContent::Drums(voices) => {
+                for voice in voices.iter_mut() {
+                    voice.events.retain(|e| !matches!(e, DrumEvent::Comment(text) if is_marker(text)));
+                }
+            }
+        }
     }
+}
 
-    pub fn parse(&self, code: &str) -> Result<ParseResult, String> {
-        let tempo = self.parse_tempo(code)
-            .ok_or("Missing tempo: LilyPond input must include a \\tempo directive (e.g., \\tempo 4 = 120)")?;
-        let variables = self.parse_variables(code);
-        let marked = self.mark_repeats(code);
-        let variables_marked: HashMap<String, VariableKind> = variables
-            .into_iter()
-            .map(|(k, v)| {
-                let marked_content = self.mark_repeats(match &v {
-                    VariableKind::Pitched(s) => s,
-                    VariableKind::Drums(s) => s,
-                });
-                let new_v = match v {
-                    VariableKind::Pitched(_) => VariableKind::Pitched(marked_content),
-                    VariableKind::Drums(_) => VariableKind::Drums(marked_content),
-                };
-                (k, new_v)
-            })
-            .collect();
+/// Comment texts of `% @strudel-of-lilypond@ loop start` / `loop end`, removed after parsing
+const LOOP_START: &str = "__LOOP_START__";
+const LOOP_END: &str = "__LOOP_END__";
+
+/// Start of the marker (`__PARSE_ERROR_0__`) left by a `\repeat` that could not be read in
+/// lenient mode, numbered after its error
+const PARSE_ERROR: &str = "__PARSE_ERROR_";
+
+/// Markers around every `\alternative` ending of a repeat
+const ALTERNATIVE_START: &str = "__ALTERNATIVE_START__";
+const ALTERNATIVE_END: &str = "__ALTERNATIVE_END__";
+
+/// Bar of every comment: the bar it is written in, or the next one when it follows the notes
+/// of its bar (a mark written just before a bar line belongs to the bar after it)
+fn comment_bars<E>(
+    events: &[E],
+    is_bar_line: impl Fn(&E) -> bool,
+    is_timed: impl Fn(&E) -> bool,
+    comment: impl Fn(&E) -> Option<&str>,
+) -> Vec<(usize, String)> {
+    let mut comments = Vec::new();
+    let mut bar = 0;
+    let mut has_content = false;
+    for event in events {
+        if is_bar_line(event) {
+            bar += has_content as usize;
+            has_content = false;
+        } else if is_timed(event) {
+            has_content = true;
+        } else if let Some(text) = comment(event) {
+            comments.push((bar + has_content as usize, text.to_string()));
+        }
+    }
+    comments
+}
 
-        // Try to parse score with staves first
-        if let Some(staves) = self.parse_score_staves(&marked, &variables_marked)? {
-            return Ok(ParseResult { staves, tempo });
+/// Split linear events into bars at their bar lines. Bars without notes or rests are
+/// dropped (like the bar count of the generator); their comments move on to the next bar.
+fn split_bars<E: Clone>(events: &[E], is_bar_line: impl Fn(&E) -> bool, is_timed: impl Fn(&E) -> bool) -> Vec<Vec<E>> {
+    let mut bars = Vec::new();
+    let mut current: Vec<E> = Vec::new();
+    for event in events {
+        if !is_bar_line(event) {
+            current.push(event.clone());
+        } else if current.iter().any(&is_timed) {
+            bars.push(std::mem::take(&mut current));
         }
+    }
+    if current.iter().any(&is_timed) {
+        bars.push(current);
+    }
+    bars
+}
 
-        // Fallback: parse as single staff
-        let notes_section = self.extract_notes_section(&marked)?;
-        let notes = self.parse_notes_from_section(&notes_section)?;
+fn pitched_bars(events: &[PitchedEvent]) -> Vec<Vec<PitchedEvent>> {
+    split_bars(
+        events,
+        |e| matches!(e, PitchedEvent::BarLine),
+        |e| matches!(e, PitchedEvent::Note(_) | PitchedEvent::Rest { .. }),
+    )
+}
 
-        Ok(ParseResult {
-            staves: vec![Staff::new_pitched(notes)],
-            tempo,
-        })
-    }
+fn drum_bars(events: &[DrumEvent]) -> Vec<Vec<DrumEvent>> {
+    split_bars(
+        events,
+        |e| matches!(e, DrumEvent::BarLine),
+        |e| matches!(e, DrumEvent::Hit(_) | DrumEvent::Rest { .. }),
+    )
+}
 
-    fn parse_variables(&self, code: &str) -> HashMap<String, VariableKind> {
-        let mut variables = HashMap::new();
+/// Linearize pitched events: `RepeatStart(n) ... RepeatEnd` becomes `n` copies of its content,
+/// each starting a new bar, its `\alternative` endings played in order after the body of the
+/// passes they close. Comments are kept in the first copy only.
+pub fn unfold_pitched_repeats(events: &[PitchedEvent]) -> Vec<PitchedEvent> {
+    unfold_events(events)
+}
 
-        // Parse regular variables: name = { ... }
-        let re = regex::Regex::new(r"(?m)^([a-zA-Z_][a-zA-Z0-9_]*)\s*=\s*\{").unwrap();
-        for caps in re.captures_iter(code) {
-            let name = caps.get(1).unwrap().as_str().to_string();
-            let brace_start = caps.get(0).unwrap().end() - 1;
+/// Linearize drum events, see [`unfold_pitched_repeats`]
+pub fn unfold_drum_repeats(events: &[DrumEvent]) -> Vec<DrumEvent> {
+    unfold_events(events)
+}
 
-            if let Some(content) = self.extract_braced_content(code, brace_start) {
-                variables.insert(name, VariableKind::Pitched(content));
+fn unfold_events<E: StaffEvent>(events: &[E]) -> Vec<E> {
+    let mut result = Vec::new();
+    let mut idx = 0;
+    while idx < events.len() {
+        match events[idx].view() {
+            EventView::RepeatStart(_) => {
+                let repeat = Repeat::read(events, idx);
+                for (times, pass) in repeat.passes() {
+                    let pass = unfold_events(&pass);
+                    for copy in 0..times {
+                        result.push(E::bar_line());
+                        result.extend(
+                            pass.iter().filter(|e| copy == 0 || !matches!(e.view(), EventView::Comment(_))).cloned(),
+                        );
+                    }
+                }
+                result.push(E::bar_line());
+                idx = repeat.end;
+                continue;
             }
+            EventView::RepeatEnd => break,
+            EventView::AlternativeStart | EventView::AlternativeEnd => {}
+            _ => result.push(events[idx].clone()),
         }
+        idx += 1;
+    }
+    result
+}
 
-        // Parse drummode variables: name = \drummode { ... }
-        let drum_re =
-            regex::Regex::new(r"(?m)^([a-zA-Z_][a-zA-Z0-9_]*)\s*=\s*\\drummode\s*\{").unwrap();
-        for caps in drum_re.captures_iter(code) {
-            let name = caps.get(1).unwrap().as_str().to_string();
-            let brace_start = caps.get(0).unwrap().end() - 1;
-
-            if let Some(content) = self.extract_braced_content(code, brace_start) {
-                variables.insert(name, VariableKind::Drums(content));
+/// Number of events once repeats are unfolded, without unfolding them (saturating)
+fn unfolded_len<E: StaffEvent>(events: &[E]) -> u64 {
+    let mut total: u64 = 0;
+    let mut idx = 0;
+    while idx < events.len() {
+        match events[idx].view() {
+            EventView::RepeatStart(_) => {
+                let repeat = Repeat::read(events, idx);
+                for (times, pass) in repeat.passes() {
+                    total = total.saturating_add(unfolded_len(&pass).saturating_add(1).saturating_mul(times as u64));
+                }
+                idx = repeat.end;
+                continue;
             }
+            EventView::RepeatEnd => break,
+            EventView::AlternativeStart | EventView::AlternativeEnd => {}
+            _ => total = total.saturating_add(1),
         }
+        idx += 1;
+    }
+    total
+}
 
-        variables
+/// Finest grid of [`quantize_pitched`] in steps per whole note, finer grids are read as this one
+const MAX_GRID: u32 = 1024;
+
+/// Steps of a grid of `grid` steps per whole note taken by the events of one bar, `None` for
+/// the untimed ones. Onsets are rounded to the nearest step, so the rounding of one event is
+/// absorbed by the next one and the bar keeps its length; an event rounded to nothing takes a
+/// step from its nearest neighbour longer than one step, if there is one.
+fn snap_bar(lengths: &[Option<Length>], grid: u32) -> Vec<Option<u128>> {
+    let grid = u128::from(grid);
+    let mut position = Length::new(0, 1);
+    let mut step = 0;
+    let mut steps: Vec<Option<u128>> = lengths
+        .iter()
+        .map(|length| {
+            position = position.plus((*length)?);
+            // Nearest step: round(position * grid / 4)
+            let (num, den) = (u128::from(position.num), u128::from(position.den));
+            let end = (2 * num * grid + 4 * den) / (8 * den);
+            let taken = end.saturating_sub(step);
+            step = step.max(end);
+            Some(taken)
+        })
+        .collect();
+    for i in 0..steps.len() {
+        if steps[i] != Some(0) {
+            continue;
+        }
+        let longer = (1..steps.len())
+            .flat_map(|distance| [i.checked_sub(distance), Some(i + distance)])
+            .flatten()
+            .find(|&j| steps.get(j).copied().flatten().is_some_and(|taken| taken > 1));
+        if let Some(j) = longer {
+            steps[j] = steps[j].map(|taken| taken - 1);
+            steps[i] = Some(1);
+        }
     }
+    steps
+}
 
-    fn parse_punchcard_color(&self, content: &str) -> Option<String> {
+/// Length to store for a written note value: `None` when the value alone describes it
+fn exact_length(duration: u32, length: Length) -> Option<Length> {
+    (Length::of_duration(duration, 0) != length).then_some(length)
+}
+
+/// Snap pitched events onto a grid of `grid` steps per whole note (at most 1024). Onsets are
+/// rounded to the nearest step, so the rounding of one note is absorbed by the next one and
+/// bars keep their length. A note or rest shorter than half a step takes a step from its
+/// neighbour; a note that cannot, every event of its bar already a single step, is dropped
+/// with a warning.
+pub fn quantize_pitched(events: &[PitchedEvent], grid: u32) -> (Vec<PitchedEvent>, Vec<Warning>) {
+    quantize_events(events, grid, |event, duration, length| match event {
+        PitchedEvent::Note(n) => PitchedEvent::Note(Note { duration, length, ..n.clone() }),
+        _ => PitchedEvent::Rest { duration, length },
+    })
+}
+
+/// Snap drum events onto a grid, see [`quantize_pitched`]
+pub fn quantize_drums(events: &[DrumEvent], grid: u32) -> (Vec<DrumEvent>, Vec<Warning>) {
+    quantize_events(events, grid, |event, duration, length| match event {
+        DrumEvent::Hit(hit) => DrumEvent::Hit(DrumHit { duration, length, ..hit.clone() }),
+        _ => DrumEvent::Rest { duration, length },
+    })
+}
+
+/// Snap the events bar by bar, `resize` giving a note, hit or rest its written value and length
+fn quantize_events<E: StaffEvent>(
+    events: &[E],
+    grid: u32,
+    resize: impl Fn(&E, u32, Option<Length>) -> E,
+) -> (Vec<E>, Vec<Warning>) {
+    let grid = grid.clamp(1, MAX_GRID);
+    let mut result = Vec::new();
+    let mut warnings = Vec::new();
+    let (mut bar, mut start) = (1, 0);
+    for i in 0..=events.len() {
+        let closing = events.get(i).map(|event| event.view());
+        if closing.as_ref().is_some_and(|view| {
+            !matches!(
+                view,
+                EventView::BarLine
+                    | EventView::RepeatStart(_)
+                    | EventView::RepeatEnd
+                    | EventView::AlternativeStart
+                    | EventView::AlternativeEnd
+            )
+        }) {
+            continue;
+        }
+
+        let segment = &events[start..i];
+        let lengths: Vec<Option<Length>> = segment
+            .iter()
+            .map(|event| match event.view() {
+                EventView::Sound(sound) => Some(sound.quarters()),
+                EventView::Rest(quarters) => Some(quarters),
+                _ => None,
+            })
+            .collect();
+        for (event, steps) in segment.iter().zip(snap_bar(&lengths, grid)) {
+            match steps {
+                None => result.push(event.clone()),
+                Some(0) => {
+                    if matches!(event.view(), EventView::Sound(_)) {
+                        warnings.push(Warning {
+                            message: format!("dropped a note too short for a grid of {} steps per whole note", grid),
+                            bar: Some(bar),
+                        });
+                    }
+                }
+                Some(steps) => {
+                    let snapped = Length::rounded(4 * steps, u128::from(grid));
+                    let written = [1, 2, 4, 8, 16, 32, 64, 128]
+                        .into_iter()
+                        .find(|&d| Length::of_duration(d, 0).as_f64() <= snapped.as_f64())
+                        .unwrap_or(128);
+                    result.push(resize(event, written, exact_length(written, snapped)));
+                }
+            }
+        }
+
+        if let Some(view) = closing {
+            if matches!(view, EventView::BarLine) {
+                bar += 1;
+            }
+            result.push(events[i].clone());
+        }
+        start = i + 1;
+    }
+    (result, warnings)
+}
+
+#[derive(Debug)]
+pub struct ParseResult {
+    pub staves: Vec<Staff>,
+    pub tempo: Tempo,
+    /// First `\time` of the input, 4/4 when there is none
+    pub time: TimeSignature,
+    /// String fields of the top-level `\header { ... }` block (title, composer, subtitle, ...)
+    pub header: HashMap<String, String>,
+    /// Tempo changes over bar ranges from the `rubato` magic comment
+    pub rubato: Vec<Rubato>,
+    /// Input that was skipped or approximated during parsing
+    pub warnings: Vec<Warning>,
+}
+
+/// Something the parser could not convert faithfully; the conversion still succeeds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub message: String,
+    /// Bar of the staff or variable being read (1-based, repeats not unfolded), when known
+    pub bar: Option<u32>,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bar {
+            Some(bar) => write!(f, "warning: bar {}: {}", bar, self.message),
+            None => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+impl ParseResult {
+    /// For backwards compatibility: returns all notes flattened (excluding bar lines and repeats)
+    pub fn notes(&self) -> Vec<Note> {
+        self.staves
+            .iter()
+            .filter_map(|s| s.events())
+            .flat_map(|events| {
+                events.iter().filter_map(|e| match e {
+                    PitchedEvent::Note(n) => Some(n.clone()),
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    /// Number of events of all staves once repeats are unfolded, computed without unfolding
+    /// them: `\repeat unfold 1000 { \repeat unfold 1000 { ... } }` is small to parse but not to play
+    pub fn unfolded_events(&self) -> u64 {
+        self.staves
+            .iter()
+            .map(|staff| match &staff.content {
+                StaffContent::Notes(events) => unfolded_len(events),
+                StaffContent::Drums(voices) => {
+                    voices.iter().map(|voice| unfolded_len(&voice.events)).fold(0, u64::saturating_add)
+                }
+            })
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Unfold the repeats of every staff, for consumers that need linear events
+    pub fn unfold_repeats(&mut self) {
+        for staff in self.staves.iter_mut() {
+            staff.unfold_repeats();
+        }
+    }
+
+    /// Snap every staff onto a grid of `grid` steps per whole note (`16` = sixteenths),
+    /// so the generator only has to write clean weights; dropped notes are warned about
+    pub fn quantize(&mut self, grid: u32) {
+        for staff in self.staves.iter_mut() {
+            let warnings = staff.quantize(grid);
+            self.warnings.extend(warnings);
+        }
+    }
+
+    /// Move every pitched staff by `semitones`, see [`Note::transpose`]
+    pub fn transpose(&mut self, semitones: i32) {
+        for staff in self.staves.iter_mut() {
+            staff.transpose(semitones);
+        }
+    }
+
+    /// Keep bars `start..end` of every staff (0-based, counted after unfolding repeats)
+    pub fn slice_bars(&mut self, start: usize, end: usize) {
+        for staff in self.staves.iter_mut() {
+            staff.slice_bars(start, end);
+        }
+    }
+
+    /// Bar range of the section named `name` (a comment marker, rehearsal mark or `Section N`
+    /// of a double bar, compared case-insensitively), up to the next section of that staff
+    pub fn section_bars(&self, name: &str) -> Option<(usize, usize)> {
+        let name = name.trim().to_lowercase();
+        self.staves.iter().find_map(|staff| {
+            let comments = staff.comment_bars();
+            let start = comments.iter().find(|(_, text)| text.to_lowercase() == name)?.0;
+            let end = comments
+                .iter()
+                .map(|&(bar, _)| bar)
+                .filter(|&bar| bar > start)
+                .min()
+                .unwrap_or(usize::MAX);
+            Some((start, end))
+        })
+    }
+
+    /// Bars between the `loop start` and `loop end` markers of the first staff having any
+    /// (from the beginning or up to the end when one of them is missing)
+    pub fn loop_bars(&self) -> Option<(usize, usize)> {
+        self.staves.iter().find_map(|staff| {
+            let comments = staff.comment_bars();
+            let find = |marker: &str| comments.iter().find(|(_, text)| text == marker).map(|&(bar, _)| bar);
+            let (start, end) = (find(LOOP_START), find(LOOP_END));
+            (start.is_some() || end.is_some()).then(|| (start.unwrap_or(0), end.unwrap_or(usize::MAX)))
+        })
+    }
+
+    /// Keep only the loop region, if the input marks one, and drop the markers
+    fn keep_loop(&mut self) {
+        // Finding the markers unfolds the repeats: not for a score too large to play anyway
+        if self.unfolded_events() <= LilyPondParser::MAX_UNFOLDED_EVENTS
+            && let Some((start, end)) = self.loop_bars()
+        {
+            self.slice_bars(start, end);
+        }
+        for staff in self.staves.iter_mut() {
+            staff.remove_loop_markers();
+        }
+    }
+
+    /// A metronome drum staff as long as the piece: one click per beat of the time signature,
+    /// the first beat of every bar accented with a cowbell (`cb`), the others on the rim (`rim`).
+    /// Meter changes (`\time`) of the longest staff are followed.
+    pub fn click_staff(&self) -> Staff {
+        let changes = match self.staves.iter().max_by_key(|s| StrudelGenerator::count_staff_bars(s)) {
+            Some(staff) => staff.meter_changes(),
+            None => Vec::new(),
+        };
+
+        // Without meter changes in the staff (e.g. `\time` in a separate global variable) every bar
+        // has the first time signature; otherwise bars before the first change are in 4/4
+        let mut time = if changes.iter().any(Option::is_some) { TimeSignature::default() } else { self.time };
+        let mut events = Vec::new();
+        for (bar, change) in changes.into_iter().enumerate() {
+            if bar > 0 {
+                events.push(DrumEvent::BarLine);
+            }
+            if let Some(change) = change {
+                time = change;
+                events.push(DrumEvent::Time(time));
+            }
+            let click = |name: &str| DrumEvent::Hit(DrumHit { name: name.to_string(), duration: time.unit, ..Default::default() });
+            events.push(click("cb"));
+            events.extend((1..time.count).map(|_| click("rim")));
+        }
+        let mut staff = Staff::new_drums(vec![DrumVoiceData { events, punchcard_color: None, gain: None, pan: None, speed: None, name: None }]);
+        staff.name = Some("click".to_string());
+        staff
+    }
+
+    /// Per-staff statistics and the playing time of the generated code
+    pub fn summary(&self) -> Summary {
+        let staves: Vec<StaffSummary> = self
+            .staves
+            .iter()
+            .enumerate()
+            .map(|(i, staff)| StaffSummary::of(staff, i))
+            .collect();
+        let seconds = StrudelGenerator::cycle_info(&self.staves, &self.tempo, &GeneratorOptions::default()).seconds;
+        Summary { staves, seconds }
+    }
+}
+
+/// Bar math of the generated code, from [`StrudelGenerator::cycle_info`]: one cycle is the
+/// whole piece, played at `.cpm(tempo/4/nbars)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleInfo {
+    /// Bars of every staff as played, repeats included
+    pub bars: Vec<u32>,
+    /// Length of the cycle in 4/4 bars, bars of other meters weighted (the `nbars` constant)
+    pub nbars: Length,
+    /// Cycles per minute, without rubato
+    pub cpm: f64,
+    /// Argument of the `.cpm()` calls, referring to the constants of the prologue
+    pub cpm_expression: String,
+    /// Length of the cycle, without rubato
+    pub seconds: f64,
+}
+
+/// Statistics of a parsed score, from [`ParseResult::summary`]
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub staves: Vec<StaffSummary>,
+    /// Length of the piece as played by the generated code
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StaffSummary {
+    /// Instrument or context name, or `staff N`
+    pub label: String,
+    pub kind: StaffKind,
+    /// Notes (chords count once) or drum hits of all voices, as written
+    pub notes: usize,
+    /// Bars as played, repeats included
+    pub bars: u32,
+    /// Lowest and highest MIDI pitch of a pitched staff
+    pub range: Option<(i32, i32)>,
+    pub key: Option<KeySignature>,
+    /// Number of notes or hits per written note value (`4` = quarter)
+    pub durations: BTreeMap<u32, usize>,
+}
+
+impl StaffSummary {
+    fn of(staff: &Staff, index: usize) -> Self {
+        let mut notes = 0;
+        let mut range: Option<(i32, i32)> = None;
+        let mut durations = BTreeMap::new();
+
+        match &staff.content {
+            StaffContent::Notes(events) => {
+                for event in events {
+                    if let PitchedEvent::Note(n) = event {
+                        notes += 1;
+                        *durations.entry(n.duration).or_insert(0) += 1;
+                        let pitches = std::iter::once(n.midi).chain(n.chord_notes.iter().flatten().map(|c| c.midi));
+                        for midi in pitches {
+                            let (low, high) = range.get_or_insert((midi, midi));
+                            *low = (*low).min(midi);
+                            *high = (*high).max(midi);
+                        }
+                    }
+                }
+            }
+            StaffContent::Drums(voices) => {
+                for event in voices.iter().flat_map(|v| v.events.iter()) {
+                    if let DrumEvent::Hit(hit) = event {
+                        notes += 1;
+                        *durations.entry(hit.duration).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        StaffSummary {
+            label: StrudelGenerator::staff_label(staff, index),
+            kind: staff.kind.clone(),
+            notes,
+            bars: StrudelGenerator::count_staff_bars(staff),
+            range,
+            key: staff.key.clone(),
+            durations,
+        }
+    }
+}
+
+/// Scientific name of a MIDI pitch (`c4` for 60)
+fn pitch_name(midi: i32) -> String {
+    const NAMES: [&str; 12] = ["c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b"];
+    format!("{}{}", NAMES[midi.rem_euclid(12) as usize], midi.div_euclid(12) - 1)
+}
+
+/// Lowest and highest MIDI pitch of the instruments of `% @strudel-of-lilypond@ range <instrument>`,
+/// as written in the score (sounding pitch, but for the guitar and bass written an octave up)
+pub const INSTRUMENT_RANGES: [(&str, i32, i32); 9] = [
+    ("violin", 55, 103),
+    ("viola", 48, 91),
+    ("cello", 36, 76),
+    ("bass", 40, 79),
+    ("flute", 60, 96),
+    ("clarinet", 50, 94),
+    ("trumpet", 52, 82),
+    ("trombone", 40, 72),
+    ("guitar", 52, 100),
+];
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for staff in &self.staves {
+            let what = match staff.kind {
+                StaffKind::Pitched => "notes",
+                StaffKind::Drums => "hits",
+            };
+            write!(f, "{}: {} {}, {} bars", staff.label, staff.notes, what, staff.bars)?;
+            if let Some((low, high)) = staff.range {
+                write!(f, ", range {}-{}", pitch_name(low), pitch_name(high))?;
+            }
+            if let Some(key) = &staff.key {
+                write!(f, ", key {}", key)?;
+            }
+            let durations: Vec<String> = staff.durations.iter().map(|(d, n)| format!("{}x{}", n, d)).collect();
+            if !durations.is_empty() {
+                write!(f, ", durations {}", durations.join(" "))?;
+            }
+            writeln!(f)?;
+        }
+        let seconds = self.seconds.round() as u64;
+        write!(f, "Length: {}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[derive(Clone)]
+enum VariableKind {
+    Pitched(String),
+    Drums(String),
+}
+
+/// Expand `\include "file.ly"` directives by recursively inlining file contents.
+pub fn expand_includes(code: &str, base_dir: &Path) -> Result<String, String> {
+    expand_includes_with_map(code, base_dir, Path::new("")).map(|(expanded, _)| expanded)
+}
+
+/// Like [`expand_includes`], with the map from the expanded text back to the sources it came
+/// from, `file` being the path of `code` itself
+pub fn expand_includes_with_map(code: &str, base_dir: &Path, file: &Path) -> Result<(String, SourceMap), String> {
+    let mut seen = HashSet::new();
+    let mut expanded = String::new();
+    let mut chunks = Vec::new();
+    expand_includes_recursive(code, base_dir, file, &mut seen, &mut expanded, &mut chunks)?;
+    let map = SourceMap::new(&expanded, &chunks);
+    Ok((expanded, map))
+}
+
+/// Where the lines of a text with its includes expanded come from
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Byte offset of every line of the expanded text, with the file and 1-based line it starts in
+    lines: Vec<(usize, PathBuf, usize)>,
+}
+
+impl SourceMap {
+    /// `chunks`: offset in `expanded` where the text of a file resumes, with that file and the
+    /// line it resumes at
+    fn new(expanded: &str, chunks: &[(usize, PathBuf, usize)]) -> Self {
+        let line_starts = std::iter::once(0).chain(expanded.match_indices('\n').map(|(i, _)| i + 1));
+        let mut chunk = 0;
+        let lines = line_starts
+            .map(|offset| {
+                while chunk + 1 < chunks.len() && chunks[chunk + 1].0 <= offset {
+                    chunk += 1;
+                }
+                let (start, file, line) = &chunks[chunk];
+                (offset, file.clone(), line + expanded[*start..offset].matches('\n').count())
+            })
+            .collect();
+        SourceMap { lines }
+    }
+
+    /// File and line of a byte offset of the expanded text
+    pub fn locate(&self, offset: usize) -> Option<(&Path, usize)> {
+        let index = self.lines.partition_point(|(start, _, _)| *start <= offset).checked_sub(1)?;
+        let (_, file, line) = &self.lines[index];
+        Some((file.as_path(), *line))
+    }
+
+    /// File and line of a 1-based line of the expanded text (the `Line 12:` of parse errors)
+    pub fn locate_line(&self, line: usize) -> Option<(&Path, usize)> {
+        let (_, file, line) = self.lines.get(line.checked_sub(1)?)?;
+        Some((file.as_path(), *line))
+    }
+
+    /// Point a parse error starting with `Line N:` at the file and line that introduced it
+    pub fn locate_error(&self, error: &str) -> String {
+        let located = error.strip_prefix("Line ").and_then(|rest| {
+            let (line, message) = rest.split_once(':')?;
+            let (file, line) = self.locate_line(line.parse().ok()?)?;
+            Some(format!("{}:{}:{}", file.display(), line, message))
+        });
+        located.unwrap_or_else(|| error.to_string())
+    }
+}
+
+fn expand_includes_recursive(
+    code: &str,
+    base_dir: &Path,
+    file: &Path,
+    seen: &mut HashSet<PathBuf>,
+    expanded: &mut String,
+    chunks: &mut Vec<(usize, PathBuf, usize)>,
+) -> Result<(), String> {
+    let re = regex::Regex::new(r#"\\include\s+"([^"]+)""#).unwrap();
+    let mut last = 0;
+
+    for caps in re.captures_iter(code) {
+        let full_match = caps.get(0).unwrap();
+        let file_name = caps.get(1).unwrap().as_str();
+        let file_path = base_dir.join(file_name);
+
+        let canonical = file_path
+            .canonicalize()
+            .map_err(|e| format!("Cannot resolve include \"{}\": {}", file_name, e))?;
+
+        if !seen.insert(canonical.clone()) {
+            return Err(format!("Circular include detected: \"{}\"", file_name));
+        }
+
+        let content = std::fs::read_to_string(&canonical)
+            .map_err(|e| format!("Cannot read include \"{}\": {}", file_name, e))?;
+
+        chunks.push((expanded.len(), file.to_path_buf(), code[..last].matches('\n').count() + 1));
+        expanded.push_str(&code[last..full_match.start()]);
+        let child_base = canonical.parent().unwrap_or(base_dir);
+        expand_includes_recursive(&content, child_base, &file_path, seen, expanded, chunks)?;
+        last = full_match.end();
+    }
+
+    chunks.push((expanded.len(), file.to_path_buf(), code[..last].matches('\n').count() + 1));
+    expanded.push_str(&code[last..]);
+    Ok(())
+}
+
+/// Provenance of a generated file: the crate version, the options it was generated with and a
+/// hash of its inputs, written as a comment so that stale outputs can be found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub version: String,
+    /// FNV-1a hash of the inputs, in hex
+    pub hash: String,
+    /// Command-line options, without the file names
+    pub options: String,
+}
+
+impl Stamp {
+    const TAG: &'static str = "strudel-of-lilypond-stamp";
+
+    /// Stamp of this version for the given input contents (includes expanded) and options
+    pub fn new(inputs: &[&str], options: &str) -> Self {
+        Stamp { version: env!("CARGO_PKG_VERSION").to_string(), hash: Self::hash(inputs), options: options.to_string() }
+    }
+
+    /// FNV-1a hash of `inputs`, in hex
+    fn hash(inputs: &[&str]) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for input in inputs {
+            // A separator, so that moving text between inputs changes the hash
+            for byte in input.bytes().chain(std::iter::once(0xff)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        format!("{hash:016x}")
+    }
+
+    fn text(&self) -> String {
+        format!("{} version={} hash={} options={}", Self::TAG, self.version, self.hash, self.options)
+    }
+
+    /// The text in an HTML comment, which `--` would end (`-->`): the second dash of every pair
+    /// (and every backslash) is escaped with a backslash, `--midi` is `-\-midi`
+    fn html_text(&self) -> String {
+        let mut text = String::new();
+        let mut previous = None;
+        for c in self.text().chars() {
+            if c == '\\' || (c == '-' && previous == Some('-')) {
+                text.push('\\');
+            }
+            text.push(c);
+            previous = Some(c);
+        }
+        text
+    }
+
+    /// Add the stamp to generated content as a comment of its file type (`html`, `ly`, `str`)
+    pub fn apply(&self, content: &str, extension: &str) -> String {
+        match extension {
+            "html" => match content.strip_prefix("<!DOCTYPE html>\n") {
+                Some(rest) => format!("<!DOCTYPE html>\n<!-- {} -->\n{}", self.html_text(), rest),
+                None => format!("<!-- {} -->\n{}", self.html_text(), content),
+            },
+            "ly" => format!("% {}\n{}", self.text(), content),
+            _ => format!("// {}\n{}", self.text(), content),
+        }
+    }
+
+    /// The stamp of a generated file, if it has one
+    pub fn read(content: &str) -> Option<Stamp> {
+        let re = regex::Regex::new(&format!(r"(?m){} version=(\S+) hash=([0-9a-f]+) options=(.*?)\s*(-->)?$", Self::TAG)).unwrap();
+        let caps = re.captures(content)?;
+        let mut options = caps[3].to_string();
+        if caps.get(4).is_some() {
+            // Unescape the HTML comment
+            let escaped = std::mem::take(&mut options);
+            let mut chars = escaped.chars();
+            while let Some(c) = chars.next() {
+                options.extend(if c == '\\' { chars.next() } else { Some(c) });
+            }
+        }
+        Some(Stamp { version: caps[1].to_string(), hash: caps[2].to_string(), options })
+    }
+
+    /// Why a file stamped with `self` is out of date compared to `current`, `None` if it is not
+    pub fn staleness(&self, current: &Stamp) -> Option<String> {
+        if self.hash != current.hash {
+            Some("inputs changed".to_string())
+        } else if self.options != current.options {
+            Some(format!("generated with options `{}`", self.options))
+        } else if self.version != current.version {
+            Some(format!("generated by version {}", self.version))
+        } else {
+            None
+        }
+    }
+}
+
+/// Stamps of the outputs of earlier runs, kept in a file, so that `--incremental` skips an
+/// input whose sources (includes expanded, library patterns) and options did not change
+/// without parsing it or reading its outputs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StampCache {
+    /// Per output named on the command line: the hash of its stamp and the files written for it
+    entries: BTreeMap<String, (String, Vec<String>)>,
+}
+
+impl StampCache {
+    /// Name of the cache file, in the current directory
+    pub const FILE_NAME: &'static str = ".strudel-of-lilypond-cache";
+
+    /// The cache in `path`: empty if there is none, or if it cannot be read
+    pub fn load(path: &Path) -> Self {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        let entries = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let (hash, output) = (fields.next()?, fields.next()?);
+                Some((output.to_string(), (hash.to_string(), fields.map(str::to_string).collect())))
+            })
+            .collect();
+        StampCache { entries }
+    }
+
+    /// One line per output: the hash, the output and the files written for it, tab-separated
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let lines: String = self
+            .entries
+            .iter()
+            .map(|(output, (hash, files))| {
+                let fields: Vec<&str> = [hash.as_str(), output.as_str()].into_iter().chain(files.iter().map(String::as_str)).collect();
+                fields.join("\t") + "\n"
+            })
+            .collect();
+        std::fs::write(path, lines).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+    }
+
+    /// Whether `output` was last written with `stamp` and all its files are still there
+    pub fn is_fresh(&self, output: &str, stamp: &Stamp) -> bool {
+        self.entries
+            .get(output)
+            .is_some_and(|(hash, files)| *hash == Self::key(stamp) && files.iter().all(|file| Path::new(file).exists()))
+    }
+
+    /// Record that `output` is written with `stamp`, as `files`
+    pub fn record(&mut self, output: &str, stamp: &Stamp, files: Vec<String>) {
+        self.entries.insert(output.to_string(), (Self::key(stamp), files));
+    }
+
+    fn key(stamp: &Stamp) -> String {
+        Stamp::hash(&[&stamp.text()])
+    }
+}
+
+/// Duration written after a note, rest, drum hit or chord: `4`, `8.`, `2..~`
+struct DurationSuffix {
+    duration: Option<u32>,
+    dots: u32,
+    tie: bool,
+}
+
+impl DurationSuffix {
+    fn read(chars: &mut std::iter::Peekable<std::str::Chars>) -> Self {
+        let mut duration_str = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() {
+                duration_str.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut dots = 0;
+        let mut tie = false;
+        while let Some(&c) = chars.peek() {
+            match c {
+                '.' => dots += 1,
+                '~' => tie = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        DurationSuffix { duration: duration_str.parse().ok(), dots, tie }
+    }
+
+    /// Resolve the written duration against the running state: a written duration (with its
+    /// dots) becomes the new default, an omitted one repeats the previous (`c4. d` is two dotted quarters).
+    /// Returns the note value and, when dotted, its exact length.
+    fn resolve(&self, state: &mut DurationState) -> (u32, Option<Length>) {
+        if let Some(duration) = self.duration {
+            *state = DurationState { duration, dots: self.dots };
+        }
+        let length = (state.dots > 0).then(|| Length::of_duration(state.duration, state.dots));
+        (state.duration, length)
+    }
+}
+
+/// Marks written after a note's duration: articulations (`->`, `-.`, `\accent`), dynamics (`\ff`),
+/// text scripts (`^"R"`), fingerings (`-1`), slurs, beams, tremolos and ties
+#[derive(Debug, Clone, Default)]
+struct Attachments {
+    articulations: Vec<String>,
+    dynamic: Option<String>,
+    hairpin: Option<Hairpin>,
+    tie: bool,
+    beam: Option<Beam>,
+    slur: Option<Slur>,
+    /// Text scripts (`^"R"`), without their quotes
+    texts: Vec<String>,
+    /// Trailing text that could not be understood
+    unrecognized: Vec<String>,
+}
+
+impl Attachments {
+    const DYNAMICS: &'static [&'static str] = &[
+        "ppppp", "pppp", "ppp", "pp", "p", "mp", "mf", "f", "ff", "fff", "ffff", "fffff",
+        "fp", "sf", "sff", "sfz", "sp", "spp", "fz", "rfz", "sfp",
+    ];
+
+    const ARTICULATIONS: &'static [&'static str] = &[
+        "accent", "staccato", "staccatissimo", "tenuto", "marcato", "portato", "espressivo",
+        "fermata", "trill", "prall", "mordent", "turn", "upbow", "downbow", "open", "stopped",
+        "flageolet", "snappizzicato", "thumb", "arpeggio", "glissando",
+    ];
+
+    fn parse(text: &str) -> Self {
+        let mut attachments = Attachments::default();
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '~' => {
+                    attachments.tie = true;
+                    i += 1;
+                }
+                '(' => {
+                    attachments.slur = Some(Slur::Start);
+                    i += 1;
+                }
+                ')' => {
+                    attachments.slur = Some(Slur::End);
+                    i += 1;
+                }
+                '[' => {
+                    attachments.beam = Some(Beam::Start);
+                    i += 1;
+                }
+                ']' => {
+                    attachments.beam = Some(Beam::End);
+                    i += 1;
+                }
+                // Tremolo: c4:32
+                ':' => {
+                    i += 1;
+                    while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                        i += 1;
+                    }
+                }
+                '\\' => i = attachments.command(&chars, i),
+                // Direction (neutral, up, down) followed by the mark itself
+                direction @ ('-' | '^' | '_') => {
+                    i += 1;
+                    match chars.get(i) {
+                        Some('"') => {
+                            let end = Self::skip_string(&chars, i);
+                            attachments.texts.push(chars[i + 1..end.saturating_sub(1).max(i + 1)].iter().collect());
+                            i = end;
+                        }
+                        Some(c) if c.is_ascii_digit() => {
+                            while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                                i += 1;
+                            }
+                        }
+                        Some('\\') => i = attachments.command(&chars, i),
+                        Some(&c) if Self::shorthand(c).is_some() => {
+                            attachments.articulations.extend(Self::shorthand(c).map(str::to_string));
+                            i += 1;
+                        }
+                        _ => attachments.unrecognized.push(direction.to_string()),
+                    }
+                }
+                _ => {
+                    attachments.unrecognized.push(chars[i..].iter().collect());
+                    break;
+                }
+            }
+        }
+
+        attachments
+    }
+
+    /// Articulation written with a direction shorthand (`->` is an accent)
+    fn shorthand(c: char) -> Option<&'static str> {
+        match c {
+            '>' => Some("accent"),
+            '.' => Some("staccato"),
+            '!' => Some("staccatissimo"),
+            '-' => Some("tenuto"),
+            '^' => Some("marcato"),
+            '_' => Some("portato"),
+            '+' => Some("stopped"),
+            _ => None,
+        }
+    }
+
+    /// Index just past the closing quote of a text script starting at `start`
+    fn skip_string(chars: &[char], start: usize) -> usize {
+        let mut i = start + 1;
+        while i < chars.len() && chars[i] != '"' {
+            i += if chars[i] == '\\' { 2 } else { 1 };
+        }
+        (i + 1).min(chars.len())
+    }
+
+    /// Record the backslash command starting at `start`, returning the index after it
+    fn command(&mut self, chars: &[char], start: usize) -> usize {
+        let mut i = start + 1;
+        let name: String = if chars.get(i).is_some_and(|c| c.is_alphabetic()) {
+            let name: String = chars[i..].iter().take_while(|c| c.is_alphabetic()).collect();
+            i += name.chars().count();
+            name
+        } else {
+            // Hairpins \< \> \!, phrasing slurs \( \)
+            i += 1;
+            chars.get(start + 1).map(|c| c.to_string()).unwrap_or_default()
+        };
+
+        if Self::DYNAMICS.contains(&name.as_str()) {
+            self.dynamic = Some(name);
+        } else if Self::ARTICULATIONS.contains(&name.as_str()) {
+            self.articulations.push(name);
+        } else if name == "<" {
+            self.hairpin = Some(Hairpin::Crescendo);
+        } else if name == ">" {
+            self.hairpin = Some(Hairpin::Decrescendo);
+        } else if name == "!" {
+            self.hairpin = Some(Hairpin::End);
+        } else if !matches!(name.as_str(), "(" | ")") {
+            self.unrecognized.push(format!("\\{}", name));
+        }
+        i.min(chars.len())
+    }
+}
+
+/// Clauses between `\new Staff` and its music: `= "lead" \with { instrumentName = "Lead" }`
+struct ContextClause {
+    name: Option<String>,
+    with_block: Option<String>,
+    /// `\relative c''` written before the music
+    relative: Option<String>,
+    /// Byte offset of the music (`{` or `<<`)
+    body: usize,
+}
+
+/// How a printed bar line (`\bar "||"`) affects the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BarLineKind {
+    /// `\bar ""`: allows a line break, does not end the bar
+    Invisible,
+    /// Single, final and repeat bar lines end the bar
+    Line,
+    /// Double bar lines end the bar and start a new section
+    Section,
+}
+
+impl BarLineKind {
+    fn of(bar_type: &str) -> Self {
+        match bar_type {
+            "" => BarLineKind::Invisible,
+            "||" | ".|" | ".." | "|.|" => BarLineKind::Section,
+            _ => BarLineKind::Line,
+        }
+    }
+}
+
+/// Pickup bar (`\partial 4`) while reading tokens: it is played in a meter of its own length,
+/// and closed with a bar line once that length is played, the staff going on in its meter
+#[derive(Debug, Default)]
+struct Pickup {
+    /// What is left to play of the pickup
+    left: Option<Length>,
+    /// The pickup was just closed
+    closed: bool,
+}
+
+impl Pickup {
+    /// Start the pickup of the `\partial` duration (`4`, `8.`, `4*3`), returning its meter
+    fn start(&mut self, token: &str) -> Option<TimeSignature> {
+        let re = regex::Regex::new(r"^(\d+)(\.*)(?:\*(\d+)(?:/(\d+))?)?$").unwrap();
+        let caps = re.captures(token)?;
+        let duration: u32 = caps[1].parse().ok()?;
+        let factor = |i: usize| caps.get(i).and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
+        let length = Length::of_duration(duration, caps[2].len() as u32).times(Length::new(factor(3), factor(4)));
+        // A bar of `length` quarters: 3/2 is 3/8
+        let unit = length.den.checked_mul(4)?;
+        if length.num == 0 {
+            return None;
+        }
+        self.left = Some(length);
+        Some(TimeSignature { count: length.num, unit })
+    }
+
+    /// Take what a token played off the pickup, true when that completes it
+    fn play(&mut self, played: Length) -> bool {
+        let Some(left) = self.left else {
+            return false;
+        };
+        self.left = left.minus(played);
+        self.closed = self.left.is_none();
+        self.closed
+    }
+
+    /// Whether `token` is the bar check written after the pickup, a bar line already there
+    fn skip_bar_check(&mut self, token: &str) -> bool {
+        std::mem::take(&mut self.closed) && token == "|"
+    }
+}
+
+/// An event of a staff as the generator reads it, the same for notes and drum hits
+enum EventView<'a, S> {
+    /// A note, chord or drum hit
+    Sound(&'a S),
+    /// A rest, with its length in quarters
+    Rest(Length),
+    CadenzaOn,
+    CadenzaOff,
+    BarLine,
+    RepeatStart(u32),
+    RepeatEnd,
+    AlternativeStart,
+    AlternativeEnd,
+    Time(TimeSignature),
+    Comment(&'a str),
+}
+
+/// A note or a drum hit, as written in the pattern
+trait Sound: Clone {
+    fn quarters(&self) -> Length;
+    fn beam(&self) -> Option<Beam>;
+    /// The same sound lasting `length`, to weigh the items of a beam group
+    fn lasting(&self, length: Length) -> Self;
+    fn format(&self, options: &GeneratorOptions) -> String;
+    /// Name of the chord for the chord names comment, none for drum hits
+    fn chord_symbol(&self) -> Option<String> {
+        None
+    }
+    /// Whether the sound is a grace note, taking no time of the bar
+    fn grace(&self) -> bool;
+}
+
+impl Sound for Note {
+    fn quarters(&self) -> Length {
+        Note::quarters(self)
+    }
+    fn beam(&self) -> Option<Beam> {
+        self.beam
+    }
+    fn lasting(&self, length: Length) -> Self {
+        Note { length: Some(length), ..self.clone() }
+    }
+    fn format(&self, options: &GeneratorOptions) -> String {
+        StrudelGenerator::format_pitched_note(self, options)
+    }
+    fn chord_symbol(&self) -> Option<String> {
+        Note::chord_symbol(self)
+    }
+    fn grace(&self) -> bool {
+        self.grace
+    }
+}
+
+impl Sound for DrumHit {
+    fn quarters(&self) -> Length {
+        DrumHit::quarters(self)
+    }
+    fn beam(&self) -> Option<Beam> {
+        self.beam
+    }
+    fn lasting(&self, length: Length) -> Self {
+        DrumHit { length: Some(length), ..self.clone() }
+    }
+    fn format(&self, options: &GeneratorOptions) -> String {
+        StrudelGenerator::format_drum_hit(self, options)
+    }
+    fn grace(&self) -> bool {
+        self.grace
+    }
+}
+
+/// Events of a pitched staff or drum voice, parsed, counted and generated by the same code
+trait StaffEvent: Clone {
+    type Sound: Sound;
+    fn view(&self) -> EventView<'_, Self::Sound>;
+    fn rest(duration: u32, length: Option<Length>) -> Self;
+    fn bar_line() -> Self;
+    fn repeat_start(count: u32) -> Self;
+    fn repeat_end() -> Self;
+    fn alternative_start() -> Self;
+    fn alternative_end() -> Self;
+    fn cadenza_on() -> Self;
+    fn cadenza_off() -> Self;
+    fn time(time: TimeSignature) -> Self;
+    fn comment(text: String) -> Self;
+    /// Give a note, hit or rest the length `scaled` makes of its own (tuplets)
+    fn scale_length(&mut self, scaled: &mut impl FnMut(Length) -> Length);
+
+    /// Time the event takes in its bar: grace notes take none
+    fn played(&self) -> Length {
+        match self.view() {
+            EventView::Sound(sound) if !sound.grace() => sound.quarters(),
+            EventView::Rest(quarters) => quarters,
+            _ => Length::new(0, 1),
+        }
+    }
+}
+
+impl StaffEvent for PitchedEvent {
+    type Sound = Note;
+    fn view(&self) -> EventView<'_, Note> {
+        match self {
+            PitchedEvent::Note(n) => EventView::Sound(n),
+            PitchedEvent::Rest { duration, length } => EventView::Rest(rest_quarters(*duration, length)),
+            PitchedEvent::CadenzaOn => EventView::CadenzaOn,
+            PitchedEvent::CadenzaOff => EventView::CadenzaOff,
+            PitchedEvent::BarLine => EventView::BarLine,
+            PitchedEvent::RepeatStart(count) => EventView::RepeatStart(*count),
+            PitchedEvent::RepeatEnd => EventView::RepeatEnd,
+            PitchedEvent::AlternativeStart => EventView::AlternativeStart,
+            PitchedEvent::AlternativeEnd => EventView::AlternativeEnd,
+            PitchedEvent::Time(time) => EventView::Time(*time),
+            PitchedEvent::Comment(text) => EventView::Comment(text),
+        }
+    }
+    fn rest(duration: u32, length: Option<Length>) -> Self {
+        PitchedEvent::Rest { duration, length }
+    }
+    fn bar_line() -> Self {
+        PitchedEvent::BarLine
+    }
+    fn repeat_start(count: u32) -> Self {
+        PitchedEvent::RepeatStart(count)
+    }
+    fn repeat_end() -> Self {
+        PitchedEvent::RepeatEnd
+    }
+    fn alternative_start() -> Self {
+        PitchedEvent::AlternativeStart
+    }
+    fn alternative_end() -> Self {
+        PitchedEvent::AlternativeEnd
+    }
+    fn cadenza_on() -> Self {
+        PitchedEvent::CadenzaOn
+    }
+    fn cadenza_off() -> Self {
+        PitchedEvent::CadenzaOff
+    }
+    fn time(time: TimeSignature) -> Self {
+        PitchedEvent::Time(time)
+    }
+    fn comment(text: String) -> Self {
+        PitchedEvent::Comment(text)
+    }
+    fn scale_length(&mut self, scaled: &mut impl FnMut(Length) -> Length) {
+        match self {
+            PitchedEvent::Note(n) => {
+                let length = scaled(n.quarters());
+                n.length = Some(length);
+                for chord_note in n.chord_notes.iter_mut().flatten() {
+                    chord_note.length = Some(length);
+                }
+            }
+            PitchedEvent::Rest { duration, length } => *length = Some(scaled(rest_quarters(*duration, length))),
+            _ => {}
+        }
+    }
+}
+
+impl StaffEvent for DrumEvent {
+    type Sound = DrumHit;
+    fn view(&self) -> EventView<'_, DrumHit> {
+        match self {
+            DrumEvent::Hit(h) => EventView::Sound(h),
+            DrumEvent::Rest { duration, length } => EventView::Rest(rest_quarters(*duration, length)),
+            DrumEvent::CadenzaOn => EventView::CadenzaOn,
+            DrumEvent::CadenzaOff => EventView::CadenzaOff,
+            DrumEvent::BarLine => EventView::BarLine,
+            DrumEvent::RepeatStart(count) => EventView::RepeatStart(*count),
+            DrumEvent::RepeatEnd => EventView::RepeatEnd,
+            DrumEvent::AlternativeStart => EventView::AlternativeStart,
+            DrumEvent::AlternativeEnd => EventView::AlternativeEnd,
+            DrumEvent::Time(time) => EventView::Time(*time),
+            DrumEvent::Comment(text) => EventView::Comment(text),
+        }
+    }
+    fn rest(duration: u32, length: Option<Length>) -> Self {
+        DrumEvent::Rest { duration, length }
+    }
+    fn bar_line() -> Self {
+        DrumEvent::BarLine
+    }
+    fn repeat_start(count: u32) -> Self {
+        DrumEvent::RepeatStart(count)
+    }
+    fn repeat_end() -> Self {
+        DrumEvent::RepeatEnd
+    }
+    fn alternative_start() -> Self {
+        DrumEvent::AlternativeStart
+    }
+    fn alternative_end() -> Self {
+        DrumEvent::AlternativeEnd
+    }
+    fn cadenza_on() -> Self {
+        DrumEvent::CadenzaOn
+    }
+    fn cadenza_off() -> Self {
+        DrumEvent::CadenzaOff
+    }
+    fn time(time: TimeSignature) -> Self {
+        DrumEvent::Time(time)
+    }
+    fn comment(text: String) -> Self {
+        DrumEvent::Comment(text)
+    }
+    fn scale_length(&mut self, scaled: &mut impl FnMut(Length) -> Length) {
+        match self {
+            DrumEvent::Hit(h) => h.length = Some(scaled(h.quarters())),
+            DrumEvent::Rest { duration, length } => *length = Some(scaled(rest_quarters(*duration, length))),
+            _ => {}
+        }
+    }
+}
+
+/// A repeat read from its `RepeatStart`: its body and the `\alternative` endings after it
+struct Repeat<'a, E> {
+    count: u32,
+    body: &'a [E],
+    endings: Vec<&'a [E]>,
+    /// Index of the event after the repeat and its endings
+    end: usize,
+}
+
+impl<'a, E: StaffEvent> Repeat<'a, E> {
+    /// Read the repeat whose `RepeatStart` is at `start` (an unterminated one runs to the end)
+    fn read(events: &'a [E], start: usize) -> Self {
+        let count = match events[start].view() {
+            EventView::RepeatStart(count) => count,
+            _ => 1,
+        };
+        // Index of the `RepeatEnd` or `AlternativeEnd` closing the events from `from`
+        let closing = |from: usize| {
+            let mut depth = 0;
+            for (i, event) in events.iter().enumerate().skip(from) {
+                match event.view() {
+                    EventView::RepeatStart(_) | EventView::AlternativeStart => depth += 1,
+                    EventView::RepeatEnd | EventView::AlternativeEnd if depth == 0 => return i,
+                    EventView::RepeatEnd | EventView::AlternativeEnd => depth -= 1,
+                    _ => {}
+                }
+            }
+            events.len()
+        };
+        let body_end = closing(start + 1);
+        let mut repeat = Repeat { count, body: &events[start + 1..body_end], endings: Vec::new(), end: body_end + 1 };
+        while repeat.end < events.len() && matches!(events[repeat.end].view(), EventView::AlternativeStart) {
+            let ending_end = closing(repeat.end + 1);
+            repeat.endings.push(&events[repeat.end + 1..ending_end]);
+            repeat.end = ending_end + 1;
+        }
+        repeat.end = repeat.end.min(events.len());
+        repeat
+    }
+
+    /// The passes in playing order, with the times each is played: the first ending closes
+    /// the first passes, every other ending one pass
+    fn passes(&self) -> Vec<(u32, Vec<E>)> {
+        let Some((first, others)) = self.endings.split_first() else {
+            return vec![(self.count, self.body.to_vec())];
+        };
+        let first_passes = self.count.saturating_sub(others.len() as u32).max(1);
+        std::iter::once((first_passes, [self.body, first].concat()))
+            .chain(others.iter().map(|ending| (1, [self.body, ending].concat())))
+            .collect()
+    }
+}
+
+/// Time scaling of `\tuplet 3/2 { ... }` and `\times 2/3 { ... }` while reading tokens
+#[derive(Debug, Default)]
+struct Tuplets {
+    /// After `\tuplet` (true) or `\times` (false): the fraction comes next
+    command: Option<bool>,
+    /// Scaling waiting for its music, a `{ ... }` group or a single note
+    pending: Option<Length>,
+    /// Scaling of the open groups, with the brace depth at which each one closes
+    open: Vec<(Length, usize)>,
+    depth: usize,
+    /// The scaling last read is rounded: tuplets nested too deep to be exact in a [`Length`]
+    rounded: bool,
+}
+
+impl Tuplets {
+    /// Read the tuplet commands and the braces of a token, returning what is left of the token
+    /// (empty when nothing) and the scaling of its notes
+    fn read(&mut self, token: &str) -> (String, Length) {
+        let unscaled = Length::new(1, 1);
+        if token == "\\tuplet" || token == "\\times" {
+            self.command = Some(token == "\\tuplet");
+            return (String::new(), unscaled);
+        }
+        if let Some(tuplet) = self.command.take()
+            && let Some((a, b)) = token.split_once('/')
+            && let (Ok(a), Ok(b)) = (a.parse::<u32>(), b.parse::<u32>())
+            && a > 0
+            && b > 0
+        {
+            // \tuplet 3/2 plays 3 notes in the time of 2, \times 2/3 multiplies by 2/3
+            self.pending = Some(if tuplet { Length::new(b, a) } else { Length::new(a, b) });
+            return (String::new(), unscaled);
+        }
+        // \tuplet 3/2 4 { ... }: the span of its beams, no timing
+        if self.pending.is_some() && !token.is_empty() && token.chars().all(|c| c.is_ascii_digit()) {
+            return (String::new(), unscaled);
+        }
+
+        let mut rest = token;
+        while let Some(inner) = rest.strip_prefix('{') {
+            self.depth += 1;
+            if let Some(scale) = self.pending.take() {
+                self.open.push((scale, self.depth));
+            }
+            rest = inner;
+        }
+        let core = rest.trim_end_matches('}');
+        let closing = rest.len() - core.len();
+
+        let mut scales: Vec<Length> = self.open.iter().map(|&(scale, _)| scale).collect();
+        if !core.is_empty() {
+            scales.extend(self.pending.take());
+        }
+        let exact = scales.iter().try_fold(unscaled, |total, scale| total.checked_times(*scale));
+        self.rounded = exact.is_none();
+        let scale = exact.unwrap_or_else(|| scales.iter().fold(unscaled, |total, scale| total.times(*scale)));
+        for _ in 0..closing {
+            if self.open.last().is_some_and(|&(_, depth)| depth == self.depth) {
+                self.open.pop();
+            }
+            self.depth = self.depth.saturating_sub(1);
+        }
+        (core.to_string(), scale)
+    }
+}
+
+/// Extent of `\relative c' { ... }` while reading tokens, see [`LilyPondParser::read_relative`]
+#[derive(Debug, Default)]
+struct RelativeScope {
+    /// After `\relative`: the reference pitch may come next
+    command: bool,
+    /// Brace depth around each relative music being read, with the previous pitch outside of it
+    open: Vec<(usize, Option<i32>)>,
+    /// Previous pitch to go back to, the relative music being closed by the previous token
+    closed: Option<Option<i32>>,
+    depth: usize,
+}
+
+/// Position of a pitch on the staff, in diatonic steps (`7 * octave` plus 0 for `c` to 6 for `b`)
+fn diatonic_step(name: char, octave: i32) -> i32 {
+    7 * octave + "cdefgab".find(name).unwrap_or(0) as i32
+}
+
+/// Duration carried over to notes, rests and hits written without one, as in LilyPond
+#[derive(Debug, Clone, Copy)]
+struct DurationState {
+    duration: u32,
+    dots: u32,
+}
+
+impl Default for DurationState {
+    fn default() -> Self {
+        // A music expression starts with quarter notes
+        DurationState { duration: 4, dots: 0 }
+    }
+}
+
+/// Conventions of the input for what notes leave out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParserOptions {
+    /// Octave of a note without `'` or `,` marks (3: `c` is C3, `c'` middle C)
+    pub default_octave: i32,
+    /// Duration of the first note, rest or hit of a staff written without one (4: a quarter)
+    pub default_duration: u32,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions { default_octave: 3, default_duration: 4 }
+    }
+}
+
+pub struct LilyPondParser {
+    note_to_midi: HashMap<char, i32>,
+    options: ParserOptions,
+    /// See [`LilyPondParser::lenient`]
+    lenient: bool,
+}
+
+/// State of one call to the parser, so that a [`LilyPondParser`] can be shared between threads
+/// and parse several inputs at once
+#[derive(Default)]
+struct ParseContext {
+    /// Collected while parsing, handed out in `ParseResult::warnings`
+    warnings: Vec<Warning>,
+    /// Bar being read, recorded with the warnings
+    bar: Option<u32>,
+    /// Previous pitch inside `\relative` music (see [`diatonic_step`]), `None` outside of it
+    relative: Option<i32>,
+    /// Meter of the score (its first `\time`), the one a staff goes on with after a `\partial`
+    /// pickup when it has no `\time` of its own
+    time: TimeSignature,
+}
+
+impl ParseContext {
+    fn warn(&mut self, message: String) {
+        self.warnings.push(Warning { message, bar: self.bar });
+    }
+}
+
+impl LilyPondParser {
+    pub fn new() -> Self {
+        Self::with_options(ParserOptions::default())
+    }
+
+    pub fn with_options(options: ParserOptions) -> Self {
+        let mut note_to_midi = HashMap::new();
+        note_to_midi.insert('c', 0);
+        note_to_midi.insert('d', 2);
+        note_to_midi.insert('e', 4);
+        note_to_midi.insert('f', 5);
+        note_to_midi.insert('g', 7);
+        note_to_midi.insert('a', 9);
+        note_to_midi.insert('b', 11);
+
+        LilyPondParser {
+            note_to_midi,
+            options,
+            lenient: false,
+        }
+    }
+
+    /// Keep going when a staff fails to parse: the other staves are converted, the failing one
+    /// becomes a staff with an [`Staff::error`] (a comment in the output) and a warning
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    pub fn parse(&self, code: &str) -> Result<ParseResult, String> {
+        self.parse_score(code, self.lenient, None)
+    }
+
+    /// [`LilyPondParser::parse`], leniently or not, with the tempo to use when the input has none
+    fn parse_score(&self, code: &str, lenient: bool, default_tempo: Option<Tempo>) -> Result<ParseResult, String> {
+        let duration = self.options.default_duration;
+        if !duration.is_power_of_two() || duration > 128 {
+            return Err(format!("Default duration {} is not a note value (1, 2, 4, ... 128)", duration));
+        }
+        let (code, midi_blocks) = self.strip_output_blocks(code);
+        let code = code.as_str();
+        let tempo = self
+            .written_tempo(code, &midi_blocks)
+            .or(default_tempo)
+            .ok_or("Missing tempo: LilyPond input must include a \\tempo directive (e.g., \\tempo 4 = 120)")?;
+        let header = self.parse_header(code);
+        let mut ctx = ParseContext { time: self.parse_time(code).unwrap_or_default(), ..Default::default() };
+        let variables = self.parse_variables(code);
+        // Errors of the staves, in lenient mode
+        let mut errors = Vec::new();
+        let marked = self.mark_repeats_recovering(code, lenient.then_some(&mut errors))?;
+        let variables_marked: HashMap<String, VariableKind> = variables
+            .into_iter()
+            .map(|(k, v)| {
+                let first_error = errors.len();
+                let marked_content = self
+                    .mark_repeats_recovering(
+                        match &v {
+                            VariableKind::Pitched(s) => s,
+                            VariableKind::Drums(s) => s,
+                        },
+                        lenient.then_some(&mut errors),
+                    )
+                    .map_err(|e| format!("In variable {}: {}", k, e))?;
+                for error in &mut errors[first_error..] {
+                    *error = format!("In variable {}: {}", k, error);
+                }
+                let new_v = match v {
+                    VariableKind::Pitched(_) => VariableKind::Pitched(marked_content),
+                    VariableKind::Drums(_) => VariableKind::Drums(marked_content),
+                };
+                Ok((k, new_v))
+            })
+            .collect::<Result<_, String>>()?;
+
+        // Try to parse score with staves first, fallback: parse as single staff
+        let staves = match self.parse_score_staves(&mut ctx, &marked, &variables_marked, &errors)? {
+            Some(staves) => staves,
+            None => {
+                let notes_section = self.extract_notes_section(&marked)?;
+                if let Some(error) = Self::staff_error(&notes_section, &errors) {
+                    vec![self.error_staff(&mut ctx, error, None, None, 0)]
+                } else {
+                    let mut staff = Staff::new_pitched(self.parse_notes_from_section(&mut ctx, &notes_section)?);
+                    staff.key = Self::parse_key(&notes_section);
+                    vec![staff]
+                }
+            }
+        };
+
+        self.check_voice_lengths(&mut ctx, &staves);
+        let rubato = self.parse_rubato(&mut ctx, code);
+        let mut result = ParseResult { staves, tempo, time: ctx.time, header, rubato, warnings: ctx.warnings };
+        result.keep_loop();
+        if code.contains("\\unfoldRepeats") {
+            if result.unfolded_events() > Self::MAX_UNFOLDED_EVENTS {
+                result.warnings.push(Warning {
+                    message: format!(
+                        "ignored \\unfoldRepeats: the score would unfold to more than {} events",
+                        Self::MAX_UNFOLDED_EVENTS
+                    ),
+                    bar: None,
+                });
+            } else {
+                result.unfold_repeats();
+            }
+        }
+        Ok(result)
+    }
+
+    /// Like [`LilyPondParser::parse`], but never fails or builds an unbounded score, for input
+    /// that cannot be trusted (a web service converting uploads). A missing tempo becomes
+    /// `4 = 120`, and staves are read leniently (see [`LilyPondParser::lenient`]); input that does
+    /// not parse even so, or unfolds to more than [`LilyPondParser::MAX_UNFOLDED_EVENTS`] events,
+    /// gives a score without staves. Everything skipped is reported in the returned warnings,
+    /// which are taken out of the result.
+    pub fn parse_lossy(&self, code: &str) -> (ParseResult, Vec<Warning>) {
+        let tempo = Tempo { beat_unit: 4, bpm: 120 };
+        let mut notes = Vec::new();
+        let (stripped, midi_blocks) = self.strip_output_blocks(code);
+        if self.written_tempo(&stripped, &midi_blocks).is_none() {
+            notes.push(Warning { message: format!("no \\tempo, using {} = {}", tempo.beat_unit, tempo.bpm), bar: None });
+        }
+
+        let fallback = |mut notes: Vec<Warning>, message: String| {
+            notes.push(Warning { message, bar: None });
+            let result = ParseResult {
+                staves: Vec::new(),
+                tempo: tempo.clone(),
+                time: TimeSignature::default(),
+                header: HashMap::new(),
+                rubato: Vec::new(),
+                warnings: Vec::new(),
+            };
+            (result, notes)
+        };
+        let mut result = match self.parse_score(code, true, Some(tempo.clone())) {
+            Ok(result) => result,
+            Err(e) => return fallback(notes, e),
+        };
+        if result.unfolded_events() > Self::MAX_UNFOLDED_EVENTS {
+            return fallback(notes, format!("the score unfolds to more than {} events", Self::MAX_UNFOLDED_EVENTS));
+        }
+
+        notes.append(&mut result.warnings);
+        (result, notes)
+    }
+
+    /// Parse one named part of the input: a variable (`melodyA = { ... }`) becomes a single staff,
+    /// otherwise a section of the score (see [`ParseResult::section_bars`]) keeps its bars only.
+    /// Tempo and header come from the whole input.
+    pub fn parse_entry(&self, code: &str, entry: &str) -> Result<ParseResult, String> {
+        let mut result = self.parse(code)?;
+        let (code, _) = self.strip_output_blocks(code);
+        let variables = self.parse_variables(&code);
+
+        let Some(kind) = variables.get(entry) else {
+            let (start, end) = result
+                .section_bars(entry)
+                .ok_or_else(|| format!("No variable or section named `{}`", entry))?;
+            result.slice_bars(start, end);
+            return Ok(result);
+        };
+
+        let mut ctx = ParseContext { time: result.time, ..Default::default() };
+        let (VariableKind::Pitched(content) | VariableKind::Drums(content)) = kind;
+        let resolved = self
+            .mark_repeats(&self.resolve_variables(content, &variables))
+            .map_err(|e| format!("In variable {}: {}", entry, e))?;
+        let mut staff = match kind {
+            VariableKind::Pitched(_) => {
+                let mut staff = Staff::new_pitched(self.parse_notes_from_section(&mut ctx, &resolved)?);
+                staff.key = Self::parse_key(&resolved);
+                staff
+            }
+            VariableKind::Drums(_) => {
+                let events = self.parse_drums_from_section(&mut ctx, &resolved)?;
+                Staff::new_drums(vec![DrumVoiceData { events, punchcard_color: None, gain: None, pan: None, speed: None, name: None }])
+            }
+        };
+        staff.name = Some(entry.to_string());
+        result.staves = vec![staff];
+        result.warnings = ctx.warnings;
+        result.keep_loop();
+        Ok(result)
+    }
+
+    /// Parse every `\score` of the input, including those nested in `\book` and `\bookpart`
+    /// blocks, into its own result. Each score keeps the top-level variables, tempo and header;
+    /// the header fields of its enclosing book, bookpart and of the score itself take precedence.
+    /// Input with at most one score parses like [`parse`](Self::parse).
+    pub fn parse_book(&self, code: &str) -> Result<Vec<ParseResult>, String> {
+        let books = self.find_blocks(code, "book");
+        let top_scores: Vec<(usize, usize)> = self
+            .find_blocks(code, "score")
+            .into_iter()
+            .filter(|&(start, _)| !books.iter().any(|&(s, e)| s < start && start < e))
+            .collect();
+
+        // (score range, enclosing header blocks from the outermost in)
+        let mut scores: Vec<((usize, usize), Vec<String>)> =
+            top_scores.iter().map(|&range| (range, Vec::new())).collect();
+        for &(book_start, book_end) in &books {
+            let book = &code[book_start..book_end];
+            let parts = self.find_blocks(book, "bookpart");
+            let book_scores = self.find_blocks(book, "score");
+            let mut nested: Vec<(usize, usize)> = parts.clone();
+            nested.extend(book_scores.iter().filter(|&&(start, _)| !parts.iter().any(|&(s, e)| s < start && start < e)));
+            nested.sort();
+            let book_header = self.own_header(book, &nested);
+            for (start, end) in book_scores {
+                let mut headers = vec![book_header.clone()];
+                if let Some(&(part_start, part_end)) = parts.iter().find(|&&(s, e)| s < start && start < e) {
+                    let part = &book[part_start..part_end];
+                    headers.push(self.own_header(part, &self.find_blocks(part, "score")));
+                }
+                scores.push(((book_start + start, book_start + end), headers));
+            }
+        }
+        scores.sort_by_key(|((start, _), _)| *start);
+
+        if scores.len() <= 1 && books.is_empty() {
+            return Ok(vec![self.parse(code)?]);
+        }
+
+        // Everything outside the books and scores: variables, tempo, global header
+        let mut removed: Vec<(usize, usize)> = books.iter().chain(top_scores.iter()).copied().collect();
+        removed.sort();
+        let mut base = String::new();
+        let mut pos = 0;
+        for (start, end) in removed {
+            base.push_str(&code[pos..start]);
+            pos = end;
+        }
+        base.push_str(&code[pos..]);
+
+        let mut results = Vec::new();
+        for ((start, end), headers) in scores {
+            let score = &code[start..end];
+            let mut result = self.parse(&format!("{}\n{}", base, score))?;
+            for header in headers.iter().map(String::as_str).chain([score]) {
+                result.header.extend(self.parse_header(header));
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Ranges of the top-level `\<command> { ... }` blocks of `code`, braces included
+    fn find_blocks(&self, code: &str, command: &str) -> Vec<(usize, usize)> {
+        let re = regex::Regex::new(&format!(r"\\{}\s*\{{", command)).unwrap();
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+
+        while let Some(m) = re.find_at(code, pos) {
+            let brace_start = m.end() - 1;
+            let Some(content) = self.extract_braced_content(code, brace_start) else {
+                break;
+            };
+            let end = brace_start + content.len() + 2;
+            blocks.push((m.start(), end));
+            pos = end;
+        }
+        blocks
+    }
+
+    /// The `\header { ... }` block of `code` lying outside the given nested blocks
+    fn own_header(&self, code: &str, nested: &[(usize, usize)]) -> String {
+        let mut pos = 0;
+        for &(start, end) in nested {
+            if let Some((header_start, header_end)) = self.find_blocks(&code[pos..start], "header").first() {
+                return code[pos + header_start..pos + header_end].to_string();
+            }
+            pos = end;
+        }
+        self.find_blocks(&code[pos..], "header")
+            .first()
+            .map(|&(start, end)| code[pos + start..pos + end].to_string())
+            .unwrap_or_default()
+    }
+
+    /// Parse drum mode content (`bd4 sn4 hh8 hh8`) on its own, returning its events and what
+    /// could not be converted
+    pub fn parse_drum_content(&self, content: &str) -> Result<(Vec<DrumEvent>, Vec<Warning>), String> {
+        let mut ctx = ParseContext::default();
+        let marked = self.mark_repeats(content)?;
+        let events = self.parse_drums_from_section(&mut ctx, &marked)?;
+        Ok((events, ctx.warnings))
+    }
+
+    /// Parse pitched content (`c'4 <e' g'>2.`) on its own, like [`Self::parse_drum_content`]
+    pub fn parse_pitched_content(&self, content: &str) -> Result<(Vec<PitchedEvent>, Vec<Warning>), String> {
+        let mut ctx = ParseContext::default();
+        let marked = self.mark_repeats(content)?;
+        let events = self.parse_notes_from_section(&mut ctx, &marked)?;
+        Ok((events, ctx.warnings))
+    }
+
+    /// Drum voices of different lengths drift apart: the shorter one loops early in the stack
+    fn check_voice_lengths(&self, ctx: &mut ParseContext, staves: &[Staff]) {
+        for (index, staff) in staves.iter().enumerate() {
+            let bars = staff.voice_bars();
+            let longest = bars.iter().copied().max().unwrap_or(0);
+            for (voice, &count) in bars.iter().enumerate().filter(|&(_, &count)| count < longest) {
+                let longest_voice = bars.iter().position(|&b| b == longest).unwrap_or(0);
+                ctx.warn(format!(
+                    "{}: voice {} has {} bars but voice {} has {}; it will loop early (pad it with rests)",
+                    StrudelGenerator::staff_label(staff, index),
+                    voice + 1,
+                    count,
+                    longest_voice + 1,
+                    longest
+                ));
+            }
+        }
+    }
+
+    /// Parse the marks following a note's duration, warning about anything not understood
+    fn parse_attachments(&self, ctx: &mut ParseContext, text: &str, token: &str) -> Attachments {
+        let attachments = Attachments::parse(text);
+        for unrecognized in &attachments.unrecognized {
+            ctx.warn(format!("ignored `{}` attached to `{}`", unrecognized, token));
+        }
+        attachments
+    }
+
+    /// Remove `\layout { ... }` and `\midi { ... }` blocks, which only hold engraving and
+    /// playback settings, returning the remaining code and the contents of the midi blocks
+    fn strip_output_blocks(&self, code: &str) -> (String, Vec<String>) {
+        let re = regex::Regex::new(r"\\(layout|midi)\s*\{").unwrap();
+        let mut result = code.to_string();
+        let mut midi_blocks = Vec::new();
+        let mut pos = 0;
+
+        while let Some(caps) = re.captures_at(&result, pos) {
+            let whole = caps.get(0).unwrap();
+            let (start, brace_start) = (whole.start(), whole.end() - 1);
+            let is_midi = &caps[1] == "midi";
+            let Some(content) = self.extract_braced_content(&result, brace_start) else {
+                break;
+            };
+            let end = brace_start + content.len() + 2;
+            if is_midi {
+                midi_blocks.push(content);
+            }
+            result.replace_range(start..end, "");
+            pos = start;
+        }
+
+        (result, midi_blocks)
+    }
+
+    /// Tempo of the score (output blocks stripped), else of its `\midi` blocks
+    fn written_tempo(&self, code: &str, midi_blocks: &[String]) -> Option<Tempo> {
+        self.parse_tempo(code).or_else(|| midi_blocks.iter().find_map(|block| self.parse_midi_tempo(block)))
+    }
+
+    /// Tempo of a `\midi` block: `\tempo 4 = 90`, or the older
+    /// `tempoWholesPerMinute = #(ly:make-moment 90 4)` context setting
+    fn parse_midi_tempo(&self, block: &str) -> Option<Tempo> {
+        if let Some(tempo) = self.parse_tempo(block) {
+            return Some(tempo);
+        }
+        let re = regex::Regex::new(r"tempoWholesPerMinute\s*=\s*#\(ly:make-moment\s+(\d+)(?:\s*/\s*|\s+)(\d+)\s*\)").unwrap();
+        let caps = re.captures(block)?;
+        let bpm: u32 = caps[1].parse().ok()?;
+        let beat_unit: u32 = caps[2].parse().ok()?;
+        Some(Tempo { beat_unit, bpm })
+    }
+
+    fn parse_header(&self, code: &str) -> HashMap<String, String> {
+        let mut header = HashMap::new();
+        let header_re = regex::Regex::new(r"\\header\s*\{").unwrap();
+        let Some(header_match) = header_re.find(code) else {
+            return header;
+        };
+        let Some(content) = self.extract_braced_content(code, header_match.end() - 1) else {
+            return header;
+        };
+
+        // Only plain string fields: title = "..." (markup values are ignored)
+        let field_re = regex::Regex::new(r#"([a-zA-Z][a-zA-Z-]*)\s*=\s*"([^"]*)""#).unwrap();
+        for caps in field_re.captures_iter(&content) {
+            header.insert(caps[1].to_string(), caps[2].to_string());
+        }
+        header
+    }
+
+    fn parse_variables(&self, code: &str) -> HashMap<String, VariableKind> {
+        let mut variables = HashMap::new();
+
+        // name = { ... }, optionally behind a chain of wrapper commands with their arguments:
+        // name = \drummode { ... }, name = \relative c'' { ... }, name = \new Voice = "up" \drummode { ... }
+        let re = regex::Regex::new(
+            r#"(?m)^([a-zA-Z_][a-zA-Z0-9_]*)\s*=\s*((?:\\[a-zA-Z]+(?:\s*=\s*"[^"\n]*"|\s+[a-zA-Z][a-zA-Z',]*)*\s*)*)\{"#,
+        )
+        .unwrap();
+        for caps in re.captures_iter(code) {
+            let name = caps.get(1).unwrap().as_str().to_string();
+            let wrappers = caps.get(2).unwrap().as_str();
+            let brace_start = caps.get(0).unwrap().end() - 1;
+
+            let commands: Vec<&str> = wrappers
+                .split_whitespace()
+                .filter_map(|w| w.strip_prefix('\\'))
+                .collect();
+            // Not music: \markup, \header, ... blocks assigned to a variable
+            if commands.iter().any(|c| matches!(*c, "markup" | "header" | "layout" | "midi" | "paper" | "score" | "book")) {
+                continue;
+            }
+
+            if let Some(content) = self.extract_braced_content(code, brace_start) {
+                let relative = wrappers.find("\\relative").and_then(|at| Self::relative_re().find(&wrappers[at..]));
+                let kind = if commands.iter().any(|c| matches!(*c, "drummode" | "drums")) {
+                    VariableKind::Drums(content)
+                } else if let Some(relative) = relative {
+                    VariableKind::Pitched(format!("{} {{{}}}", relative.as_str().trim_end(), content))
+                } else {
+                    VariableKind::Pitched(content)
+                };
+                variables.insert(name, kind);
+            }
+        }
+
+        variables
+    }
+
+    /// The two parts of the first `\partcombine` (or `\partCombine`) in `content`, each a
+    /// variable reference or a braced block, and the offset just after the second part
+    fn partcombine_parts(&self, content: &str) -> Option<(String, String, usize)> {
+        let re = regex::Regex::new(r"\\part[cC]ombine\b").unwrap();
+        let mut pos = re.find(content)?.end();
+        let mut parts = Vec::new();
+
+        while parts.len() < 2 {
+            pos += content[pos..].len() - content[pos..].trim_start().len();
+            let rest = &content[pos..];
+            if rest.starts_with('{') {
+                let inner = self.extract_braced_content(content, pos)?;
+                pos += inner.len() + 2;
+                parts.push(inner);
+            } else if let Some(name) = rest.strip_prefix('\\') {
+                let len = 1 + name.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(name.len());
+                parts.push(rest[..len].to_string());
+                pos += len;
+            } else {
+                return None;
+            }
+        }
+
+        let second = parts.pop()?;
+        let first = parts.pop()?;
+        Some((first, second, pos))
+    }
+
+    /// Parse the optional `= "name"` and `\with { ... }` clauses following `\new Context`
+    /// at `pos`, up to the start of the context's music
+    fn parse_context_clause(&self, code: &str, pos: usize) -> Option<ContextClause> {
+        let name_re = regex::Regex::new(r#"^\s*(?:=\s*"([^"]*)")?\s*"#).unwrap();
+        let caps = name_re.captures(&code[pos..])?;
+        let name = caps.get(1).map(|m| m.as_str().to_string());
+        let mut body = pos + caps.get(0).unwrap().end();
+
+        let mut with_block = None;
+        let with_re = regex::Regex::new(r"^\\with\s*\{").unwrap();
+        if let Some(with_match) = with_re.find(&code[body..]) {
+            let brace = body + with_match.end() - 1;
+            let content = self.extract_braced_content(code, brace)?;
+            body = brace + content.len() + 2;
+            body += code[body..].len() - code[body..].trim_start().len();
+            with_block = Some(content);
+        }
+
+        let relative = Self::relative_re().find(&code[body..]).map(|m| {
+            body += m.end();
+            m.as_str().trim_end().to_string()
+        });
+
+        Some(ContextClause { name, with_block, relative, body })
+    }
+
+    /// `\relative`, with its reference pitch if any, at the start of the text
+    fn relative_re() -> regex::Regex {
+        regex::Regex::new(r"^\\relative(?:\s+[a-g][a-z]*[',]*)?\s*").unwrap()
+    }
+
+    /// `instrumentName` set in the `\with` block, or with `\set Staff.instrumentName` in the music
+    fn parse_instrument_name(&self, clause: &ContextClause, content: &str) -> Option<String> {
+        let re = regex::Regex::new(r#"instrumentName\s*=\s*#?"([^"]*)""#).unwrap();
+        clause
+            .with_block
+            .as_deref()
+            .and_then(|block| re.captures(block))
+            .or_else(|| re.captures(content))
+            .map(|caps| caps.get(1).unwrap().as_str().to_string())
+    }
+
+    fn parse_punchcard_color(&self, content: &str) -> Option<String> {
         // Look for % @strudel-of-lilypond@ <color> punchcard comment
         let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+(\w+)\s+punchcard").unwrap();
         re.captures(content).map(|caps| caps.get(1).unwrap().as_str().to_string())
@@ -296,7 +2749,20 @@ impl LilyPondParser {
         // Look for % @strudel-of-lilypond@ gain <value> comment
         // Value can be a number (2) or a Strudel pattern (<0.5 1 1.5>)
         let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+gain\s+([^\n]+)").unwrap();
-        re.captures(content).map(|caps| caps.get(1).unwrap().as_str().trim().to_string())
+        let gain = re.captures(content).map(|caps| caps.get(1).unwrap().as_str().trim().to_string());
+
+        // % @strudel-of-lilypond@ accents <1 .6 .8 .6>: the values played over every 4/4 bar,
+        // scaling the gain if there is one
+        let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+accents\s+([^\n]+)").unwrap();
+        let Some(caps) = re.captures(content) else {
+            return gain;
+        };
+        let values = caps[1].trim().trim_start_matches('<').trim_end_matches('>').trim();
+        let accents = format!("\"{}\".fast(nbars)", values);
+        Some(match gain {
+            Some(gain) => format!("{}.mul({})", accents, StrudelGenerator::format_pattern_value(&gain)),
+            None => accents,
+        })
     }
 
     fn parse_pan(&self, content: &str) -> Option<String> {
@@ -306,6 +2772,216 @@ impl LilyPondParser {
         re.captures(content).map(|caps| caps.get(1).unwrap().as_str().trim().to_string())
     }
 
+    fn parse_speed(&self, content: &str) -> Option<Speed> {
+        // Look for % @strudel-of-lilypond@ slow <factor> or fast <factor> comment
+        let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+(slow|fast)\s+([^\n]+)").unwrap();
+        re.captures(content).map(|caps| {
+            let factor = caps.get(2).unwrap().as_str().trim().to_string();
+            match caps.get(1).unwrap().as_str() {
+                "slow" => Speed::Slow(factor),
+                _ => Speed::Fast(factor),
+            }
+        })
+    }
+
+    /// `% @strudel-of-lilypond@ percussion c'=bd e'=sd g'=hh`: the drum sound of each pitch
+    /// (by MIDI number) of a pitched staff written as a rhythm part
+    fn parse_percussion_map(&self, ctx: &mut ParseContext, content: &str) -> Option<HashMap<i32, String>> {
+        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+percussion\s+(.+)$").unwrap();
+        let caps = re.captures(content)?;
+        let mut map = HashMap::new();
+        for pair in caps[1].split_whitespace() {
+            let note = pair
+                .split_once('=')
+                .filter(|(_, sound)| !sound.is_empty())
+                .and_then(|(pitch, sound)| {
+                    let note = self.parse_single_note(ctx, pitch, &mut DurationState::default()).ok()??;
+                    Some((note.midi, sound.to_string()))
+                });
+            match note {
+                Some((midi, sound)) => {
+                    map.insert(midi, sound);
+                }
+                None => ctx.warn(format!("ignored `{}` in percussion mapping: expected pitch=sound (e.g. c'=bd)", pair)),
+            }
+        }
+        Some(map)
+    }
+
+    /// Turn pitched events into drum voices through a percussion mapping: one voice per chord
+    /// note, unmapped pitches become rests
+    fn percussion_voices(&self, ctx: &mut ParseContext, events: &[PitchedEvent], map: &HashMap<i32, String>) -> Vec<Vec<DrumEvent>> {
+        let voice_count = events
+            .iter()
+            .map(|e| match e {
+                PitchedEvent::Note(n) => 1 + n.chord_notes.as_ref().map_or(0, Vec::len),
+                _ => 1,
+            })
+            .max()
+            .unwrap_or(1);
+        let mut unmapped: Vec<i32> = Vec::new();
+        let mut voices = vec![Vec::new(); voice_count];
+
+        for event in events {
+            for (v, voice) in voices.iter_mut().enumerate() {
+                let drum_event = match event {
+                    PitchedEvent::Note(n) => {
+                        let pitch = if v == 0 { Some(n) } else { n.chord_notes.as_ref().and_then(|c| c.get(v - 1)) };
+                        match pitch.and_then(|p| map.get(&p.midi)) {
+                            Some(sound) => DrumEvent::Hit(DrumHit {
+                                name: sound.clone(),
+                                duration: n.duration,
+                                length: n.length,
+                                beam: n.beam,
+                                dynamic: n.dynamic.clone(),
+                                hairpin: n.hairpin,
+                                articulations: n.articulations.clone(),
+                                ..Default::default()
+                            }),
+                            None => {
+                                if let Some(p) = pitch.filter(|p| !unmapped.contains(&p.midi)) {
+                                    unmapped.push(p.midi);
+                                    ctx.warn(format!(
+                                        "no percussion sound for `{}`, played as a rest",
+                                        StrudelGenerator::format_note(p, &GeneratorOptions::default())
+                                    ));
+                                }
+                                DrumEvent::Rest { duration: n.duration, length: n.length }
+                            }
+                        }
+                    }
+                    PitchedEvent::Rest { duration, length } => DrumEvent::Rest { duration: *duration, length: *length },
+                    PitchedEvent::BarLine => DrumEvent::BarLine,
+                    PitchedEvent::RepeatStart(count) => DrumEvent::RepeatStart(*count),
+                    PitchedEvent::RepeatEnd => DrumEvent::RepeatEnd,
+                    PitchedEvent::AlternativeStart => DrumEvent::AlternativeStart,
+                    PitchedEvent::AlternativeEnd => DrumEvent::AlternativeEnd,
+                    PitchedEvent::CadenzaOn => DrumEvent::CadenzaOn,
+                    PitchedEvent::CadenzaOff => DrumEvent::CadenzaOff,
+                    PitchedEvent::Time(time) => DrumEvent::Time(*time),
+                    // Section names once per staff
+                    PitchedEvent::Comment(text) if v == 0 => DrumEvent::Comment(text.clone()),
+                    PitchedEvent::Comment(_) => continue,
+                };
+                voice.push(drum_event);
+            }
+        }
+        voices
+    }
+
+    /// `% @strudel-of-lilypond@ rubato 5-8=0.8 12=0.5`: tempo multipliers of bar ranges
+    fn parse_rubato(&self, ctx: &mut ParseContext, code: &str) -> Vec<Rubato> {
+        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+rubato\s+(.+)$").unwrap();
+        let mut rubato = Vec::new();
+        for caps in re.captures_iter(code) {
+            for pair in caps[1].split_whitespace() {
+                let range = pair.split_once('=').and_then(|(bars, factor)| {
+                    let (first, last) = bars.split_once('-').unwrap_or((bars, bars));
+                    let (first_bar, last_bar) = (first.parse::<u32>().ok()?, last.parse::<u32>().ok()?);
+                    let valid = first_bar >= 1 && first_bar <= last_bar && factor.parse::<f64>().is_ok_and(|f| f > 0.0);
+                    valid.then(|| Rubato { first_bar, last_bar, factor: factor.to_string() })
+                });
+                match range {
+                    Some(range) => rubato.push(range),
+                    None => ctx.warn(format!("ignored `{}` in rubato map: expected bars=factor (e.g. 5-8=0.8)", pair)),
+                }
+            }
+        }
+        rubato
+    }
+
+    fn parse_sample(&self, content: &str) -> Option<Sample> {
+        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+sample\s+(\S+)(\s+n)?\s*$").unwrap();
+        re.captures(content).map(|caps| Sample { name: caps[1].to_string(), slices: caps.get(2).is_some() })
+    }
+
+    /// `% @strudel-of-lilypond@ range violin` (see [`INSTRUMENT_RANGES`]) or `range g c''''`:
+    /// warn about the notes of the staff outside of it, which often come from a wrong octave mark
+    fn check_range(&self, ctx: &mut ParseContext, content: &str, events: &[PitchedEvent]) {
+        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+range\s+(.+)$").unwrap();
+        let Some(caps) = re.captures(content) else {
+            return;
+        };
+        let words: Vec<&str> = caps[1].split_whitespace().collect();
+        // Absolute pitches, whatever `\relative` the staff is in
+        let pitch = |word: &str| {
+            let note = self.parse_single_note(&mut ParseContext::default(), word, &mut DurationState::default()).ok()??;
+            Some(note.midi)
+        };
+        let (name, low, high) = match words.as_slice() {
+            [name] => match INSTRUMENT_RANGES.iter().find(|(instrument, _, _)| instrument == name) {
+                Some(&(instrument, low, high)) => (instrument.to_string(), low, high),
+                None => {
+                    let known: Vec<&str> = INSTRUMENT_RANGES.iter().map(|(instrument, _, _)| *instrument).collect();
+                    ctx.warn(format!("unknown instrument range `{}` (expected {})", name, known.join(", ")));
+                    return;
+                }
+            },
+            [low, high] => match (pitch(low), pitch(high)) {
+                (Some(low), Some(high)) => ("given".to_string(), low, high),
+                _ => {
+                    ctx.warn(format!("ignored range `{}`: expected an instrument or two pitches", &caps[1].trim()));
+                    return;
+                }
+            },
+            _ => {
+                ctx.warn(format!("ignored range `{}`: expected an instrument or two pitches", &caps[1].trim()));
+                return;
+            }
+        };
+
+        let mut bar = 1;
+        for event in events {
+            match event {
+                PitchedEvent::BarLine => bar += 1,
+                PitchedEvent::Note(note) => {
+                    let pitches = std::iter::once(note.midi).chain(note.chord_notes.iter().flatten().map(|c| c.midi));
+                    for midi in pitches.filter(|midi| !(low..=high).contains(midi)) {
+                        let side = if midi < low { "below" } else { "above" };
+                        ctx.warnings.push(Warning {
+                            message: format!(
+                                "{} is {} the {} range ({} to {})",
+                                pitch_name(midi),
+                                side,
+                                name,
+                                pitch_name(low),
+                                pitch_name(high)
+                            ),
+                            bar: Some(bar),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The first `\key` of pitched content
+    fn parse_key(content: &str) -> Option<KeySignature> {
+        let re = regex::Regex::new(r"\\key\s+([a-g](?:isis|eses|is|es|s)?)\s*\\([a-z]+)").unwrap();
+        let caps = re.captures(content)?;
+        Some(KeySignature { tonic: caps[1].to_string(), mode: caps[2].to_string() })
+    }
+
+    fn parse_repeat_style(&self, ctx: &mut ParseContext, content: &str) -> Option<RepeatStyle> {
+        // Look for % @strudel-of-lilypond@ repeats unrolled (or compressed)
+        let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+repeats\s+(\S+)").unwrap();
+        let caps = re.captures(content)?;
+        match caps[1].parse() {
+            Ok(style) => Some(style),
+            Err(e) => {
+                ctx.warn(e);
+                None
+            }
+        }
+    }
+
+    fn parse_octave_shift(&self, content: &str) -> Option<i32> {
+        // Look for % @strudel-of-lilypond@ octave <n> comment, e.g. octave -1
+        let re = regex::Regex::new(r"%\s*@strudel-of-lilypond@\s+octave\s+([+-]?\d+)").unwrap();
+        re.captures(content).and_then(|caps| caps.get(1).unwrap().as_str().parse().ok())
+    }
+
     fn extract_braced_content(&self, code: &str, brace_start: usize) -> Option<String> {
         let mut depth = 1;
 
@@ -325,10 +3001,72 @@ impl LilyPondParser {
         None
     }
 
+    /// Extract the content of a `<< ... >>` block starting at `start`, matching nested
+    /// `<<`/`>>` pairs (single `<`/`>` chord brackets are not counted)
+    fn extract_simultaneous_content(&self, code: &str, start: usize) -> Option<String> {
+        let bytes = code.as_bytes();
+        let mut depth = 0;
+        let mut i = start;
+
+        while i + 1 < bytes.len() {
+            if bytes[i] == b'<' && bytes[i + 1] == b'<' {
+                depth += 1;
+                i += 2;
+            } else if bytes[i] == b'>' && bytes[i + 1] == b'>' {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(code[start + 2..i].to_string());
+                }
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+        None
+    }
+
+    /// Contents of the top-level `<< ... >>` blocks of `code`, in order
+    fn simultaneous_groups(&self, code: &str) -> Vec<String> {
+        let mut groups = Vec::new();
+        let mut pos = 0;
+
+        while let Some(offset) = code[pos..].find("<<") {
+            let start = pos + offset;
+            let Some(content) = self.extract_simultaneous_content(code, start) else {
+                break;
+            };
+            pos = start + content.len() + 4;
+            groups.push(content);
+        }
+        groups
+    }
+
+    /// Error of the first `\repeat` of `content` that could not be read, see [`PARSE_ERROR`]
+    fn staff_error(content: &str, errors: &[String]) -> Option<String> {
+        let start = content.find(PARSE_ERROR)? + PARSE_ERROR.len();
+        let digits: String = content[start..].chars().take_while(char::is_ascii_digit).collect();
+        errors.get(digits.parse::<usize>().ok()?).cloned()
+    }
+
+    /// Empty staff standing for the staff `index` that failed to parse, with a warning
+    fn error_staff(&self, ctx: &mut ParseContext, error: String, name: Option<String>, instrument: Option<String>, index: usize) -> Staff {
+        let mut staff = Staff::new_pitched(Vec::new());
+        staff.name = name;
+        staff.instrument = instrument;
+        ctx.warnings.push(Warning {
+            message: format!("{} could not be converted: {}", StrudelGenerator::staff_label(&staff, index), error),
+            bar: None,
+        });
+        staff.error = Some(error);
+        staff
+    }
+
     fn parse_score_staves(
         &self,
+        ctx: &mut ParseContext,
         code: &str,
         variables: &HashMap<String, VariableKind>,
+        errors: &[String],
     ) -> Result<Option<Vec<Staff>>, String> {
         // Find \score { << ... >> } blocks
         let score_re = regex::Regex::new(r"\\score\s*\{").unwrap();
@@ -342,72 +3080,137 @@ impl LilyPondParser {
             return Ok(None);
         };
 
-        // Find << >> block within score
-        let Some(sim_start) = score_content.find("<<") else {
-            return Ok(None);
-        };
-        let Some(sim_end) = score_content.rfind(">>") else {
-            return Ok(None);
-        };
-
-        if sim_start >= sim_end {
+        // Find the top-level << >> block(s) within score
+        let groups = self.simultaneous_groups(&score_content);
+        if groups.is_empty() {
             return Ok(None);
         }
 
-        let simultaneous_content = &score_content[sim_start + 2..sim_end];
+        let simultaneous_content = &groups.join("\n");
 
         let mut staves = Vec::new();
 
-        // Find all \new Staff or \new TabStaff blocks (pitched)
-        let staff_re = regex::Regex::new(r"\\new\s+(Staff|TabStaff)\s*\{").unwrap();
+        // Find all \new Staff, \new TabStaff and \new DrumStaff blocks in one pass, so the tracks
+        // keep the order of the score (this also picks up staves nested in StaffGroup and similar groups)
+        let staff_re = regex::Regex::new(r"\\new\s+(Staff|TabStaff|DrumStaff)\b").unwrap();
         for caps in staff_re.captures_iter(simultaneous_content) {
-            let full_match = caps.get(0).unwrap();
-            let brace_pos = simultaneous_content[..full_match.end()]
-                .rfind('{')
-                .unwrap();
+            let Some(clause) = self.parse_context_clause(simultaneous_content, caps.get(0).unwrap().end()) else {
+                continue;
+            };
+            let body = &simultaneous_content[clause.body..];
+
+            if &caps[1] == "DrumStaff" {
+                // Either a { ... } or a << ... >> body
+                let staff_content = if body.starts_with('{') {
+                    self.extract_braced_content(simultaneous_content, clause.body)
+                } else if body.starts_with("<<") {
+                    self.extract_simultaneous_content(simultaneous_content, clause.body)
+                        .map(|content| format!("<<{}>>", content))
+                } else {
+                    None
+                };
 
-            if let Some(staff_content) =
-                self.extract_braced_content(simultaneous_content, brace_pos)
-            {
+                if let Some(staff_content) = staff_content {
+                    if let Some(error) = Self::staff_error(&self.resolve_variables(&staff_content, variables), errors) {
+                        let instrument = self.parse_instrument_name(&clause, &staff_content);
+                        staves.push(self.error_staff(ctx, error, clause.name, instrument, staves.len()));
+                        continue;
+                    }
+                    let voices = self.parse_drum_voices(ctx, &staff_content, variables)?;
+                    if !voices.is_empty() {
+                        let mut staff = Staff::new_drums(voices);
+                        staff.instrument = self.parse_instrument_name(&clause, &staff_content);
+                        staff.repeat_style = self.parse_repeat_style(ctx, &staff_content);
+                        staff.name = clause.name;
+                        staves.push(staff);
+                    }
+                }
+                continue;
+            }
+
+            let staff_content = if body.starts_with('{') {
+                self.extract_braced_content(simultaneous_content, clause.body)
+            } else {
+                // \new Staff \partcombine \flute \oboe
+                self.partcombine_parts(body).map(|(_, _, end)| body[..end].to_string())
+            };
+
+            let Some(mut staff_content) = staff_content else {
+                continue;
+            };
+            if let Some(relative) = &clause.relative {
+                staff_content = format!("{} {{{}}}", relative, staff_content);
+            }
+
+            // \partcombine degrades into one staff per part, played together
+            let sections = match self.partcombine_parts(&staff_content) {
+                Some((first, second, _)) => vec![first, second],
+                None => vec![staff_content.clone()],
+            };
+
+            for section in sections {
+                let instrument = self.parse_instrument_name(&clause, &staff_content);
                 let punchcard_color = self.parse_punchcard_color(&staff_content);
                 let gain = self.parse_gain(&staff_content);
                 let pan = self.parse_pan(&staff_content);
-                let resolved = self.resolve_variables(&staff_content, variables);
+                let resolved = self.resolve_variables(&section, variables);
+                if let Some(error) = Self::staff_error(&resolved, errors) {
+                    staves.push(self.error_staff(ctx, error, clause.name.clone(), instrument, staves.len()));
+                    continue;
+                }
                 // Check if resolved content is from a drum variable
-                if self.is_drum_content(&staff_content, variables) {
-                    let hits = self.parse_drums_from_section(&resolved)?;
+                if self.is_drum_content(&section, variables) {
+                    let hits = self.parse_drums_from_section(ctx, &resolved)?;
                     if !hits.is_empty() {
-                        let voice_data = DrumVoiceData { events: hits, punchcard_color, gain, pan };
-                        staves.push(Staff::new_drums(vec![voice_data]));
+                        let speed = self.parse_speed(&staff_content);
+                        let voice_data = DrumVoiceData { events: hits, punchcard_color, gain, pan, speed, name: None };
+                        let mut staff = Staff::new_drums(vec![voice_data]);
+                        staff.name = clause.name.clone();
+                        staff.instrument = instrument;
+                        staff.repeat_style = self.parse_repeat_style(ctx, &staff_content);
+                        staves.push(staff);
+                    }
+                } else if let Some(map) = self.parse_percussion_map(ctx, &staff_content) {
+                    // A rhythm part written with pitches, played as drums
+                    let notes = self.parse_notes_from_section(ctx, &resolved)?;
+                    if !notes.is_empty() {
+                        let speed = self.parse_speed(&staff_content);
+                        let voices = self
+                            .percussion_voices(ctx, &notes, &map)
+                            .into_iter()
+                            .map(|events| DrumVoiceData {
+                                events,
+                                punchcard_color: punchcard_color.clone(),
+                                gain: gain.clone(),
+                                pan: pan.clone(),
+                                speed: speed.clone(),
+                                name: None,
+                            })
+                            .collect();
+                        let mut staff = Staff::new_drums(voices);
+                        staff.name = clause.name.clone();
+                        staff.instrument = instrument;
+                        staff.repeat_style = self.parse_repeat_style(ctx, &staff_content);
+                        staves.push(staff);
                     }
                 } else {
-                    let notes = self.parse_notes_from_section(&resolved)?;
+                    let notes = self.parse_notes_from_section(ctx, &resolved)?;
                     if !notes.is_empty() {
-                        let staff = Staff::new_pitched_with_options(notes, punchcard_color, gain, pan);
+                        self.check_range(ctx, &staff_content, &notes);
+                        let mut staff = Staff::new_pitched_with_options(notes, punchcard_color, gain, pan);
+                        staff.octave_shift = self.parse_octave_shift(&staff_content).unwrap_or(0);
+                        staff.sample = self.parse_sample(&staff_content);
+                        staff.speed = self.parse_speed(&staff_content);
+                        staff.name = clause.name.clone();
+                        staff.instrument = instrument;
+                        staff.repeat_style = self.parse_repeat_style(ctx, &staff_content);
+                        staff.key = Self::parse_key(&resolved);
                         staves.push(staff);
                     }
                 }
             }
         }
 
-        // Find all \new DrumStaff blocks
-        let drum_staff_re = regex::Regex::new(r"\\new\s+DrumStaff\s*\{").unwrap();
-        for caps in drum_staff_re.captures_iter(simultaneous_content) {
-            let full_match = caps.get(0).unwrap();
-            let brace_pos = simultaneous_content[..full_match.end()]
-                .rfind('{')
-                .unwrap();
-
-            if let Some(staff_content) =
-                self.extract_braced_content(simultaneous_content, brace_pos)
-            {
-                let voices = self.parse_drum_voices(&staff_content, variables)?;
-                if !voices.is_empty() {
-                    staves.push(Staff::new_drums(voices));
-                }
-            }
-        }
-
         // If no \new Staff blocks found, look for direct variable references
         if staves.is_empty() {
             let var_ref_re = regex::Regex::new(r"\\([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
@@ -416,15 +3219,17 @@ impl LilyPondParser {
                 if let Some(var_kind) = variables.get(var_name) {
                     match var_kind {
                         VariableKind::Pitched(content) => {
-                            let notes = self.parse_notes_from_section(content)?;
+                            let notes = self.parse_notes_from_section(ctx, content)?;
                             if !notes.is_empty() {
-                                staves.push(Staff::new_pitched(notes));
+                                let mut staff = Staff::new_pitched(notes);
+                                staff.key = Self::parse_key(content);
+                                staves.push(staff);
                             }
                         }
                         VariableKind::Drums(content) => {
-                            let hits = self.parse_drums_from_section(content)?;
+                            let hits = self.parse_drums_from_section(ctx, content)?;
                             if !hits.is_empty() {
-                                let voice_data = DrumVoiceData { events: hits, punchcard_color: None, gain: None, pan: None };
+                                let voice_data = DrumVoiceData { events: hits, punchcard_color: None, gain: None, pan: None, speed: None, name: None };
                                 staves.push(Staff::new_drums(vec![voice_data]));
                             }
                         }
@@ -452,7 +3257,14 @@ impl LilyPondParser {
     }
 
     fn resolve_variables(&self, content: &str, variables: &HashMap<String, VariableKind>) -> String {
-        let mut result = content.to_string();
+        // `\tuplet 3/2 \riff` scales the whole variable, not just its first note
+        let tuplet_re = regex::Regex::new(r"(\\(?:tuplet|times)\s+\d+/\d+(?:\s+\d+)?)\s+(\\[a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+        let mut result = tuplet_re
+            .replace_all(content, |caps: &regex::Captures| match variables.contains_key(&caps[2][1..]) {
+                true => format!("{} {{ {} }}", &caps[1], &caps[2]),
+                false => caps[0].to_string(),
+            })
+            .to_string();
         let var_ref_re = regex::Regex::new(r"\\([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
 
         // Keep resolving until no more changes (handles nested references)
@@ -478,73 +3290,527 @@ impl LilyPondParser {
                 break;
             }
         }
-
-        result
+
+        result
+    }
+
+    /// Remove `% ...` line comments and `%{ ... %}` block comments (but not a `%` in a string),
+    /// so that their words are not read as notes
+    fn strip_comments(code: &str) -> String {
+        let mut result = String::with_capacity(code.len());
+        let mut chars = code.chars().peekable();
+        let mut in_string = false;
+        let mut escaped = false;
+
+        while let Some(c) = chars.next() {
+            if in_string {
+                in_string = escaped || c != '"';
+                escaped = !escaped && c == '\\';
+                result.push(c);
+            } else if c == '%' && chars.peek() == Some(&'{') {
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '%' && c == '}' {
+                        break;
+                    }
+                    previous = c;
+                }
+                result.push(' ');
+            } else if c == '%' {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        result.push('\n');
+                        break;
+                    }
+                }
+            } else {
+                in_string = c == '"';
+                result.push(c);
+            }
+        }
+        result
+    }
+
+    /// Remove inline Scheme (`#red`, `##f`, `#'(2 . 3)`, `#(set-accidental-style 'modern)`) and
+    /// the property commands it goes with (`\once \override NoteHead.color = ...`, `\set`,
+    /// `\tweak`), which would otherwise leave words that read as notes
+    fn strip_scheme(code: &str) -> String {
+        fn skip_string(chars: &mut std::iter::Peekable<std::str::Chars>) {
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                if !escaped && c == '"' {
+                    break;
+                }
+                escaped = !escaped && c == '\\';
+            }
+        }
+
+        let mut result = String::with_capacity(code.len());
+        let mut chars = code.chars().peekable();
+        let mut in_string = false;
+        let mut escaped = false;
+
+        while let Some(c) = chars.next() {
+            if in_string {
+                in_string = escaped || c != '"';
+                escaped = !escaped && c == '\\';
+                result.push(c);
+            } else if c == '#' {
+                // Quote, quasiquote or a second hash (`##t`) before the expression
+                while chars.next_if(|&c| matches!(c, '#' | '\'' | '`')).is_some() {}
+                match chars.next() {
+                    Some('(') => {
+                        let mut depth = 1;
+                        while depth > 0 {
+                            match chars.next() {
+                                Some('(') => depth += 1,
+                                Some(')') => depth -= 1,
+                                Some('"') => skip_string(&mut chars),
+                                Some(_) => {}
+                                None => break,
+                            }
+                        }
+                    }
+                    Some('"') => skip_string(&mut chars),
+                    Some(_) => while chars.next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}')).is_some() {},
+                    None => {}
+                }
+                result.push(' ');
+            } else {
+                in_string = c == '"';
+                result.push(c);
+            }
+        }
+
+        let property_re = regex::Regex::new(
+            r#"\\once\b|\\(?:override|revert|set|unset)\s+[A-Za-z][\w.-]*(?:\s*=\s*(?:"[^"]*"|-?[\d.]+)?)?|\\tweak\s+[A-Za-z][\w.-]*"#,
+        )
+        .unwrap();
+        property_re.replace_all(&result, " ").to_string()
+    }
+
+    fn mark_comments(&self, section: &str) -> String {
+        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+comment\s+(.+)$").unwrap();
+        let section = re.replace_all(section, |caps: &regex::Captures| {
+            let text = caps.get(1).unwrap().as_str().replace(' ', "\x01");
+            format!("__COMMENT_{}__", text)
+        });
+        let loop_re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+loop\s+(start|end)\s*$").unwrap();
+        let section = loop_re.replace_all(&section, |caps: &regex::Captures| {
+            if &caps[1] == "start" { format!(" {} ", LOOP_START) } else { format!(" {} ", LOOP_END) }
+        });
+        let section = Self::strip_scheme(&Self::strip_comments(&section));
+
+        // Rehearsal marks name sections too: \mark "Verse", or \mark \default for A, B, C...
+        let mark_re = regex::Regex::new(r#"\\mark\s*(?:"([^"]*)"|\\default\b)"#).unwrap();
+        let mut letter = 0u8;
+        mark_re.replace_all(&section, |caps: &regex::Captures| {
+            let text = match caps.get(1) {
+                Some(text) => text.as_str().trim().replace(' ', "\x01"),
+                None => {
+                    letter += 1;
+                    ((b'A' + (letter - 1) % 26) as char).to_string()
+                }
+            };
+            format!(" __COMMENT_{}__ ", text)
+        }).to_string()
+    }
+
+    /// Most events a score may unfold to, repeats played out (see
+    /// [`ParseResult::unfolded_events`]): bigger scores are not unfolded by `\unfoldRepeats` or
+    /// loop markers, and [`LilyPondParser::parse_lossy`] gives them without staves
+    pub const MAX_UNFOLDED_EVENTS: u64 = 1_000_000;
+
+    /// Most bars a single multi-measure rest (`R1*16`) may fill
+    const MAX_REST_BARS: u32 = 10_000;
+
+    /// Most strokes a tremolo (`c1:32`) may be played as
+    const MAX_TREMOLO_STROKES: u32 = 1024;
+
+    /// Commands making the next note (or `{ ... }` group) grace notes
+    const GRACE_COMMANDS: &'static [&'static str] = &["\\grace", "\\acciaccatura", "\\appoggiatura", "\\slashedGrace"];
+
+    fn parse_notes_from_section(&self, ctx: &mut ParseContext, section: &str) -> Result<Vec<PitchedEvent>, String> {
+        let events = self.parse_section(
+            ctx,
+            section,
+            |token, state| self.parse_rest(token, state),
+            |ctx, token, state, grace| {
+                if let (written, Some(tremolo)) = Self::split_tremolo(token)
+                    && let Some(note) = self.parse_note(ctx, &written, state)?
+                {
+                    let notes = self.tremolo_notes(ctx, Note { grace, ..note }, tremolo, token);
+                    return Ok(Some(notes.into_iter().map(PitchedEvent::Note).collect()));
+                }
+                Ok(self.parse_note(ctx, token, state)?.map(|note| vec![PitchedEvent::Note(Note { grace, ..note })]))
+            },
+        )?;
+        Ok(Self::merge_ties(events))
+    }
+
+    /// Events of the tokens of a staff or drum voice: everything but the notes or hits, which
+    /// `sounds` reads (with the grace flag they take), and the rests, which `rest` reads
+    fn parse_section<E: StaffEvent>(
+        &self,
+        ctx: &mut ParseContext,
+        section: &str,
+        rest: impl Fn(&str, &mut DurationState) -> Option<E>,
+        sounds: impl Fn(&mut ParseContext, &str, &mut DurationState, bool) -> Result<Option<Vec<E>>, String>,
+    ) -> Result<Vec<E>, String> {
+        let mut events: Vec<E> = Vec::new();
+        let section = self.mark_comments(section);
+        let tokens = self.tokenize(&section);
+        let repeat_start_re = regex::Regex::new(r"^__REPEAT_START_(\d+)__$").unwrap();
+        let comment_re = regex::Regex::new(r"^__COMMENT_(.+)__$").unwrap();
+        let mut state = DurationState { duration: self.options.default_duration, dots: 0 };
+        let mut in_cadenza = false;
+        // Double bars become section comments unless the staff has its own comment markers
+        let surface_sections = !section.contains("__COMMENT_");
+        let mut sections = 0;
+        let mut bar_command = false;
+        let mut time_command = false;
+        let mut partial_command = false;
+        let mut pickup = Pickup::default();
+        let mut meter = ctx.time;
+        let mut key_command = false;
+        // Length of a bar in quarters, for multi-measure rests
+        let mut bar = Length::new(4, 1);
+        // After \grace: the next note or hit, or the ones of a `{ ... }` group, are grace notes
+        let mut grace_command = false;
+        let mut in_grace_group = false;
+        let mut tuplets = Tuplets::default();
+        let mut relative = RelativeScope::default();
+        let (mut bar_lines, mut counted) = (0, 0);
+
+        for mut token in tokens {
+            bar_lines += events[counted..].iter().filter(|e| matches!(e.view(), EventView::BarLine)).count() as u32;
+            counted = events.len();
+            ctx.bar = Some(bar_lines + 1);
+            if self.read_relative(ctx, &mut relative, &token) {
+                continue;
+            }
+            if grace_command && let Some(rest) = token.strip_prefix('{') {
+                grace_command = false;
+                in_grace_group = true;
+                token = rest.to_string();
+            }
+            let grace = grace_command || in_grace_group;
+            if in_grace_group && let Some(rest) = token.strip_suffix('}') {
+                in_grace_group = false;
+                token = rest.to_string();
+            }
+            let (token, scale) = tuplets.read(&token);
+            // For the warning about a rounded length, the token being moved into the events
+            let scaled_token = (scale != Length::new(1, 1)).then(|| token.clone());
+            if token.is_empty() || pickup.skip_bar_check(&token) {
+                continue;
+            }
+            let first_event = events.len();
+
+            if std::mem::take(&mut bar_command) {
+                // \bar "||": printed bar line, its type in quotes
+                if let Some(bar_type) = token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+                    let kind = BarLineKind::of(bar_type);
+                    if kind != BarLineKind::Invisible && !in_cadenza {
+                        events.push(E::bar_line());
+                    }
+                    if kind == BarLineKind::Section && surface_sections {
+                        sections += 1;
+                        events.push(E::comment(format!("Section {}", sections + 1)));
+                    }
+                    continue;
+                }
+            }
+
+            if std::mem::take(&mut time_command)
+                && let Some(time) = Self::parse_time_signature(&token)
+            {
+                events.push(E::time(time));
+                bar = Length::new(time.count, time.unit).times(Length::new(4, 1));
+                meter = time;
+                continue;
+            }
+
+            if std::mem::take(&mut partial_command)
+                && let Some(time) = pickup.start(&token)
+            {
+                events.push(E::time(time));
+                continue;
+            }
+
+            // The tonic of \key is not a note
+            if std::mem::take(&mut key_command) {
+                continue;
+            }
+
+            if token == "\\bar" {
+                bar_command = true;
+            } else if token == "\\time" {
+                time_command = true;
+            } else if token == "\\partial" {
+                partial_command = true;
+            } else if token == "\\key" {
+                key_command = true;
+            } else if Self::GRACE_COMMANDS.contains(&token.as_str()) {
+                grace_command = true;
+            } else if let Some(caps) = comment_re.captures(&token) {
+                events.push(E::comment(caps.get(1).unwrap().as_str().replace('\x01', " ")));
+            } else if token == LOOP_START || token == LOOP_END {
+                events.push(E::comment(token));
+            } else if token == "\\cadenzaOn" {
+                in_cadenza = true;
+                events.push(E::cadenza_on());
+            } else if token == "\\cadenzaOff" {
+                in_cadenza = false;
+                events.push(E::cadenza_off());
+            } else if token == "|" {
+                // Bar checks inside a cadenza do not split the free bar
+                if !in_cadenza {
+                    events.push(E::bar_line());
+                }
+            } else if token.starts_with('|') {
+                ctx.warn(format!("ignored `{}`: not a bar check (use \\bar \"{}\" for a bar line)", token, token));
+            } else if let Some(caps) = repeat_start_re.captures(&token) {
+                let count: u32 = caps.get(1).unwrap().as_str().parse().unwrap_or(1);
+                events.push(E::repeat_start(count));
+            } else if token == "__REPEAT_END__" {
+                events.push(E::repeat_end());
+            } else if token == ALTERNATIVE_START {
+                events.push(E::alternative_start());
+            } else if token == ALTERNATIVE_END {
+                events.push(E::alternative_end());
+            } else if let Some(bars) = self.parse_multi_measure_rest(ctx, &token, &mut state, bar) {
+                // One rest per bar, so the bar count (and the cpm) stays right
+                for (i, length) in bars.into_iter().enumerate() {
+                    if i > 0 {
+                        events.push(E::bar_line());
+                    }
+                    let length = (length != Length::new(4, 1)).then_some(length);
+                    events.push(E::rest(1, length));
+                }
+            } else if let Some(rest) = rest(&token, &mut state) {
+                events.push(rest);
+            } else if let Some(sounds) = sounds(ctx, &token, &mut state, grace)? {
+                grace_command = false;
+                events.extend(sounds);
+            }
+
+            if let Some(scaled_token) = scaled_token {
+                let mut rounded = tuplets.rounded;
+                let mut scaled = |length: Length| {
+                    length.checked_times(scale).unwrap_or_else(|| {
+                        rounded = true;
+                        length.times(scale)
+                    })
+                };
+                for event in &mut events[first_event..] {
+                    event.scale_length(&mut scaled);
+                }
+                if rounded {
+                    ctx.warn(Self::rounded_warning(&scaled_token));
+                }
+            }
+
+            let played = events[first_event..].iter().fold(Length::new(0, 1), |total, event| total.plus(event.played()));
+            if pickup.play(played) {
+                events.push(E::bar_line());
+                events.push(E::time(meter));
+            }
+        }
+
+        Self::check_bar_lengths(ctx, &events);
+        ctx.bar = None;
+        ctx.relative = None;
+        if sections > 0 {
+            events.insert(0, E::comment("Section 1".to_string()));
+        }
+
+        Ok(events)
+    }
+
+    /// Warn about the bars whose notes do not add up exactly in a [`Length`] (tuplets of very
+    /// fine, unrelated ratios): their timing is rounded when generating
+    fn check_bar_lengths<E: StaffEvent>(ctx: &mut ParseContext, events: &[E]) {
+        let (mut bar, mut total) = (1, Some(Length::new(0, 1)));
+        for event in events {
+            if matches!(event.view(), EventView::BarLine) {
+                (bar, total) = (bar + 1, Some(Length::new(0, 1)));
+            } else if let Some(sum) = total {
+                total = sum.checked_plus(event.played());
+                if total.is_none() {
+                    ctx.bar = Some(bar);
+                    ctx.warn("the notes of the bar are too short to add up exactly, their timing is rounded".to_string());
+                }
+            }
+        }
     }
 
-    fn mark_comments(&self, section: &str) -> String {
-        let re = regex::Regex::new(r"(?m)%\s*@strudel-of-lilypond@\s+comment\s+(.+)$").unwrap();
-        re.replace_all(section, |caps: &regex::Captures| {
-            let text = caps.get(1).unwrap().as_str().replace(' ', "\x01");
-            format!("__COMMENT_{}__", text)
-        }).to_string()
+    fn rounded_warning(token: &str) -> String {
+        format!("`{}` is too short to be timed exactly in its tuplets, it is rounded", token)
     }
 
-    fn parse_notes_from_section(&self, section: &str) -> Result<Vec<PitchedEvent>, String> {
-        let mut events = Vec::new();
-        let section = self.mark_comments(section);
-        let tokens = self.tokenize(&section);
-        let repeat_start_re = regex::Regex::new(r"^__REPEAT_START_(\d+)__$").unwrap();
-        let comment_re = regex::Regex::new(r"^__COMMENT_(.+)__$").unwrap();
+    /// Merge notes and chords tied to an identical successor within the same bar into one
+    /// longer event. Ties across bar lines stay separate (each bar is its own mini-notation group).
+    fn merge_ties(events: Vec<PitchedEvent>) -> Vec<PitchedEvent> {
+        fn pitches(n: &Note) -> Vec<i32> {
+            let mut midi = vec![n.midi];
+            midi.extend(n.chord_notes.iter().flatten().map(|c| c.midi));
+            midi.sort();
+            midi
+        }
 
-        for token in tokens {
-            if let Some(caps) = comment_re.captures(&token) {
-                events.push(PitchedEvent::Comment(caps.get(1).unwrap().as_str().replace('\x01', " ")));
-            } else if token.starts_with('|') {
-                events.push(PitchedEvent::BarLine);
-            } else if let Some(caps) = repeat_start_re.captures(&token) {
-                let count: u32 = caps.get(1).unwrap().as_str().parse().unwrap_or(1);
-                events.push(PitchedEvent::RepeatStart(count));
-            } else if token == "__REPEAT_END__" {
-                events.push(PitchedEvent::RepeatEnd);
-            } else if let Some(rest) = self.parse_rest(&token) {
-                events.push(rest);
-            } else if let Some(note) = self.parse_note(&token)? {
-                events.push(PitchedEvent::Note(note));
+        let mut merged: Vec<PitchedEvent> = Vec::with_capacity(events.len());
+        for event in events {
+            if let (Some(PitchedEvent::Note(prev)), PitchedEvent::Note(next)) = (merged.last_mut(), &event)
+                && prev.tied
+                && pitches(prev) == pitches(next)
+            {
+                let length = prev.quarters().plus(next.quarters());
+                prev.length = Some(length);
+                prev.tied = next.tied;
+                if next.beam == Some(Beam::End) {
+                    prev.beam = next.beam;
+                }
+                if next.slur == Some(Slur::End) {
+                    prev.slur = next.slur;
+                }
+                for chord_note in prev.chord_notes.iter_mut().flatten() {
+                    chord_note.length = Some(length);
+                    chord_note.tied = next.tied;
+                }
+                continue;
             }
+            merged.push(event);
         }
-
-        Ok(events)
+        merged
     }
 
-    fn parse_drums_from_section(&self, section: &str) -> Result<Vec<DrumEvent>, String> {
-        let mut events = Vec::new();
-        let section = self.mark_comments(section);
-        let tokens = self.tokenize(&section);
-        let repeat_start_re = regex::Regex::new(r"^__REPEAT_START_(\d+)__$").unwrap();
-        let comment_re = regex::Regex::new(r"^__COMMENT_(.+)__$").unwrap();
+    fn parse_drums_from_section(&self, ctx: &mut ParseContext, section: &str) -> Result<Vec<DrumEvent>, String> {
+        let unknown_drum_re = regex::Regex::new(r"^[a-z]+(\d+\.*)?([~\-^_(\[\]:].*)?$").unwrap();
+        self.parse_section(
+            ctx,
+            section,
+            |token, state| self.parse_drum_rest(token, state),
+            |ctx, token, state, grace| {
+                if let (written, Some(tremolo)) = Self::split_tremolo(token)
+                    && let Some(hit) = self.parse_drum_hit(ctx, &written, state)
+                {
+                    let hits = self.tremolo_hits(ctx, DrumHit { grace, ..hit }, tremolo, token);
+                    return Ok(Some(hits.into_iter().map(DrumEvent::Hit).collect()));
+                }
+                if let Some(hit) = self.parse_drum_hit(ctx, token, state) {
+                    return Ok(Some(vec![DrumEvent::Hit(DrumHit { grace, ..hit })]));
+                }
+                if unknown_drum_re.is_match(token) {
+                    // Looks like a hit (`snn4`) but names no drum: a typo that would silently change the groove
+                    ctx.warn(format!("ignored `{}`: unknown drum name", token));
+                }
+                Ok(None)
+            },
+        )
+    }
 
-        for token in tokens {
-            if let Some(caps) = comment_re.captures(&token) {
-                events.push(DrumEvent::Comment(caps.get(1).unwrap().as_str().replace('\x01', " ")));
-            } else if token.starts_with('|') {
-                events.push(DrumEvent::BarLine);
-            } else if let Some(caps) = repeat_start_re.captures(&token) {
-                let count: u32 = caps.get(1).unwrap().as_str().parse().unwrap_or(1);
-                events.push(DrumEvent::RepeatStart(count));
-            } else if token == "__REPEAT_END__" {
-                events.push(DrumEvent::RepeatEnd);
-            } else if let Some(rest) = self.parse_drum_rest(&token) {
-                events.push(rest);
-            } else if let Some(hit) = self.parse_drum_hit(&token) {
-                events.push(DrumEvent::Hit(hit));
+    /// Split a tremolo (`c4:16`, `sn4:32`) off a note or hit, returning the written note and
+    /// the value of its strokes
+    fn split_tremolo(token: &str) -> (String, Option<u32>) {
+        if let Some((note, after)) = token.split_once(':')
+            && !note.is_empty()
+            && !note.contains('"')
+        {
+            let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            if digits > 0 {
+                return (format!("{}{}", note, &after[digits..]), after[..digits].parse().ok());
             }
         }
+        (token.to_string(), None)
+    }
 
-        Ok(events)
+    /// Number and length of the strokes a tremolo of `quarters` is played as, `None` when the
+    /// strokes are not shorter than the note or do not fill it evenly
+    fn tremolo_strokes(&self, ctx: &mut ParseContext, quarters: Length, tremolo: u32, token: &str) -> Option<(u32, Length)> {
+        let stroke = Length::of_duration(tremolo, 0);
+        let (total, each) = quarters.cross(stroke);
+        let count = u32::try_from(total / each.max(1)).unwrap_or(u32::MAX);
+        if total % each.max(1) != 0 {
+            ctx.warn(format!("ignored the tremolo of `{}`: the strokes do not divide the note", token));
+            return None;
+        }
+        if count > Self::MAX_TREMOLO_STROKES {
+            ctx.warn(format!("ignored the tremolo of `{}`: more than {} strokes", token, Self::MAX_TREMOLO_STROKES));
+            return None;
+        }
+        (count > 1).then_some((count, stroke))
+    }
+
+    /// A tremolo note as its repeated strokes; beams, slurs and ties stay on the first or last stroke
+    fn tremolo_notes(&self, ctx: &mut ParseContext, note: Note, tremolo: u32, token: &str) -> Vec<Note> {
+        let Some((count, stroke)) = self.tremolo_strokes(ctx, note.quarters(), tremolo, token) else {
+            return vec![note];
+        };
+        (0..count)
+            .map(|i| {
+                let (first, last) = (i == 0, i == count - 1);
+                let stroke_of = |n: &Note| Note {
+                    duration: tremolo,
+                    length: Some(stroke),
+                    tied: last && n.tied,
+                    beam: n.beam.filter(|b| if *b == Beam::Start { first } else { last }),
+                    slur: n.slur.filter(|s| if *s == Slur::Start { first } else { last }),
+                    ..n.clone()
+                };
+                Note {
+                    chord_notes: note.chord_notes.as_ref().map(|c| c.iter().map(stroke_of).collect()),
+                    ..stroke_of(&note)
+                }
+            })
+            .collect()
+    }
+
+    /// A drum roll written as a tremolo (`sn4:32`) as its repeated strokes
+    fn tremolo_hits(&self, ctx: &mut ParseContext, hit: DrumHit, tremolo: u32, token: &str) -> Vec<DrumHit> {
+        let Some((count, stroke)) = self.tremolo_strokes(ctx, hit.quarters(), tremolo, token) else {
+            return vec![hit];
+        };
+        (0..count)
+            .map(|i| DrumHit {
+                duration: tremolo,
+                length: Some(stroke),
+                beam: hit.beam.filter(|b| if *b == Beam::Start { i == 0 } else { i == count - 1 }),
+                ..hit.clone()
+            })
+            .collect()
+    }
+
+    /// A multi-measure rest (`R1*4`, `R2.*3`, `R1*3/4`) as the lengths of the bars it fills,
+    /// `bar` being the length of a bar in the current time signature
+    fn parse_multi_measure_rest(&self, ctx: &mut ParseContext, token: &str, state: &mut DurationState, bar: Length) -> Option<Vec<Length>> {
+        let mut chars = token.strip_prefix('R')?.chars().peekable();
+        let suffix = DurationSuffix::read(&mut chars);
+        let multiplier: String = chars.collect();
+        let (num, den) = match multiplier.strip_prefix('*') {
+            Some(factor) => match factor.split_once('/') {
+                Some((num, den)) => (num.parse::<u32>().ok()?, den.parse::<u32>().ok()?),
+                None => (factor.parse::<u32>().ok()?, 1),
+            },
+            None if multiplier.is_empty() => (1, 1),
+            None => return None,
+        };
+        let (duration, length) = suffix.resolve(state);
+        let single = length.unwrap_or_else(|| Length::of_duration(duration, 0));
+        let total = single.times(Length::new(num, den));
+
+        // Whole bars, then what is left
+        let bars = (u128::from(total.num) * u128::from(bar.den)) / (u128::from(total.den) * u128::from(bar.num)).max(1);
+        if bars > Self::MAX_REST_BARS.into() {
+            ctx.warn(format!("ignored `{}`: a rest of more than {} bars", token, Self::MAX_REST_BARS));
+            return Some(Vec::new());
+        }
+        let mut lengths = vec![bar; bars as usize];
+        lengths.extend(total.minus(bar.times(Length::new(bars as u32, 1))));
+        Some(lengths)
     }
 
-    fn parse_drum_rest(&self, token: &str) -> Option<DrumEvent> {
+    fn parse_drum_rest(&self, token: &str, state: &mut DurationState) -> Option<DrumEvent> {
         let token = token.trim();
 
         // Must start with 'r' and not be a command like \repeat
@@ -554,73 +3820,80 @@ impl LilyPondParser {
 
         // Parse duration after 'r'
         let mut chars = token[1..].chars().peekable();
-        let mut duration_str = String::new();
-
-        while let Some(&c) = chars.peek() {
-            if c.is_numeric() {
-                duration_str.push(c);
-                chars.next();
-            } else {
-                break;
-            }
-        }
+        let suffix = DurationSuffix::read(&mut chars);
 
         // Verify no alphabetic characters follow (would indicate this isn't a rest)
         if chars.any(|c| c.is_alphabetic()) {
             return None;
         }
 
-        let duration = if duration_str.is_empty() {
-            4 // Default to quarter note
-        } else {
-            duration_str.parse::<u32>().unwrap_or(4)
-        };
-
-        Some(DrumEvent::Rest { duration })
+        let (duration, length) = suffix.resolve(state);
+        Some(DrumEvent::Rest { duration, length })
     }
 
-    fn parse_drum_voices(
+    fn parse_drum_voice_group(
         &self,
-        staff_content: &str,
+        ctx: &mut ParseContext,
+        simultaneous: &str,
         variables: &HashMap<String, VariableKind>,
     ) -> Result<Vec<DrumVoiceData>, String> {
         let mut voices = Vec::new();
 
-        // Check if there's a << >> block inside the DrumStaff
-        if let (Some(sim_start), Some(sim_end)) = (staff_content.find("<<"), staff_content.rfind(">>")) {
-            if sim_start < sim_end {
-                let simultaneous = &staff_content[sim_start + 2..sim_end];
-
-                // Find all \new DrumVoice blocks
-                let voice_re = regex::Regex::new(r"\\new\s+DrumVoice\s*\{").unwrap();
-                for caps in voice_re.captures_iter(simultaneous) {
-                    let full_match = caps.get(0).unwrap();
-                    let brace_pos = simultaneous[..full_match.end()].rfind('{').unwrap();
-
-                    if let Some(voice_content) = self.extract_braced_content(simultaneous, brace_pos) {
-                        let punchcard_color = self.parse_punchcard_color(&voice_content);
-                        let gain = self.parse_gain(&voice_content);
-                        let pan = self.parse_pan(&voice_content);
-                        let resolved = self.resolve_variables(&voice_content, variables);
-                        let events = self.parse_drums_from_section(&resolved)?;
-                        if !events.is_empty() {
-                            voices.push(DrumVoiceData { events, punchcard_color, gain, pan });
-                        }
+        // Find all \new DrumVoice blocks
+        let voice_re = regex::Regex::new(r"\\new\s+DrumVoice\s*\{").unwrap();
+        for caps in voice_re.captures_iter(simultaneous) {
+            let full_match = caps.get(0).unwrap();
+            let brace_pos = simultaneous[..full_match.end()].rfind('{').unwrap();
+
+            if let Some(voice_content) = self.extract_braced_content(simultaneous, brace_pos) {
+                let punchcard_color = self.parse_punchcard_color(&voice_content);
+                let gain = self.parse_gain(&voice_content);
+                let pan = self.parse_pan(&voice_content);
+                let resolved = self.resolve_variables(&voice_content, variables);
+                let events = self.parse_drums_from_section(ctx, &resolved)?;
+                if !events.is_empty() {
+                    let speed = self.parse_speed(&voice_content);
+                    voices.push(DrumVoiceData { events, punchcard_color, gain, pan, speed, name: None });
+                }
+            }
+        }
+
+        // If no DrumVoice blocks, look for direct variable references
+        if voices.is_empty() {
+            let var_ref_re = regex::Regex::new(r"\\([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+            for caps in var_ref_re.captures_iter(simultaneous) {
+                let var_name = caps.get(1).unwrap().as_str();
+                if let Some(VariableKind::Drums(content)) = variables.get(var_name) {
+                    let events = self.parse_drums_from_section(ctx, content)?;
+                    if !events.is_empty() {
+                        voices.push(DrumVoiceData { events, punchcard_color: None, gain: None, pan: None, speed: None, name: None });
                     }
                 }
+            }
+        }
 
-                // If no DrumVoice blocks, look for direct variable references
-                if voices.is_empty() {
-                    let var_ref_re = regex::Regex::new(r"\\([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-                    for caps in var_ref_re.captures_iter(simultaneous) {
-                        let var_name = caps.get(1).unwrap().as_str();
-                        if let Some(VariableKind::Drums(content)) = variables.get(var_name) {
-                            let events = self.parse_drums_from_section(content)?;
-                            if !events.is_empty() {
-                                voices.push(DrumVoiceData { events, punchcard_color: None, gain: None, pan: None });
-                            }
-                        }
+        Ok(voices)
+    }
+
+    fn parse_drum_voices(
+        &self,
+        ctx: &mut ParseContext,
+        staff_content: &str,
+        variables: &HashMap<String, VariableKind>,
+    ) -> Result<Vec<DrumVoiceData>, String> {
+        let mut voices: Vec<DrumVoiceData> = Vec::new();
+
+        // Each top-level << >> block inside the DrumStaff holds simultaneous voices;
+        // successive blocks continue the same voices
+        for simultaneous in self.simultaneous_groups(staff_content) {
+            let group_voices = self.parse_drum_voice_group(ctx, &simultaneous, variables)?;
+            for (i, voice) in group_voices.into_iter().enumerate() {
+                match voices.get_mut(i) {
+                    Some(existing) => {
+                        existing.events.push(DrumEvent::BarLine);
+                        existing.events.extend(voice.events);
                     }
+                    None => voices.push(voice),
                 }
             }
         }
@@ -628,16 +3901,16 @@ impl LilyPondParser {
         // Fallback: parse the whole content as a single voice
         if voices.is_empty() {
             let resolved = self.resolve_variables(staff_content, variables);
-            let events = self.parse_drums_from_section(&resolved)?;
+            let events = self.parse_drums_from_section(ctx, &resolved)?;
             if !events.is_empty() {
-                voices.push(DrumVoiceData { events, punchcard_color: None, gain: None, pan: None });
+                voices.push(DrumVoiceData { events, punchcard_color: None, gain: None, pan: None, speed: None, name: None });
             }
         }
 
         Ok(voices)
     }
 
-    fn parse_drum_hit(&self, token: &str) -> Option<DrumHit> {
+    fn parse_drum_hit(&self, ctx: &mut ParseContext, token: &str, state: &mut DurationState) -> Option<DrumHit> {
         let token = token.trim();
 
         // Skip bar lines and commands
@@ -675,24 +3948,25 @@ impl LilyPondParser {
         // Map LilyPond drum names to Strudel drum names
         let strudel_name = Self::lilypond_to_strudel_drum(&name);
 
-        // Parse duration
-        let mut duration_str = String::new();
-        while let Some(&c) = chars.peek() {
-            if c.is_numeric() {
-                duration_str.push(c);
-                chars.next();
-            } else {
-                break;
-            }
-        }
-
-        let duration = if duration_str.is_empty() {
-            4
-        } else {
-            duration_str.parse::<u32>().unwrap_or(4)
-        };
+        // Parse duration, then articulations, dynamics and sticking text
+        let suffix = DurationSuffix::read(&mut chars);
+        let (duration, length) = suffix.resolve(state);
+        let attachments = self.parse_attachments(ctx, &chars.collect::<String>(), token);
 
-        Some(DrumHit { name: strudel_name, duration })
+        Some(DrumHit {
+            name: strudel_name,
+            duration,
+            length,
+            articulations: attachments.articulations,
+            dynamic: attachments.dynamic,
+            hairpin: attachments.hairpin,
+            beam: attachments.beam,
+            sticking: attachments
+                .texts
+                .into_iter()
+                .find(|text| !text.is_empty() && text.chars().all(|c| matches!(c, 'R' | 'L' | 'r' | 'l'))),
+            grace: false,
+        })
     }
 
     /// Map LilyPond drum names to Strudel drum names
@@ -711,44 +3985,136 @@ impl LilyPondParser {
         }
     }
 
-    fn mark_repeats(&self, code: &str) -> String {
-        let mut result = code.to_string();
-        let re = regex::Regex::new(r"\\repeat\s+\w+\s+(\d+)\s*\{").unwrap();
+    fn mark_repeats(&self, code: &str) -> Result<String, String> {
+        self.mark_repeats_recovering(code, None)
+    }
 
-        loop {
-            let Some(caps) = re.captures(&result) else {
-                break;
-            };
+    /// Like [`Self::mark_repeats`], but with `errors` a `\repeat` that cannot be read is replaced
+    /// by a [`PARSE_ERROR`] marker numbered after its error, pushed to `errors`
+    fn mark_repeats_recovering(&self, code: &str, mut errors: Option<&mut Vec<String>>) -> Result<String, String> {
+        let mut result = code.to_string();
+        let re = regex::Regex::new(r"\\repeat\s+(\w+)\s+([^\s{]+)\s*").unwrap();
 
+        while let Some(caps) = re.captures(&result) {
             let full_match = caps.get(0).unwrap();
-            let count: usize = caps.get(1).unwrap().as_str().parse().unwrap_or(1);
             let start = full_match.start();
-            let brace_start = full_match.end() - 1;
-
-            let mut depth = 1;
-            let mut end = brace_start + 1;
-            for (i, c) in result[brace_start + 1..].char_indices() {
-                match c {
-                    '{' => depth += 1,
-                    '}' => {
-                        depth -= 1;
-                        if depth == 0 {
-                            end = brace_start + 1 + i;
-                            break;
-                        }
-                    }
-                    _ => {}
+            let body_start = full_match.end();
+            // Line in the original input: markers inserted so far contain no newlines
+            // other than the ones they replaced, so this stays close enough for errors
+            let line = result[..start].matches('\n').count() + 1;
+
+            let body = || -> Result<(usize, String, usize), String> {
+                let count_str = caps.get(2).unwrap().as_str();
+                let count: usize = count_str.parse().map_err(|_| {
+                    format!(
+                        "Line {}: \\repeat {} expects a repeat count, found `{}`",
+                        line,
+                        caps.get(1).unwrap().as_str(),
+                        count_str
+                    )
+                })?;
+
+                // The body is a braced block, or a single note, chord or variable (\repeat unfold 4 c8)
+                if result[body_start..].starts_with('{') {
+                    let content = self.extract_braced_content(&result, body_start).ok_or_else(|| {
+                        format!("Line {}: \\repeat body starting with `{{` is missing its closing `}}`", line)
+                    })?;
+                    let end = body_start + content.len() + 2;
+                    return Ok((count, content, end));
                 }
-            }
+                let token_len = if result[body_start..].starts_with('<') {
+                    result[body_start..].find('>').map(|i| i + 1)
+                } else {
+                    None
+                }
+                .unwrap_or(0);
+                let rest = &result[body_start + token_len..];
+                let len = token_len + rest.find(|c: char| c.is_whitespace() || c == '}').unwrap_or(rest.len());
+                if len == 0 {
+                    return Err(format!("Line {}: \\repeat without a body", line));
+                }
+                Ok((count, result[body_start..body_start + len].to_string(), body_start + len))
+            };
+            let repeat = || -> Result<(usize, String, String, usize), String> {
+                let (count, content, end) = body()?;
+                match self.mark_alternatives(&result, end, line)? {
+                    Some((endings, end)) => Ok((count, content, endings, end)),
+                    None => Ok((count, content, String::new(), end)),
+                }
+            };
+            let (count, content, endings, end) = match (repeat(), errors.as_deref_mut()) {
+                (Ok(repeat), _) => repeat,
+                (Err(e), Some(errors)) => {
+                    result = format!("{} {}{}__ {}", &result[..start], PARSE_ERROR, errors.len(), &result[body_start..]);
+                    errors.push(e);
+                    continue;
+                }
+                (Err(e), None) => return Err(e),
+            };
 
-            let content = &result[brace_start + 1..end];
             // Add markers instead of expanding
-            let marked = format!(" __REPEAT_START_{}__ {} __REPEAT_END__ ", count, content);
+            let marked = format!(" __REPEAT_START_{}__ {} __REPEAT_END__ {} ", count, content, endings);
 
-            result = format!("{}{}{}", &result[..start], marked, &result[end + 1..]);
+            result = format!("{}{}{}", &result[..start], marked, &result[end..]);
         }
 
-        result
+        Ok(result)
+    }
+
+    /// The `\alternative { { ... } { ... } }` endings of a repeat whose body ends at `start`, each
+    /// between markers (`__ALTERNATIVE_START__ ... __ALTERNATIVE_END__`), with the index after
+    /// them; `None` without endings
+    fn mark_alternatives(&self, code: &str, start: usize, line: usize) -> Result<Option<(String, usize)>, String> {
+        let rest = &code[start..];
+        let Some(after) = rest.trim_start().strip_prefix("\\alternative") else {
+            return Ok(None);
+        };
+        let open = code.len() - after.trim_start().len();
+        let block = code[open..]
+            .starts_with('{')
+            .then(|| self.extract_braced_content(code, open))
+            .flatten()
+            .ok_or_else(|| format!("Line {}: \\alternative expects its endings in `{{ ... }}`", line))?;
+
+        // Every ending is a braced block or a single note
+        let mut endings = Vec::new();
+        let mut i = 0;
+        while let Some(offset) = block[i..].find(|c: char| !c.is_whitespace()) {
+            i += offset;
+            if block[i..].starts_with('{') {
+                let ending = self
+                    .extract_braced_content(&block, i)
+                    .ok_or_else(|| format!("Line {}: \\alternative ending is missing its closing `}}`", line))?;
+                i += ending.len() + 2;
+                endings.push(ending);
+            } else {
+                let len = block[i..].find(char::is_whitespace).unwrap_or(block.len() - i);
+                endings.push(block[i..i + len].to_string());
+                i += len;
+            }
+        }
+        if endings.is_empty() {
+            return Err(format!("Line {}: \\alternative without endings", line));
+        }
+        let marked = endings
+            .iter()
+            .map(|ending| format!("{} {} {}", ALTERNATIVE_START, ending, ALTERNATIVE_END))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(Some((marked, open + block.len() + 2)))
+    }
+
+    fn parse_time(&self, code: &str) -> Option<TimeSignature> {
+        let re = regex::Regex::new(r"\\time\s+(\d+\s*/\s*\d+)").unwrap();
+        Self::parse_time_signature(&re.captures(code)?[1])
+    }
+
+    /// `7/8` (the argument of `\time`)
+    fn parse_time_signature(text: &str) -> Option<TimeSignature> {
+        let (count, unit) = text.split_once('/')?;
+        let count: u32 = count.trim().parse().ok()?;
+        let unit: u32 = unit.trim().parse().ok()?;
+        (count > 0 && unit > 0).then_some(TimeSignature { count, unit })
     }
 
     fn parse_tempo(&self, code: &str) -> Option<Tempo> {
@@ -786,6 +4152,15 @@ impl LilyPondParser {
             return Err("Invalid syntax".to_string());
         }
 
+        // \relative c'' { ... }: the music keeps its \relative
+        let before = &code[..start];
+        if let Some(at) = before.rfind("\\relative")
+            && let Some(relative) = Self::relative_re().find(&before[at..])
+            && relative.end() == before.len() - at
+        {
+            return Ok(format!("{} {{{}}}", relative.as_str().trim_end(), &code[start + 1..end]));
+        }
+
         Ok(code[start + 1..end].to_string())
     }
 
@@ -793,9 +4168,37 @@ impl LilyPondParser {
         let mut tokens = Vec::new();
         let mut current = String::new();
         let mut in_chord = false;
+        let mut in_string = false;
+        let chars: Vec<char> = section.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            let escaped = current.ends_with('\\');
+
+            // Text scripts (^"R L") stay attached to their note, spaces and brackets included
+            if c == '"' && !escaped {
+                in_string = !in_string;
+            }
+            if in_string || c == '"' {
+                current.push(c);
+                i += 1;
+                continue;
+            }
+
+            if !in_chord && !escaped && ((c == '<' && next == Some('<')) || (c == '>' && next == Some('>'))) {
+                // Simultaneous group delimiter: a token of its own, never a chord
+                if !current.trim().is_empty() {
+                    tokens.push(current.trim().to_string());
+                }
+                current = String::new();
+                tokens.push(if c == '<' { "<<" } else { ">>" }.to_string());
+                i += 2;
+                continue;
+            }
 
-        for c in section.chars() {
-            if c == '<' {
+            if c == '<' && !escaped {
                 // Start of chord - save any pending token
                 if !current.trim().is_empty() {
                     tokens.push(current.trim().to_string());
@@ -803,7 +4206,7 @@ impl LilyPondParser {
                 current = String::new();
                 current.push(c);
                 in_chord = true;
-            } else if c == '>' {
+            } else if c == '>' && !escaped {
                 // End of chord bracket
                 current.push(c);
                 in_chord = false;
@@ -820,6 +4223,7 @@ impl LilyPondParser {
             } else {
                 current.push(c);
             }
+            i += 1;
         }
 
         if !current.trim().is_empty() {
@@ -829,7 +4233,7 @@ impl LilyPondParser {
         tokens.into_iter().filter(|s| !s.is_empty()).collect()
     }
 
-    fn parse_rest(&self, token: &str) -> Option<PitchedEvent> {
+    fn parse_rest(&self, token: &str, state: &mut DurationState) -> Option<PitchedEvent> {
         let token = token.trim();
 
         // Must start with 'r' and not be a command like \repeat
@@ -839,32 +4243,52 @@ impl LilyPondParser {
 
         // Parse duration after 'r'
         let mut chars = token[1..].chars().peekable();
-        let mut duration_str = String::new();
-
-        while let Some(&c) = chars.peek() {
-            if c.is_numeric() {
-                duration_str.push(c);
-                chars.next();
-            } else {
-                break;
-            }
-        }
+        let suffix = DurationSuffix::read(&mut chars);
 
         // Verify no alphabetic characters follow (would indicate this isn't a rest)
         if chars.any(|c| c.is_alphabetic()) {
             return None;
         }
 
-        let duration = if duration_str.is_empty() {
-            4 // Default to quarter note
-        } else {
-            duration_str.parse::<u32>().unwrap_or(4)
-        };
+        let (duration, length) = suffix.resolve(state);
+        Some(PitchedEvent::Rest { duration, length })
+    }
+
+    /// Follow `\relative` and the braces of its music, returning whether `token` was the command
+    /// or its reference pitch. The notes read in between are placed by [`Self::parse_single_note`].
+    fn read_relative(&self, ctx: &mut ParseContext, scope: &mut RelativeScope, token: &str) -> bool {
+        if let Some(previous) = scope.closed.take() {
+            ctx.relative = previous;
+        }
+        if token == "\\relative" {
+            scope.command = true;
+            return true;
+        }
+        if std::mem::take(&mut scope.command) {
+            scope.open.push((scope.depth, ctx.relative));
+            ctx.relative = None;
+            if !token.starts_with('{')
+                && let Ok(Some(pitch)) = self.parse_single_note(ctx, token, &mut DurationState::default())
+            {
+                ctx.relative = Some(diatonic_step(pitch.name, pitch.octave));
+                return true;
+            }
+            // Without a reference pitch, the first note is placed from an unmarked f
+            ctx.relative = Some(diatonic_step('f', self.options.default_octave));
+        }
 
-        Some(PitchedEvent::Rest { duration })
+        let rest = token.trim_start_matches('{');
+        scope.depth += token.len() - rest.len();
+        let closing = rest.len() - rest.trim_end_matches('}').len();
+        scope.depth = scope.depth.saturating_sub(closing);
+        if closing > 0 && scope.open.last().is_some_and(|&(outer, _)| scope.depth <= outer) {
+            // The notes of this token are still relative
+            scope.closed = scope.open.pop().map(|(_, previous)| previous);
+        }
+        false
     }
 
-    fn parse_note(&self, token: &str) -> Result<Option<Note>, String> {
+    fn parse_note(&self, ctx: &mut ParseContext, token: &str, state: &mut DurationState) -> Result<Option<Note>, String> {
         let token = token.trim();
 
         if token.starts_with('|') || token.starts_with('\\') {
@@ -873,13 +4297,13 @@ impl LilyPondParser {
 
         // Check for chord syntax <note note note>duration
         if token.starts_with('<') {
-            return self.parse_chord(token);
+            return self.parse_chord(ctx, token, state);
         }
 
-        self.parse_single_note(token, None)
+        self.parse_single_note(ctx, token, state)
     }
 
-    fn parse_chord(&self, token: &str) -> Result<Option<Note>, String> {
+    fn parse_chord(&self, ctx: &mut ParseContext, token: &str, state: &mut DurationState) -> Result<Option<Note>, String> {
         // Parse <a c e>4 style chord
         let Some(close_bracket) = token.find('>') else {
             return Ok(None);
@@ -888,21 +4312,12 @@ impl LilyPondParser {
         let chord_content = &token[1..close_bracket];
         let after_bracket = &token[close_bracket + 1..];
 
-        // Parse duration after the >
-        let mut duration_str = String::new();
-        for c in after_bracket.chars() {
-            if c.is_numeric() {
-                duration_str.push(c);
-            } else if c != '.' && c != '~' {
-                break;
-            }
-        }
-
-        let duration = if duration_str.is_empty() {
-            4
-        } else {
-            duration_str.parse::<u32>().unwrap_or(4)
-        };
+        // Parse duration, dots and tie after the >
+        let mut after_chars = after_bracket.chars().peekable();
+        let suffix = DurationSuffix::read(&mut after_chars);
+        let attachments = self.parse_attachments(ctx, &after_chars.collect::<String>(), token);
+        let mut chord_state = *state;
+        let (duration, length) = suffix.resolve(&mut chord_state);
 
         // Parse individual notes in the chord
         let note_tokens: Vec<&str> = chord_content.split_whitespace().collect();
@@ -912,14 +4327,32 @@ impl LilyPondParser {
 
         let mut chord_notes = Vec::new();
         for note_token in &note_tokens {
-            if let Some(note) = self.parse_single_note(note_token, Some(duration))? {
-                chord_notes.push(note);
+            // Durations written inside the chord are ignored, the chord's own one applies
+            if let Some(note) = self.parse_single_note(ctx, note_token, &mut chord_state.clone())? {
+                chord_notes.push(Note { duration, ..note });
             }
         }
 
         if chord_notes.is_empty() {
             return Ok(None);
         }
+        *state = chord_state;
+        // Each note of a relative chord follows the previous one, the next note the first one
+        if ctx.relative.is_some() {
+            ctx.relative = Some(diatonic_step(chord_notes[0].name, chord_notes[0].octave));
+        }
+
+        // The chord is tied after the bracket (<c e>2~) or when every note is tied (<c~ e~>2)
+        let tied = suffix.tie || attachments.tie || chord_notes.iter().all(|n| n.tied);
+        for note in chord_notes.iter_mut() {
+            note.length = length;
+            note.tied = tied;
+            note.beam = attachments.beam;
+            note.slur = attachments.slur;
+            note.dynamic = attachments.dynamic.clone();
+            note.hairpin = attachments.hairpin;
+            note.articulations = attachments.articulations.clone();
+        }
 
         // First note becomes the main note, rest go in chord_notes
         let mut first_note = chord_notes.remove(0);
@@ -932,7 +4365,7 @@ impl LilyPondParser {
         Ok(Some(first_note))
     }
 
-    fn parse_single_note(&self, token: &str, override_duration: Option<u32>) -> Result<Option<Note>, String> {
+    fn parse_single_note(&self, ctx: &mut ParseContext, token: &str, state: &mut DurationState) -> Result<Option<Note>, String> {
         let token = token.trim();
 
         let mut chars = token.chars().peekable();
@@ -951,7 +4384,7 @@ impl LilyPondParser {
             }
         }
 
-        let mut octave = 3; // LilyPond base octave (c = C3, c' = C4 middle C)
+        let mut octave = self.options.default_octave;
         while let Some(&c) = chars.peek() {
             match c {
                 '\'' => {
@@ -966,305 +4399,1185 @@ impl LilyPondParser {
             }
         }
 
-        let mut duration_str = String::new();
-        while let Some(&c) = chars.peek() {
-            if c.is_numeric() {
-                duration_str.push(c);
-                chars.next();
-            } else {
-                break;
+        let suffix = DurationSuffix::read(&mut chars);
+
+        // A word starting with a note letter (`bass`, `down`) is not a note
+        if chars.peek().is_some_and(|c| c.is_alphabetic()) {
+            return Ok(None);
+        }
+
+        let attachments = self.parse_attachments(ctx, &chars.collect::<String>(), token);
+        let (duration, length) = suffix.resolve(state);
+
+        // Under \relative, the note goes to the octave nearest the previous one (a fourth or
+        // less away) before its marks move it
+        if let Some(previous) = ctx.relative {
+            let up = (diatonic_step(note_name, 0) - previous).rem_euclid(7);
+            let nearest = previous + if up > 3 { up - 7 } else { up };
+            let step = nearest + 7 * (octave - self.options.default_octave);
+            octave = step.div_euclid(7);
+            ctx.relative = Some(step);
+        }
+
+        let mut midi = *self.note_to_midi.get(&note_name).unwrap();
+
+        if let Some(ref acc) = accidental {
+            match acc.as_str() {
+                "is" => midi += 1,
+                "es" => midi -= 1,
+                _ => {}
+            }
+        }
+
+        midi += (octave + 1) * 12; // MIDI octave offset: C4 = 60
+
+        Ok(Some(Note {
+            name: note_name,
+            octave,
+            accidental,
+            duration,
+            midi,
+            chord_notes: None,
+            length,
+            tied: suffix.tie || attachments.tie,
+            beam: attachments.beam,
+            slur: attachments.slur,
+            dynamic: attachments.dynamic,
+            hairpin: attachments.hairpin,
+            articulations: attachments.articulations,
+            grace: false,
+        }))
+    }
+}
+
+impl Default for LilyPondParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How `@` weights are written in mini-notation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WeightStyle {
+    /// `@0.5`, `@1.5`; weights without an exact decimal (a third) are written as fractions (`@1/3`)
+    #[default]
+    Decimal,
+    /// `@1/2`, `@3/2`
+    Fraction,
+}
+
+/// Which note gives its time to grace notes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GraceTiming {
+    /// The ornamented note, the one after the grace notes, starts later
+    #[default]
+    Ornamented,
+    /// The note before the grace notes is shortened, so the ornamented note keeps its beat
+    Previous,
+}
+
+/// Color scheme of the generated HTML page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl std::str::FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("unknown theme '{}' (expected 'light' or 'dark')", s)),
+        }
+    }
+}
+
+/// How repeated bars are written in mini-notation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RepeatStyle {
+    /// `[c4 d4 e4 f4]!2`
+    #[default]
+    Compressed,
+    /// Every bar written out, for functions counting cycles or bars (`.every(4, ...)`)
+    Unrolled,
+}
+
+impl std::str::FromStr for RepeatStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compressed" => Ok(RepeatStyle::Compressed),
+            "unrolled" => Ok(RepeatStyle::Unrolled),
+            _ => Err(format!("unknown repeat style '{}' (expected 'compressed' or 'unrolled')", s)),
+        }
+    }
+}
+
+/// How mini-notation strings are quoted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// Template literals with one bar per line
+    #[default]
+    Backtick,
+    /// Single-line double-quoted strings, bars separated by spaces, for contexts where
+    /// backticks are awkward (markdown, shell heredocs, JSON)
+    Double,
+}
+
+impl std::str::FromStr for QuoteStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backtick" => Ok(QuoteStyle::Backtick),
+            "double" => Ok(QuoteStyle::Double),
+            _ => Err(format!("unknown quote style '{}' (expected 'backtick' or 'double')", s)),
+        }
+    }
+}
+
+/// What to emit for staves without notes or drum hits (cue staves, rests only)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyStaves {
+    /// `$: // No notes to convert`
+    #[default]
+    Comment,
+    /// Leave them out of the output
+    Drop,
+    /// `$: silence // Staff 2`
+    Silence,
+}
+
+impl std::str::FromStr for EmptyStaves {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "comment" => Ok(EmptyStaves::Comment),
+            "drop" => Ok(EmptyStaves::Drop),
+            "silence" => Ok(EmptyStaves::Silence),
+            _ => Err(format!("unknown empty staves mode '{}' (expected 'comment', 'drop' or 'silence')", s)),
+        }
+    }
+}
+
+/// Options controlling how `StrudelGenerator` formats its output
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    /// Emit MIDI note numbers (`60`) instead of note names (`c4`)
+    pub midi_numbers: bool,
+    /// Added to the octave of every note name, for sample sets numbering octaves differently
+    /// from Strudel (`-1` writes middle C `c3`); MIDI numbers move by as many octaves
+    pub octave_offset: i32,
+    /// How `@` weights are written: decimals (`@0.5`, the default) or fractions (`@1/2`)
+    pub weight_style: WeightStyle,
+    /// Name of the tempo constant referenced by `.cpm()` (default `tempo`)
+    pub tempo_name: String,
+    /// Start `generate_multi_with_options` output with the `const` declarations it relies on,
+    /// so the plain Strudel code runs standalone (HTML output always has them)
+    pub emit_prologue: bool,
+    /// Add a collapsible panel per staff (name, color swatch, copy button) below the HTML player
+    pub staff_panels: bool,
+    /// Add play/stop buttons, a tempo slider and a loop-section selector above the HTML player
+    pub transport_controls: bool,
+    /// Page color scheme for the HTML output (`None` keeps the browser defaults)
+    pub theme: Option<Theme>,
+    /// Extra CSS appended to the HTML `<style>` block
+    pub css: Option<String>,
+    /// Page author, emitted as `author` meta tag (and in the heading)
+    pub author: Option<String>,
+    /// Page description, emitted as description/OpenGraph meta tags (and in the heading)
+    pub description: Option<String>,
+    /// Show the title, author and description as a visible heading above the player
+    pub show_heading: bool,
+    /// Punchcard colors given in turn to stacked drum voices when none of them sets a color
+    /// (`None` leaves them uncolored), e.g. [`DEFAULT_VOICE_PALETTE`]
+    pub voice_palette: Option<Vec<String>>,
+    /// Add a `.label()` pattern naming the section (comment markers, double bars) of every bar,
+    /// so the section names show up alongside the events in Strudel
+    pub section_labels: bool,
+    pub empty_staves: EmptyStaves,
+    /// Drum machine sample bank for drum staves, as `.bank("RolandTR909")`
+    pub drum_bank: Option<String>,
+    /// Sounds played instead of others, see [`Staff::map_drums`], to audition a score with
+    /// other samples without editing it
+    pub drum_map: BTreeMap<String, String>,
+    /// Pad the drum voices shorter than the longest one with rest bars, so a stack of voices of
+    /// different lengths stays phase-aligned over the whole cycle
+    pub pad_short_voices: bool,
+    /// Tempo multipliers over bar ranges (see [`ParseResult::rubato`])
+    pub rubato: Vec<Rubato>,
+    pub grace_timing: GraceTiming,
+    /// Parse warnings listed in a "conversion notes" panel of the HTML page
+    pub warnings: Vec<Warning>,
+    /// Break mini-notation lines longer than this many characters inside their groups, one
+    /// nesting level per indentation step, so that dense bars stay readable
+    pub max_line_width: Option<usize>,
+    /// LilyPond source shown in a collapsible panel below the HTML player, so the notation
+    /// travels with the page
+    pub lilypond_source: Option<String>,
+    /// Play the hi-hats of a drum voice mixing open and closed (or pedal) hats as a pattern of
+    /// their own with `.cut(1)`, so that an open hat is choked by the next hat
+    pub choke_hats: bool,
+    /// List the bars below the HTML player, with their start time, the sections they start and
+    /// their code as a tooltip, to find one's way in long pieces
+    pub bar_minimap: bool,
+    /// How repeats are written, for the staves that do not set their own style
+    pub repeat_style: RepeatStyle,
+    /// How mini-notation strings are quoted
+    pub quote_style: QuoteStyle,
+    /// Name the chords of every bar in a comment after it (`[[c4,e4,g4,b4]@4] // Cmaj7`), see
+    /// [`Note::chord_symbol`]; only with backtick quotes, where patterns span several lines
+    pub chord_names: bool,
+}
+
+/// Punchcard colors for stacked drum voices (kick, snare, hats, ...) without a color comment
+pub const DEFAULT_VOICE_PALETTE: [&str; 6] = ["cyan", "magenta", "yellow", "lime", "orange", "white"];
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            midi_numbers: false,
+            octave_offset: 0,
+            weight_style: WeightStyle::default(),
+            tempo_name: "tempo".to_string(),
+            emit_prologue: false,
+            staff_panels: false,
+            transport_controls: false,
+            theme: None,
+            css: None,
+            author: None,
+            description: None,
+            show_heading: false,
+            voice_palette: None,
+            section_labels: false,
+            empty_staves: EmptyStaves::default(),
+            drum_bank: None,
+            drum_map: BTreeMap::new(),
+            pad_short_voices: false,
+            rubato: Vec::new(),
+            grace_timing: GraceTiming::default(),
+            warnings: Vec::new(),
+            max_line_width: None,
+            lilypond_source: None,
+            choke_hats: false,
+            bar_minimap: false,
+            repeat_style: RepeatStyle::Compressed,
+            quote_style: QuoteStyle::Backtick,
+            chord_names: false,
+        }
+    }
+}
+
+/// Break the mini-notation lines (between backquotes) of generated code that are longer than
+/// `width`, see [`GeneratorOptions::max_line_width`]
+fn wrap_mini_notation(code: &str, width: usize) -> String {
+    let mut lines = Vec::new();
+    let mut in_pattern = false;
+    for line in code.lines() {
+        let inside = in_pattern;
+        in_pattern ^= line.matches('`').count() % 2 == 1;
+        // The last line of a pattern goes on with code after its closing backquote
+        let (pattern, code_after) = line.split_at(line.find('`').unwrap_or(line.len()));
+        // A chord comment stays at the end of the bar
+        let (pattern, code_after) = match pattern.find(" //") {
+            Some(comment) => (&pattern[..comment], &line[comment..]),
+            None => (pattern, code_after),
+        };
+        if !inside || pattern.len() <= width {
+            lines.push(line.to_string());
+            continue;
+        }
+        let indent = pattern.len() - pattern.trim_start().len();
+        wrap_events(pattern.trim(), indent, width, &mut lines);
+        if let Some(last) = lines.last_mut() {
+            last.push_str(code_after);
+        }
+    }
+    lines.join("\n")
+}
+
+/// Split mini-notation at the spaces outside of its groups
+fn split_events(pattern: &str) -> Vec<&str> {
+    let mut events = Vec::new();
+    let (mut depth, mut start) = (0usize, None);
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '[' | '<' | '{' | '(' => depth += 1,
+            ']' | '>' | '}' | ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if let Some(s) = start.take() {
+                events.push(&pattern[s..i]);
             }
+        } else if start.is_none() {
+            start = Some(i);
         }
+    }
+    events.extend(start.map(|s| &pattern[s..]));
+    events
+}
 
-        while let Some(&c) = chars.peek() {
-            if c == '.' || c == '~' {
-                chars.next();
+/// Write one event (a group with its `@2` or `!2` suffix) or a sequence of events at `indent`:
+/// what does not fit on a line is packed over several, a group too long for a line of its own
+/// gets its brackets on lines of their own and its events indented
+fn wrap_events(pattern: &str, indent: usize, width: usize, lines: &mut Vec<String>) {
+    let pad = " ".repeat(indent);
+    if indent + pattern.len() <= width {
+        lines.push(format!("{pad}{pattern}"));
+        return;
+    }
+    let events = split_events(pattern);
+    if events.len() > 1 {
+        let mut current = String::new();
+        for event in events {
+            if !current.is_empty() && indent + current.len() + 1 + event.len() <= width {
+                current.push(' ');
+                current.push_str(event);
+                continue;
+            }
+            if !current.is_empty() {
+                lines.push(format!("{pad}{}", std::mem::take(&mut current)));
+            }
+            if indent + event.len() <= width {
+                current = event.to_string();
             } else {
-                break;
+                wrap_events(event, indent, width, lines);
             }
         }
+        if !current.is_empty() {
+            lines.push(format!("{pad}{current}"));
+        }
+        return;
+    }
 
-        if chars.any(|c| c.is_alphabetic()) {
-            return Ok(None);
+    // A single group: its events go inside, on lines of their own
+    let mut depth = 0;
+    let end = pattern.char_indices().find_map(|(i, c)| {
+        match c {
+            '[' | '<' | '{' | '(' => depth += 1,
+            ']' | '>' | '}' | ')' => depth -= 1,
+            _ => {}
         }
+        (depth == 0).then_some(i)
+    });
+    match end {
+        Some(end) if end > 0 && matches!(pattern.chars().next(), Some('[' | '<' | '{')) => {
+            lines.push(format!("{pad}{}", &pattern[..1]));
+            wrap_events(pattern[1..end].trim(), indent + 2, width, lines);
+            lines.push(format!("{pad}{}", &pattern[end..]));
+        }
+        // A chord or a single note: nowhere to break it
+        _ => lines.push(format!("{pad}{pattern}")),
+    }
+}
 
-        let duration = override_duration.unwrap_or_else(|| {
-            if duration_str.is_empty() {
-                4
-            } else {
-                duration_str.parse::<u32>().unwrap_or(4)
-            }
-        });
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
-        let mut midi = *self.note_to_midi.get(&note_name).unwrap();
+/// Quote text as a JavaScript string literal that is safe to inline in a `<script>` block
+fn js_string(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace("</", "<\\/");
+    format!("\"{}\"", escaped)
+}
 
-        if let Some(ref acc) = accidental {
-            match acc.as_str() {
-                "is" => midi += 1,
-                "es" => midi -= 1,
-                _ => {}
-            }
-        }
+fn gcd(a: u128, b: u128) -> u128 {
+    if b == 0 { a.max(1) } else { gcd(b, a % b) }
+}
 
-        midi += (octave + 1) * 12; // MIDI octave offset: C4 = 60
+/// Notes under a manual beam (`c8[ d e f]`), written as one sub-group of their bar
+#[derive(Default)]
+struct BeamGroup {
+    /// Formatted with their weights
+    weighted: Vec<String>,
+    /// Formatted as if each were a quarter, used when all have the same length
+    plain: Vec<String>,
+    lengths: Vec<Length>,
+}
 
-        Ok(Some(Note {
-            name: note_name,
-            octave,
-            accidental,
-            duration,
-            midi,
-            chord_notes: None,
-        }))
+impl BeamGroup {
+    fn push(&mut self, weighted: String, plain: String, length: Length) {
+        self.weighted.push(weighted);
+        self.plain.push(plain);
+        self.lengths.push(length);
     }
-}
 
-impl Default for LilyPondParser {
-    fn default() -> Self {
-        Self::new()
+    /// `[c5 d5 e5 f5]@2`, the group weighted by its length in quarters
+    fn format(self, options: &GeneratorOptions) -> String {
+        if self.lengths.len() == 1 {
+            return self.weighted.join(" ");
+        }
+        let total = self.lengths.iter().fold(Length::new(0, 1), |sum, &length| sum.plus(length));
+        let items = if self.lengths.windows(2).all(|w| w[0] == w[1]) { &self.plain } else { &self.weighted };
+        StrudelGenerator::format_bar(items, total, options)
     }
 }
 
 pub struct StrudelGenerator;
 
 impl StrudelGenerator {
-    /// Count bars in pitched events (including repeats)
-    fn count_pitched_bars(events: &[PitchedEvent], idx: &mut usize) -> u32 {
-        let mut bars = 0;
+    /// Count bars in the events of a staff or drum voice (including repeats)
+    fn count_bars<E: StaffEvent>(events: &[E], idx: &mut usize) -> u32 {
+        let mut bars: u32 = 0;
         let mut has_content = false;
+        let mut cadenza: Option<Length> = None;
 
         while *idx < events.len() {
-            match &events[*idx] {
-                PitchedEvent::Note(_) | PitchedEvent::Rest { .. } => {
+            match events[*idx].view() {
+                EventView::Sound(sound) => {
                     has_content = true;
+                    cadenza = cadenza.map(|q| q.plus(sound.quarters()));
                     *idx += 1;
                 }
-                PitchedEvent::BarLine => {
-                    if has_content {
-                        bars += 1;
-                        has_content = false;
-                    }
+                EventView::Rest(quarters) => {
+                    has_content = true;
+                    cadenza = cadenza.map(|q| q.plus(quarters));
                     *idx += 1;
                 }
-                PitchedEvent::RepeatStart(count) => {
+                EventView::CadenzaOn => {
                     if has_content {
                         bars += 1;
                         has_content = false;
                     }
-                    *idx += 1;
-                    let inner_bars = Self::count_pitched_bars(events, idx);
-                    bars += inner_bars * count;
-                }
-                PitchedEvent::RepeatEnd => {
-                    *idx += 1;
-                    break;
-                }
-                PitchedEvent::Comment(_) => {
+                    cadenza = Some(Length::new(0, 1));
                     *idx += 1;
                 }
-            }
-        }
-
-        // Count final bar if there's content
-        if has_content {
-            bars += 1;
-        }
-
-        bars
-    }
-
-    /// Count bars in drum events (including repeats)
-    fn count_drum_bars(events: &[DrumEvent], idx: &mut usize) -> u32 {
-        let mut bars = 0;
-        let mut has_content = false;
-
-        while *idx < events.len() {
-            match &events[*idx] {
-                DrumEvent::Hit(_) | DrumEvent::Rest { .. } => {
-                    has_content = true;
+                EventView::CadenzaOff => {
+                    if let Some(quarters) = cadenza.take() {
+                        bars = bars.saturating_add(Self::free_bar_count(quarters));
+                        has_content = false;
+                    }
                     *idx += 1;
                 }
-                DrumEvent::BarLine => {
+                EventView::BarLine => {
                     if has_content {
                         bars += 1;
                         has_content = false;
                     }
                     *idx += 1;
                 }
-                DrumEvent::RepeatStart(count) => {
+                EventView::RepeatStart(_) => {
                     if has_content {
                         bars += 1;
                         has_content = false;
                     }
-                    *idx += 1;
-                    let inner_bars = Self::count_drum_bars(events, idx);
-                    bars += inner_bars * count;
+                    let repeat = Repeat::read(events, *idx);
+                    bars = bars.saturating_add(Self::count_repeat_bars(&repeat));
+                    *idx = repeat.end;
                 }
-                DrumEvent::RepeatEnd => {
+                EventView::RepeatEnd => {
                     *idx += 1;
                     break;
                 }
-                DrumEvent::Comment(_) => {
+                EventView::AlternativeStart | EventView::AlternativeEnd | EventView::Time(_) | EventView::Comment(_) => {
                     *idx += 1;
                 }
             }
         }
 
-        // Count final bar if there's content
-        if has_content {
+        // Count final bar if there's content (an unterminated cadenza runs to the end)
+        if let Some(quarters) = cadenza {
+            bars = bars.saturating_add(Self::free_bar_count(quarters));
+        } else if has_content {
             bars += 1;
         }
 
         bars
     }
 
-    /// Generate CPM expression using the nbars constant
-    fn format_cpm_expression(_bars: u32) -> String {
-        "tempo/4/nbars".to_string()
+    /// Bars of a repeat and its endings, every pass counted
+    fn count_repeat_bars<E: StaffEvent>(repeat: &Repeat<E>) -> u32 {
+        repeat
+            .passes()
+            .iter()
+            .map(|(times, pass)| Self::count_bars(pass, &mut 0).saturating_mul(*times))
+            .fold(0, u32::saturating_add)
+    }
+
+    /// Write an open beam group into the bar
+    fn close_beam(beam: &mut Option<BeamGroup>, current_bar: &mut Vec<String>, options: &GeneratorOptions) {
+        if let Some(group) = beam.take() {
+            current_bar.push(group.format(options));
+        }
+    }
+
+    /// A bar of the pattern, weighted by its length when not in 4/4 (`[...]@7/8`)
+    fn format_bar(content: &[String], weight: Length, options: &GeneratorOptions) -> String {
+        match Self::format_weight(weight, options) {
+            Some(w) => format!("[{}]@{}", content.join(" "), w),
+            None => format!("[{}]", content.join(" ")),
+        }
+    }
+
+    /// Length of a staff in 4/4 bars: its bar count, with bars of other meters weighted
+    fn staff_measure(staff: &Staff) -> Length {
+        let options = GeneratorOptions::default();
+        match &staff.content {
+            StaffContent::Notes(events) => {
+                Self::generate_pattern_with_bars(events, &mut 0, &mut TimeSignature::default(), &options).1
+            }
+            StaffContent::Drums(voices) => voices
+                .iter()
+                .map(|voice| Self::generate_pattern_with_bars(&voice.events, &mut 0, &mut TimeSignature::default(), &options).1)
+                .max_by(|a, b| a.as_f64().total_cmp(&b.as_f64()))
+                .unwrap_or(Length::new(0, 1)),
+        }
+    }
+
+    /// Number of whole bars (of four quarters) an unmetered passage occupies
+    fn free_bar_count(quarters: Length) -> u32 {
+        let bars = u128::from(quarters.num).div_ceil(4 * u128::from(quarters.den));
+        u32::try_from(bars).unwrap_or(u32::MAX).max(1)
+    }
+
+    /// Render a cadenza as one bar stretched over as many bars as it needs, padded with a
+    /// rest so that the notes keep their written speed
+    fn format_free_bar(content: &[String], quarters: Length, options: &GeneratorOptions) -> String {
+        let bars = Self::free_bar_count(quarters);
+        let mut content = content.to_vec();
+        if let Some(padding) = Length::new(bars, 1).times(Length::new(4, 1)).minus(quarters) {
+            content.push(Self::format_rest(padding, options));
+        }
+        if bars > 1 {
+            format!("[{}]@{}", content.join(" "), bars)
+        } else {
+            format!("[{}]", content.join(" "))
+        }
+    }
+
+    /// Generate CPM expression using the tempo and nbars constants. With a rubato map, the
+    /// cycle is split into segments of bars, each scaling the tempo by its factor.
+    fn format_cpm_expression(bars: u32, options: &GeneratorOptions) -> String {
+        let cpm = format!("{}/4/nbars", options.tempo_name);
+        let factor = |bar: u32| {
+            options.rubato.iter().rev()
+                .find(|r| (r.first_bar..=r.last_bar).contains(&bar))
+                .map_or("1", |r| r.factor.as_str())
+        };
+        if (1..=bars).all(|bar| factor(bar) == "1") {
+            return cpm;
+        }
+
+        let mut segments: Vec<(&str, u32)> = Vec::new();
+        for bar in 1..=bars {
+            match segments.last_mut() {
+                Some((f, count)) if *f == factor(bar) => *count += 1,
+                _ => segments.push((factor(bar), 1)),
+            }
+        }
+        let segments: Vec<String> = segments
+            .iter()
+            .map(|(f, count)| if *count == 1 { f.to_string() } else { format!("{f}@{count}") })
+            .collect();
+        format!("\"[{}]\".mul({cpm})", segments.join(" "))
     }
 
-    fn format_note(n: &Note) -> String {
+    fn format_note(n: &Note, options: &GeneratorOptions) -> String {
+        if options.midi_numbers {
+            return (n.midi + 12 * options.octave_offset).to_string();
+        }
         let acc = match &n.accidental {
             Some(a) if a == "is" => "#",
             Some(a) if a == "es" => "b",
             _ => "",
         };
-        format!("{}{}{}", n.name, acc, n.octave)
+        format!("{}{}{}", n.name, acc, n.octave + options.octave_offset)
     }
 
     /// Format modifier value - wrap in quotes if it's a Strudel pattern
     fn format_pattern_value(value: &str) -> String {
-        if value.contains('<') {
+        // Expressions starting with a quoted pattern (accents) are kept as they are
+        if value.contains('<') && !value.starts_with('"') {
             format!("\"{}\"", value)
         } else {
             value.to_string()
         }
     }
 
-    /// Format weight as explicit numeric value (4, 2, 1, 0.5, 0.25)
-    fn format_weight(duration: u32) -> Option<String> {
-        match duration {
-            1 => Some("4".to_string()),       // whole note = 4 quarter notes
-            2 => Some("2".to_string()),       // half note = 2 quarter notes
-            4 => None,                         // quarter note = 1 (no weight needed)
-            8 => Some("0.5".to_string()),     // eighth note = 0.5 quarter notes
-            16 => Some("0.25".to_string()),   // sixteenth note = 0.25 quarter notes
-            _ => {
-                let weight = 4.0 / duration as f32;
-                Some(weight.to_string())
+    /// Format a length in quarter notes as weight: numeric value (4, 2, 1.5, 0.5, 0.25)
+    /// or fraction (3/2, 1/2, 1/4). A quarter note needs no weight.
+    fn format_weight(length: Length, options: &GeneratorOptions) -> Option<String> {
+        if length.num == length.den {
+            return None;
+        }
+        if length.den == 1 {
+            return Some(length.num.to_string());
+        }
+        // Only denominators made of 2s and 5s end, the others (`0.333...`) stay fractions
+        let mut den = length.den;
+        for factor in [2, 5] {
+            while den.is_multiple_of(factor) {
+                den /= factor;
             }
         }
+        match options.weight_style {
+            WeightStyle::Decimal if den == 1 => Some((length.num as f64 / length.den as f64).to_string()),
+            _ => Some(format!("{}/{}", length.num, length.den)),
+        }
     }
 
-    fn format_pitched_note(n: &Note) -> String {
+    fn format_pitched_note(n: &Note, options: &GeneratorOptions) -> String {
         // Check if this is a chord
         let note_str = if let Some(ref chord_notes) = n.chord_notes {
             // Format as [note1,note2,note3]
-            let mut all_notes = vec![Self::format_note(n)];
+            let mut all_notes = vec![Self::format_note(n, options)];
             for cn in chord_notes {
-                all_notes.push(Self::format_note(cn));
+                all_notes.push(Self::format_note(cn, options));
             }
             format!("[{}]", all_notes.join(","))
         } else {
-            Self::format_note(n)
+            Self::format_note(n, options)
         };
 
-        match Self::format_weight(n.duration) {
+        match Self::format_weight(n.quarters(), options) {
             Some(w) => format!("{}@{}", note_str, w),
             None => note_str,
         }
     }
 
-    fn format_rest(duration: u32) -> String {
-        // Convert rest duration to number of quarter note rests
+    fn format_rest(length: Length, options: &GeneratorOptions) -> String {
+        // Convert rest length to quarter note rests
         // duration 4 = 1 quarter note = "~"
         // duration 2 = half note = 2 quarter notes = "~ ~"
         // duration 1 = whole note = 4 quarter notes = "~ ~ ~ ~"
-        let quarter_notes = 4 / duration;
-        if quarter_notes <= 1 {
-            "~".to_string()
+        // anything else (eighth, dotted quarter) is a single weighted rest: "~@0.5"
+        if length.den == 1 && length.num >= 1 {
+            vec!["~"; length.num as usize].join(" ")
         } else {
-            vec!["~"; quarter_notes as usize].join(" ")
+            match Self::format_weight(length, options) {
+                Some(w) => format!("~@{}", w),
+                None => "~".to_string(),
+            }
+        }
+    }
+
+    /// Rest of `length` starting `position` into the bar, split at the beats it crosses
+    /// (`~@0.5 ~ ~@0.5` for a half rest after an eighth) so that the bar keeps its groove
+    fn format_beat_rests(position: Length, length: Length, beat: Length, options: &GeneratorOptions) -> String {
+        let into_beat = position.offset_in(beat);
+        let mut room = beat.minus(into_beat).unwrap_or(beat);
+        let mut left = length;
+        let mut rests = Vec::new();
+        loop {
+            match left.minus(room) {
+                Some(rest) => {
+                    rests.push(Self::format_rest(room, options));
+                    left = rest;
+                    room = beat;
+                }
+                None => {
+                    rests.push(Self::format_rest(left, options));
+                    break;
+                }
+            }
+        }
+        rests.join(" ")
+    }
+
+    /// Comment naming the chords of a bar (` // C F`), ending the line so that the brackets
+    /// closing around the bar go on the next one; empties `chords`
+    fn chord_comment(chords: &mut Vec<String>) -> String {
+        if chords.is_empty() {
+            return String::new();
         }
+        format!(" // {}\n", std::mem::take(chords).join(" "))
     }
 
     /// Returns (pattern_string, bar_count)
-    fn generate_pitched_pattern_with_bars(events: &[PitchedEvent], idx: &mut usize) -> (String, u32) {
+    fn generate_pattern_with_bars<E: StaffEvent>(
+        events: &[E],
+        idx: &mut usize,
+        meter: &mut TimeSignature,
+        options: &GeneratorOptions,
+    ) -> (String, Length) {
         let mut bars: Vec<String> = Vec::new();
         let mut current_bar: Vec<String> = Vec::new();
-        let mut bar_count: u32 = 0;
+        let mut bar_count = Length::new(0, 1);
+        // Length of the cadenza being collected, if inside one
+        let mut cadenza: Option<Length> = None;
+        let mut beam: Option<BeamGroup> = None;
+        // Offset in the bar, to align rests on the beats
+        let mut position = Length::new(0, 1);
+        // Symbols of the chords of the bar, for its comment
+        let mut chords: Vec<String> = Vec::new();
+        let name_chords = options.chord_names && options.quote_style == QuoteStyle::Backtick;
 
         while *idx < events.len() {
-            match &events[*idx] {
-                PitchedEvent::Note(n) => {
-                    current_bar.push(Self::format_pitched_note(n));
+            match events[*idx].view() {
+                EventView::Sound(sound) => {
+                    let token = sound.format(options);
+                    if name_chords && let Some(symbol) = sound.chord_symbol() {
+                        chords.push(symbol);
+                    }
+                    if sound.beam() == Some(Beam::Start) {
+                        Self::close_beam(&mut beam, &mut current_bar, options);
+                        beam = Some(BeamGroup::default());
+                    }
+                    match beam.as_mut() {
+                        Some(group) => {
+                            let quarter = sound.lasting(Length::new(1, 1));
+                            group.push(token, quarter.format(options), sound.quarters());
+                        }
+                        None => current_bar.push(token),
+                    }
+                    if sound.beam() == Some(Beam::End) {
+                        Self::close_beam(&mut beam, &mut current_bar, options);
+                    }
+                    cadenza = cadenza.map(|q| q.plus(sound.quarters()));
+                    position = position.plus(sound.quarters());
+                    *idx += 1;
+                }
+                EventView::Rest(quarters) => {
+                    let token = match cadenza {
+                        // Unmetered, no beats to follow
+                        Some(_) => Self::format_rest(quarters, options),
+                        None => Self::format_beat_rests(position, quarters, meter.beat(), options),
+                    };
+                    match beam.as_mut() {
+                        Some(group) => {
+                            group.push(token, Self::format_rest(Length::new(1, 1), options), quarters)
+                        }
+                        None => current_bar.push(token),
+                    }
+                    cadenza = cadenza.map(|q| q.plus(quarters));
+                    position = position.plus(quarters);
+                    *idx += 1;
+                }
+                EventView::CadenzaOn => {
+                    Self::close_beam(&mut beam, &mut current_bar, options);
+                    if !current_bar.is_empty() {
+                        bars.push(Self::format_bar(&current_bar, meter.bar_weight(), options) + &Self::chord_comment(&mut chords));
+                        current_bar = Vec::new();
+                        position = Length::new(0, 1);
+                        bar_count = bar_count.plus(meter.bar_weight());
+                    }
+                    cadenza = Some(Length::new(0, 1));
                     *idx += 1;
                 }
-                PitchedEvent::Rest { duration } => {
-                    current_bar.push(Self::format_rest(*duration));
+                EventView::CadenzaOff => {
+                    Self::close_beam(&mut beam, &mut current_bar, options);
+                    if let Some(quarters) = cadenza.take() {
+                        bars.push(Self::format_free_bar(&current_bar, quarters, options) + &Self::chord_comment(&mut chords));
+                        current_bar = Vec::new();
+                        position = Length::new(0, 1);
+                        bar_count = bar_count.plus(Length::new(Self::free_bar_count(quarters), 1));
+                    }
                     *idx += 1;
                 }
-                PitchedEvent::BarLine => {
+                EventView::BarLine => {
                     // Save current bar and start a new one
+                    Self::close_beam(&mut beam, &mut current_bar, options);
                     if !current_bar.is_empty() {
-                        bars.push(format!("[{}]", current_bar.join(" ")));
+                        bars.push(Self::format_bar(&current_bar, meter.bar_weight(), options) + &Self::chord_comment(&mut chords));
                         current_bar = Vec::new();
-                        bar_count += 1;
+                        position = Length::new(0, 1);
+                        bar_count = bar_count.plus(meter.bar_weight());
                     }
                     *idx += 1;
                 }
-                PitchedEvent::RepeatStart(count) => {
+                EventView::RepeatStart(_) => {
                     // Save current bar content before repeat
+                    Self::close_beam(&mut beam, &mut current_bar, options);
                     if !current_bar.is_empty() {
-                        bars.push(format!("[{}]", current_bar.join(" ")));
+                        bars.push(Self::format_bar(&current_bar, meter.bar_weight(), options) + &Self::chord_comment(&mut chords));
                         current_bar = Vec::new();
-                        bar_count += 1;
+                        position = Length::new(0, 1);
+                        bar_count = bar_count.plus(meter.bar_weight());
                     }
-                    *idx += 1;
-                    let (inner, inner_bars) = Self::generate_pitched_pattern_with_bars(events, idx);
-                    let total_bars = inner_bars * count;
-                    // If more than one bar in repeat, add duration
-                    if inner_bars > 1 {
-                        bars.push(format!("[[{}]!{}]@{}", inner, count, total_bars));
-                    } else {
-                        bars.push(format!("[{}]!{}", inner, count));
+                    // The passes closed by each ending follow each other, a pass played once is
+                    // written out
+                    let repeat = Repeat::read(events, *idx);
+                    for (times, pass) in repeat.passes() {
+                        let (inner, inner_bars) = Self::generate_pattern_with_bars(&pass, &mut 0, meter, options);
+                        let total_bars = inner_bars.times(Length::new(times, 1));
+                        if times == 1 && !repeat.endings.is_empty() {
+                            if !inner.is_empty() {
+                                bars.push(inner);
+                            }
+                        } else if inner_bars == Length::new(1, 1) {
+                            // Unless the repeat is a single 4/4 bar, add duration
+                            bars.push(format!("[{}]!{}", inner, times));
+                        } else {
+                            let weight = Self::format_weight(total_bars, options).unwrap_or_else(|| "1".to_string());
+                            bars.push(format!("[[{}]!{}]@{}", inner, times, weight));
+                        }
+                        bar_count = bar_count.plus(total_bars);
                     }
-                    bar_count += total_bars;
+                    *idx = repeat.end;
                 }
-                PitchedEvent::RepeatEnd => {
+                EventView::RepeatEnd => {
                     *idx += 1;
                     break; // Exit this level of recursion
                 }
-                PitchedEvent::Comment(_) => {
+                EventView::Time(time) => {
+                    *meter = time;
+                    *idx += 1;
+                }
+                EventView::AlternativeStart | EventView::AlternativeEnd | EventView::Comment(_) => {
                     *idx += 1;
                 }
             }
         }
 
-        // Don't forget the last bar
-        if !current_bar.is_empty() {
-            bars.push(format!("[{}]", current_bar.join(" ")));
-            bar_count += 1;
+        // Don't forget the last bar (an unterminated cadenza runs to the end)
+        Self::close_beam(&mut beam, &mut current_bar, options);
+        if let Some(quarters) = cadenza {
+            bars.push(Self::format_free_bar(&current_bar, quarters, options) + &Self::chord_comment(&mut chords));
+            bar_count = bar_count.plus(Length::new(Self::free_bar_count(quarters), 1));
+        } else if !current_bar.is_empty() {
+            bars.push(Self::format_bar(&current_bar, meter.bar_weight(), options) + &Self::chord_comment(&mut chords));
+            bar_count = bar_count.plus(meter.bar_weight());
         }
 
-        (bars.join("\n"), bar_count)
+        // A bar with a comment already ends its line
+        let mut pattern = String::new();
+        for bar in &bars {
+            if !pattern.is_empty() && !pattern.ends_with('\n') {
+                pattern.push('\n');
+            }
+            pattern.push_str(bar);
+        }
+        (pattern, bar_count)
     }
 
-    fn generate_pitched_pattern(events: &[PitchedEvent], idx: &mut usize) -> String {
-        Self::generate_pitched_pattern_with_bars(events, idx).0
+    fn generate_pattern<E: StaffEvent>(events: &[E], idx: &mut usize, options: &GeneratorOptions) -> String {
+        Self::generate_pattern_with_bars(events, idx, &mut TimeSignature::default(), options).0
     }
 
     pub fn generate_pitched_staff(events: &[PitchedEvent], tempo: &Tempo) -> String {
-        Self::generate_pitched_staff_with_options(events, tempo, &None, &None, &None)
+        Self::generate_pitched_staff_with_options(events, tempo, "", 0, None, &GeneratorOptions::default())
+    }
+
+    /// Move every note (and chord note) by whole octaves, keeping MIDI numbers consistent
+    fn shift_octaves(events: &[PitchedEvent], octaves: i32) -> Vec<PitchedEvent> {
+        fn shift(n: &Note, octaves: i32) -> Note {
+            Note {
+                octave: n.octave + octaves,
+                midi: n.midi + 12 * octaves,
+                chord_notes: n.chord_notes.as_ref().map(|cn| cn.iter().map(|c| shift(c, octaves)).collect()),
+                ..n.clone()
+            }
+        }
+
+        events
+            .iter()
+            .map(|e| match e {
+                PitchedEvent::Note(n) => PitchedEvent::Note(shift(n, octaves)),
+                other => other.clone(),
+            })
+            .collect()
+    }
+
+    /// Give grace notes their time by shortening a neighbouring note of the same bar (see
+    /// [`GraceTiming`]); when that note is too short, the other neighbour is tried, otherwise
+    /// the grace notes keep their written length
+    fn steal_grace_time(events: &[PitchedEvent], timing: GraceTiming) -> Vec<PitchedEvent> {
+        Self::steal_time(
+            events,
+            timing,
+            |e| match e {
+                PitchedEvent::Note(n) => Some((n.grace, n.quarters())),
+                _ => None,
+            },
+            |e, length| {
+                if let PitchedEvent::Note(n) = e {
+                    n.length = Some(length);
+                    for chord_note in n.chord_notes.iter_mut().flatten() {
+                        chord_note.length = Some(length);
+                    }
+                }
+            },
+        )
+    }
+
+    /// [`Self::steal_grace_time`] for the grace hits of a drum voice (flams, drags)
+    fn steal_drum_grace_time(events: &[DrumEvent], timing: GraceTiming) -> Vec<DrumEvent> {
+        Self::steal_time(
+            events,
+            timing,
+            |e| match e {
+                DrumEvent::Hit(h) => Some((h.grace, h.quarters())),
+                _ => None,
+            },
+            |e, length| {
+                if let DrumEvent::Hit(h) = e {
+                    h.length = Some(length);
+                }
+            },
+        )
+    }
+
+    /// Shorten the neighbours of runs of grace events by the length of the run; `sounding`
+    /// tells whether an event is a grace one and its length, `None` for rests and bar lines
+    fn steal_time<E: Clone>(
+        events: &[E],
+        timing: GraceTiming,
+        sounding: impl Fn(&E) -> Option<(bool, Length)>,
+        set_length: impl Fn(&mut E, Length),
+    ) -> Vec<E> {
+        let is_main = |event: Option<&E>| matches!(event.and_then(&sounding), Some((false, _)));
+
+        let mut events = events.to_vec();
+        let mut i = 0;
+        while i < events.len() {
+            let end = i + events[i..]
+                .iter()
+                .take_while(|e| matches!(sounding(e), Some((true, _))))
+                .count();
+            if end == i {
+                i += 1;
+                continue;
+            }
+
+            let grace = events[i..end]
+                .iter()
+                .filter_map(&sounding)
+                .fold(Length::new(0, 1), |total, (_, length)| total.plus(length));
+            let previous = i.checked_sub(1).filter(|&p| is_main(events.get(p)));
+            let next = Some(end).filter(|&n| is_main(events.get(n)));
+            let candidates = match timing {
+                GraceTiming::Ornamented => [next, previous],
+                GraceTiming::Previous => [previous, next],
+            };
+            for target in candidates.into_iter().flatten() {
+                if let Some((_, length)) = sounding(&events[target])
+                    && let Some(length) = length.minus(grace)
+                {
+                    set_length(&mut events[target], length);
+                    break;
+                }
+            }
+            i = end;
+        }
+        events
+    }
+
+    /// Length of a note played with an articulation, as a `.clip()` value
+    fn articulation_clip(articulation: &str) -> Option<&'static str> {
+        match articulation {
+            "staccatissimo" => Some("0.25"),
+            "staccato" => Some("0.5"),
+            "portato" => Some("0.75"),
+            "tenuto" => Some("1"),
+            _ => None,
+        }
+    }
+
+    /// The `.clip()` value of every note, in the rhythm of the notes: staccato, portato and
+    /// tenuto notes are shortened or held by their articulation, slurred notes held to the next
+    /// one (`1`), the others slightly detached (`0.9`)
+    fn note_clips(events: &[PitchedEvent]) -> Vec<DrumEvent> {
+        let mut in_slur = false;
+        Self::pitched_rhythm(events, |n| {
+            let legato = in_slur || n.slur == Some(Slur::Start);
+            in_slur = match n.slur {
+                Some(slur) => slur == Slur::Start,
+                None => in_slur,
+            };
+            let clip = n.articulations.iter().find_map(|a| Self::articulation_clip(a));
+            DrumHit { name: clip.unwrap_or(if legato { "1" } else { "0.9" }).to_string(), ..Default::default() }
+        })
+    }
+
+    /// Pitched events as drum events in the same rhythm, each note becoming the hit made by `hit`
+    /// (its duration and beam set from the note), for patterns following the notes
+    fn pitched_rhythm(events: &[PitchedEvent], mut hit: impl FnMut(&Note) -> DrumHit) -> Vec<DrumEvent> {
+        events
+            .iter()
+            .filter_map(|event| {
+                Some(match event {
+                    PitchedEvent::Note(n) => {
+                        DrumEvent::Hit(DrumHit { duration: n.duration, length: n.length, beam: n.beam, ..hit(n) })
+                    }
+                    PitchedEvent::Rest { duration, length } => DrumEvent::Rest { duration: *duration, length: *length },
+                    PitchedEvent::BarLine => DrumEvent::BarLine,
+                    PitchedEvent::RepeatStart(count) => DrumEvent::RepeatStart(*count),
+                    PitchedEvent::RepeatEnd => DrumEvent::RepeatEnd,
+                    PitchedEvent::AlternativeStart => DrumEvent::AlternativeStart,
+                    PitchedEvent::AlternativeEnd => DrumEvent::AlternativeEnd,
+                    PitchedEvent::CadenzaOn => DrumEvent::CadenzaOn,
+                    PitchedEvent::CadenzaOff => DrumEvent::CadenzaOff,
+                    PitchedEvent::Time(time) => DrumEvent::Time(*time),
+                    PitchedEvent::Comment(_) => return None,
+                })
+            })
+            .collect()
+    }
+
+    /// Volume of a dynamic mark from 0.25 (`ppppp`) to 1 (`fffff`), and the level it leaves for the
+    /// notes after it: `fp` plays forte then piano, accents (`sfz`) leave the level unchanged
+    fn dynamic_volume(dynamic: &str) -> Option<(f64, Option<f64>)> {
+        let level = |volume: f64| Some((volume, Some(volume)));
+        match dynamic {
+            "ppppp" => level(0.25),
+            "pppp" => level(0.3),
+            "ppp" => level(0.35),
+            "pp" => level(0.4),
+            "p" | "sp" => level(0.5),
+            "spp" => level(0.4),
+            "mp" => level(0.6),
+            "mf" => level(0.7),
+            "f" => level(0.8),
+            "ff" => level(0.87),
+            "fff" => level(0.93),
+            "ffff" => level(0.97),
+            "fffff" => level(1.0),
+            "fp" => Some((0.8, Some(0.5))),
+            "sfp" => Some((0.93, Some(0.5))),
+            "sf" | "sfz" | "fz" | "rfz" => Some((0.93, None)),
+            "sff" => Some((1.0, None)),
+            _ => None,
+        }
+    }
+
+    /// Volume of every hit from the dynamic marks and hairpins, hairpins ramping from the level
+    /// where they start to the dynamic ending them (or a step further for `\!`), accented hits
+    /// louder
+    fn hit_volumes(hits: &[&DrumHit]) -> Vec<f64> {
+        // Before the first mark, mezzo-forte
+        let mut level = 0.7;
+        let mut volumes = vec![level; hits.len()];
+        let mut ramp: Option<(usize, f64, Hairpin)> = None;
+        let step = |from: f64, hairpin: Hairpin| match hairpin {
+            Hairpin::Decrescendo => (from - 0.1).max(0.25),
+            _ => (from + 0.1).min(1.0),
+        };
+        let fill = |volumes: &mut Vec<f64>, (start, from, _): (usize, f64, Hairpin), end: usize, to: f64| {
+            for (j, volume) in volumes.iter_mut().enumerate().take(end).skip(start) {
+                *volume = from + (to - from) * (j - start) as f64 / (end - start) as f64;
+            }
+        };
+
+        for (i, hit) in hits.iter().enumerate() {
+            let mark = hit.dynamic.as_deref().and_then(Self::dynamic_volume);
+            // A dynamic, `\!` or another hairpin ends the hairpin under way
+            if (mark.is_some() || hit.hairpin.is_some())
+                && let Some(open) = ramp.take()
+            {
+                let to = match mark {
+                    Some((volume, after)) => after.unwrap_or(volume),
+                    None => step(open.1, open.2),
+                };
+                fill(&mut volumes, open, i, to);
+                level = to;
+            }
+            let (volume, after) = mark.unwrap_or((level, None));
+            volumes[i] = volume;
+            level = after.unwrap_or(level);
+            if let Some(hairpin @ (Hairpin::Crescendo | Hairpin::Decrescendo)) = hit.hairpin {
+                ramp = Some((i, level, hairpin));
+            }
+        }
+        if let Some(open) = ramp {
+            fill(&mut volumes, open, hits.len(), step(open.1, open.2));
+        }
+        // Accents stand out of the level around them
+        for (volume, hit) in volumes.iter_mut().zip(hits) {
+            if hit.articulations.iter().any(|a| a == "marcato") {
+                *volume = (*volume + 0.25).min(1.0);
+            } else if hit.articulations.iter().any(|a| a == "accent") {
+                *volume = (*volume + 0.15).min(1.0);
+            }
+        }
+        volumes
+    }
+
+    /// Dynamics of the hits as a `.velocity()` pattern in the rhythm of the voice, when any hit
+    /// has a dynamic, a hairpin or an accent (`->`, `-^`). Velocity scales the `gain`.
+    fn format_dynamics(events: &[DrumEvent], indent: &str, options: &GeneratorOptions) -> String {
+        let hits: Vec<&DrumHit> = events
+            .iter()
+            .filter_map(|e| match e {
+                DrumEvent::Hit(h) => Some(h),
+                _ => None,
+            })
+            .collect();
+        let accented = |h: &DrumHit| h.articulations.iter().any(|a| a == "accent" || a == "marcato");
+        if !hits.iter().any(|h| h.dynamic.is_some() || h.hairpin.is_some() || accented(h)) {
+            return String::new();
+        }
+        let mut volumes = Self::hit_volumes(&hits).into_iter();
+        let velocities: Vec<DrumEvent> = events
+            .iter()
+            .map(|event| match event {
+                DrumEvent::Hit(h) => {
+                    let volume = volumes.next().unwrap_or(1.0);
+                    DrumEvent::Hit(DrumHit { name: format!("{}", (volume * 100.0).round() / 100.0), ..h.clone() })
+                }
+                other => other.clone(),
+            })
+            .collect();
+        let pattern = Self::generate_pattern(&velocities, &mut 0, options);
+        format!("\n{indent}.velocity({})", Self::quote_pattern(&pattern, options))
+    }
+
+    /// Pitches as sample numbers: semitones above the lowest note
+    fn sample_indices(events: &[PitchedEvent]) -> Vec<PitchedEvent> {
+        fn all_midi(n: &Note) -> impl Iterator<Item = i32> + '_ {
+            std::iter::once(n.midi).chain(n.chord_notes.iter().flatten().map(|c| c.midi))
+        }
+        fn shift(n: &Note, root: i32) -> Note {
+            Note {
+                midi: n.midi - root,
+                chord_notes: n.chord_notes.as_ref().map(|cn| cn.iter().map(|c| shift(c, root)).collect()),
+                ..n.clone()
+            }
+        }
+
+        let root = events
+            .iter()
+            .filter_map(|e| match e {
+                PitchedEvent::Note(n) => all_midi(n).min(),
+                _ => None,
+            })
+            .min()
+            .unwrap_or(0);
+        events
+            .iter()
+            .map(|e| match e {
+                PitchedEvent::Note(n) => PitchedEvent::Note(shift(n, root)),
+                other => other.clone(),
+            })
+            .collect()
     }
 
+    /// `modifiers` are the staff's `.gain()`, `.pan()`, ... calls, see [`Self::format_voice_modifiers`]
     fn generate_pitched_staff_with_options(
         events: &[PitchedEvent],
         _tempo: &Tempo,
-        punchcard_color: &Option<String>,
-        gain: &Option<String>,
-        pan: &Option<String>,
+        modifiers: &str,
+        octave_shift: i32,
+        sample: Option<&Sample>,
+        options: &GeneratorOptions,
     ) -> String {
+        let shifted;
+        let events = if octave_shift != 0 {
+            shifted = Self::shift_octaves(events, octave_shift);
+            &shifted[..]
+        } else {
+            events
+        };
+        let graced;
+        let events = if events.iter().any(|e| matches!(e, PitchedEvent::Note(n) if n.grace)) {
+            graced = Self::steal_grace_time(events, options.grace_timing);
+            &graced[..]
+        } else {
+            events
+        };
+
         let notes: Vec<&Note> = events
             .iter()
             .filter_map(|e| match e {
@@ -1278,119 +5591,57 @@ impl StrudelGenerator {
         }
 
         let mut idx = 0;
-        let pattern = Self::generate_pitched_pattern(events, &mut idx);
-
-        // Build modifiers with newlines
-        let mut modifiers = String::new();
-        if let Some(g) = gain {
-            modifiers.push_str(&format!("\n.gain({})", Self::format_pattern_value(g)));
-        }
-        if let Some(p) = pan {
-            modifiers.push_str(&format!("\n.pan({})", Self::format_pattern_value(p)));
-        }
-        if let Some(color) = punchcard_color {
-            modifiers.push_str(&format!("\n.color(\"{}\")", color));
-            modifiers.push_str("\n._punchcard()");
-        }
+        let (function, pattern) = match sample {
+            Some(Sample { slices: true, .. }) => {
+                let index_options = GeneratorOptions { midi_numbers: true, octave_offset: 0, ..options.clone() };
+                ("n", Self::generate_pattern(&Self::sample_indices(events), &mut idx, &index_options))
+            }
+            _ => ("note", Self::generate_pattern(events, &mut idx, options)),
+        };
+        let sound = sample.map_or("piano", |s| s.name.as_str());
 
-        let base = format!(
-            "note(`\n{}`){}\n  .s(\"piano\")",
-            pattern, modifiers
+        let mut base = format!(
+            "{}({}){}\n  .s(\"{}\")",
+            function, Self::quote_pattern(&pattern, options), modifiers, sound
         );
+        if notes.iter().any(|n| n.slur.is_some() || n.articulations.iter().any(|a| Self::articulation_clip(a).is_some())) {
+            let clip = Self::generate_pattern(&Self::note_clips(events), &mut 0, options);
+            base = format!("{base}\n  .clip({})", Self::quote_pattern(&clip, options));
+        }
+        let marks = Self::pitched_rhythm(events, |n| DrumHit {
+            dynamic: n.dynamic.clone(),
+            hairpin: n.hairpin,
+            articulations: n.articulations.clone(),
+            ..Default::default()
+        });
+        base.push_str(&Self::format_dynamics(&marks, "  ", options));
 
         let mut bar_idx = 0;
-        let bars = Self::count_pitched_bars(events, &mut bar_idx);
+        let bars = Self::count_bars(events, &mut bar_idx);
         if bars > 0 {
-            format!("{base}\n  .cpm({})", Self::format_cpm_expression(bars))
+            format!("{base}\n  .cpm({})", Self::format_cpm_expression(bars, options))
         } else {
             base
         }
     }
 
-    fn format_drum_hit(h: &DrumHit) -> String {
-        match Self::format_weight(h.duration) {
+    fn format_drum_hit(h: &DrumHit, options: &GeneratorOptions) -> String {
+        match Self::format_weight(h.quarters(), options) {
             Some(w) => format!("{}@{}", h.name, w),
             None => h.name.clone(),
         }
     }
 
-    /// Returns (pattern_string, bar_count)
-    fn generate_drum_pattern_with_bars(events: &[DrumEvent], idx: &mut usize) -> (String, u32) {
-        let mut bars: Vec<String> = Vec::new();
-        let mut current_bar: Vec<String> = Vec::new();
-        let mut bar_count: u32 = 0;
-
-        while *idx < events.len() {
-            match &events[*idx] {
-                DrumEvent::Hit(h) => {
-                    current_bar.push(Self::format_drum_hit(h));
-                    *idx += 1;
-                }
-                DrumEvent::Rest { duration } => {
-                    current_bar.push(Self::format_rest(*duration));
-                    *idx += 1;
-                }
-                DrumEvent::BarLine => {
-                    // Save current bar and start a new one
-                    if !current_bar.is_empty() {
-                        bars.push(format!("[{}]", current_bar.join(" ")));
-                        current_bar = Vec::new();
-                        bar_count += 1;
-                    }
-                    *idx += 1;
-                }
-                DrumEvent::RepeatStart(count) => {
-                    // Save current bar content before repeat
-                    if !current_bar.is_empty() {
-                        bars.push(format!("[{}]", current_bar.join(" ")));
-                        current_bar = Vec::new();
-                        bar_count += 1;
-                    }
-                    *idx += 1;
-                    let (inner, inner_bars) = Self::generate_drum_pattern_with_bars(events, idx);
-                    let total_bars = inner_bars * count;
-                    // If more than one bar in repeat, add duration
-                    if inner_bars > 1 {
-                        bars.push(format!("[[{}]!{}]@{}", inner, count, total_bars));
-                    } else {
-                        bars.push(format!("[{}]!{}", inner, count));
-                    }
-                    bar_count += total_bars;
-                }
-                DrumEvent::RepeatEnd => {
-                    *idx += 1;
-                    break; // Exit this level of recursion
-                }
-                DrumEvent::Comment(_) => {
-                    *idx += 1;
-                }
-            }
-        }
-
-        // Don't forget the last bar
-        if !current_bar.is_empty() {
-            bars.push(format!("[{}]", current_bar.join(" ")));
-            bar_count += 1;
-        }
-
-        (bars.join("\n"), bar_count)
-    }
-
-    fn generate_drum_pattern(events: &[DrumEvent], idx: &mut usize) -> String {
-        Self::generate_drum_pattern_with_bars(events, idx).0
-    }
-
     #[allow(dead_code)]
     fn generate_single_drum_voice(events: &[DrumEvent], tempo: &Tempo) -> String {
-        Self::generate_single_drum_voice_with_options(events, tempo, &None, &None, &None)
+        Self::generate_single_drum_voice_with_options(events, tempo, "", &GeneratorOptions::default())
     }
 
     fn generate_single_drum_voice_with_options(
         events: &[DrumEvent],
         _tempo: &Tempo,
-        punchcard_color: &Option<String>,
-        gain: &Option<String>,
-        pan: &Option<String>,
+        modifiers: &str,
+        options: &GeneratorOptions,
     ) -> String {
         let hits: Vec<&DrumHit> = events
             .iter()
@@ -1405,100 +5656,257 @@ impl StrudelGenerator {
         }
 
         let mut idx = 0;
-        let pattern = Self::generate_drum_pattern(events, &mut idx);
-        let base = format!("sound(`\n{}`)", pattern);
-
-        // Build modifiers with newlines
-        let mut modifiers = String::new();
-        if let Some(g) = gain {
-            modifiers.push_str(&format!("\n.gain({})", Self::format_pattern_value(g)));
-        }
-        if let Some(p) = pan {
-            modifiers.push_str(&format!("\n.pan({})", Self::format_pattern_value(p)));
-        }
-        if let Some(color) = punchcard_color {
-            modifiers.push_str(&format!("\n.color(\"{}\")", color));
-            modifiers.push_str("\n._punchcard()");
-        }
-
+        let pattern = Self::generate_pattern(events, &mut idx, options);
+        let base = format!(
+            "sound({}){}{}",
+            Self::quote_pattern(&pattern, options),
+            Self::format_sticking(events, "", options),
+            Self::format_dynamics(events, "", options)
+        );
         let with_modifiers = format!("{}{}", base, modifiers);
 
         let mut bar_idx = 0;
-        let bars = Self::count_drum_bars(events, &mut bar_idx);
+        let bars = Self::count_bars(events, &mut bar_idx);
         if bars > 0 {
-            format!("{with_modifiers}\n  .cpm({})", Self::format_cpm_expression(bars))
+            format!("{with_modifiers}\n  .cpm({})", Self::format_cpm_expression(bars, options))
         } else {
             with_modifiers
         }
     }
 
-    fn format_voice_modifiers(punchcard_color: &Option<String>, gain: &Option<String>, pan: &Option<String>) -> String {
+    /// Modifier calls of a staff or voice, one per line, each line starting with `indent`
+    fn format_voice_modifiers(
+        punchcard_color: &Option<String>,
+        gain: &Option<String>,
+        pan: &Option<String>,
+        speed: &Option<Speed>,
+        indent: &str,
+    ) -> String {
         let mut modifiers = String::new();
         if let Some(g) = gain {
-            modifiers.push_str(&format!("\n  .gain({})", Self::format_pattern_value(g)));
+            modifiers.push_str(&format!("\n{indent}.gain({})", Self::format_pattern_value(g)));
         }
         if let Some(p) = pan {
-            modifiers.push_str(&format!("\n  .pan({})", Self::format_pattern_value(p)));
+            modifiers.push_str(&format!("\n{indent}.pan({})", Self::format_pattern_value(p)));
+        }
+        match speed {
+            Some(Speed::Slow(factor)) => {
+                modifiers.push_str(&format!("\n{indent}.slow({})", Self::format_pattern_value(factor)))
+            }
+            Some(Speed::Fast(factor)) => {
+                modifiers.push_str(&format!("\n{indent}.fast({})", Self::format_pattern_value(factor)))
+            }
+            None => {}
         }
         if let Some(color) = punchcard_color {
-            modifiers.push_str(&format!("\n  .color(\"{}\")", color));
-            modifiers.push_str("\n  ._punchcard()");
+            modifiers.push_str(&format!("\n{indent}.color(\"{}\")", color));
+            modifiers.push_str(&format!("\n{indent}._punchcard()"));
         }
         modifiers
     }
 
+    /// A mini-notation pattern as a string literal in the quote style of `options`
+    fn quote_pattern(pattern: &str, options: &GeneratorOptions) -> String {
+        match options.quote_style {
+            QuoteStyle::Backtick => format!("`\n{}`", pattern),
+            QuoteStyle::Double => format!("\"{}\"", pattern.split_whitespace().collect::<Vec<_>>().join(" ")),
+        }
+    }
+
+    /// Sticking of the hits as a `.label()` pattern in the rhythm of the voice (`R`, `L`, `~`
+    /// for hits without sticking), when any hit has one
+    fn format_sticking(events: &[DrumEvent], indent: &str, options: &GeneratorOptions) -> String {
+        if !events.iter().any(|e| matches!(e, DrumEvent::Hit(h) if h.sticking.is_some())) {
+            return String::new();
+        }
+        let hands: Vec<DrumEvent> = events
+            .iter()
+            .map(|event| match event {
+                DrumEvent::Hit(h) => match &h.sticking {
+                    Some(sticking) => DrumEvent::Hit(DrumHit { name: sticking.clone(), ..h.clone() }),
+                    None => DrumEvent::Rest { duration: h.duration, length: h.length },
+                },
+                other => other.clone(),
+            })
+            .collect();
+        let labels = Self::generate_pattern(&hands, &mut 0, options);
+        format!("\n{indent}.label({})", Self::quote_pattern(&labels, options))
+    }
+
     pub fn generate_drum_staff(voices: &[DrumVoiceData], tempo: &Tempo) -> String {
+        Self::generate_drum_staff_with_options(voices, tempo, "", &GeneratorOptions::default())
+    }
+
+    /// `staff_modifiers` apply to the whole staff (after the voice's own for a single voice)
+    fn generate_drum_staff_with_options(
+        voices: &[DrumVoiceData],
+        tempo: &Tempo,
+        staff_modifiers: &str,
+        options: &GeneratorOptions,
+    ) -> String {
         if voices.is_empty() {
             return String::from("// No drum hits to convert");
         }
 
+        let bank = match &options.drum_bank {
+            Some(bank) => format!("\n.bank(\"{}\")", bank),
+            None => String::new(),
+        };
+        let staff_modifiers = &(bank + staff_modifiers);
+        // Grace hits (flams, drags) take their time from a neighbouring hit
+        let graced: Vec<DrumVoiceData> = voices
+            .iter()
+            .map(|v| DrumVoiceData { events: Self::steal_drum_grace_time(&v.events, options.grace_timing), ..v.clone() })
+            .collect();
+        let voices = &graced[..];
+        let (voices, choked) = if options.choke_hats {
+            Self::split_hats(voices)
+        } else {
+            (voices.to_vec(), vec![false; voices.len()])
+        };
+        let cut = |i: usize, indent: &str| if choked[i] { format!("\n{indent}.cut(1)") } else { String::new() };
+
         if voices.len() == 1 {
             let voice = &voices[0];
-            return Self::generate_single_drum_voice_with_options(
-                &voice.events,
-                tempo,
-                &voice.punchcard_color,
-                &voice.gain,
-                &voice.pan,
-            );
+            let modifiers =
+                Self::format_voice_modifiers(&voice.punchcard_color, &voice.gain, &voice.pan, &voice.speed, "")
+                    + &cut(0, "")
+                    + staff_modifiers;
+            return Self::generate_single_drum_voice_with_options(&voice.events, tempo, &modifiers, options);
         }
 
-        // Multiple voices: use stack() with per-voice punchcard
+        // Multiple voices: use stack() with per-voice punchcard, from the palette if no voice has a color
+        let palette = options
+            .voice_palette
+            .as_ref()
+            .filter(|p| !p.is_empty() && voices.iter().all(|v| v.punchcard_color.is_none()));
         let voice_patterns: Vec<String> = voices
             .iter()
-            .map(|voice| {
+            .enumerate()
+            .map(|(i, voice)| {
                 let mut idx = 0;
-                let pattern = Self::generate_drum_pattern(&voice.events, &mut idx);
-                let modifiers = Self::format_voice_modifiers(&voice.punchcard_color, &voice.gain, &voice.pan);
-                format!("sound(`\n{}`){}", pattern, modifiers)
+                let pattern = Self::generate_pattern(&voice.events, &mut idx, options);
+                let color = match palette {
+                    Some(palette) => Some(palette[i % palette.len()].clone()),
+                    None => voice.punchcard_color.clone(),
+                };
+                let modifiers = Self::format_voice_modifiers(&color, &voice.gain, &voice.pan, &voice.speed, "  ") + &cut(i, "  ");
+                let sticking = Self::format_sticking(&voice.events, "  ", options)
+                    + &Self::format_dynamics(&voice.events, "  ", options);
+                let header = match &voice.name {
+                    Some(name) => format!("// {}\n  ", name),
+                    None => String::new(),
+                };
+                format!("{}sound({}){}{}", header, Self::quote_pattern(&pattern, options), sticking, modifiers)
             })
             .collect();
 
-        let stacked = format!("stack(\n  {},\n)", voice_patterns.join(",\n  "));
+        let stacked = format!("stack(\n  {},\n){}", voice_patterns.join(",\n  "), staff_modifiers.replace('\n', "\n  "));
 
         // Use the longest voice to calculate bars
         let max_bars: u32 = voices
             .iter()
             .map(|voice| {
                 let mut idx = 0;
-                Self::count_drum_bars(&voice.events, &mut idx)
+                Self::count_bars(&voice.events, &mut idx)
             })
             .max()
             .unwrap_or(0);
 
         if max_bars > 0 {
-            format!("{stacked}\n  .cpm({})", Self::format_cpm_expression(max_bars))
+            format!("{stacked}\n  .cpm({})", Self::format_cpm_expression(max_bars, options))
         } else {
             stacked
         }
     }
 
+    /// The voices mixing open and closed hi-hats split in two, the hats apart, with whether each
+    /// voice is choked (only hats)
+    fn split_hats(voices: &[DrumVoiceData]) -> (Vec<DrumVoiceData>, Vec<bool>) {
+        let is_hat = |name: &str| matches!(name, "hh" | "oh" | "hhp");
+        let hits = |voice: &DrumVoiceData| {
+            voice.events.iter().filter_map(|e| match e {
+                DrumEvent::Hit(hit) => Some(hit.name.clone()),
+                _ => None,
+            }).collect::<Vec<String>>()
+        };
+        // Hits not kept by `keep` become rests of the same length
+        let only = |voice: &DrumVoiceData, keep: &dyn Fn(&str) -> bool| {
+            let events = voice.events.iter().map(|e| match e {
+                DrumEvent::Hit(hit) if !keep(&hit.name) => DrumEvent::Rest { duration: hit.duration, length: hit.length },
+                other => other.clone(),
+            }).collect();
+            DrumVoiceData { events, ..voice.clone() }
+        };
+
+        let mut split = Vec::new();
+        let mut choked = Vec::new();
+        for voice in voices {
+            let names = hits(voice);
+            let open = names.iter().any(|n| n == "oh");
+            let closed = names.iter().any(|n| n == "hh" || n == "hhp");
+            if !(open && closed) {
+                split.push(voice.clone());
+                choked.push(false);
+            } else if names.iter().all(|n| is_hat(n)) {
+                split.push(voice.clone());
+                choked.push(true);
+            } else {
+                split.push(only(voice, &|name| !is_hat(name)));
+                choked.push(false);
+                split.push(only(voice, &is_hat));
+                choked.push(true);
+            }
+        }
+        (split, choked)
+    }
+
     pub fn generate_staff(staff: &Staff, tempo: &Tempo) -> String {
-        match &staff.content {
-            StaffContent::Notes(events) => {
-                Self::generate_pitched_staff_with_options(events, tempo, &staff.punchcard_color, &staff.gain, &staff.pan)
+        Self::generate_staff_with_options(staff, tempo, &GeneratorOptions::default())
+    }
+
+    /// Strudel code of one staff, pitched or drums, with its magic comments applied: the pattern
+    /// `generate_multi_with_options` writes after `$: `, for applications laying out the staves
+    /// themselves. The `.cpm()` call refers to the constants of [`Self::generate_prologue`].
+    pub fn generate_staff_with_options(staff: &Staff, tempo: &Tempo, options: &GeneratorOptions) -> String {
+        if let Some(error) = &staff.error {
+            return format!("// Not converted: {}", error.replace('\n', "\n// "));
+        }
+        let mut unrolled;
+        let staff = match staff.repeat_style.unwrap_or(options.repeat_style) {
+            RepeatStyle::Compressed => staff,
+            RepeatStyle::Unrolled => {
+                unrolled = staff.clone();
+                unrolled.unfold_repeats();
+                &unrolled
+            }
+        };
+        let code = match &staff.content {
+            StaffContent::Notes(events) => Self::generate_pitched_staff_with_options(
+                events,
+                tempo,
+                &(Self::format_voice_modifiers(&staff.punchcard_color, &staff.gain, &staff.pan, &staff.speed, "")
+                    + &Self::format_section_labels(staff, options)),
+                staff.octave_shift,
+                staff.sample.as_ref(),
+                options,
+            ),
+            StaffContent::Drums(_) if options.pad_short_voices || !options.drum_map.is_empty() => {
+                let mut adjusted = staff.clone();
+                if options.pad_short_voices {
+                    adjusted.pad_short_voices();
+                }
+                adjusted.map_drums(&options.drum_map);
+                let StaffContent::Drums(voices) = &adjusted.content else { unreachable!() };
+                Self::generate_drum_staff_with_options(voices, tempo, &Self::format_section_labels(staff, options), options)
+            }
+            StaffContent::Drums(voices) => {
+                Self::generate_drum_staff_with_options(voices, tempo, &Self::format_section_labels(staff, options), options)
             }
-            StaffContent::Drums(voices) => Self::generate_drum_staff(voices, tempo),
+        };
+        match options.max_line_width {
+            Some(width) => wrap_mini_notation(&code, width),
+            None => code,
         }
     }
 
@@ -1510,28 +5918,289 @@ impl StrudelGenerator {
 
     /// Generate Strudel code for multiple staves
     pub fn generate_multi(staves: &[Staff], tempo: &Tempo) -> String {
+        Self::generate_multi_with_options(staves, tempo, &GeneratorOptions::default())
+    }
+
+    /// Generate Strudel code for multiple staves using the given options
+    pub fn generate_multi_with_options(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> String {
+        let tracks = Self::generate_tracks(staves, tempo, options);
+        if options.emit_prologue && !staves.is_empty() {
+            format!("{}\n\n{}", Self::generate_prologue(staves, tempo, options), tracks)
+        } else {
+            tracks
+        }
+    }
+
+    fn generate_tracks(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> String {
         if staves.is_empty() {
             return String::from("// No staves to convert");
         }
 
         staves
             .iter()
-            .map(|staff| format!("$: {}", Self::generate_staff(staff, tempo)))
+            .enumerate()
+            .filter_map(|(i, staff)| match options.empty_staves {
+                // A commented block, not a track
+                _ if staff.error.is_some() => Some(Self::generate_staff_with_options(staff, tempo, options)),
+                EmptyStaves::Drop if Self::is_empty_staff(staff) => None,
+                EmptyStaves::Silence if Self::is_empty_staff(staff) => {
+                    Some(format!("$: silence // {}", Self::staff_label(staff, i)))
+                }
+                _ => Some(format!("$: {}", Self::generate_staff_with_options(staff, tempo, options))),
+            })
             .collect::<Vec<_>>()
             .join("\n\n")
     }
 
+    /// A staff without any note or drum hit (only rests, bar lines, comments)
+    fn is_empty_staff(staff: &Staff) -> bool {
+        match &staff.content {
+            StaffContent::Notes(events) => !events.iter().any(|e| matches!(e, PitchedEvent::Note(_))),
+            StaffContent::Drums(voices) => !voices
+                .iter()
+                .flat_map(|v| v.events.iter())
+                .any(|e| matches!(e, DrumEvent::Hit(_))),
+        }
+    }
+
+    /// Bar counts and cycle length of the code generated for `staves`, for tools synchronizing
+    /// visuals or other layers with it
+    pub fn cycle_info(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> CycleInfo {
+        let nbars = Self::cycle_measure(staves);
+        let bars: Vec<u32> = staves.iter().map(Self::count_staff_bars).collect();
+        let minutes = nbars.as_f64() * 4.0 / tempo.bpm as f64;
+        CycleInfo {
+            cpm_expression: Self::format_cpm_expression(bars.iter().copied().max().unwrap_or(0), options),
+            bars,
+            nbars,
+            cpm: if nbars.num == 0 || tempo.bpm == 0 { 0.0 } else { 1.0 / minutes },
+            seconds: if tempo.bpm == 0 { 0.0 } else { minutes * 60.0 },
+        }
+    }
+
+    /// Length of the longest staff in 4/4 bars, see [`Self::staff_measure`]
+    fn cycle_measure(staves: &[Staff]) -> Length {
+        staves.iter()
+            .map(Self::staff_measure)
+            .max_by(|a, b| a.as_f64().total_cmp(&b.as_f64()))
+            .unwrap_or(Length::new(0, 1))
+    }
+
+    /// The `const` declarations (tempo and bar count) referenced by the generated `.cpm()` calls
+    pub fn generate_prologue(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> String {
+        let nbars = Self::format_weight(Self::cycle_measure(staves), options).unwrap_or_else(|| "1".to_string());
+        format!("const {} = {};\nconst nbars = {};", options.tempo_name, tempo.bpm, nbars)
+    }
+
+    /// Comments of the events of a staff or drum voice with the (0-based) bar they introduce
+    fn voice_comment_bars<E: StaffEvent>(events: &[E]) -> Vec<(u32, String)> {
+        let mut comments = Vec::new();
+        let mut bars: u32 = 0;
+        let mut has_content = false;
+        let mut cadenza: Option<Length> = None;
+        let mut idx = 0;
+
+        while idx < events.len() {
+            match events[idx].view() {
+                EventView::Sound(sound) => {
+                    has_content = true;
+                    cadenza = cadenza.map(|q| q.plus(sound.quarters()));
+                }
+                EventView::Rest(quarters) => {
+                    has_content = true;
+                    cadenza = cadenza.map(|q| q.plus(quarters));
+                }
+                EventView::CadenzaOn => {
+                    if has_content {
+                        bars += 1;
+                        has_content = false;
+                    }
+                    cadenza = Some(Length::new(0, 1));
+                }
+                EventView::CadenzaOff => {
+                    if let Some(quarters) = cadenza.take() {
+                        bars = bars.saturating_add(Self::free_bar_count(quarters));
+                        has_content = false;
+                    }
+                }
+                EventView::BarLine => {
+                    if has_content {
+                        bars += 1;
+                        has_content = false;
+                    }
+                }
+                EventView::RepeatStart(_) => {
+                    if has_content {
+                        bars += 1;
+                        has_content = false;
+                    }
+                    let repeat = Repeat::read(events, idx);
+                    bars = bars.saturating_add(Self::count_repeat_bars(&repeat));
+                    idx = repeat.end;
+                    continue;
+                }
+                EventView::RepeatEnd | EventView::AlternativeStart | EventView::AlternativeEnd | EventView::Time(_) => {}
+                EventView::Comment(text) => comments.push((bars, text.to_string())),
+            }
+            idx += 1;
+        }
+
+        comments
+    }
+
+    /// Comments of a staff (first drum voice for drum staves) with the bar they introduce
+    fn staff_comment_bars(staff: &Staff) -> Vec<(u32, String)> {
+        match &staff.content {
+            StaffContent::Notes(events) => Self::voice_comment_bars(events),
+            StaffContent::Drums(voices) => voices
+                .first()
+                .map(|v| Self::voice_comment_bars(&v.events))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `.label()` modifier spreading the staff's section names over its bars, e.g.
+    /// `.label("Intro@2 Verse@4")`; empty unless `section_labels` is set and the staff has sections
+    fn format_section_labels(staff: &Staff, options: &GeneratorOptions) -> String {
+        if !options.section_labels {
+            return String::new();
+        }
+        let comments = Self::staff_comment_bars(staff);
+        let nbars = Self::count_staff_bars(staff);
+        let Some(&(first, _)) = comments.first() else {
+            return String::new();
+        };
+
+        // Bars before the first marker get a neutral name: a rest would silence them
+        let mut labels = Vec::new();
+        if first > 0 {
+            labels.push(format!("start@{}", first));
+        }
+        for (i, (start, name)) in comments.iter().enumerate() {
+            let end = comments.get(i + 1).map(|(next, _)| *next).unwrap_or(nbars);
+            if end > *start {
+                labels.push(format!("{}@{}", Self::label_word(name), end - start));
+            }
+        }
+        format!("\n.label(\"{}\")", labels.join(" "))
+    }
+
+    /// A section name as one mini-notation word inside a JS string: spaces become `_` and
+    /// anything but letters, digits, `_` and `-` is dropped (`Chorus "B"` is `Chorus_B`)
+    fn label_word(name: &str) -> String {
+        let word: String = name
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join("_")
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+            .collect();
+        if word.is_empty() { "section".to_string() } else { word }
+    }
+
+    /// Named sections `(name, first bar, last bar)` (1-based, inclusive) delimited by comments
+    fn sections(staves: &[Staff], nbars: u32) -> Vec<(String, u32, u32)> {
+        let Some(comments) = staves
+            .iter()
+            .map(Self::staff_comment_bars)
+            .find(|c| !c.is_empty())
+        else {
+            return Vec::new();
+        };
+
+        comments
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (start, name))| {
+                let end = comments.get(i + 1).map(|(next, _)| *next).unwrap_or(nbars);
+                (end > *start).then(|| (name.clone(), start + 1, end))
+            })
+            .collect()
+    }
+
+    fn generate_transport_controls(
+        staves: &[Staff],
+        tempo: &Tempo,
+        prologue: &str,
+        pattern: &str,
+        options: &GeneratorOptions,
+    ) -> String {
+        let nbars: u32 = staves.iter().map(Self::count_staff_bars).max().unwrap_or(0).max(1);
+        let mut section_options = vec![format!(r#"<option value="1-{nbars}">Whole piece</option>"#)];
+        for (name, start, end) in Self::sections(staves, nbars) {
+            section_options.push(format!(
+                r#"<option value="{start}-{end}">{} (bars {start}-{end})</option>"#,
+                escape_html(&name)
+            ));
+        }
+
+        format!(
+            r#"  <div class="transport">
+    <button id="transport-play">play</button>
+    <button id="transport-stop">stop</button>
+    <label>tempo <input id="transport-tempo" type="range" min="20" max="300" value="{bpm}"> <span id="transport-bpm">{bpm}</span></label>
+    <label>loop <select id="transport-section">
+      {section_options}
+    </select></label>
+  </div>
+  <script>
+    (function () {{
+      const source = {source};
+      const tempoName = {tempo_name};
+      const totalBars = {nbars};
+      const editorElement = document.querySelector('strudel-editor');
+      const tempoInput = document.getElementById('transport-tempo');
+      const sectionInput = document.getElementById('transport-section');
+      let playing = false;
+
+      function render() {{
+        const [from, to] = sectionInput.value.split('-').map(Number);
+        let code = source.replace(new RegExp('const ' + tempoName + ' = \\d+;'), 'const ' + tempoName + ' = ' + tempoInput.value + ';');
+        if (from > 1 || to < totalBars) {{
+          code = code
+            .replace(/const nbars = [\d.\/]+;/, 'const nbars = ' + (to - from + 1) + ';')
+            .split('\n  .cpm(').join('\n  .zoom(' + (from - 1) / totalBars + ', ' + to / totalBars + ')\n  .cpm(');
+        }}
+        editorElement.editor.setCode(code);
+        if (playing) {{
+          editorElement.editor.evaluate();
+        }}
+      }}
+
+      tempoInput.addEventListener('input', function () {{
+        document.getElementById('transport-bpm').textContent = tempoInput.value;
+        render();
+      }});
+      sectionInput.addEventListener('change', render);
+      document.getElementById('transport-play').addEventListener('click', function () {{
+        playing = true;
+        render();
+      }});
+      document.getElementById('transport-stop').addEventListener('click', function () {{
+        playing = false;
+        editorElement.editor.stop();
+      }});
+    }})();
+  </script>
+"#,
+            bpm = tempo.bpm,
+            section_options = section_options.join("\n      "),
+            source = js_string(&format!("{prologue}\n\n{pattern}")),
+            tempo_name = js_string(&options.tempo_name),
+        )
+    }
+
     fn count_staff_bars(staff: &Staff) -> u32 {
         match &staff.content {
             StaffContent::Notes(events) => {
                 let mut idx = 0;
-                Self::count_pitched_bars(events, &mut idx)
+                Self::count_bars(events, &mut idx)
             }
             StaffContent::Drums(voices) => {
                 voices.iter()
                     .map(|voice| {
                         let mut idx = 0;
-                        Self::count_drum_bars(&voice.events, &mut idx)
+                        Self::count_bars(&voice.events, &mut idx)
                     })
                     .max()
                     .unwrap_or(0)
@@ -1539,36 +6208,281 @@ impl StrudelGenerator {
         }
     }
 
+    /// Display name of a staff, used for panels and per-staff outputs
+    fn staff_label(staff: &Staff, index: usize) -> String {
+        if let Some(name) = staff.instrument.as_ref().or(staff.name.as_ref()) {
+            return name.clone();
+        }
+        match staff.kind {
+            StaffKind::Pitched => format!("Staff {}", index + 1),
+            StaffKind::Drums => format!("DrumStaff {}", index + 1),
+        }
+    }
+
+    /// Punchcard color of a staff, or of its first colored drum voice
+    fn staff_color<'a>(staff: &'a Staff, options: &'a GeneratorOptions) -> Option<&'a str> {
+        staff.punchcard_color.as_deref().or_else(|| {
+            let voices = staff.drum_voices()?;
+            voices.iter().find_map(|v| v.punchcard_color.as_deref()).or_else(|| {
+                // Stacked voices without colors take theirs from the palette
+                let palette = options.voice_palette.as_ref().filter(|_| voices.len() > 1)?;
+                palette.first().map(String::as_str)
+            })
+        })
+    }
+
+    fn generate_staff_panels(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> String {
+        let panels: Vec<String> = staves
+            .iter()
+            .enumerate()
+            .map(|(i, staff)| {
+                let swatch = match Self::staff_color(staff, options) {
+                    Some(color) => format!(
+                        r#"<span class="swatch" style="background: {}"></span>"#,
+                        escape_html(color)
+                    ),
+                    None => String::new(),
+                };
+                let code = format!("$: {}", Self::generate_staff_with_options(staff, tempo, options));
+                format!(
+                    r#"    <details class="staff-panel">
+      <summary>{swatch}{label}</summary>
+      <button class="copy-pattern">copy pattern</button>
+      <pre><code>{code}</code></pre>
+    </details>"#,
+                    label = escape_html(&Self::staff_label(staff, i)),
+                    code = escape_html(&code),
+                )
+            })
+            .collect();
+
+        format!(
+            r#"  <section class="staff-panels">
+{}
+  </section>
+  <script>
+    document.querySelectorAll('.copy-pattern').forEach(function (button) {{
+      button.addEventListener('click', function () {{
+        navigator.clipboard.writeText(button.nextElementSibling.textContent);
+      }});
+    }});
+  </script>
+"#,
+            panels.join("\n")
+        )
+    }
+
+    /// List of the bars with their start time (following meter changes and rubato), the
+    /// sections they start and, as a tooltip, their code
+    fn generate_bar_minimap(staves: &[Staff], tempo: &Tempo, options: &GeneratorOptions) -> String {
+        let Some(longest) = staves.iter().max_by_key(|s| Self::count_staff_bars(s)) else {
+            return String::new();
+        };
+        let changes = longest.meter_changes();
+        let comments: Vec<(usize, String)> = staves
+            .iter()
+            .flat_map(|staff| staff.comment_bars())
+            .filter(|(_, text)| text != LOOP_START && text != LOOP_END)
+            .collect();
+        let factor = |bar: usize| {
+            let rubato = options.rubato.iter().rev().find(|r| (r.first_bar..=r.last_bar).contains(&(bar as u32 + 1)));
+            let factor = rubato.map_or("1", |r| r.factor.as_str());
+            match factor.split_once('/') {
+                Some((num, den)) => num.trim().parse::<f64>().unwrap_or(1.0) / den.trim().parse::<f64>().unwrap_or(1.0),
+                None => factor.trim().parse().unwrap_or(1.0),
+            }
+        };
+        // One bar of every staff, without wrapping, for the tooltip
+        let bar_options = GeneratorOptions { max_line_width: None, ..options.clone() };
+        let slices: Vec<Vec<Staff>> = staves.iter().map(|staff| staff.bar_slices(changes.len())).collect();
+        let bar_code = |bar: usize| {
+            let mut lines = Vec::new();
+            for staff in slices.iter().map(|slices| &slices[bar]) {
+                let code = Self::generate_staff_with_options(staff, tempo, &bar_options);
+                lines.extend(code.split('`').skip(1).step_by(2).map(|pattern| pattern.trim().to_string()));
+            }
+            lines.join("\n")
+        };
+
+        let mut time = TimeSignature::default();
+        let mut seconds: f64 = 0.0;
+        let mut items = Vec::new();
+        for (bar, change) in changes.iter().enumerate() {
+            if let Some(change) = change {
+                time = *change;
+            }
+            let sections: Vec<&str> = comments.iter().filter(|(b, _)| *b == bar).map(|(_, text)| text.as_str()).collect();
+            let sections = if sections.is_empty() { String::new() } else { format!(" {}", escape_html(&sections.join(", "))) };
+            let start = seconds.round() as u64;
+            items.push(format!(
+                r#"      <li id="bar-{n}" title="{code}"><span class="bar-number">{n}</span> <span class="bar-time">{m}:{s:02}</span>{sections}</li>"#,
+                n = bar + 1,
+                code = escape_html(&bar_code(bar)),
+                m = start / 60,
+                s = start % 60,
+            ));
+            if tempo.bpm > 0 {
+                seconds += time.bar_weight().as_f64() * 4.0 * 60.0 / (tempo.bpm as f64 * factor(bar));
+            }
+        }
+
+        format!(
+            "  <details class=\"bar-minimap\">\n    <summary>Bars ({})</summary>\n    <ol>\n{}\n    </ol>\n  </details>\n",
+            items.len(),
+            items.join("\n")
+        )
+    }
+
     pub fn generate_html(staves: &[Staff], tempo: &Tempo, title: &str) -> String {
-        let pattern = Self::generate_multi(staves, tempo);
-        let tempo_const = format!("const tempo = {};", tempo.bpm);
-        let nbars: u32 = staves.iter()
-            .map(|s| Self::count_staff_bars(s))
-            .max()
-            .unwrap_or(0);
+        Self::generate_html_with_options(staves, tempo, title, &GeneratorOptions::default())
+    }
+
+    pub fn generate_html_with_options(
+        staves: &[Staff],
+        tempo: &Tempo,
+        title: &str,
+        options: &GeneratorOptions,
+    ) -> String {
+        let pattern = Self::generate_tracks(staves, tempo, options);
+        let prologue = Self::generate_prologue(staves, tempo, options);
+        let (panel_style, panels) = if options.staff_panels {
+            (
+                r#"
+    .staff-panels { padding: 0.5em 1em; font-family: sans-serif; }
+    .staff-panel summary { cursor: pointer; font-weight: bold; }
+    .staff-panel .swatch { display: inline-block; width: 0.8em; height: 0.8em; margin-right: 0.4em; border-radius: 2px; }"#,
+                Self::generate_staff_panels(staves, tempo, options),
+            )
+        } else {
+            ("", String::new())
+        };
+        let theme_style = match options.theme {
+            Some(Theme::Light) => r#"
+    html { color-scheme: light; }
+    body { background: #ffffff; color: #222222; }
+    pre { background: #f4f4f4; }"#,
+            Some(Theme::Dark) => r#"
+    html { color-scheme: dark; }
+    body { background: #1e1e1e; color: #dddddd; }
+    pre { background: #2a2a2a; }"#,
+            None => "",
+        };
+        let custom_style = match &options.css {
+            Some(css) => format!("\n{}", css.trim_end()),
+            None => String::new(),
+        };
+        let mut meta = vec![format!(r#"  <meta property="og:title" content="{}">"#, escape_html(title))];
+        if let Some(author) = &options.author {
+            meta.push(format!(r#"  <meta name="author" content="{}">"#, escape_html(author)));
+        }
+        if let Some(description) = &options.description {
+            meta.push(format!(r#"  <meta name="description" content="{}">"#, escape_html(description)));
+            meta.push(format!(r#"  <meta property="og:description" content="{}">"#, escape_html(description)));
+        }
+        let meta = meta.join("\n");
+        let heading = if options.show_heading {
+            let mut lines = vec![format!("    <h1>{}</h1>", escape_html(title))];
+            if let Some(author) = &options.author {
+                lines.push(format!(r#"    <p class="author">{}</p>"#, escape_html(author)));
+            }
+            if let Some(description) = &options.description {
+                lines.push(format!(r#"    <p class="description">{}</p>"#, escape_html(description)));
+            }
+            format!("  <header class=\"page-heading\">\n{}\n  </header>\n", lines.join("\n"))
+        } else {
+            String::new()
+        };
+        let heading_style = if options.show_heading {
+            r#"
+    .page-heading { padding: 0.5em 1em; font-family: sans-serif; }
+    .page-heading h1 { margin: 0; }
+    .page-heading p { margin: 0.2em 0; }"#
+        } else {
+            ""
+        };
+        let (notes, notes_style) = if options.warnings.is_empty() {
+            (String::new(), "")
+        } else {
+            let items: Vec<String> = options
+                .warnings
+                .iter()
+                .map(|w| match w.bar {
+                    Some(bar) => format!("      <li>bar {}: {}</li>", bar, escape_html(&w.message)),
+                    None => format!("      <li>{}</li>", escape_html(&w.message)),
+                })
+                .collect();
+            (
+                format!(
+                    "  <details class=\"conversion-notes\">\n    <summary>Conversion notes ({})</summary>\n    <ul>\n{}\n    </ul>\n  </details>\n",
+                    items.len(),
+                    items.join("\n")
+                ),
+                r#"
+    .conversion-notes { padding: 0.5em 1em; font-family: sans-serif; }
+    .conversion-notes summary { cursor: pointer; font-weight: bold; }"#,
+            )
+        };
+        let (minimap, minimap_style) = if options.bar_minimap {
+            (
+                Self::generate_bar_minimap(staves, tempo, options),
+                r#"
+    .bar-minimap { padding: 0.5em 1em; font-family: sans-serif; }
+    .bar-minimap summary { cursor: pointer; font-weight: bold; }
+    .bar-minimap ol { list-style: none; padding-left: 0; }
+    .bar-minimap .bar-number { display: inline-block; min-width: 3em; font-weight: bold; }
+    .bar-minimap .bar-time { display: inline-block; min-width: 4em; }"#,
+            )
+        } else {
+            (String::new(), "")
+        };
+        let (source, source_style) = match &options.lilypond_source {
+            Some(code) => (
+                format!(
+                    "  <details class=\"lilypond-source\">\n    <summary>LilyPond source</summary>\n    <pre>{}</pre>\n  </details>\n",
+                    escape_html(code.trim_end())
+                ),
+                r#"
+    .lilypond-source { padding: 0.5em 1em; font-family: sans-serif; }
+    .lilypond-source summary { cursor: pointer; font-weight: bold; }"#,
+            ),
+            None => (String::new(), ""),
+        };
+        let title = escape_html(title);
+        // The transport controls need the scriptable editor rather than the embedded iframe
+        let (player_script, player_tag, controls, transport_style) = if options.transport_controls {
+            (
+                "https://unpkg.com/@strudel/repl@latest",
+                "strudel-editor",
+                Self::generate_transport_controls(staves, tempo, &prologue, &pattern, options),
+                r#"
+    .transport { padding: 0.5em 1em; font-family: sans-serif; display: flex; gap: 1em; align-items: center; }"#,
+            )
+        } else {
+            ("https://unpkg.com/@strudel/embed@latest", "strudel-repl", String::new(), "")
+        };
         format!(
             r#"<!DOCTYPE html>
 <html>
 <head>
   <meta charset="UTF-8">
   <title>{title}</title>
-  <script src="https://unpkg.com/@strudel/embed@latest"></script>
+{meta}
+  <script src="{player_script}"></script>
   <style>
     html, body {{ margin: 0; padding: 0; width: 100%; height: 100%; }}
-    strudel-repl {{ width: 100%; height: 100%; display: block; }}
-    strudel-repl iframe {{ width: 100%; height: 100%; border: none; }}
+    {player_tag} {{ width: 100%; height: 100%; display: block; }}
+    {player_tag} iframe {{ width: 100%; height: 100%; border: none; }}{heading_style}{transport_style}{notes_style}{panel_style}{minimap_style}{source_style}{theme_style}{custom_style}
   </style>
 </head>
 <body>
-  <strudel-repl>
+{heading}{notes}{controls}  <{player_tag}>
 <!--
-{tempo_const}
-const nbars = {nbars};
+{prologue}
 
 {pattern}
 -->
-  </strudel-repl>
-</body>
+  </{player_tag}>
+{panels}{minimap}{source}</body>
 </html>"#
         )
     }
@@ -1,29 +1,373 @@
-use std::env;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
-use strudel_of_lilypond::{expand_includes, LilyPondParser, StrudelGenerator, PitchedEvent, DrumEvent};
+use argh::FromArgs;
+use strudel_of_lilypond::diff::diff_scores;
+use strudel_of_lilypond::{
+    expand_includes_with_map, EmptyStaves, GeneratorOptions, GraceTiming, LilyPondParser, ParseResult, ParserOptions, StaffKind, Stamp, StrudelGenerator,
+    QuoteStyle, RepeatStyle, StampCache, Theme, WeightStyle, DEFAULT_VOICE_PALETTE,
+};
+
+/// Convert a LilyPond file to an HTML page with an embedded Strudel REPL
+#[derive(FromArgs)]
+struct Args {
+    /// emit MIDI note numbers instead of note names
+    #[argh(switch)]
+    midi: bool,
+
+    /// write weights as fractions (@1/2) instead of decimals (@0.5)
+    #[argh(switch)]
+    fraction_weights: bool,
+
+    /// add a collapsible code panel per staff below the player
+    #[argh(switch)]
+    panels: bool,
+
+    /// add play/stop, tempo slider and loop-section controls to the page
+    #[argh(switch)]
+    transport: bool,
+
+    /// play the hi-hats of voices mixing open and closed hats on their own, choking each other
+    #[argh(switch)]
+    choke_hats: bool,
+
+    /// list the bars below the player, with their start time, sections and code
+    #[argh(switch)]
+    minimap: bool,
+
+    /// show the LilyPond source (includes expanded) in a collapsible panel below the player
+    #[argh(switch)]
+    embed_source: bool,
+
+    /// break mini-notation lines longer than this many characters inside their groups
+    #[argh(option)]
+    max_width: Option<usize>,
+
+    /// add this to the octave of note names, for sample sets where middle C is not c4 (e.g. -1)
+    #[argh(option, default = "0")]
+    octave_offset: i32,
+
+    /// transpose pitched staves by this many semitones (e.g. 2 or -3)
+    #[argh(option, default = "0")]
+    transpose: i32,
+
+    /// convert only this variable (e.g. melodyA) or section (comment marker, rehearsal mark)
+    #[argh(option)]
+    entry: Option<String>,
+
+    /// first bar to convert, counted from 1 after unfolding repeats
+    #[argh(option, default = "1")]
+    from_bar: usize,
+
+    /// convert at most this many bars (from --from-bar on)
+    #[argh(option)]
+    max_bars: Option<usize>,
+
+    /// add a metronome staff clicking the beats of the \time signature (accented first beat)
+    #[argh(switch)]
+    click: bool,
+
+    /// write repeats out in full instead of as !N (like \unfoldRepeats)
+    #[argh(switch)]
+    unfold_repeats: bool,
+
+    /// print per-staff statistics (notes, bars, range, durations) and the playing time
+    #[argh(switch)]
+    stats: bool,
+
+    /// also write the Strudel source next to the HTML file (<output stem>.str)
+    #[argh(switch)]
+    also_str: bool,
+
+    /// color stacked drum voices that have no punchcard color with a default palette
+    #[argh(switch)]
+    auto_colors: bool,
+
+    /// how repeats are written: compressed (default, `!N`) or unrolled (every bar written out)
+    #[argh(option, default = "RepeatStyle::Compressed")]
+    repeats: RepeatStyle,
+
+    /// how patterns are quoted: backtick (default, one bar per line) or double (single-line
+    /// "..." strings)
+    #[argh(option, default = "QuoteStyle::Backtick")]
+    quotes: QuoteStyle,
+
+    /// name the chords of every bar in a comment after it (`// Cmaj7`)
+    #[argh(switch)]
+    chord_names: bool,
+
+    /// octave of notes without `'` or `,` marks (default 3: `c'` is middle C)
+    #[argh(option, default = "3")]
+    default_octave: i32,
+
+    /// duration of the first note of a staff written without one (default 4: a quarter)
+    #[argh(option, default = "4")]
+    default_duration: u32,
+
+    /// convert the staves that parse when others do not, the failing ones left as comments
+    #[argh(switch)]
+    lenient: bool,
+
+    /// also write one page per staff, named after its instrument (`song-violin.html`), e.g. to
+    /// hand parts to band members
+    #[argh(switch)]
+    split: bool,
+
+    /// output for staves without notes: comment (default), drop or silence
+    #[argh(option, default = "EmptyStaves::Comment")]
+    empty_staves: EmptyStaves,
+
+    /// do not write anything, report whether the outputs are up to date with their sources
+    /// (exit status 1 if any is stale)
+    #[argh(switch)]
+    check_stale: bool,
+
+    /// leave outputs that are up to date with their sources untouched (see --check-stale), for
+    /// quick regeneration of many files; their stamps are cached in .strudel-of-lilypond-cache
+    #[argh(switch)]
+    incremental: bool,
+
+    /// label every bar with its section name (comment markers, double bars)
+    #[argh(switch)]
+    labels: bool,
+
+    /// pad shorter drum voices with rest bars so stacked voices stay aligned
+    #[argh(switch)]
+    pad_voices: bool,
+
+    /// take the time of grace notes from the note before them instead of the ornamented note
+    #[argh(switch)]
+    grace_before: bool,
+
+    /// comma-separated punchcard colors for stacked drum voices (implies --auto-colors)
+    #[argh(option)]
+    palette: Option<String>,
+
+    /// drum machine sample bank for drum staves (e.g. RolandTR909)
+    #[argh(option)]
+    bank: Option<String>,
+
+    /// play a drum with another sound, e.g. `--map sd=cp` or `--map sn=cp,hh=rim` (LilyPond or
+    /// Strudel names); may be repeated
+    #[argh(option)]
+    map: Vec<String>,
+
+    /// page color scheme: light or dark
+    #[argh(option)]
+    theme: Option<Theme>,
+
+    /// CSS file appended to the page styles
+    #[argh(option)]
+    css: Option<String>,
+
+    /// page title (defaults to the \header title, then the input file stem)
+    #[argh(option)]
+    title: Option<String>,
+
+    /// page author (defaults to the \header composer)
+    #[argh(option)]
+    author: Option<String>,
+
+    /// page description (defaults to the \header subtitle)
+    #[argh(option)]
+    description: Option<String>,
+
+    /// name of the tempo constant used by .cpm() (default: tempo)
+    #[argh(option, default = "String::from(\"tempo\")")]
+    tempo_name: String,
+
+    /// input LilyPond file, then the output HTML file (defaults to <input stem>.html); or several
+    /// LilyPond files, each written next to itself; `./diff` for a file named like a command
+    #[argh(positional)]
+    files: Vec<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Diff(DiffCommand),
+}
+
+/// Compare two LilyPond files note by note, bar by bar and staff by staff (exit status 1 if
+/// they differ)
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff")]
+struct DiffCommand {
+    /// original LilyPond file
+    #[argh(positional)]
+    old: String,
+
+    /// modified LilyPond file
+    #[argh(positional)]
+    new: String,
+}
+
+/// Parse a file and its includes for `diff`, exiting with status 2 (as diff(1)) on errors
+fn parse_file(path: &str) -> ParseResult {
+    let raw_input = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Error reading {path}: {e}");
+            std::process::exit(2);
+        }
+    };
+
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let (input, source_map) = match expand_includes_with_map(&raw_input, base_dir, Path::new(path)) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            eprintln!("Error expanding includes in {path}: {e}");
+            std::process::exit(2);
+        }
+    };
+
+    match LilyPondParser::new().parse(&input) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Parse error in {path}: {}", source_map.locate_error(&e));
+            std::process::exit(2);
+        }
+    }
+}
+
+fn diff_command(command: &DiffCommand) {
+    let old = parse_file(&command.old);
+    let new = parse_file(&command.new);
+
+    // Like diff(1): exit status 1 when the scores differ
+    let differences = diff_scores(&old, &new);
+    for difference in &differences {
+        println!("{difference}");
+    }
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Args = argh::from_env();
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <input.ly> [output.html]", args[0]);
+    let conversions = match (&args.command, args.files.as_slice()) {
+        (Some(Command::Diff(command)), []) => return diff_command(command),
+        (Some(_), files) => {
+            eprintln!("Unexpected arguments before the command: {}", files.join(" "));
+            std::process::exit(1);
+        }
+        (None, files) => match conversions(files) {
+            Ok(conversions) => conversions,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let css = match &args.css {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(e) => {
+                eprintln!("Error reading {path}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut drum_map = BTreeMap::new();
+    for pair in args.map.iter().flat_map(|map| map.split(',')) {
+        match pair.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                drum_map.insert(from.trim().to_string(), to.trim().to_string());
+            }
+            _ => {
+                eprintln!("Error: --map expects drum=sound pairs (e.g. sd=cp), found `{pair}`");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let options = GeneratorOptions {
+        midi_numbers: args.midi,
+        octave_offset: args.octave_offset,
+        max_line_width: args.max_width,
+        weight_style: if args.fraction_weights {
+            WeightStyle::Fraction
+        } else {
+            WeightStyle::Decimal
+        },
+        tempo_name: args.tempo_name.clone(),
+        staff_panels: args.panels,
+        transport_controls: args.transport,
+        theme: args.theme,
+        css,
+        section_labels: args.labels,
+        empty_staves: args.empty_staves,
+        repeat_style: args.repeats,
+        quote_style: args.quotes,
+        chord_names: args.chord_names,
+        drum_bank: args.bank.clone(),
+        drum_map,
+        pad_short_voices: args.pad_voices,
+        choke_hats: args.choke_hats,
+        bar_minimap: args.minimap,
+        grace_timing: if args.grace_before { GraceTiming::Previous } else { GraceTiming::Ornamented },
+        voice_palette: match &args.palette {
+            Some(list) => Some(list.split(',').map(|c| c.trim().to_string()).collect()),
+            None if args.auto_colors => Some(DEFAULT_VOICE_PALETTE.iter().map(|c| c.to_string()).collect()),
+            None => None,
+        },
+        ..Default::default()
+    };
+
+    let cache_path = Path::new(StampCache::FILE_NAME);
+    let mut cache = if args.incremental { StampCache::load(cache_path) } else { StampCache::default() };
+    let mut stale = false;
+    for (input_path, output_path) in &conversions {
+        stale |= convert(&args, input_path, output_path, &options, &mut cache);
+    }
+
+    if args.incremental
+        && !args.check_stale
+        && let Err(e) = cache.save(cache_path)
+    {
+        eprintln!("Error: {e}");
         std::process::exit(1);
     }
+    if stale {
+        std::process::exit(1);
+    }
+}
+
+/// The inputs and outputs named on the command line: one input and an optional output, or
+/// several inputs, each written next to itself
+fn conversions(files: &[String]) -> Result<Vec<(String, String)>, String> {
+    let next_to = |input: &String| Path::new(input).with_extension("html").display().to_string();
+    match files {
+        [] => Err("Missing the input LilyPond file (see --help)".to_string()),
+        [input] => {
+            let stem = Path::new(input).file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+            Ok(vec![(input.clone(), format!("{stem}.html"))])
+        }
+        inputs if inputs.iter().all(|file| file.ends_with(".ly")) => {
+            Ok(inputs.iter().map(|input| (input.clone(), next_to(input))).collect())
+        }
+        [input, output] => Ok(vec![(input.clone(), output.clone())]),
+        [_, _, extra @ ..] => Err(format!("Unexpected arguments: {}", extra.join(" "))),
+    }
+}
 
-    let input_path = &args[1];
+/// Convert one LilyPond file, exiting on errors; with --check-stale, report whether its
+/// outputs are up to date and return whether any is stale
+fn convert(args: &Args, input_path: &str, output_path: &str, options: &GeneratorOptions, cache: &mut StampCache) -> bool {
     let stem = Path::new(input_path)
         .file_stem()
         .and_then(|s| s.to_str())
         .unwrap_or("output");
 
-    let output_path = if args.len() > 2 {
-        args[2].clone()
-    } else {
-        format!("{stem}.html")
-    };
-
     let raw_input = match fs::read_to_string(input_path) {
         Ok(content) => content,
         Err(e) => {
@@ -36,7 +380,7 @@ fn main() {
         .parent()
         .unwrap_or(Path::new("."));
 
-    let input = match expand_includes(&raw_input, base_dir) {
+    let (input, source_map) = match expand_includes_with_map(&raw_input, base_dir, Path::new(input_path)) {
         Ok(expanded) => expanded,
         Err(e) => {
             eprintln!("Error expanding includes: {e}");
@@ -44,39 +388,333 @@ fn main() {
         }
     };
 
-    let parser = LilyPondParser::new();
-
-    match parser.parse(&input) {
-        Ok(result) => {
-            let pitched_count: usize = result.staves.iter()
-                .filter_map(|s| s.events())
-                .flat_map(|events| events.iter())
-                .filter(|e| matches!(e, PitchedEvent::Note(_)))
-                .count();
-            let drum_count: usize = result.staves.iter()
-                .filter_map(|s| s.drum_voices())
-                .flat_map(|voices| voices.iter())
-                .flat_map(|voice| voice.events.iter())
-                .filter(|e| matches!(e, DrumEvent::Hit(_)))
-                .count();
-            eprintln!(
-                "Parsed {} staves ({} notes, {} drum hits)",
-                result.staves.len(), pitched_count, drum_count
-            );
-            eprintln!("Tempo: {} = {} BPM", result.tempo.beat_unit, result.tempo.bpm);
-            let html: String = StrudelGenerator::generate_html(&result.staves, &result.tempo, stem);
-
-            match fs::write(&output_path, &html) {
-                Ok(_) => println!("{output_path}"),
-                Err(e) => {
-                    eprintln!("Error writing {output_path}: {e}");
-                    std::process::exit(1);
+    // The stamp of the outputs: the sources (includes expanded) and the options
+    let mut sources = vec![input.as_str()];
+    sources.extend(options.css.as_deref());
+    let stamp = Stamp::new(&sources, &stamp_options(args));
+    let mut stale = false;
+    let str_path = |html: &str| Path::new(html).with_extension("str").display().to_string();
+    let up_to_date = |html: &str| {
+        stale_reason(html, &stamp).is_none() && (!args.also_str || stale_reason(&str_path(html), &stamp).is_none())
+    };
+    // An unchanged score is not even parsed: the cache knows the names of its split and book
+    // pages, and a single page carries its stamp
+    if args.incremental && !args.check_stale {
+        if cache.is_fresh(output_path, &stamp) {
+            eprintln!("unchanged: {output_path}");
+            return false;
+        }
+        if !args.split && up_to_date(output_path) {
+            eprintln!("unchanged: {output_path}");
+            let mut files = vec![output_path.to_string()];
+            files.extend(args.also_str.then(|| str_path(output_path)));
+            cache.record(output_path, &stamp, files);
+            return false;
+        }
+    }
+
+    let parser = LilyPondParser::with_options(ParserOptions {
+        default_octave: args.default_octave,
+        default_duration: args.default_duration,
+    });
+    let parser = if args.lenient { parser.lenient() } else { parser };
+
+    let parsed = match &args.entry {
+        Some(entry) => parser.parse_entry(&input, entry).map(|result| vec![result]),
+        None => parser.parse_book(&input),
+    };
+    let results = match parsed {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Parse error: {}", source_map.locate_error(&e));
+            std::process::exit(1);
+        }
+    };
+
+    // One page per score; books name them after their bookpart (or score) titles
+    let output_stem = Path::new(output_path).with_extension("");
+    let mut used_names: Vec<String> = Vec::new();
+    let output_paths: Vec<String> = results
+        .iter()
+        .enumerate()
+        .map(|(index, result)| {
+            if results.len() == 1 {
+                return output_path.to_string();
+            }
+            let mut name = result.header.get("title").map(|t| slug(t)).filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "score".to_string());
+            if used_names.contains(&name) {
+                name = format!("{name}-{}", index + 1);
+            }
+            used_names.push(name.clone());
+            format!("{}-{name}.html", output_stem.display())
+        })
+        .collect();
+
+    let mut options = options.clone();
+    let mut written = Vec::new();
+    for (mut result, output_path) in results.into_iter().zip(output_paths) {
+        if args.unfold_repeats {
+            result.unfold_repeats();
+        }
+        if args.transpose != 0 {
+            result.transpose(args.transpose);
+        }
+        if args.from_bar != 1 || args.max_bars.is_some() {
+            let bars = result.summary().staves.iter().map(|s| s.bars as usize).max().unwrap_or(0);
+            if args.from_bar == 0 || args.from_bar > bars {
+                eprintln!("Error: --from-bar {} is outside the score (bars 1 to {bars})", args.from_bar);
+                std::process::exit(1);
+            }
+            let start = args.from_bar - 1;
+            result.slice_bars(start, args.max_bars.map_or(usize::MAX, |count| start.saturating_add(count)));
+        }
+
+        let summary = result.summary();
+        let pitched_count: usize = summary.staves.iter()
+            .filter(|s| matches!(s.kind, StaffKind::Pitched))
+            .map(|s| s.notes)
+            .sum();
+        let drum_count: usize = summary.staves.iter()
+            .filter(|s| matches!(s.kind, StaffKind::Drums))
+            .map(|s| s.notes)
+            .sum();
+        eprintln!(
+            "Parsed {} staves ({} notes, {} drum hits)",
+            result.staves.len(), pitched_count, drum_count
+        );
+        eprintln!("Tempo: {} = {} BPM", result.tempo.beat_unit, result.tempo.bpm);
+        if args.stats {
+            eprintln!("{summary}");
+        }
+        for warning in &result.warnings {
+            eprintln!("{warning}");
+        }
+        if args.click {
+            let click = result.click_staff();
+            result.staves.push(click);
+        }
+
+        let header_title = result.header.get("title");
+        options.show_heading = args.title.is_some()
+            || args.author.is_some()
+            || args.description.is_some()
+            || header_title.is_some();
+        let title = args.title.as_ref().or(header_title).map(String::as_str).unwrap_or(stem);
+        options.rubato = result.rubato.clone();
+        options.warnings = result.warnings.clone();
+        options.author = args.author.clone().or_else(|| result.header.get("composer").cloned());
+        options.description = args.description.clone().or_else(|| result.header.get("subtitle").cloned());
+        if args.embed_source {
+            options.lilypond_source = Some(input.clone());
+        }
+
+        // The combined page, then one page per staff (the click left out)
+        let mut pages = vec![(output_path.clone(), result.staves.clone(), title.to_string())];
+        if args.split {
+            let labels: Vec<&str> = summary.staves.iter().map(|s| s.label.as_str()).collect();
+            let paths = staff_paths(&output_path, &labels);
+            for ((path, staff), label) in paths.into_iter().zip(&result.staves).zip(&labels) {
+                pages.push((path, vec![staff.clone()], format!("{title} ({label})")));
+            }
+        }
+
+        if args.check_stale {
+            for (path, _, _) in &pages {
+                stale |= !check_stale(path, &stamp);
+                if args.also_str {
+                    stale |= !check_stale(&str_path(path), &stamp);
                 }
             }
+            continue;
+        }
+        for (path, _, _) in &pages {
+            written.push(path.clone());
+            written.extend(args.also_str.then(|| str_path(path)));
+        }
+        if args.incremental && pages.iter().all(|(path, _, _)| up_to_date(path)) {
+            eprintln!("unchanged: {output_path}");
+            continue;
+        }
+
+        for (path, staves, title) in &pages {
+            let html = StrudelGenerator::generate_html_with_options(staves, &result.tempo, title, &options);
+            write_output(path, &stamp.apply(&html, "html"));
+
+            if args.also_str {
+                let source = StrudelGenerator::generate_multi_with_options(
+                    staves,
+                    &result.tempo,
+                    &GeneratorOptions { emit_prologue: true, ..options.clone() },
+                );
+                write_output(&str_path(path), &stamp.apply(&format!("{source}\n"), "str"));
+            }
         }
+    }
+
+    if !args.check_stale {
+        cache.record(output_path, &stamp, written);
+    }
+    stale
+}
+
+/// Write a generated file and print its path, exiting on failure
+fn write_output(path: &str, content: &str) {
+    match fs::write(path, content) {
+        Ok(_) => println!("{path}"),
         Err(e) => {
-            eprintln!("Parse error: {e}");
+            eprintln!("Error writing {path}: {e}");
             std::process::exit(1);
         }
     }
 }
+
+/// Pages of the staves next to `output_path`, named after their labels: "Violin I" in
+/// `song.html` -> `song-violin-i.html`
+fn staff_paths(output_path: &str, labels: &[&str]) -> Vec<String> {
+    let output_stem = Path::new(output_path).with_extension("");
+    let mut used_names: Vec<String> = Vec::new();
+    labels
+        .iter()
+        .enumerate()
+        .map(|(index, label)| {
+            let mut name = slug(label);
+            // Unnamed and repeated labels fall back to the staff number, itself maybe a label
+            let mut number = index + 1;
+            while name.is_empty() || used_names.contains(&name) {
+                name = format!("staff-{}", number);
+                number += 1;
+            }
+            used_names.push(name.clone());
+            format!("{}-{name}.html", output_stem.display())
+        })
+        .collect()
+}
+
+/// Options of the stamp: the parsed options changing the outputs, in a fixed order and one
+/// spelling (`--transpose 2`), however they were written on the command line
+fn stamp_options(args: &Args) -> String {
+    let switches = [
+        ("midi", args.midi),
+        ("fraction-weights", args.fraction_weights),
+        ("panels", args.panels),
+        ("transport", args.transport),
+        ("choke-hats", args.choke_hats),
+        ("minimap", args.minimap),
+        ("embed-source", args.embed_source),
+        ("click", args.click),
+        ("unfold-repeats", args.unfold_repeats),
+        ("also-str", args.also_str),
+        ("auto-colors", args.auto_colors),
+        ("chord-names", args.chord_names),
+        ("lenient", args.lenient),
+        ("split", args.split),
+        ("labels", args.labels),
+        ("pad-voices", args.pad_voices),
+        ("grace-before", args.grace_before),
+    ];
+    let not_default = |value: String, default: &str| (value != default).then_some(value);
+    let values = [
+        ("max-width", args.max_width.map(|width| width.to_string())),
+        ("octave-offset", not_default(args.octave_offset.to_string(), "0")),
+        ("transpose", not_default(args.transpose.to_string(), "0")),
+        ("entry", args.entry.clone()),
+        ("from-bar", not_default(args.from_bar.to_string(), "1")),
+        ("max-bars", args.max_bars.map(|bars| bars.to_string())),
+        ("repeats", not_default(format!("{:?}", args.repeats).to_lowercase(), "compressed")),
+        ("quotes", not_default(format!("{:?}", args.quotes).to_lowercase(), "backtick")),
+        ("default-octave", not_default(args.default_octave.to_string(), "3")),
+        ("default-duration", not_default(args.default_duration.to_string(), "4")),
+        ("empty-staves", not_default(format!("{:?}", args.empty_staves).to_lowercase(), "comment")),
+        ("palette", args.palette.clone()),
+        ("bank", args.bank.clone()),
+        ("map", (!args.map.is_empty()).then(|| args.map.join(","))),
+        ("theme", args.theme.map(|theme| format!("{theme:?}").to_lowercase())),
+        ("css", args.css.clone()),
+        ("title", args.title.clone()),
+        ("author", args.author.clone()),
+        ("description", args.description.clone()),
+        ("tempo-name", not_default(args.tempo_name.clone(), "tempo")),
+    ];
+    switches
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| format!("--{name}"))
+        .chain(values.into_iter().filter_map(|(name, value)| Some(format!("--{name} {}", value?))))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Why a generated file is not up to date with the sources of `stamp`, `None` if it is
+fn stale_reason(path: &str, stamp: &Stamp) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => match Stamp::read(&content) {
+            Some(found) => found.staleness(stamp),
+            None => Some("no stamp".to_string()),
+        },
+        Err(_) => Some("missing".to_string()),
+    }
+}
+
+/// Print whether a generated file is up to date with the sources of `stamp`
+fn check_stale(path: &str, stamp: &Stamp) -> bool {
+    let reason = stale_reason(path, stamp);
+    match &reason {
+        Some(reason) => println!("stale: {path} ({reason})"),
+        None => println!("up to date: {path}"),
+    }
+    reason.is_none()
+}
+
+/// Lowercase file-name fragment of a title: "Part II: Allegro" -> "part-ii-allegro"
+fn slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_options() {
+        let parse = |args: &[&str]| Args::from_args(&["strudel-of-lilypond"], args).unwrap();
+        let options = stamp_options(&parse(&["--transpose", "2", "--midi", "--title", "song.ly", "song.ly", "out.html"]));
+        assert_eq!(options, "--midi --transpose 2 --title song.ly");
+        // Order, defaults written out and the file names make no difference
+        assert_eq!(stamp_options(&parse(&["--title", "song.ly", "--midi", "--from-bar", "1", "other.ly", "--transpose", "2"])), options);
+        assert_eq!(stamp_options(&parse(&["--incremental", "--check-stale", "song.ly"])), "");
+    }
+
+    #[test]
+    fn test_conversions() {
+        let files = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect::<Vec<_>>();
+        assert_eq!(conversions(&files(&["songs/a.ly"])), Ok(pairs(&[("songs/a.ly", "a.html")])));
+        assert_eq!(conversions(&files(&["a.ly", "out.html"])), Ok(pairs(&[("a.ly", "out.html")])));
+        // Several inputs are written next to themselves
+        assert_eq!(
+            conversions(&files(&["songs/a.ly", "b.ly", "c.ly"])),
+            Ok(pairs(&[("songs/a.ly", "songs/a.html"), ("b.ly", "b.html"), ("c.ly", "c.html")]))
+        );
+        assert!(conversions(&files(&["a.ly", "out.html", "c.ly"])).is_err());
+        assert!(conversions(&[]).is_err());
+    }
+
+    #[test]
+    fn test_staff_paths() {
+        assert_eq!(
+            staff_paths("out/song.html", &["Violin I", "Violin I", ""]),
+            vec!["out/song-violin-i.html", "out/song-staff-2.html", "out/song-staff-3.html"]
+        );
+        // Fallbacks skip the names taken by labels, and labels the names taken by fallbacks
+        assert_eq!(
+            staff_paths("song.html", &["Staff 2", "", "staff-3"]),
+            vec!["song-staff-2.html", "song-staff-3.html", "song-staff-4.html"]
+        );
+    }
+}
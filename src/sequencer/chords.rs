@@ -0,0 +1,95 @@
+//! Chord symbols of the patterns (`chords:` in `\chordmode` syntax), spelled out as notes for
+//! the Strudel output
+
+/// Intervals above the root of the chord modifiers, after the `:` (`c1:m7`)
+const QUALITIES: [(&str, &[i32]); 16] = [
+    ("", &[0, 4, 7]),
+    ("5", &[0, 7]),
+    ("m", &[0, 3, 7]),
+    ("6", &[0, 4, 7, 9]),
+    ("m6", &[0, 3, 7, 9]),
+    ("7", &[0, 4, 7, 10]),
+    ("m7", &[0, 3, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("9", &[0, 4, 7, 10, 14]),
+    ("m9", &[0, 3, 7, 10, 14]),
+    ("dim", &[0, 3, 6]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("m7.5-", &[0, 3, 6, 10]),
+    ("aug", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+];
+
+const NOTE_NAMES: [&str; 12] = ["c", "des", "d", "ees", "e", "f", "ges", "g", "aes", "a", "bes", "b"];
+
+/// Rewrite chord mode (`c1:m7 f2 g:7 | r1`) as LilyPond chords in absolute octaves
+/// (`<c' ees' g' bes'>1 <f' a' c''>2 <g' b' d'' f''>`), roots from middle C up. Durations,
+/// rests, bar checks and multi-measure rests are kept as written; skips (`s1`) become rests.
+pub fn notes_of_chords(chords: &str) -> Result<String, String> {
+    let chord_re = regex::Regex::new(r"^([a-g])(isis|eses|is|es|s)?(\d+\.*)?(?::(.*))?$").unwrap();
+    let mut tokens = Vec::new();
+
+    for token in chords.split_whitespace() {
+        if token == "|" || token.starts_with('R') || token.starts_with('r') {
+            tokens.push(token.to_string());
+            continue;
+        }
+        if let Some(duration) = token.strip_prefix('s') {
+            tokens.push(format!("r{}", duration));
+            continue;
+        }
+        let caps = chord_re.captures(token).ok_or_else(|| format!("unknown chord `{}`", token))?;
+        let modifier = caps.get(4).map_or("", |m| m.as_str());
+        let (_, intervals) = QUALITIES.iter().find(|(name, _)| *name == modifier).ok_or_else(|| {
+            let known: Vec<&str> = QUALITIES.iter().map(|(name, _)| *name).filter(|n| !n.is_empty()).collect();
+            format!("unknown chord modifier `{}` in `{}` (expected {})", modifier, token, known.join(", "))
+        })?;
+
+        let step = match &caps[1] {
+            "c" => 0,
+            "d" => 2,
+            "e" => 4,
+            "f" => 5,
+            "g" => 7,
+            "a" => 9,
+            _ => 11,
+        };
+        let alteration = match caps.get(2).map(|m| m.as_str()) {
+            Some("isis") => 2,
+            Some("is") => 1,
+            Some("es") | Some("s") => -1,
+            Some("eses") => -2,
+            _ => 0,
+        };
+        let root = 60 + step + alteration;
+        let notes: Vec<String> = intervals.iter().map(|interval| note_name(root + interval)).collect();
+        let duration = caps.get(3).map_or("", |m| m.as_str());
+        tokens.push(format!("<{}>{}", notes.join(" "), duration));
+    }
+
+    Ok(tokens.join(" "))
+}
+
+/// LilyPond name of a MIDI pitch (`ees'` for 63), spelled with flats
+fn note_name(midi: i32) -> String {
+    let octave = midi.div_euclid(12) - 4;
+    let marks = if octave >= 0 { "'".repeat(octave as usize) } else { ",".repeat(-octave as usize) };
+    format!("{}{}", NOTE_NAMES[midi.rem_euclid(12) as usize], marks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notes_of_chords() {
+        assert_eq!(
+            notes_of_chords("c1:m7 | bes2 g:7 | s1 | R1*2").unwrap(),
+            "<c' ees' g' bes'>1 | <bes' d'' f''>2 <g' b' d'' f''> | r1 | R1*2"
+        );
+        assert_eq!(notes_of_chords("ces4:dim").unwrap(), "<b d' f'>4");
+        assert!(notes_of_chords("c1:m13").unwrap_err().starts_with("unknown chord modifier `m13` in `c1:m13`"));
+        assert_eq!(notes_of_chords("h1").unwrap_err(), "unknown chord `h1`");
+    }
+}
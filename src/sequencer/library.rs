@@ -0,0 +1,248 @@
+//! Shared pattern libraries, declared by URL in the sequence file and fetched into a local
+//! cache with `git` or `curl` and `tar`
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::model::LibrarySource;
+
+/// The cache of the libraries declared by a sequence file: a directory next to it, holding one
+/// directory per library. It is searched like a `--library` root, so `grooves/backbeat` is the
+/// `backbeat.yml` pattern of library `grooves`.
+pub fn cache_dir(sequence_path: &Path) -> PathBuf {
+    sequence_path.parent().unwrap_or(Path::new(".")).join(".strudel-libraries")
+}
+
+/// Fetch the declared libraries missing from `cache`, or all of them when `update`, returning
+/// the names of the libraries fetched
+pub fn fetch_libraries(
+    libraries: &BTreeMap<String, LibrarySource>,
+    cache: &Path,
+    update: bool,
+) -> Result<Vec<String>, String> {
+    let mut fetched = Vec::new();
+    for (name, source) in libraries {
+        check_name(name)?;
+        let target = cache.join(name);
+        if target.exists() && !update {
+            continue;
+        }
+
+        // Fetched next to the cached copy, which is only replaced once the fetch succeeded
+        let download = cache.join(format!(".{name}.download"));
+        remove_dir(&download)?;
+        std::fs::create_dir_all(cache).map_err(|e| format!("Cannot create {}: {}", cache.display(), e))?;
+        let result = match source {
+            LibrarySource::Git { url, rev } => fetch_git(url, rev.as_deref(), &download),
+            LibrarySource::Tarball { url } => fetch_tarball(url, &download),
+        };
+        if let Err(e) = result {
+            remove_dir(&download)?;
+            return Err(format!("Cannot fetch library `{}`: {}", name, e));
+        }
+        remove_dir(&target)?;
+        std::fs::rename(&download, &target).map_err(|e| format!("Cannot write {}: {}", target.display(), e))?;
+        fetched.push(name.clone());
+    }
+    Ok(fetched)
+}
+
+/// Error naming the first declared library that is not in `cache` yet
+pub fn check_fetched(libraries: &BTreeMap<String, LibrarySource>, cache: &Path) -> Result<(), String> {
+    match libraries.keys().find(|name| !cache.join(name.as_str()).is_dir()) {
+        Some(name) => Err(format!("library `{}` is not fetched yet (run `library fetch` first)", name)),
+        None => Ok(()),
+    }
+}
+
+/// Library names become directory names and the first part of pattern names
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid { Ok(()) } else { Err(format!("invalid library name `{}`: use letters, digits, `-` and `_`", name)) }
+}
+
+fn remove_dir(dir: &Path) -> Result<(), String> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("Cannot remove {}: {}", dir.display(), e)),
+        _ => Ok(()),
+    }
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command.output().map_err(|e| format!("cannot run {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// URLs come from the sequence file: only the given schemes, so none is read as an option
+/// (`--upload-pack=...`) or names a transport running commands (`ext::`)
+fn check_url(url: &str, schemes: &[&str]) -> Result<(), String> {
+    if schemes.iter().any(|scheme| url.starts_with(scheme) && url.len() > scheme.len()) {
+        Ok(())
+    } else {
+        Err(format!("unsupported URL `{}` (expected {})", url, schemes.join(", ")))
+    }
+}
+
+fn fetch_git(url: &str, rev: Option<&str>, target: &Path) -> Result<(), String> {
+    check_url(url, &["https://", "ssh://", "git@", "file://"])?;
+    let mut git = Command::new("git");
+    git.args(["clone", "--quiet", "--depth", "1"]);
+    if let Some(rev) = rev {
+        if rev.is_empty() || rev.starts_with('-') {
+            return Err(format!("invalid rev `{}`", rev));
+        }
+        git.args(["--branch", rev]);
+    }
+    run(git.arg("--").arg(url).arg(target))?;
+    remove_dir(&target.join(".git"))
+}
+
+fn fetch_tarball(url: &str, target: &Path) -> Result<(), String> {
+    check_url(url, &["https://", "file://"])?;
+    std::fs::create_dir_all(target).map_err(|e| e.to_string())?;
+    let archive = target.join(".archive.tar.gz");
+    run(Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--proto", "=https,file", "--output"])
+        .arg(&archive)
+        .arg("--")
+        .arg(url))?;
+    check_archive(&archive)?;
+    run(Command::new("tar").args(["--no-same-owner", "-xzf"]).arg(&archive).arg("-C").arg(target))?;
+    std::fs::remove_file(&archive).map_err(|e| e.to_string())?;
+    check_inside(target, target)?;
+
+    // Archives of repositories hold everything in one directory (`grooves-1.0/`)
+    let entries: Vec<PathBuf> = std::fs::read_dir(target)
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    if let [single] = entries.as_slice()
+        && single.is_dir()
+    {
+        for entry in std::fs::read_dir(single).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            std::fs::rename(entry.path(), target.join(entry.file_name())).map_err(|e| e.to_string())?;
+        }
+        std::fs::remove_dir(single).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Refuse archives with entries outside of the directory they are extracted to
+fn check_archive(archive: &Path) -> Result<(), String> {
+    let output = Command::new("tar").arg("-tzf").arg(archive).output().map_err(|e| format!("cannot run tar: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("tar failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    for entry in String::from_utf8_lossy(&output.stdout).lines() {
+        let path = Path::new(entry);
+        if path.is_absolute() || path.components().any(|c| c == std::path::Component::ParentDir) {
+            return Err(format!("archive entry `{}` is outside of the library", entry));
+        }
+    }
+    Ok(())
+}
+
+/// Refuse extracted links leading out of `root`
+fn check_inside(dir: &Path, root: &Path) -> Result<(), String> {
+    let root = root.canonicalize().map_err(|e| e.to_string())?;
+    for entry in std::fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let resolved = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        if !resolved.starts_with(&root) {
+            return Err(format!("archive entry `{}` leads outside of the library", path.display()));
+        }
+        if path.is_dir() && !path.is_symlink() {
+            check_inside(&path, &root)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// `curl` and `tar` on the PATH, which the tarball tests need
+    fn has_tools() -> bool {
+        ["curl", "tar"].iter().all(|tool| Command::new(tool).arg("--version").output().is_ok())
+    }
+
+    #[test]
+    fn test_library_urls_and_revs() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("lib");
+        let err = fetch_git("--upload-pack=touch pwned", None, &target).unwrap_err();
+        assert!(err.starts_with("unsupported URL `--upload-pack=touch pwned`"), "{err}");
+        assert!(fetch_git("ext::sh -c touch% pwned", None, &target).unwrap_err().starts_with("unsupported URL"));
+        assert_eq!(fetch_git("https://example.com/g.git", Some("--upload-pack=x"), &target).unwrap_err(), "invalid rev `--upload-pack=x`");
+        assert!(fetch_tarball("ssh://example.com/g.tar.gz", &target).unwrap_err().starts_with("unsupported URL"));
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_fetch_tarball_library() {
+        if !has_tools() {
+            eprintln!("skipped: needs curl and tar");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("grooves-1.0");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("backbeat.yml"), "description: backbeat\nvoices:\n  - bd4 sn4 bd4 sn4\n").unwrap();
+        let archive = dir.path().join("grooves.tar.gz");
+        run(Command::new("tar").arg("-czf").arg(&archive).arg("-C").arg(dir.path()).arg("grooves-1.0")).unwrap();
+
+        let url = format!("file://{}", archive.display());
+        let libraries = BTreeMap::from([("grooves".to_string(), LibrarySource::Tarball { url })]);
+        let cache = cache_dir(&dir.path().join("song.yml"));
+        assert!(check_fetched(&libraries, &cache).unwrap_err().contains("library `grooves` is not fetched"));
+
+        assert_eq!(fetch_libraries(&libraries, &cache, false).unwrap(), vec!["grooves".to_string()]);
+        assert!(cache.join("grooves/backbeat.yml").is_file());
+        check_fetched(&libraries, &cache).unwrap();
+        // Already there: only an update fetches it again
+        assert!(fetch_libraries(&libraries, &cache, false).unwrap().is_empty());
+        assert_eq!(fetch_libraries(&libraries, &cache, true).unwrap(), vec!["grooves".to_string()]);
+
+        let missing = BTreeMap::from([(
+            "other".to_string(),
+            LibrarySource::Tarball { url: format!("file://{}", dir.path().join("missing.tar.gz").display()) },
+        )]);
+        let err = fetch_libraries(&missing, &cache, false).unwrap_err();
+        assert!(err.starts_with("Cannot fetch library `other`: curl failed"), "{err}");
+        assert!(!cache.join("other").exists() && !cache.join(".other.download").exists());
+
+        // An archive reaching out of the library is refused before it is extracted
+        let evil = dir.path().join("evil");
+        fs::create_dir_all(evil.join("inner")).unwrap();
+        fs::write(dir.path().join("outside.yml"), "voices: []\n").unwrap();
+        let archive = dir.path().join("evil.tar.gz");
+        // `-P` keeps the `..` that tar would strip
+        run(Command::new("tar").arg("-czPf").arg(&archive).arg("-C").arg(evil.join("inner")).arg("../../outside.yml")).unwrap();
+        let evil_libraries = BTreeMap::from([(
+            "evil".to_string(),
+            LibrarySource::Tarball { url: format!("file://{}", archive.display()) },
+        )]);
+        let err = fetch_libraries(&evil_libraries, &cache, false).unwrap_err();
+        assert!(err.contains("archive entry `../../outside.yml` is outside of the library"), "{err}");
+        assert!(!cache.join("evil").exists());
+
+        // So is one whose links lead out of it once extracted
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path(), evil.join("escape")).unwrap();
+            run(Command::new("tar").arg("-czf").arg(&archive).arg("-C").arg(&evil).arg("escape")).unwrap();
+            let err = fetch_libraries(&evil_libraries, &cache, false).unwrap_err();
+            assert!(err.contains("escape` leads outside of the library"), "{err}");
+            assert!(!cache.join("evil").exists() && !cache.join(".evil.download").exists());
+        }
+    }
+}
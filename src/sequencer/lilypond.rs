@@ -1,7 +1,30 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use super::model::{Pattern, Bar, EBarSequence, BarSequence};
-use crate::{LilyPondParser, StrudelGenerator};
+use super::chords::notes_of_chords;
+use super::model::{Pattern, Bar, EBarSequence, BarSequence, Order, SequenceItem, Transition};
+use crate::{
+    escape_html, DrumEvent, DrumVoiceData, GeneratorOptions, LilyPondParser, Staff, StaffContent, StrudelGenerator,
+    Tempo, DEFAULT_VOICE_PALETTE,
+};
+
+/// How a sequence is rendered to an HTML player
+#[derive(Debug, Clone, Default)]
+pub struct SequenceRenderOptions {
+    /// Page title
+    pub title: String,
+    /// Drum machine sample bank, as `.bank("RolandTR909")`
+    pub bank: Option<String>,
+    /// Gain of the voices (`.gain(0.8)`)
+    pub gain: Option<String>,
+    /// Color the voices with the default palette and show them as a punchcard
+    pub punchcard: bool,
+    /// HTML page replacing the built-in one: `{{title}}` is replaced by the title and `{{code}}`
+    /// by the standalone Strudel code
+    pub template: Option<String>,
+    /// Options of the underlying generator (theme, CSS, weights, ...)
+    pub generator: GeneratorOptions,
+}
 
 pub fn lilypond_bar_of_snippet(patterns: &[Pattern]) -> String {
     patterns
@@ -19,15 +42,142 @@ pub fn lilypond_bar_of_snippet(patterns: &[Pattern]) -> String {
 pub fn parse_pattern(path: &Path) -> Result<Pattern, String> {
     let content = std::fs::read_to_string(path)
         .map_err(|e| format!("Cannot read pattern file '{}': {}", path.display(), e))?;
-    serde_yaml::from_str(&content)
-        .map_err(|e| format!("Cannot parse pattern file '{}': {}", path.display(), e))
+    let mut pattern: Pattern = serde_yaml::from_str(&content)
+        .map_err(|e| format!("Cannot parse pattern file '{}': {}", path.display(), e))?;
+    for voice in pattern.voices.iter_mut() {
+        *voice = expand_voice_shorthand(voice)
+            .map_err(|e| format!("In pattern file '{}': {}", path.display(), e))?;
+    }
+    if let Some(chords) = pattern.chords.as_mut() {
+        *chords = expand_voice_shorthand(chords)
+            .map_err(|e| format!("In pattern file '{}': {}", path.display(), e))?;
+    }
+    Ok(pattern)
+}
+
+/// Expand the compact voice notation of pattern files into plain LilyPond: a measure
+/// consisting of `%` repeats the previous one (measures are separated by `|`), `hh8*4`
+/// repeats a token and `(bd8 hh8)*2` a group of tokens
+pub fn expand_voice_shorthand(voice: &str) -> Result<String, String> {
+    let group_re = regex::Regex::new(r"\(|\)(\*[^\s()]*)?").unwrap();
+    let mut measures: Vec<String> = Vec::new();
+
+    for measure in voice.split('|') {
+        let measure = measure.trim();
+        if measure == "%" {
+            let previous = measures.last().ok_or("`%` repeats the previous measure, but there is none")?;
+            measures.push(previous.clone());
+            continue;
+        }
+
+        let spaced = group_re.replace_all(measure, " $0 ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut pos = 0;
+        let expanded = expand_tokens(&tokens, &mut pos)?;
+        if pos < tokens.len() {
+            return Err(format!("unbalanced `)` in `{}`", measure));
+        }
+        measures.push(expanded.join(" "));
+    }
+
+    Ok(measures.join(" | "))
+}
+
+/// Expand tokens from `pos` up to the end or to an unmatched `)` token, which is left at `pos`
+fn expand_tokens(tokens: &[&str], pos: &mut usize) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::new();
+
+    while let Some(&token) = tokens.get(*pos) {
+        if token.starts_with(')') {
+            break;
+        }
+        *pos += 1;
+
+        let (group, count) = if token == "(" {
+            let group = expand_tokens(tokens, pos)?;
+            let closing = tokens.get(*pos).ok_or("unbalanced `(`")?;
+            *pos += 1;
+            (group, repeat_count(&closing[1..], closing)?)
+        } else {
+            match token.split_once('*') {
+                Some((note, count)) => (vec![note.to_string()], repeat_count(count, token)?),
+                None => (vec![token.to_string()], 1),
+            }
+        };
+
+        for _ in 0..count {
+            expanded.extend(group.iter().cloned());
+        }
+    }
+
+    Ok(expanded)
+}
+
+fn repeat_count(suffix: &str, token: &str) -> Result<usize, String> {
+    if suffix.is_empty() {
+        return Ok(1);
+    }
+    suffix
+        .trim_start_matches('*')
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or(format!("invalid repeat count in `{}`", token))
+}
+
+/// Replace the `${name}` placeholders of the pattern's voices, the bar's values taking
+/// precedence over the pattern's `params` defaults
+pub fn instantiate_pattern(pattern: &Pattern, pattern_name: &str, params: &HashMap<String, String>) -> Result<Pattern, String> {
+    let placeholder_re = regex::Regex::new(r"\$\{([a-zA-Z_][a-zA-Z0-9_]*)\}").unwrap();
+    let mut voices = Vec::new();
+
+    for voice in &pattern.voices {
+        let mut missing = None;
+        let instantiated = placeholder_re.replace_all(voice, |caps: &regex::Captures| {
+            let name = &caps[1];
+            match params.get(name).or_else(|| pattern.params.get(name)) {
+                Some(value) => value.clone(),
+                None => {
+                    missing.get_or_insert_with(|| name.to_string());
+                    String::new()
+                }
+            }
+        });
+        if let Some(name) = missing {
+            return Err(format!("pattern '{}' uses ${{{}}} but no value is given", pattern_name, name));
+        }
+        voices.push(instantiated.into_owned());
+    }
+
+    Ok(Pattern { voices, ..pattern.clone() })
 }
 
-fn resolve_pattern(pattern_name: &str, libraries: &[PathBuf]) -> Result<Pattern, String> {
+/// Fail on anything in the pattern's voices that would not make it into the output,
+/// such as a mistyped drum name
+fn check_pattern(pattern: &Pattern, pattern_name: &str) -> Result<(), String> {
+    let parser = LilyPondParser::new();
+    for (index, voice) in pattern.voices.iter().enumerate() {
+        let (_, warnings) = parser
+            .parse_drum_content(voice)
+            .map_err(|e| format!("pattern '{}', voice {}: {}", pattern_name, index + 1, e))?;
+        if let Some(warning) = warnings.first() {
+            return Err(format!("pattern '{}', voice {}: {}", pattern_name, index + 1, warning.message));
+        }
+    }
+    if let Some(chords) = &pattern.chords {
+        notes_of_chords(chords).map_err(|e| format!("pattern '{}', chords: {}", pattern_name, e))?;
+    }
+    Ok(())
+}
+
+fn resolve_pattern(bar: &Bar, libraries: &[PathBuf]) -> Result<Pattern, String> {
+    let pattern_name = &bar.pattern_name;
     for lib in libraries {
         let yml_path = lib.join(format!("{pattern_name}.yml"));
         if yml_path.exists() {
-            return parse_pattern(&yml_path);
+            let pattern = instantiate_pattern(&parse_pattern(&yml_path)?, pattern_name, &bar.params)?;
+            check_pattern(&pattern, pattern_name)?;
+            return Ok(pattern);
         }
     }
     Err(format!(
@@ -37,6 +187,11 @@ fn resolve_pattern(pattern_name: &str, libraries: &[PathBuf]) -> Result<Pattern,
     ))
 }
 
+/// `count` empty 4/4 bars
+fn multi_measure_rest(count: u32) -> String {
+    format!("R1*{}", count)
+}
+
 fn generate_voice_content<F>(
     sequence: &[EBarSequence],
     libraries: &[PathBuf],
@@ -46,7 +201,7 @@ fn generate_voice_content<F>(
     need_bar_sep: &mut bool,
 ) -> Result<String, String>
 where
-    F: Fn(&Pattern) -> &str,
+    F: Fn(&Pattern) -> String,
 {
     let mut lines: Vec<String> = Vec::new();
 
@@ -54,7 +209,7 @@ where
         let c = if i == 0 { comment } else { None };
         match item {
             EBarSequence::Single(bar) => {
-                let pattern = resolve_pattern(&bar.pattern_name, libraries)?;
+                let pattern = resolve_pattern(bar, libraries)?;
                 if *need_bar_sep {
                     lines.push(format!("{}|", indent));
                 }
@@ -69,7 +224,7 @@ where
                 lines.push(inner);
             }
             EBarSequence::RepeatBar(count, bar) => {
-                let pattern = resolve_pattern(&bar.pattern_name, libraries)?;
+                let pattern = resolve_pattern(bar, libraries)?;
                 if *need_bar_sep {
                     lines.push(format!("{}|", indent));
                 }
@@ -95,59 +250,220 @@ where
                 lines.push(format!("{}}}", indent));
                 *need_bar_sep = false;
             }
+            EBarSequence::Rest(count) => {
+                if *need_bar_sep {
+                    lines.push(format!("{}|", indent));
+                }
+                if let Some(text) = c {
+                    lines.push(format!("{}% @strudel-of-lilypond@ comment {}", indent, text));
+                }
+                lines.push(format!("{}{}", indent, multi_measure_rest(*count)));
+                *need_bar_sep = true;
+            }
         }
     }
 
     Ok(lines.join("\n"))
 }
 
-fn find_first_bar(items: &[EBarSequence]) -> Option<&Bar> {
-    for item in items {
-        match item {
-            EBarSequence::Single(bar) | EBarSequence::RepeatBar(_, bar) => return Some(bar),
-            EBarSequence::Group(inner) | EBarSequence::RepeatGroup(_, inner) => {
-                if let Some(bar) = find_first_bar(inner) {
-                    return Some(bar);
+/// The sections, with a bar of the transition pattern after each section that has a
+/// `transition` (but the last one)
+fn with_transitions(bar_sequence: &BarSequence) -> Result<Vec<SequenceItem>, String> {
+    let mut sections = Vec::new();
+    for (index, section) in bar_sequence.sequence.iter().enumerate() {
+        sections.push(section.clone());
+        let (name, pattern) = match section.transition {
+            Transition::None => continue,
+            Transition::Fill => ("fill", &bar_sequence.transitions.fill),
+            Transition::Crash => ("crash", &bar_sequence.transitions.crash),
+        };
+        if index + 1 == bar_sequence.sequence.len() {
+            continue;
+        }
+        let Some(pattern_name) = pattern else {
+            return Err(format!(
+                "section '{}' has `transition: {}` but no `transitions: {{ {}: <pattern> }}` is set",
+                section.description, name, name
+            ));
+        };
+        sections.push(SequenceItem {
+            item: EBarSequence::Single(Bar { pattern_name: pattern_name.clone(), params: HashMap::new() }),
+            description: format!("{} ({})", section.description, name),
+            order: Order::Original,
+            transition: Transition::None,
+        });
+    }
+    Ok(sections)
+}
+
+/// Apply the `order` of each section to the items of its group (`!Group`, `!RepeatGroup`),
+/// returning a sequence in original order. Shuffles are reproducible for a given seed.
+pub fn arrange_sequence(bar_sequence: &BarSequence, seed: u64) -> BarSequence {
+    let mut rng = SplitMix64(seed);
+    let mut arranged = bar_sequence.clone();
+
+    for section in arranged.sequence.iter_mut() {
+        let order = std::mem::take(&mut section.order);
+        let items = match &mut section.item {
+            EBarSequence::Group(items) | EBarSequence::RepeatGroup(_, items) => items,
+            EBarSequence::Single(_) | EBarSequence::RepeatBar(_, _) | EBarSequence::Rest(_) => continue,
+        };
+        match order {
+            Order::Original => {}
+            Order::Retrograde => items.reverse(),
+            Order::Shuffle => {
+                // Fisher-Yates
+                for i in (1..items.len()).rev() {
+                    let j = (rng.next() % (i as u64 + 1)) as usize;
+                    items.swap(i, j);
                 }
             }
         }
     }
-    None
+
+    arranged
+}
+
+/// Small deterministic generator, so that a seed gives the same order on every platform and version
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn collect_bars<'a>(items: &'a [EBarSequence], bars: &mut Vec<&'a Bar>) {
+    for item in items {
+        match item {
+            EBarSequence::Single(bar) | EBarSequence::RepeatBar(_, bar) => bars.push(bar),
+            EBarSequence::Group(inner) | EBarSequence::RepeatGroup(_, inner) => collect_bars(inner, bars),
+            EBarSequence::Rest(_) => {}
+        }
+    }
+}
+
+/// Voices of the sequence, with the first name a pattern gives each: patterns with fewer voices
+/// than the others are padded with full-bar rests
+fn sequence_voices(items: &[EBarSequence], libraries: &[PathBuf]) -> Result<Vec<Option<String>>, String> {
+    let mut bars = Vec::new();
+    collect_bars(items, &mut bars);
+    if bars.is_empty() {
+        return Err("Empty sequence".to_string());
+    }
+    let mut names: Vec<Option<String>> = Vec::new();
+    for bar in bars {
+        let pattern = resolve_pattern(bar, libraries)?;
+        if names.len() < pattern.voices.len() {
+            names.resize(pattern.voices.len(), None);
+        }
+        for (name, pattern_name) in names.iter_mut().zip(&pattern.voice_names) {
+            name.get_or_insert_with(|| pattern_name.clone());
+        }
+    }
+    Ok(names)
+}
+
+/// Whether any pattern of the sequence has chord symbols
+fn sequence_has_chords(items: &[EBarSequence], libraries: &[PathBuf]) -> Result<bool, String> {
+    let mut bars = Vec::new();
+    collect_bars(items, &mut bars);
+    for bar in bars {
+        if resolve_pattern(bar, libraries)?.chords.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Chord symbols of the pattern (as notes unless `chordmode`), or a full-bar skip (`s1`) or
+/// rest (`r1`) for each measure of its first voice
+fn pattern_chords(pattern: &Pattern, chordmode: bool) -> String {
+    match &pattern.chords {
+        // Already checked by resolve_pattern
+        Some(chords) if chordmode => chords.clone(),
+        Some(chords) => notes_of_chords(chords).unwrap_or_default(),
+        None => {
+            let measures = pattern.voices.first().map_or(1, |voice| voice.split('|').count());
+            vec![if chordmode { "s1" } else { "r1" }; measures].join(" | ")
+        }
+    }
+}
+
+/// The chord part of the sections, laid out like the drum voices
+fn chords_content(sections: &[SequenceItem], libraries: &[PathBuf], chordmode: bool, indent: &str) -> Result<String, String> {
+    let mut parts = Vec::new();
+    let mut sep = false;
+    for section in sections {
+        parts.push(generate_voice_content(
+            std::slice::from_ref(&section.item),
+            libraries,
+            &|p: &Pattern| pattern_chords(p, chordmode),
+            indent,
+            Some(&section.description),
+            &mut sep,
+        )?);
+    }
+    Ok(parts.join("\n"))
+}
+
+/// Content of the pattern's voice, or as many full-bar rests (`r1`) as its first voice has measures
+fn pattern_voice(pattern: &Pattern, voice_idx: usize) -> String {
+    match pattern.voices.get(voice_idx) {
+        Some(voice) => voice.clone(),
+        None => {
+            let measures = pattern.voices.first().map_or(1, |voice| voice.split('|').count());
+            vec!["r1"; measures].join(" | ")
+        }
+    }
 }
 
 pub fn lilypond_of_sequence(bar_sequence: &BarSequence, libraries: &[PathBuf], _output_dir: &Path) -> Result<String, String> {
     let indent = "            ";
-    let items: Vec<EBarSequence> = bar_sequence.sequence.iter().map(|si| si.item.clone()).collect();
-    let descriptions: Vec<&str> = bar_sequence.sequence.iter().map(|si| si.description.as_str()).collect();
+    let sections = with_transitions(bar_sequence)?;
+    let items: Vec<EBarSequence> = sections.iter().map(|si| si.item.clone()).collect();
+    let descriptions: Vec<&str> = sections.iter().map(|si| si.description.as_str()).collect();
 
-    let first_bar = find_first_bar(&items).ok_or("Empty sequence")?;
-    let first_pattern = resolve_pattern(&first_bar.pattern_name, libraries)?;
-    let num_voices = first_pattern.voices.len();
+    let voice_names = sequence_voices(&items, libraries)?;
 
     let voice_directives = ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
 
     let mut voice_blocks = Vec::new();
-    for voice_idx in 0..num_voices {
+    for (voice_idx, voice_name) in voice_names.iter().enumerate() {
         let mut parts = Vec::new();
         let mut sep = false;
 
         for (item, desc) in items.iter().zip(descriptions.iter()) {
             let v = generate_voice_content(
                 std::slice::from_ref(item), libraries,
-                &|p: &Pattern| p.voices[voice_idx].as_str(), indent, Some(desc), &mut sep,
+                &|p: &Pattern| pattern_voice(p, voice_idx), indent, Some(desc), &mut sep,
             )?;
             parts.push(v);
         }
 
         let voice_content = parts.join("\n");
         let directive = voice_directives.get(voice_idx).copied().unwrap_or("");
+        let label = match voice_name {
+            Some(name) => format!("          % {}\n", name),
+            None => String::new(),
+        };
         voice_blocks.push(format!(
-            "        \\new DrumVoice {{\n          {}\n{}\n        }}",
-            directive, voice_content
+            "        \\new DrumVoice {{\n{}          {}\n{}\n        }}",
+            label, directive, voice_content
         ));
     }
 
     let voices = voice_blocks.join("\n");
+    let chords = if sequence_has_chords(&items, libraries)? {
+        let content = chords_content(&sections, libraries, true, "        ")?;
+        format!("    \\new ChordNames {{\n      \\chordmode {{\n{}\n      }}\n    }}\n\n", content)
+    } else {
+        String::new()
+    };
 
     Ok(format!(
         r#"\version "2.24.4"
@@ -169,7 +485,7 @@ pub fn lilypond_of_sequence(bar_sequence: &BarSequence, libraries: &[PathBuf], _
   <<
     \tempo 4 = {tempo}
 
-    \new DrumStaff {{
+{chords}    \new DrumStaff {{
       <<
 {voices}
       >>
@@ -181,21 +497,189 @@ pub fn lilypond_of_sequence(bar_sequence: &BarSequence, libraries: &[PathBuf], _
 "#,
         tempo = bar_sequence.tempo,
         voices = voices,
+        chords = chords,
     ))
 }
 
-pub fn strudel_of_sequence(bar_sequence: &BarSequence, libraries: &[PathBuf], title: &str) -> Result<String, String> {
-    let dummy_dir = Path::new(".");
-    let ly = lilypond_of_sequence(bar_sequence, libraries, dummy_dir)?;
+/// Build the drum staff of a sequence directly from the pattern voices, without going
+/// through LilyPond text (which [`lilypond_of_sequence`] produces for engraving)
+pub fn staves_of_sequence(bar_sequence: &BarSequence, libraries: &[PathBuf]) -> Result<(Vec<Staff>, Tempo), String> {
+    let sections = with_transitions(bar_sequence)?;
+    let items: Vec<EBarSequence> = sections.iter().map(|si| si.item.clone()).collect();
+    let voice_names = sequence_voices(&items, libraries)?;
     let parser = LilyPondParser::new();
-    let result = parser.parse(&ly).map_err(|e| format!("LilyPond parse error: {e}"))?;
-    Ok(StrudelGenerator::generate_html(&result.staves, &result.tempo, title))
+
+    let mut voices = Vec::new();
+    for (voice_idx, name) in voice_names.into_iter().enumerate() {
+        let mut events = Vec::new();
+        let mut sep = false;
+        for section in &sections {
+            events.extend(voice_events(
+                std::slice::from_ref(&section.item),
+                libraries,
+                &parser,
+                voice_idx,
+                Some(&section.description),
+                &mut sep,
+            )?);
+        }
+        voices.push(DrumVoiceData { events, punchcard_color: None, gain: None, pan: None, speed: None, name });
+    }
+
+    let mut staves = vec![Staff::new_drums(voices)];
+    if sequence_has_chords(&items, libraries)? {
+        let (events, _) = parser.parse_pitched_content(&chords_content(&sections, libraries, false, "")?)?;
+        let mut chords = Staff::new_pitched(events);
+        chords.name = Some("chords".to_string());
+        staves.push(chords);
+    }
+
+    let tempo = Tempo { beat_unit: 4, bpm: bar_sequence.tempo };
+    Ok((staves, tempo))
+}
+
+/// Events of one voice, laid out like [`generate_voice_content`] lays out the LilyPond text
+fn voice_events(
+    sequence: &[EBarSequence],
+    libraries: &[PathBuf],
+    parser: &LilyPondParser,
+    voice_idx: usize,
+    comment: Option<&str>,
+    need_bar_sep: &mut bool,
+) -> Result<Vec<DrumEvent>, String> {
+    let mut events = Vec::new();
+    let bar_events = |bar: &Bar| -> Result<Vec<DrumEvent>, String> {
+        let pattern = resolve_pattern(bar, libraries)?;
+        let (events, _) = parser.parse_drum_content(&pattern_voice(&pattern, voice_idx))?;
+        Ok(events)
+    };
+
+    for (i, item) in sequence.iter().enumerate() {
+        let c = if i == 0 { comment } else { None };
+        if !matches!(item, EBarSequence::Group(_)) {
+            if std::mem::take(need_bar_sep) {
+                events.push(DrumEvent::BarLine);
+            }
+            if let Some(text) = c {
+                events.push(DrumEvent::Comment(text.to_string()));
+            }
+        }
+        match item {
+            EBarSequence::Single(bar) => {
+                events.extend(bar_events(bar)?);
+                *need_bar_sep = true;
+            }
+            EBarSequence::Group(items) => {
+                events.extend(voice_events(items, libraries, parser, voice_idx, c, need_bar_sep)?);
+            }
+            EBarSequence::RepeatBar(count, bar) => {
+                events.push(DrumEvent::RepeatStart(*count));
+                events.extend(bar_events(bar)?);
+                events.push(DrumEvent::RepeatEnd);
+            }
+            EBarSequence::RepeatGroup(count, items) => {
+                events.push(DrumEvent::RepeatStart(*count));
+                events.extend(voice_events(items, libraries, parser, voice_idx, None, &mut false)?);
+                events.push(DrumEvent::RepeatEnd);
+            }
+            EBarSequence::Rest(count) => {
+                let (rest, _) = parser.parse_drum_content(&multi_measure_rest(*count))?;
+                events.extend(rest);
+                *need_bar_sep = true;
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+pub fn strudel_of_sequence(
+    bar_sequence: &BarSequence,
+    libraries: &[PathBuf],
+    options: &SequenceRenderOptions,
+) -> Result<String, String> {
+    let (staves, tempo) = staves_of_sequence(bar_sequence, libraries)?;
+    Ok(render_html(staves, &tempo, &options.title, options))
+}
+
+fn render_html(staves: Vec<Staff>, tempo: &Tempo, title: &str, options: &SequenceRenderOptions) -> String {
+    let (staves, generator) = apply_render_options(staves, options);
+    match &options.template {
+        Some(template) => {
+            let code = StrudelGenerator::generate_multi_with_options(
+                &staves,
+                tempo,
+                &GeneratorOptions { emit_prologue: true, ..generator },
+            );
+            template.replace("{{title}}", &escape_html(title)).replace("{{code}}", &code)
+        }
+        None => StrudelGenerator::generate_html_with_options(&staves, tempo, title, &generator),
+    }
+}
+
+/// The staves with the voice gains set, and the generator options with the bank and palette
+pub(super) fn apply_render_options(mut staves: Vec<Staff>, options: &SequenceRenderOptions) -> (Vec<Staff>, GeneratorOptions) {
+    let mut generator = options.generator.clone();
+    if options.bank.is_some() {
+        generator.drum_bank = options.bank.clone();
+    }
+    if options.punchcard && generator.voice_palette.is_none() {
+        generator.voice_palette = Some(DEFAULT_VOICE_PALETTE.iter().map(|c| c.to_string()).collect());
+    }
+    if let Some(gain) = &options.gain {
+        for staff in staves.iter_mut() {
+            if let StaffContent::Drums(voices) = &mut staff.content {
+                for voice in voices.iter_mut() {
+                    voice.gain.get_or_insert_with(|| gain.clone());
+                }
+            }
+        }
+    }
+    (staves, generator)
+}
+
+/// One HTML player per voice of the sequence, for practicing along with a single part.
+/// Each stem is named after its voice (`voice_names` of the patterns), or else after the drums
+/// the voice plays (`bd-sn`, `hh`, ...).
+pub fn strudel_stems_of_sequence(
+    bar_sequence: &BarSequence,
+    libraries: &[PathBuf],
+    options: &SequenceRenderOptions,
+) -> Result<Vec<(String, String)>, String> {
+    let (staves, tempo) = staves_of_sequence(bar_sequence, libraries)?;
+    let voices: Vec<_> = staves.iter().filter_map(|s| s.drum_voices()).flatten().collect();
+
+    let mut stems: Vec<(String, String)> = Vec::new();
+    for (index, voice) in voices.into_iter().enumerate() {
+        let mut drums: Vec<&str> = Vec::new();
+        for event in &voice.events {
+            match event {
+                DrumEvent::Hit(hit) if !drums.contains(&hit.name.as_str()) => drums.push(&hit.name),
+                _ => {}
+            }
+        }
+        let mut name = match &voice.name {
+            Some(name) => name.clone(),
+            None if drums.is_empty() => format!("voice{}", index + 1),
+            None => drums.join("-"),
+        };
+        if stems.iter().any(|(n, _)| *n == name) {
+            name = format!("{}-{}", name, index + 1);
+        }
+
+        let staves = vec![Staff::new_drums(vec![voice.clone()])];
+        let html = render_html(staves, &tempo, &format!("{} ({name})", options.title), options);
+        stems.push((name, html));
+    }
+
+    Ok(stems)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use super::super::model::{Bar, SequenceItem};
+    use std::collections::HashMap;
     use std::fs;
 
     #[test]
@@ -217,14 +701,20 @@ mod tests {
             tempo: 120,
             sequence: vec![
                 SequenceItem {
-                    item: EBarSequence::Single(Bar { pattern_name: "pattern1".to_string() }),
+                    item: EBarSequence::Single(Bar { pattern_name: "pattern1".to_string(), params: HashMap::new() }),
                     description: "kick and snare".to_string(),
+                    order: Order::Original,
+                    transition: Transition::None,
                 },
                 SequenceItem {
-                    item: EBarSequence::Single(Bar { pattern_name: "pattern2".to_string() }),
+                    item: EBarSequence::Single(Bar { pattern_name: "pattern2".to_string(), params: HashMap::new() }),
                     description: "kick only".to_string(),
+                    order: Order::Original,
+                    transition: Transition::None,
                 },
             ],
+            libraries: Default::default(),
+            transitions: Default::default(),
         };
 
         let libraries = vec![lib_dir];
@@ -242,4 +732,412 @@ mod tests {
         assert!(result.contains("% @strudel-of-lilypond@ comment kick and snare"));
         assert!(result.contains("% @strudel-of-lilypond@ comment kick only"));
     }
+
+    #[test]
+    fn test_voice_shorthand() {
+        assert_eq!(expand_voice_shorthand("hh8*4 sn4*2").unwrap(), "hh8 hh8 hh8 hh8 sn4 sn4");
+        assert_eq!(expand_voice_shorthand("(bd8 hh8)*2 (sn8 (hh16)*2)*2").unwrap(),
+            "bd8 hh8 bd8 hh8 sn8 hh16 hh16 sn8 hh16 hh16");
+        assert_eq!(expand_voice_shorthand("bd4 sn4 bd4 sn4 | % | bd2 sn2").unwrap(),
+            "bd4 sn4 bd4 sn4 | bd4 sn4 bd4 sn4 | bd2 sn2");
+
+        assert!(expand_voice_shorthand("% | bd1").is_err());
+        assert!(expand_voice_shorthand("(bd4 sn4*2").is_err());
+        assert!(expand_voice_shorthand("bd4 sn4)*2").is_err());
+        assert!(expand_voice_shorthand("hh8*0").is_err());
+
+        // Pattern files are expanded when loaded
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("groove.yml");
+        fs::write(&path, "description: groove\nvoices:\n  - (bd4 sn4)*2 | %\n  - hh8*8 | %\n").unwrap();
+        let pattern = parse_pattern(&path).unwrap();
+        assert_eq!(pattern.voices[0], "bd4 sn4 bd4 sn4 | bd4 sn4 bd4 sn4");
+        assert_eq!(pattern.voices[1].split_whitespace().filter(|t| *t == "hh8").count(), 16);
+    }
+
+    #[test]
+    fn test_pattern_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib_dir = dir.path().join("library");
+        fs::create_dir_all(&lib_dir).unwrap();
+        fs::write(
+            lib_dir.join("backbeat.yml"),
+            "description: backbeat\nvoices:\n  - bd4 ${acc}4 bd4 ${acc}4\nparams:\n  acc: sn\n",
+        ).unwrap();
+
+        let sequence: BarSequence = serde_yaml::from_str(
+            r#"
+tempo: 100
+sequence:
+  - description: snare
+    item: !Single
+      pattern_name: backbeat
+  - description: clap
+    item: !RepeatBar
+      - 2
+      - pattern_name: backbeat
+        params:
+          acc: cp
+"#,
+        ).unwrap();
+
+        let libraries = vec![lib_dir.clone()];
+        let result = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert!(result.contains("bd4 sn4 bd4 sn4"));
+        assert!(result.contains("bd4 cp4 bd4 cp4"));
+
+        fs::write(lib_dir.join("open.yml"), "description: open\nvoices:\n  - bd4 ${fill}2.\n").unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100\nsequence:\n  - description: x\n    item: !Single\n      pattern_name: open\n",
+        ).unwrap();
+        let err = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap_err();
+        assert!(err.contains("${fill}"), "{err}");
+    }
+
+    #[test]
+    fn test_arrange_sequence() {
+        let sequence: BarSequence = serde_yaml::from_str(
+            r#"
+tempo: 100
+sequence:
+  - description: drill
+    order: shuffle
+    item: !Group
+      - !Single { pattern_name: a }
+      - !Single { pattern_name: b }
+      - !Single { pattern_name: c }
+      - !Single { pattern_name: d }
+      - !Single { pattern_name: e }
+      - !Single { pattern_name: f }
+  - description: backwards
+    order: retrograde
+    item: !RepeatGroup
+      - 2
+      - - !Single { pattern_name: a }
+        - !Single { pattern_name: b }
+"#,
+        ).unwrap();
+
+        let names = |sequence: &BarSequence, index: usize| -> Vec<String> {
+            match &sequence.sequence[index].item {
+                EBarSequence::Group(items) | EBarSequence::RepeatGroup(_, items) => items
+                    .iter()
+                    .map(|item| {
+                        let mut bars = Vec::new();
+                        collect_bars(std::slice::from_ref(item), &mut bars);
+                        bars[0].pattern_name.clone()
+                    })
+                    .collect(),
+                _ => unreachable!(),
+            }
+        };
+
+        let first = arrange_sequence(&sequence, 7);
+        assert_eq!(names(&arrange_sequence(&sequence, 7), 0), names(&first, 0));
+        let mut shuffled = names(&first, 0);
+        shuffled.sort();
+        assert_eq!(shuffled, vec!["a", "b", "c", "d", "e", "f"]);
+        assert!((0..20).any(|seed| names(&arrange_sequence(&sequence, seed), 0) != names(&first, 0)));
+
+        assert_eq!(names(&first, 1), vec!["b", "a"]);
+        assert_eq!(first.sequence[1].order, Order::Original);
+    }
+
+    #[test]
+    fn test_patterns_with_fewer_voices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("count.yml"), "description: count\nvoices:\n  - hh4 hh4 hh4 hh4\n").unwrap();
+        fs::write(
+            dir.path().join("groove.yml"),
+            "description: groove\nvoices:\n  - hh8*8 | %\n  - bd4 sn4 bd4 sn4 | %\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            r#"
+tempo: 100
+sequence:
+  - description: count
+    item: !Single { pattern_name: count }
+  - description: groove
+    item: !Single { pattern_name: groove }
+  - description: count again
+    item: !Single { pattern_name: count }
+"#,
+        ).unwrap();
+
+        let libraries = [dir.path().to_path_buf()];
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert!(ly.contains(r"\voiceTwo"));
+        assert_eq!(ly.matches("r1").count(), 2);
+
+        let html = strudel_of_sequence(&sequence, &libraries, &SequenceRenderOptions::default()).unwrap();
+        assert!(html.contains("$: stack("));
+    }
+
+    #[test]
+    fn test_pattern_with_unknown_drum() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("typo.yml"),
+            "description: typo\nvoices:\n  - hh8*8\n  - bd4 snn4 bd4 sn4\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100\nsequence:\n  - description: typo\n    item: !Single\n      pattern_name: typo\n",
+        ).unwrap();
+
+        let err = lilypond_of_sequence(&sequence, &[dir.path().to_path_buf()], dir.path()).unwrap_err();
+        assert!(err.contains("pattern 'typo', voice 2"), "{err}");
+        assert!(err.contains("`snn4`"), "{err}");
+    }
+
+    #[test]
+    fn test_staves_of_sequence_match_lilypond_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("count.yml"), "description: count\nvoices:\n  - hh4 hh4 hh4 hh4\n").unwrap();
+        fs::write(
+            dir.path().join("groove.yml"),
+            "description: groove\nvoices:\n  - hh8*8\n  - bd4 sn4 bd4 sn4\n",
+        ).unwrap();
+        fs::write(
+            dir.path().join("fill.yml"),
+            "description: fill\nvoices:\n  - cymc2 r2\n  - sn8*4 tomh8 tomh8 toml4\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            r#"
+tempo: 96
+sequence:
+  - description: count in
+    item: !Single { pattern_name: count }
+  - description: verse
+    item: !RepeatBar [3, { pattern_name: groove }]
+  - description: turnaround
+    item: !RepeatGroup
+      - 2
+      - - !Single { pattern_name: groove }
+        - !Single { pattern_name: fill }
+  - description: ending
+    item: !Group
+      - !Single { pattern_name: groove }
+      - !Single { pattern_name: fill }
+"#,
+        ).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        let (staves, tempo) = staves_of_sequence(&sequence, &libraries).unwrap();
+        assert_eq!(tempo.bpm, 96);
+        assert_eq!(staves[0].drum_voices().unwrap().len(), 2);
+
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        let parsed = LilyPondParser::new().parse(&ly).unwrap();
+        assert_eq!(
+            StrudelGenerator::generate_multi(&staves, &tempo),
+            StrudelGenerator::generate_multi(&parsed.staves, &parsed.tempo)
+        );
+    }
+
+    #[test]
+    fn test_rest_items() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("groove.yml"), "description: groove\nvoices:\n  - bd4 sn4 bd4 sn4\n  - hh8*8\n").unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            r#"
+tempo: 100
+sequence:
+  - description: groove
+    item: !Single { pattern_name: groove }
+  - description: break
+    item: !Rest 2
+  - description: again
+    item: !Group
+      - !Rest 1
+      - !Single { pattern_name: groove }
+"#,
+        ).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert!(ly.contains("% @strudel-of-lilypond@ comment break\n            R1*2\n"), "{ly}");
+        assert_eq!(ly.matches("R1*1").count(), 2);
+
+        // Same code whether built from the patterns or from the LilyPond text
+        let (staves, tempo) = staves_of_sequence(&sequence, &libraries).unwrap();
+        let parsed = LilyPondParser::new().parse(&ly).unwrap();
+        let code = StrudelGenerator::generate_multi(&staves, &tempo);
+        assert_eq!(code, StrudelGenerator::generate_multi(&parsed.staves, &parsed.tempo));
+        // Three empty cycles in each of the two voices
+        assert_eq!(code.lines().filter(|line| *line == "[~ ~ ~ ~]").count(), 6, "{code}");
+    }
+
+    #[test]
+    fn test_section_transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("groove.yml"), "description: groove\nvoices:\n  - bd4 sn4 bd4 sn4\n  - hh8*8\n").unwrap();
+        fs::write(dir.path().join("fill.yml"), "description: fill\nvoices:\n  - sn8*4 tomh8 tomh8 toml4\n").unwrap();
+        let yaml = r#"
+tempo: 100
+transitions:
+  fill: fill
+sequence:
+  - description: verse
+    item: !RepeatBar [2, { pattern_name: groove }]
+    transition: fill
+  - description: chorus
+    item: !Single { pattern_name: groove }
+    transition: fill
+"#;
+        let sequence: BarSequence = serde_yaml::from_str(yaml).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        // Between the sections only, the second voice resting through the fill
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert_eq!(ly.matches("comment verse (fill)").count(), 2, "{ly}");
+        assert!(!ly.contains("chorus (fill)"));
+        assert!(ly.contains("sn8 sn8 sn8 sn8 tomh8 tomh8 toml4"), "{ly}");
+        let (staves, tempo) = staves_of_sequence(&sequence, &libraries).unwrap();
+        let parsed = LilyPondParser::new().parse(&ly).unwrap();
+        assert_eq!(
+            StrudelGenerator::generate_multi(&staves, &tempo),
+            StrudelGenerator::generate_multi(&parsed.staves, &parsed.tempo)
+        );
+
+        let sequence: BarSequence = serde_yaml::from_str(&yaml.replace("transition: fill\n  - description: chorus", "transition: crash\n  - description: chorus")).unwrap();
+        let err = staves_of_sequence(&sequence, &libraries).unwrap_err();
+        assert_eq!(err, "section 'verse' has `transition: crash` but no `transitions: { crash: <pattern> }` is set");
+    }
+
+    #[test]
+    fn test_stems_of_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("groove.yml"),
+            "description: groove\nvoices:\n  - bd4 sn4 bd4 sn4\n  - hh8*8\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100\nsequence:\n  - description: groove\n    item: !RepeatBar\n      - 2\n      - pattern_name: groove\n",
+        ).unwrap();
+
+        let options = SequenceRenderOptions { title: "groove".to_string(), ..Default::default() };
+        let stems = strudel_stems_of_sequence(&sequence, &[dir.path().to_path_buf()], &options).unwrap();
+        let names: Vec<&str> = stems.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["bd-sd", "hh"]);
+        assert!(stems[0].1.contains("bd sd bd sd"));
+        assert!(!stems[0].1.contains("hh hh"));
+        assert!(stems[1].1.contains("[[hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5]]!2"));
+        assert!(!stems[1].1.contains("bd sd"));
+    }
+
+    #[test]
+    fn test_named_pattern_voices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("count.yml"), "description: count
+voices:
+  - hh4 hh4 hh4 hh4
+").unwrap();
+        fs::write(
+            dir.path().join("groove.yml"),
+            "description: groove
+voices:
+  - bd4 sn4 bd4 sn4
+  - hh8*8
+voice_names: [kick, hats]
+",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100
+sequence:
+  - description: count
+    item: !Single { pattern_name: count }
+  - description: groove
+    item: !Single { pattern_name: groove }
+",
+        ).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        // The first pattern has no names, the next one names both voices
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert!(ly.contains("\\new DrumVoice {\n          % kick\n          \\voiceOne"), "{ly}");
+        assert!(ly.contains("% hats\n          \\voiceTwo"), "{ly}");
+
+        let html = strudel_of_sequence(&sequence, &libraries, &SequenceRenderOptions::default()).unwrap();
+        assert!(html.contains("stack(\n  // kick\n  sound(`"), "{html}");
+        assert!(html.contains(",\n  // hats\n  sound(`"), "{html}");
+
+        let stems = strudel_stems_of_sequence(&sequence, &libraries, &SequenceRenderOptions::default()).unwrap();
+        let names: Vec<&str> = stems.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["kick", "hats"]);
+    }
+
+    #[test]
+    fn test_pattern_chords() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("count.yml"), "description: count\nvoices:\n  - hh4*4\n").unwrap();
+        fs::write(
+            dir.path().join("comping.yml"),
+            "description: comping\nvoices:\n  - bd4 sn4 bd4 sn4 | %\nchords: c1:m7 | f2 g2:7\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100
+sequence:
+  - description: count
+    item: !Single { pattern_name: count }
+  - description: verse
+    item: !RepeatBar [2, { pattern_name: comping }]
+",
+        ).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        // Chord names above the drums, skipping the bars without chords
+        let ly = lilypond_of_sequence(&sequence, &libraries, dir.path()).unwrap();
+        assert!(ly.contains("    \\new ChordNames {\n      \\chordmode {\n"), "{ly}");
+        assert!(ly.contains("        s1\n        |\n"), "{ly}");
+        assert!(ly.contains("        \\repeat volta 2 {\n          c1:m7 | f2 g2:7\n"), "{ly}");
+
+        let (staves, _) = staves_of_sequence(&sequence, &libraries).unwrap();
+        assert_eq!(staves.len(), 2);
+        assert_eq!(staves[1].name.as_deref(), Some("chords"));
+        let html = strudel_of_sequence(&sequence, &libraries, &SequenceRenderOptions::default()).unwrap();
+        assert!(html.contains("$: note(`\n[~ ~ ~ ~]\n[[[[c4,eb4,g4,bb4]@4]\n[[f4,a4,c5]@2 [g4,b4,d5,f5]@2]]!2]@4`)"), "{html}");
+
+        fs::write(dir.path().join("comping.yml"), "description: comping\nvoices:\n  - bd1\nchords: c1:m11\n").unwrap();
+        let err = staves_of_sequence(&sequence, &libraries).unwrap_err();
+        assert!(err.starts_with("pattern 'comping', chords: unknown chord modifier `m11`"), "{err}");
+    }
+
+    #[test]
+    fn test_sequence_render_options() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("groove.yml"),
+            "description: groove\nvoices:\n  - bd4 sn4 bd4 sn4\n  - hh8*8\n",
+        ).unwrap();
+        let sequence: BarSequence = serde_yaml::from_str(
+            "tempo: 100\nsequence:\n  - description: groove\n    item: !Single\n      pattern_name: groove\n",
+        ).unwrap();
+        let libraries = [dir.path().to_path_buf()];
+
+        let plain = strudel_of_sequence(&sequence, &libraries, &SequenceRenderOptions::default()).unwrap();
+        assert!(!plain.contains(".bank("));
+        assert!(!plain.contains("._punchcard()"));
+
+        let options = SequenceRenderOptions {
+            title: "Groove & fill".to_string(),
+            bank: Some("RolandTR909".to_string()),
+            gain: Some("0.8".to_string()),
+            punchcard: true,
+            ..Default::default()
+        };
+        let html = strudel_of_sequence(&sequence, &libraries, &options).unwrap();
+        assert!(html.contains("<title>Groove &amp; fill</title>"));
+        assert!(html.contains(".bank(\"RolandTR909\")"));
+        assert!(html.contains(".gain(0.8)"));
+        assert!(html.contains(".color(\"cyan\")"));
+
+        let options = SequenceRenderOptions {
+            title: "Groove".to_string(),
+            template: Some("<h1>{{title}}</h1><strudel-repl><!--\n{{code}}\n--></strudel-repl>".to_string()),
+            ..Default::default()
+        };
+        let html = strudel_of_sequence(&sequence, &libraries, &options).unwrap();
+        assert!(html.starts_with("<h1>Groove</h1><strudel-repl><!--\nconst tempo = 100"), "{html}");
+        assert!(html.contains("$: stack("));
+    }
 }
@@ -1,2 +1,5 @@
 pub mod model;
 pub mod lilypond;
+pub mod library;
+pub mod setlist;
+pub mod chords;
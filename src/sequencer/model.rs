@@ -1,14 +1,29 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Pattern {
     pub description: String,
     pub voices: Vec<String>,
+    /// Labels of the voices, in the same order (`kick`, `hats`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub voice_names: Vec<String>,
+    /// Chord symbols in `\chordmode` syntax (`c1:m7 | f1`), engraved as chord names and
+    /// played as a comping part
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chords: Option<String>,
+    /// Default values of the `${name}` placeholders used in the voices
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub params: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Bar {
     pub pattern_name: String,
+    /// Placeholder values overriding the pattern's defaults
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub params: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -17,16 +32,89 @@ pub enum EBarSequence {
     Group(Vec<EBarSequence>),
     RepeatBar(u32, Bar),
     RepeatGroup(u32, Vec<EBarSequence>),
+    /// Empty bars, without a pattern (`!Rest 2`)
+    Rest(u32),
+}
+
+/// Order in which the bars of a group are played
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Original,
+    /// Random order, reproducible with a seed
+    Shuffle,
+    /// Last bar first
+    Retrograde,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SequenceItem {
     pub item: EBarSequence,
     pub description: String,
+    #[serde(default, skip_serializing_if = "is_original")]
+    pub order: Order,
+    /// Bar inserted between this section and the next one
+    #[serde(default, skip_serializing_if = "is_no_transition")]
+    pub transition: Transition,
+}
+
+fn is_original(order: &Order) -> bool {
+    *order == Order::Original
+}
+
+/// Glue bar between two sections, from the patterns set in [`BarSequence::transitions`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    #[default]
+    None,
+    /// A fill bar
+    Fill,
+    /// A bar starting with a crash
+    Crash,
+}
+
+fn is_no_transition(transition: &Transition) -> bool {
+    *transition == Transition::None
+}
+
+/// Patterns played by the `transition` of the sections
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Transitions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash: Option<String>,
+}
+
+impl Transitions {
+    fn is_empty(&self) -> bool {
+        self.fill.is_none() && self.crash.is_none()
+    }
+}
+
+/// Where a shared pattern library is fetched from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LibrarySource {
+    /// A git repository, at a branch or tag (`rev`) or its default branch
+    Git {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rev: Option<String>,
+    },
+    /// A `.tar.gz` archive (a single top-level directory in it is left out)
+    Tarball { url: String },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BarSequence {
     pub tempo: u32,
     pub sequence: Vec<SequenceItem>,
+    /// Shared libraries by name, their patterns named `<library>/<pattern>` once fetched
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub libraries: BTreeMap<String, LibrarySource>,
+    /// Pattern names of the transitions
+    #[serde(default, skip_serializing_if = "Transitions::is_empty")]
+    pub transitions: Transitions,
 }
@@ -0,0 +1,213 @@
+//! Several sequences played one after the other: a timing report and a page with one player per song
+
+use std::path::PathBuf;
+
+use super::lilypond::{apply_render_options, staves_of_sequence, SequenceRenderOptions};
+use super::model::BarSequence;
+use crate::{escape_html, GeneratorOptions, ParseResult, StrudelGenerator, TimeSignature};
+
+/// A sequence of the setlist, with the library roots its patterns are searched in
+#[derive(Debug, Clone)]
+pub struct Song {
+    pub name: String,
+    pub sequence: BarSequence,
+    pub libraries: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetlistEntry {
+    pub song: String,
+    pub tempo: u32,
+    /// Bars as played, repeats included
+    pub bars: u32,
+    pub seconds: f64,
+}
+
+/// Timing of the songs of a setlist, in playing order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setlist {
+    pub entries: Vec<SetlistEntry>,
+}
+
+impl Setlist {
+    pub fn bars(&self) -> u32 {
+        self.entries.iter().map(|e| e.bars).sum()
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.entries.iter().map(|e| e.seconds).sum()
+    }
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl std::fmt::Display for Setlist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.entries.iter().map(|e| e.song.chars().count()).chain([5]).max().unwrap_or(5);
+        writeln!(f, "{:>3}  {:<width$}  {:>5}  {:>5}  {:>8}", "#", "song", "tempo", "bars", "duration")?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>3}  {:<width$}  {:>5}  {:>5}  {:>8}",
+                i + 1,
+                entry.song,
+                entry.tempo,
+                entry.bars,
+                format_seconds(entry.seconds)
+            )?;
+        }
+        write!(f, "{:>3}  {:<width$}  {:>5}  {:>5}  {:>8}", "", "total", "", self.bars(), format_seconds(self.seconds()))
+    }
+}
+
+/// Tempo, bars and playing time of every song
+pub fn setlist_of_songs(songs: &[Song]) -> Result<Setlist, String> {
+    let mut entries = Vec::new();
+    for song in songs {
+        let (staves, tempo) = staves_of_sequence(&song.sequence, &song.libraries).map_err(|e| format!("{}: {}", song.name, e))?;
+        let result = ParseResult {
+            staves,
+            tempo,
+            time: TimeSignature::default(),
+            header: Default::default(),
+            rubato: Vec::new(),
+            warnings: Vec::new(),
+        };
+        let summary = result.summary();
+        entries.push(SetlistEntry {
+            song: song.name.clone(),
+            tempo: song.sequence.tempo,
+            bars: summary.staves.iter().map(|s| s.bars).max().unwrap_or(0),
+            seconds: summary.seconds,
+        });
+    }
+    Ok(Setlist { entries })
+}
+
+/// One page with the setlist report and a player per song. The page title is `options.title`;
+/// `options.template` does not apply.
+pub fn setlist_html(songs: &[Song], options: &SequenceRenderOptions) -> Result<String, String> {
+    let setlist = setlist_of_songs(songs)?;
+
+    let mut rows = Vec::new();
+    let mut players = Vec::new();
+    for (i, (song, entry)) in songs.iter().zip(&setlist.entries).enumerate() {
+        let name = escape_html(&song.name);
+        let duration = format_seconds(entry.seconds);
+        rows.push(format!(
+            "      <tr><td>{}</td><td><a href=\"#song-{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            i + 1,
+            i + 1,
+            name,
+            entry.tempo,
+            entry.bars,
+            duration
+        ));
+
+        let (staves, tempo) = staves_of_sequence(&song.sequence, &song.libraries).map_err(|e| format!("{}: {}", song.name, e))?;
+        let (staves, generator) = apply_render_options(staves, options);
+        let code = StrudelGenerator::generate_multi_with_options(
+            &staves,
+            &tempo,
+            &GeneratorOptions { emit_prologue: true, ..generator },
+        );
+        players.push(format!(
+            "  <section id=\"song-{}\">\n    <h2>{}. {} ({})</h2>\n    <strudel-repl>\n<!--\n{}\n-->\n    </strudel-repl>\n  </section>\n",
+            i + 1,
+            i + 1,
+            name,
+            duration,
+            code
+        ));
+    }
+
+    let title = escape_html(&options.title);
+    Ok(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="UTF-8">
+  <title>{title}</title>
+  <script src="https://unpkg.com/@strudel/embed@latest"></script>
+  <style>
+    body {{ margin: 0; padding: 0 1em; font-family: sans-serif; }}
+    .setlist td, .setlist th {{ padding: 0.2em 0.8em; text-align: right; }}
+    .setlist td:nth-child(2), .setlist th:nth-child(2) {{ text-align: left; }}
+    strudel-repl {{ width: 100%; height: 60vh; display: block; }}
+    strudel-repl iframe {{ width: 100%; height: 100%; border: none; }}
+  </style>
+</head>
+<body>
+  <h1>{title}</h1>
+  <table class="setlist">
+    <thead>
+      <tr><th>#</th><th>song</th><th>tempo</th><th>bars</th><th>duration</th></tr>
+    </thead>
+    <tbody>
+{rows}
+    </tbody>
+    <tfoot>
+      <tr><td></td><td>total</td><td></td><td>{bars}</td><td>{total}</td></tr>
+    </tfoot>
+  </table>
+{players}</body>
+</html>"#,
+        rows = rows.join("\n"),
+        bars = setlist.bars(),
+        total = format_seconds(setlist.seconds()),
+        players = players.join(""),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_setlist_of_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib_dir = dir.path().join("library");
+        fs::create_dir_all(&lib_dir).unwrap();
+        fs::write(lib_dir.join("beat.yml"), "description: beat\nvoices:\n  - bd4 sn4 bd4 sn4\n").unwrap();
+
+        let song = |name: &str, yaml: &str| Song {
+            name: name.to_string(),
+            sequence: serde_yaml::from_str(yaml).unwrap(),
+            libraries: vec![dir.path().to_path_buf()],
+        };
+        let songs = vec![
+            song(
+                "opener",
+                "tempo: 120\nsequence:\n  - description: verse\n    item: !RepeatBar\n      - 4\n      - pattern_name: library/beat\n",
+            ),
+            song(
+                "ballad",
+                "tempo: 60\nsequence:\n  - description: intro\n    item: !Single\n      pattern_name: library/beat\n",
+            ),
+        ];
+
+        let setlist = setlist_of_songs(&songs).unwrap();
+        assert_eq!(
+            setlist.entries,
+            vec![
+                SetlistEntry { song: "opener".to_string(), tempo: 120, bars: 4, seconds: 8.0 },
+                SetlistEntry { song: "ballad".to_string(), tempo: 60, bars: 1, seconds: 4.0 },
+            ]
+        );
+        let report = setlist.to_string();
+        assert!(report.contains("  1  opener    120      4      0:08"), "{report}");
+        assert!(report.ends_with("     total              5      0:12"), "{report}");
+
+        let options = SequenceRenderOptions { title: "Friday".to_string(), ..Default::default() };
+        let html = setlist_html(&songs, &options).unwrap();
+        assert_eq!(html.matches("<strudel-repl>").count(), 2);
+        assert!(html.contains("<h2>2. ballad (0:04)</h2>"));
+
+        let missing = vec![song("broken", "tempo: 90\nsequence:\n  - description: x\n    item: !Single\n      pattern_name: library/nope\n")];
+        assert!(setlist_of_songs(&missing).unwrap_err().starts_with("broken: "));
+    }
+}
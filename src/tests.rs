@@ -64,6 +64,7 @@ fn test_generate_strudel() {
             duration: 4,
             midi: 60,
             chord_notes: None,
+            ..Default::default()
         },
     ];
 
@@ -92,6 +93,7 @@ fn test_generate_with_tempo() {
             duration: 4,
             midi: 60,
             chord_notes: None,
+            ..Default::default()
         },
     ];
     let tempo = Tempo { beat_unit: 4, bpm: 120 };
@@ -181,6 +183,7 @@ fn test_generate_multi_staff() {
             duration: 4,
             midi: 60,
             chord_notes: None,
+            ..Default::default()
         })]),
         Staff::new_pitched(vec![PitchedEvent::Note(Note {
             name: 'e',
@@ -189,6 +192,7 @@ fn test_generate_multi_staff() {
             duration: 4,
             midi: 64,
             chord_notes: None,
+            ..Default::default()
         })]),
     ];
 
@@ -230,12 +234,14 @@ mydrums = \drummode { bd4 hh4 sn4 hh4 }
 fn test_generate_drum_staff() {
     let voices = vec![DrumVoiceData {
         events: vec![
-            DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4 }),
-            DrumEvent::Hit(DrumHit { name: "hh".to_string(), duration: 4 }),
+            DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4, ..Default::default() }),
+            DrumEvent::Hit(DrumHit { name: "hh".to_string(), duration: 4, ..Default::default() }),
         ],
         punchcard_color: None,
         gain: None,
         pan: None,
+        speed: None,
+        name: None,
     }];
 
     let strudel = StrudelGenerator::generate_drum_staff(&voices, &DEFAULT_TEMPO);
@@ -282,16 +288,20 @@ hats = \drummode { hh8 hh8 hh8 hh8 }
 fn test_generate_multi_voice_drum_staff() {
     let voices = vec![
         DrumVoiceData {
-            events: vec![DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4 })],
+            events: vec![DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4, ..Default::default() })],
             punchcard_color: None,
             gain: None,
             pan: None,
+            speed: None,
+            name: None,
         },
         DrumVoiceData {
-            events: vec![DrumEvent::Hit(DrumHit { name: "hh".to_string(), duration: 8 })],
+            events: vec![DrumEvent::Hit(DrumHit { name: "hh".to_string(), duration: 8, ..Default::default() })],
             punchcard_color: None,
             gain: None,
             pan: None,
+            speed: None,
+            name: None,
         },
     ];
 
@@ -333,12 +343,15 @@ fn test_generate_mixed_staves() {
             duration: 4,
             midi: 60,
             chord_notes: None,
+            ..Default::default()
         })]),
         Staff::new_drums(vec![DrumVoiceData {
-            events: vec![DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4 })],
+            events: vec![DrumEvent::Hit(DrumHit { name: "bd".to_string(), duration: 4, ..Default::default() })],
             punchcard_color: None,
             gain: None,
             pan: None,
+            speed: None,
+            name: None,
         }]),
     ];
 
@@ -387,6 +400,7 @@ fn test_generate_chord() {
                     duration: 4,
                     midi: 48,
                     chord_notes: None,
+                    ..Default::default()
                 },
                 Note {
                     name: 'e',
@@ -395,8 +409,10 @@ fn test_generate_chord() {
                     duration: 4,
                     midi: 52,
                     chord_notes: None,
+                    ..Default::default()
                 },
             ]),
+            ..Default::default()
         },
     ];
 
@@ -580,3 +596,2254 @@ fn test_include_mytempo() {
     assert!(result.contains("\\tempo 4 = 120"));
 }
 
+
+#[test]
+fn test_midi_numbers_option() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'4 e'4 <c' e' g'>2 }"#;
+    let result = parser.parse(code).unwrap();
+
+    let options = GeneratorOptions { midi_numbers: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[60 64 [60,64,67]@2]"));
+    assert!(!strudel.contains("c4"));
+}
+
+#[test]
+fn test_octave_shift_magic_comment() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+bass = { c4 <e g>4 }
+
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ octave -1
+      \bass
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.staves[0].octave_shift, -1);
+    // Parsed notes keep their written octave
+    assert_eq!(result.notes()[0].octave, 3);
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c2 [e2,g2]]"));
+
+    let options = GeneratorOptions { midi_numbers: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[36 [40,43]]"));
+}
+
+#[test]
+fn test_weight_style_fraction() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = { c'8 d'8 e'2 f'16 }
+drums = \drummode { bd8 sn16 hh1 }
+
+\score {
+  <<
+    \new Staff { \melody }
+    \new DrumStaff { \drums }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let options = GeneratorOptions { weight_style: WeightStyle::Fraction, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[c4@1/2 d4@1/2 e4@2 f4@1/4]"));
+    assert!(strudel.contains("[bd@1/2 sd@1/4 hh@4]"));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@0.5 d4@0.5 e4@2 f4@0.25]"));
+
+    // A third has no exact decimal
+    let result = parser.parse("\\tempo 4 = 120\n{ \\tuplet 3/2 { c'8 d' e'4 } f'2. }").unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@1/3 d4@1/3 e4@2/3 f4@3]"), "{strudel}");
+}
+
+#[test]
+fn test_tempo_name_and_prologue() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 90
+    { c'4 d'4 | e'4 f'4 }"#;
+    let result = parser.parse(code).unwrap();
+
+    // Default: no prologue in plain output
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(!strudel.contains("const tempo"));
+
+    let options = GeneratorOptions {
+        tempo_name: "bpm".to_string(),
+        emit_prologue: true,
+        ..Default::default()
+    };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.starts_with("const bpm = 90;\nconst nbars = 2;\n\n$: "));
+    assert!(strudel.contains(".cpm(bpm/4/nbars)"));
+
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "t", &options);
+    assert!(html.contains("const bpm = 90;"));
+    assert_eq!(html.matches("const bpm").count(), 1);
+}
+
+#[test]
+fn test_html_staff_panels() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = { c'4 d'4 }
+drums = \drummode { bd4 sn4 }
+
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ red punchcard
+      \melody
+    }
+    \new DrumStaff { \drums }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let html = StrudelGenerator::generate_html(&result.staves, &result.tempo, "t");
+    assert!(!html.contains("staff-panel"));
+
+    let options = GeneratorOptions { staff_panels: true, ..Default::default() };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "t", &options);
+    assert_eq!(html.matches("<details class=\"staff-panel\">").count(), 2);
+    assert!(html.contains(r#"<span class="swatch" style="background: red"></span>Staff 1"#));
+    assert!(html.contains("DrumStaff 2"));
+    assert!(html.contains("copy pattern"));
+    // The REPL still receives the full program
+    assert!(html.contains("<strudel-repl>\n<!--\nconst tempo = 120;"));
+}
+
+#[test]
+fn test_html_transport_controls() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 100
+    {
+      % @strudel-of-lilypond@ comment intro
+      c'4 d'4 | e'4 f'4 |
+      % @strudel-of-lilypond@ comment verse
+      g'4 a'4 | b'4 c''4 | d''1
+    }"#;
+    let result = parser.parse(code).unwrap();
+
+    let options = GeneratorOptions { transport_controls: true, ..Default::default() };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "t", &options);
+    assert!(html.contains("@strudel/repl"));
+    assert!(html.contains("<strudel-editor>"));
+    assert!(html.contains(r#"id="transport-tempo" type="range" min="20" max="300" value="100""#));
+    assert!(html.contains(r#"<option value="1-5">Whole piece</option>"#));
+    assert!(html.contains(r#"<option value="1-2">intro (bars 1-2)</option>"#));
+    assert!(html.contains(r#"<option value="3-5">verse (bars 3-5)</option>"#));
+    assert!(html.contains(r#"const source = "const tempo = 100;\nconst nbars = 5;"#));
+}
+
+#[test]
+fn test_html_theme_and_css() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'4 }"#;
+    let result = parser.parse(code).unwrap();
+
+    let html = StrudelGenerator::generate_html(&result.staves, &result.tempo, "t");
+    assert!(!html.contains("color-scheme"));
+
+    let options = GeneratorOptions {
+        theme: Some("dark".parse().unwrap()),
+        css: Some("h1 { font-size: 3em; }\n".to_string()),
+        ..Default::default()
+    };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "t", &options);
+    assert!(html.contains("color-scheme: dark;"));
+    assert!(html.contains("h1 { font-size: 3em; }\n  </style>"));
+
+    assert!("blue".parse::<Theme>().is_err());
+}
+
+#[test]
+fn test_parse_header_and_html_metadata() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\header {
+  title = "Blue & Green"
+  composer = "Someone"
+  subtitle = "A study"
+}
+\tempo 4 = 120
+melody = { c'4 }
+\score { << \new Staff { \melody } >> }
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.header.get("title").map(String::as_str), Some("Blue & Green"));
+    assert_eq!(result.header.get("composer").map(String::as_str), Some("Someone"));
+
+    let options = GeneratorOptions {
+        author: result.header.get("composer").cloned(),
+        description: result.header.get("subtitle").cloned(),
+        show_heading: true,
+        ..Default::default()
+    };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "Blue & Green", &options);
+    assert!(html.contains("<title>Blue &amp; Green</title>"));
+    assert!(html.contains(r#"<meta property="og:title" content="Blue &amp; Green">"#));
+    assert!(html.contains(r#"<meta name="author" content="Someone">"#));
+    assert!(html.contains(r#"<meta property="og:description" content="A study">"#));
+    assert!(html.contains("<h1>Blue &amp; Green</h1>"));
+}
+
+#[test]
+fn test_multiple_drum_staves_in_staff_group() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new StaffGroup <<
+      \new DrumStaff <<
+        \new DrumVoice { \drummode { bd4 sn4 } }
+        \new DrumVoice { \drummode { hh8 hh8 hh8 hh8 } }
+      >>
+      \new DrumStaff <<
+        \new DrumVoice { \drummode { cb4 cb4 } }
+      >>
+    >>
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.staves.len(), 2);
+    assert_eq!(result.staves[0].drum_voices().unwrap().len(), 2);
+    assert_eq!(result.staves[1].drum_voices().unwrap().len(), 1);
+    match &result.staves[1].drum_voices().unwrap()[0].events[0] {
+        DrumEvent::Hit(h) => assert_eq!(h.name, "cb"),
+        other => panic!("expected a hit, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_sibling_simultaneous_groups() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff {
+      <<
+        \new DrumVoice { \drummode { bd4 bd4 } }
+        \new DrumVoice { \drummode { hh4 hh4 } }
+      >>
+      <<
+        \new DrumVoice { \drummode { sn4 sn4 } }
+        \new DrumVoice { \drummode { cymc4 r4 } }
+      >>
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.staves.len(), 1);
+    let voices = result.staves[0].drum_voices().unwrap();
+    // Successive groups continue the same two voices instead of being merged into one span
+    assert_eq!(voices.len(), 2);
+
+    let strudel = StrudelGenerator::generate_drum_staff(voices, &result.tempo);
+    assert!(strudel.contains("[bd bd]\n[sd sd]"));
+    assert!(strudel.contains("[hh hh]\n[cr ~]"));
+}
+
+#[test]
+fn test_tokenize_simultaneous_groups() {
+    let parser = LilyPondParser::new();
+    let tokens = parser.tokenize("c4 << { d4 } \\\\ { <e g>4 } >> f4\\< g4\\!");
+    assert_eq!(
+        tokens,
+        vec!["c4", "<<", "{", "d4", "}", "\\\\", "{", "<e g>4", "}", ">>", "f4\\<", "g4\\!"]
+    );
+
+    // Resolved content with a << >> group keeps its chords and notes intact
+    let code = r#"\tempo 4 = 120
+    { c'4 << { <e' g'>4 } >> d'4 }"#;
+    let result = parser.parse(code).unwrap();
+    let notes = result.notes();
+    assert_eq!(notes.len(), 3);
+    assert_eq!(notes[1].chord_notes.as_ref().unwrap().len(), 1);
+    assert_eq!(notes[2].name, 'd');
+}
+
+#[test]
+fn test_chord_ties_and_dots() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { <c' e' g'>2.~ <c' e' g'>4 | <c'~ e'~>4 <c' e'>4 c'4. r8 }"#;
+    let result = parser.parse(code).unwrap();
+
+    let notes = result.notes();
+    // The tied chords in the first bar and in the second bar each merge into one chord
+    assert_eq!(notes.len(), 3);
+    assert_eq!(notes[0].quarters(), Length::new(4, 1));
+    assert_eq!(notes[1].quarters(), Length::new(2, 1));
+    assert_eq!(notes[2].quarters(), Length::new(3, 2));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[[c4,e4,g4]@4]"));
+    assert!(strudel.contains("[[c4,e4]@2 c4@1.5 ~@0.5]"));
+}
+
+#[test]
+fn test_duration_inheritance() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = { c'8 d' e'4. f' }
+groove = \drummode { bd8 hh sn4 r bd16 bd }
+
+\score {
+  <<
+    \new Staff { \melody }
+    \new DrumStaff { \groove }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let durations: Vec<u32> = result.notes().iter().map(|n| n.duration).collect();
+    assert_eq!(durations, vec![8, 8, 4, 4]);
+    assert_eq!(result.notes()[3].quarters(), Length::new(3, 2));
+
+    let voices = result.staves[1].drum_voices().unwrap();
+    let durations: Vec<u32> = voices[0].events.iter().filter_map(|e| match e {
+        DrumEvent::Hit(h) => Some(h.duration),
+        DrumEvent::Rest { duration, .. } => Some(*duration),
+        _ => None,
+    }).collect();
+    assert_eq!(durations, vec![8, 8, 4, 4, 16, 16]);
+}
+
+#[test]
+fn test_drum_hits_with_articulations() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+groove = \drummode { sn4-> hh8^"R L" hh8 bd4\ff sn4-.( }
+
+\score {
+  <<
+    \new DrumStaff { \groove }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let voices = result.staves[0].drum_voices().unwrap();
+    let hits: Vec<_> = voices[0].events.iter().filter_map(|e| match e {
+        DrumEvent::Hit(h) => Some(h),
+        _ => None,
+    }).collect();
+    assert_eq!(hits.len(), 5);
+    assert_eq!(hits[0].articulations, vec!["accent"]);
+    assert_eq!(hits[1].name, "hh");
+    assert_eq!(hits[3].dynamic.as_deref(), Some("ff"));
+    assert_eq!(hits[4].articulations, vec!["staccato"]);
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[sd hh@0.5 hh@0.5 bd sd]"));
+}
+
+#[test]
+fn test_notes_with_attachments() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'4^"solo" d'4-1 e'4(\p f'4) <c' e'>4-> g'4?? }"#;
+    let result = parser.parse(code).unwrap();
+
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['c', 'd', 'e', 'f', 'c', 'g']);
+
+    assert_eq!(result.warnings.len(), 1);
+    assert!(result.warnings[0].message.contains("g'4??"));
+}
+
+#[test]
+fn test_cadenza_free_bar() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'1 | \cadenzaOn d'4 e' f' | g' a' b' c'' \cadenzaOff | c'1 }"#;
+    let result = parser.parse(code).unwrap();
+
+    let strudel = StrudelGenerator::generate_multi_with_options(
+        &result.staves,
+        &result.tempo,
+        &GeneratorOptions { emit_prologue: true, ..Default::default() },
+    );
+    // 7 quarters stretched over two bars, padded with a quarter rest
+    assert!(strudel.contains("[d4 e4 f4 g4 a4 b4 c5 ~]@2"));
+    assert!(strudel.contains("const nbars = 4;"));
+}
+
+#[test]
+fn test_bar_types_and_double_bar_sections() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'1 | d'1 \bar "||" e'2 \bar "" e'2 | f'1 \bar "|." }"#;
+    let result = parser.parse(code).unwrap();
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@4]\n[d4@4]\n[e4@2 e4@2]\n[f4@4]"));
+
+    let sections = StrudelGenerator::sections(&result.staves, 4);
+    assert_eq!(
+        sections,
+        vec![("Section 1".to_string(), 1, 2), ("Section 2".to_string(), 3, 4)]
+    );
+    assert!(result.warnings.is_empty());
+}
+
+#[test]
+fn test_repeat_delimiter_errors() {
+    let parser = LilyPondParser::new();
+
+    // A single music expression as body
+    let code = r#"\tempo 4 = 120
+    { \repeat unfold 4 c'8 \repeat unfold 2{ d'4 } }"#;
+    let result = parser.parse(code).unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[[c4@0.5]]!4"));
+    assert!(strudel.contains("[[d4]]!2"));
+
+    let code = "\\tempo 4 = 120\n{\n  c'4\n  \\repeat unfold 2 { d'4 e'4\n";
+    let err = parser.parse(code).unwrap_err();
+    assert!(err.contains("Line 4"), "{err}");
+    assert!(err.contains("closing `}`"), "{err}");
+
+    let code = "\\tempo 4 = 120\n{ \\repeat volta x { c'4 } }";
+    let err = parser.parse(code).unwrap_err();
+    assert!(err.contains("Line 2") && err.contains("`x`"), "{err}");
+}
+
+#[test]
+fn test_unfold_repeats() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+    { c'1 | \repeat volta 2 { d'2 e'2 | \repeat unfold 2 { f'1 } } }"#;
+    let mut result = parser.parse(code).unwrap();
+    result.unfold_repeats();
+
+    let events = result.staves[0].events().unwrap();
+    assert!(!events.iter().any(|e| matches!(e, PitchedEvent::RepeatStart(_) | PitchedEvent::RepeatEnd)));
+    let names: String = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, "cdeffdeff");
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@4]\n[d4@2 e4@2]\n[f4@4]\n[f4@4]\n[d4@2 e4@2]"));
+
+    // \unfoldRepeats in the input has the same effect
+    let code = r#"\tempo 4 = 120
+    \unfoldRepeats { \repeat volta 2 { c'1 } }"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.notes().len(), 2);
+}
+
+#[test]
+fn test_slow_fast_magic_comments() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = { c'1 }
+kicks = \drummode { bd4 bd4 bd4 bd4 }
+hats = \drummode { hh8 hh8 hh8 hh8 hh8 hh8 hh8 hh8 }
+
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ slow 2
+      \melody
+    }
+    \new DrumStaff {
+      <<
+        \new DrumVoice { \kicks }
+        \new DrumVoice {
+          % @strudel-of-lilypond@ fast 2
+          \hats
+        }
+      >>
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.staves[0].speed, Some(Speed::Slow("2".to_string())));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("note(`\n[c4@4]`)\n.slow(2)"));
+    assert!(strudel.contains("hh@0.5]`)\n  .fast(2)"));
+}
+
+#[test]
+fn test_voice_palette() {
+    let voice = |name: &str| DrumVoiceData {
+        events: vec![DrumEvent::Hit(DrumHit { name: name.to_string(), duration: 4, ..Default::default() })],
+        punchcard_color: None,
+        gain: None,
+        pan: None,
+        speed: None,
+        name: None,
+    };
+    let staves = vec![Staff::new_drums(vec![voice("bd"), voice("sd"), voice("hh")])];
+
+    // No palette: voices stay uncolored
+    let strudel = StrudelGenerator::generate_multi(&staves, &DEFAULT_TEMPO);
+    assert!(!strudel.contains(".color("));
+
+    let options = GeneratorOptions {
+        voice_palette: Some(vec!["red".to_string(), "blue".to_string()]),
+        ..Default::default()
+    };
+    let strudel = StrudelGenerator::generate_multi_with_options(&staves, &DEFAULT_TEMPO, &options);
+    assert!(strudel.contains("sound(`\n[bd]`)\n  .color(\"red\")\n  ._punchcard()"));
+    assert!(strudel.contains("sound(`\n[sd]`)\n  .color(\"blue\")"));
+    assert!(strudel.contains("sound(`\n[hh]`)\n  .color(\"red\")"));
+
+    // An explicit color on any voice disables the palette
+    let mut colored = vec![voice("bd"), voice("sd")];
+    colored[1].punchcard_color = Some("green".to_string());
+    let strudel = StrudelGenerator::generate_multi_with_options(&[Staff::new_drums(colored)], &DEFAULT_TEMPO, &options);
+    assert!(!strudel.contains("\"red\""));
+    assert!(strudel.contains(".color(\"green\")"));
+}
+
+#[test]
+fn test_section_labels() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = {
+  c'1 |
+  % @strudel-of-lilypond@ comment Verse one
+  d'1 | e'1 |
+  % @strudel-of-lilypond@ comment Chorus "B" x@2
+  f'1
+}
+groove = \drummode { bd1 | sn1 \bar "||" hh1 | hh1 }
+
+\score {
+  <<
+    \new Staff { \melody }
+    \new DrumStaff {
+      <<
+        \new DrumVoice { \groove }
+        \new DrumVoice { \groove }
+      >>
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(!strudel.contains(".label("));
+
+    let options = GeneratorOptions { section_labels: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("\n.label(\"start@1 Verse_one@2 Chorus_B_x2@1\")\n  .s(\"piano\")"), "{strudel}");
+    assert!(strudel.contains("\n)\n  .label(\"Section_1@2 Section_2@2\")\n  .cpm("));
+}
+
+#[test]
+fn test_empty_staves_option() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'1 }
+    \new Staff { r1 }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("$: // No notes to convert"));
+
+    let options = GeneratorOptions { empty_staves: EmptyStaves::Drop, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert_eq!(strudel.matches("$:").count(), 1);
+
+    let options = GeneratorOptions { empty_staves: "silence".parse().unwrap(), ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.ends_with("$: silence // Staff 2"));
+}
+
+#[test]
+fn test_variables_with_wrapper_commands() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+melody = \relative c'' { c4 d e f }
+drumsUp = \drummode \relative { hh8 hh hh hh hh hh hh hh }
+drumsDown = \new DrumVoice = "down" \drummode { bd4 sn bd sn }
+title = \markup { Not music }
+
+\score {
+  <<
+    \new Staff { \melody }
+    \new DrumStaff {
+      <<
+        \new DrumVoice { \drumsUp }
+        \new DrumVoice { \drumsDown }
+      >>
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    assert_eq!(result.staves.len(), 2);
+    assert_eq!(result.notes().len(), 4);
+    let voices = result.staves[1].drum_voices().unwrap();
+    assert_eq!(voices.len(), 2);
+    assert!(matches!(&voices[1].events[0], DrumEvent::Hit(h) if h.name == "bd"));
+}
+
+#[test]
+fn test_context_names_and_with_blocks() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff = "lead" \with { instrumentName = "Lead" } { c'4 d'4 }
+    \new Staff = "bass" { c4 d4 }
+    \new DrumStaff \with { \consists "Instrument_name_engraver" } {
+      \set Staff.instrumentName = #"Kit"
+      \drummode { bd4 sn4 }
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    assert_eq!(result.staves.len(), 3);
+    assert_eq!(result.staves[0].name.as_deref(), Some("lead"));
+    assert_eq!(result.staves[0].instrument.as_deref(), Some("Lead"));
+    assert_eq!(result.staves[1].name.as_deref(), Some("bass"));
+    assert_eq!(result.staves[1].instrument, None);
+    assert_eq!(result.staves[2].instrument.as_deref(), Some("Kit"));
+
+    let options = GeneratorOptions { empty_staves: EmptyStaves::Silence, ..Default::default() };
+    let staves = vec![Staff { name: Some("cue".to_string()), ..Staff::new_pitched(vec![]) }];
+    let strudel = StrudelGenerator::generate_multi_with_options(&staves, &result.tempo, &options);
+    assert_eq!(strudel, "$: silence // cue");
+}
+
+#[test]
+fn test_partcombine_two_parts() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+flute = { e''4 f'' g'' a'' }
+oboe = { c''4 d'' e'' f'' }
+
+\score {
+  <<
+    \new Staff \partcombine \flute \oboe
+    \new Staff = "winds" {
+      % @strudel-of-lilypond@ gain 0.5
+      \partCombine \flute { g'1 }
+    }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+
+    assert_eq!(result.staves.len(), 4);
+    let first: Vec<char> = result.staves[0].events().unwrap().iter().filter_map(|e| match e {
+        PitchedEvent::Note(n) => Some(n.name),
+        _ => None,
+    }).collect();
+    assert_eq!(first, vec!['e', 'f', 'g', 'a']);
+    assert_eq!(result.staves[3].name.as_deref(), Some("winds"));
+    assert_eq!(result.staves[3].gain.as_deref(), Some("0.5"));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c5 d5 e5 f5]"));
+    assert!(strudel.contains("[g4@4]"));
+}
+
+#[test]
+fn test_score_layout_and_midi_blocks() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\score {
+  \new Staff { c'4 d' e' f' }
+  \layout {
+    \context { \Staff \remove "Time_signature_engraver" }
+  }
+  \midi { \tempo 4 = 90 }
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.tempo.bpm, 90);
+    assert_eq!(result.staves.len(), 1);
+    assert_eq!(result.staves[0].events().unwrap().len(), 4);
+
+    // Fallback brace extraction is not fooled by a trailing layout block
+    let code = "\\tempo 4 = 120\n{ c'1 }\n\\layout { indent = 0 }\n";
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.tempo.bpm, 120);
+    assert_eq!(result.staves[0].events().unwrap().len(), 1);
+
+    // A top-level tempo wins over the midi one; the older context setting is understood
+    let code = r#"
+\tempo 4 = 100
+\score {
+  { c'1 }
+  \midi { \context { \Score tempoWholesPerMinute = #(ly:make-moment 72 4) } }
+}
+"#;
+    assert_eq!(parser.parse(code).unwrap().tempo.bpm, 100);
+    let code = "\\score { { c'1 } \\midi { \\context { \\Score tempoWholesPerMinute = #(ly:make-moment 72 4) } } }";
+    let tempo = parser.parse(code).unwrap().tempo;
+    assert_eq!((tempo.beat_unit, tempo.bpm), (4, 72));
+}
+
+#[test]
+fn test_book_with_bookparts() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 100
+\header { title = "Suite" composer = "Anon" }
+theme = { c'4 d' e' f' }
+
+\book {
+  \bookpart {
+    \header { title = "Prelude" }
+    \score { << \new Staff { \theme } >> }
+  }
+  \bookpart {
+    \header { title = "Gigue" }
+    \score {
+      << \new Staff { g'2 a' } >>
+      \header { subtitle = "Fast" }
+    }
+  }
+}
+"#;
+    let results = parser.parse_book(code).unwrap();
+    assert_eq!(results.len(), 2);
+
+    assert_eq!(results[0].header.get("title").map(String::as_str), Some("Prelude"));
+    assert_eq!(results[0].header.get("composer").map(String::as_str), Some("Anon"));
+    assert_eq!(results[0].notes().len(), 4);
+    assert_eq!(results[0].tempo.bpm, 100);
+
+    assert_eq!(results[1].header.get("title").map(String::as_str), Some("Gigue"));
+    assert_eq!(results[1].header.get("subtitle").map(String::as_str), Some("Fast"));
+    assert_eq!(results[1].notes().len(), 2);
+
+    // A single score parses like parse()
+    let single = "\\tempo 4 = 100\n\\score { << \\new Staff { c'1 } >> }";
+    assert_eq!(parser.parse_book(single).unwrap().len(), 1);
+}
+
+#[test]
+fn test_staves_keep_score_order() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff = "lead" { c'4 d' e' f' }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 sn4 } }
+    \new Staff = "bass" { c4 c c c }
+  >>
+}
+"#;
+    let result = parser.parse(code).unwrap();
+    assert_eq!(result.staves.len(), 3);
+    assert_eq!(result.staves[0].name.as_deref(), Some("lead"));
+    assert!(matches!(result.staves[1].kind, StaffKind::Drums));
+    assert_eq!(result.staves[2].name.as_deref(), Some("bass"));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    let lead = strudel.find("c4 d4 e4 f4").unwrap();
+    let drums = strudel.find("bd sd bd sd").unwrap();
+    let bass = strudel.find("c3 c3 c3 c3").unwrap();
+    assert!(lead < drums && drums < bass);
+}
+
+#[test]
+fn test_summary() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff \with { instrumentName = "Piano" } { c'4 <e' g'>4 b8 b8 c''2 | \repeat volta 2 { d'1 } }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 sn4 | bd1 } }
+  >>
+}
+"#;
+    let summary = parser.parse(code).unwrap().summary();
+    assert_eq!(summary.staves.len(), 2);
+
+    let piano = &summary.staves[0];
+    assert_eq!(piano.label, "Piano");
+    assert_eq!(piano.notes, 6);
+    assert_eq!(piano.bars, 3);
+    assert_eq!(piano.range, Some((59, 72)));
+    assert_eq!(piano.durations.get(&8), Some(&2));
+    assert_eq!(piano.durations.get(&4), Some(&2));
+
+    let drums = &summary.staves[1];
+    assert_eq!(drums.notes, 5);
+    assert_eq!(drums.bars, 2);
+    assert_eq!(drums.range, None);
+
+    // 3 bars of 4 beats at 120 BPM
+    assert!((summary.seconds - 6.0).abs() < 1e-9);
+    let text = summary.to_string();
+    assert!(text.contains("Piano: 6 notes, 3 bars, range b3-c5"), "{text}");
+    assert!(text.ends_with("Length: 0:06"), "{text}");
+}
+
+#[test]
+fn test_diff_scores() {
+    use crate::diff::{diff_scores, Difference};
+
+    let parser = LilyPondParser::new();
+    let old = parser.parse(r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff = "lead" { c'4 d' e' f' | g'1 | a'2 g' }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 sn4 } }
+  >>
+}
+"#).unwrap();
+    let new = parser.parse(r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff = "lead" { c'4 d' e' f' | b2 c'2 | g'1 | a'2 g'4 f' }
+  >>
+}
+"#).unwrap();
+
+    let differences = diff_scores(&old, &new);
+    assert_eq!(
+        differences,
+        vec![
+            Difference::BarAdded {
+                staff: "lead".to_string(),
+                bar: 2,
+                added: vec!["b3:2".to_string(), "c4:2".to_string()],
+            },
+            Difference::BarChanged {
+                staff: "lead".to_string(),
+                old_bar: 3,
+                new_bar: 4,
+                removed: vec!["g4:2".to_string()],
+                added: vec!["g4:1".to_string(), "f4:1".to_string()],
+            },
+            Difference::StaffRemoved { staff: "DrumStaff 2".to_string() },
+        ]
+    );
+    assert_eq!(differences[0].to_string(), "lead, bar 2 added: +b3:2 +c4:2");
+    assert_eq!(differences[1].to_string(), "lead, bar 4 (was 3): -g4:2 +g4:1 +f4:1");
+    assert!(diff_scores(&old, &old).is_empty());
+}
+
+#[test]
+fn test_transpose() {
+    let parser = LilyPondParser::new();
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4 f' b' <ees' g' bes'>4 }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 sn4 } }
+  >>
+}
+"#;
+    let mut result = parser.parse(code).unwrap();
+    result.transpose(2);
+
+    let notes = result.notes();
+    let names: Vec<String> = notes
+        .iter()
+        .map(|n| format!("{}{}{}", n.name, n.accidental.as_deref().unwrap_or(""), n.octave))
+        .collect();
+    assert_eq!(names, vec!["d4", "g4", "cis5", "f4"]);
+    assert_eq!(notes[2].midi, 73);
+    let chord: Vec<i32> = notes[3].chord_notes.as_ref().unwrap().iter().map(|n| n.midi).collect();
+    assert_eq!(chord, vec![69, 72]);
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("d4 g4 c#5 [f4,a4,c5]"), "{strudel}");
+    assert!(strudel.contains("bd sd bd sd"));
+
+    // Down a minor third; a tritone up from f is spelled b; the round trip restores the notes
+    let mut down = parser.parse(code).unwrap();
+    down.transpose(-3);
+    let first = &down.notes()[0];
+    assert_eq!((first.name, first.accidental.as_deref(), first.octave, first.midi), ('a', None, 3, 57));
+    let f = parser.parse(code).unwrap().notes()[1].transpose(6);
+    assert_eq!((f.name, f.accidental.as_deref(), f.octave), ('b', None, 4));
+    down.transpose(3);
+    assert_eq!(down.notes()[2].midi, 71);
+}
+
+#[test]
+fn test_quantize() {
+    let note = |name: char, num: u32, den: u32| {
+        PitchedEvent::Note(Note {
+            name,
+            octave: 4,
+            duration: 4,
+            midi: 60,
+            length: Some(Length::new(num, den)),
+            ..Default::default()
+        })
+    };
+    // Sloppy lengths (in quarters) of a 4/4 bar: 0.9 + 1.15 + 0.45 + 0.02 + 1.48
+    let events = vec![
+        note('c', 9, 10),
+        note('d', 23, 20),
+        note('e', 9, 20),
+        note('f', 1, 50),
+        PitchedEvent::Rest { duration: 4, length: Some(Length::new(37, 25)) },
+        PitchedEvent::BarLine,
+        note('g', 13, 10),
+    ];
+    let mut staff = Staff::new_pitched(events.clone());
+    assert!(staff.quantize(16).is_empty());
+
+    let lengths: Vec<(Option<char>, Length, u32)> = staff
+        .events()
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            PitchedEvent::Note(n) => Some((Some(n.name), n.quarters(), n.duration)),
+            PitchedEvent::Rest { duration, length } => {
+                Some((None, length.unwrap_or(Length::of_duration(*duration, 0)), *duration))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        lengths,
+        vec![
+            (Some('c'), Length::new(1, 1), 4),
+            (Some('d'), Length::new(1, 1), 4),
+            // f, rounded to nothing, takes a step from e
+            (Some('e'), Length::new(1, 4), 16),
+            (Some('f'), Length::new(1, 4), 16),
+            (None, Length::new(3, 2), 4),
+            (Some('g'), Length::new(5, 4), 4),
+        ]
+    );
+
+    let tempo = Tempo { beat_unit: 4, bpm: 120 };
+    let strudel = StrudelGenerator::generate_staff(&staff, &tempo);
+    // The rest after the eighth is split on the beat
+    assert!(strudel.contains("[c4 d4 e4@0.25 f4@0.25 ~@0.5 ~]\n[g4@1.25]"), "{strudel}");
+
+    // Five notes for the four steps of a bar at quarter steps: one has to go, with a warning
+    let fifths: Vec<PitchedEvent> = "cdefg".chars().map(|name| note(name, 4, 5)).collect();
+    let mut staff = Staff::new_pitched(fifths);
+    let warnings = staff.quantize(4);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].bar, Some(1));
+    assert_eq!(staff.events().unwrap().len(), 4);
+
+    // Grids finer than the finest one are read as it, without overflowing
+    let mut staff = Staff::new_pitched(events);
+    assert!(staff.quantize(u32::MAX).is_empty());
+    assert_eq!(staff.events().unwrap().len(), 7);
+}
+
+#[test]
+fn test_entry_variable_and_section() {
+    let input = r#"
+\tempo 4 = 100
+melodyA = { c'4 d' e' f' | g'1 }
+melodyB = { \mark "Verse" a'1 | b'1 | \mark \default c''1 | d''1 }
+\score {
+  <<
+    \new Staff { \melodyA | \melodyB }
+  >>
+}
+"#;
+    let parser = LilyPondParser::new();
+
+    let result = parser.parse_entry(input, "melodyA").unwrap();
+    assert_eq!(result.tempo.bpm, 100);
+    assert_eq!(result.staves.len(), 1);
+    assert_eq!(result.staves[0].name.as_deref(), Some("melodyA"));
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['c', 'd', 'e', 'f', 'g']);
+
+    // The verse runs up to the next rehearsal mark, \default names it A
+    let result = parser.parse_entry(input, "verse").unwrap();
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['a', 'b']);
+    let result = parser.parse_entry(input, "A").unwrap();
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['c', 'd']);
+
+    let err = parser.parse_entry(input, "chorus").unwrap_err();
+    assert!(err.contains("`chorus`"), "{err}");
+}
+
+#[test]
+fn test_slice_bars() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'1 | \repeat volta 2 { d'1 | e'1 } | f'1 | g'1 }
+    \new DrumStaff { \drummode { bd1 | bd1 | sn1 | sn1 | hh1 | hh1 | hh1 } }
+  >>
+}
+"#;
+    let mut result = LilyPondParser::new().parse(input).unwrap();
+    // Bars 3 to 5 of the unfolded piece: e' d' e'
+    result.slice_bars(2, 5);
+
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['e', 'd', 'e']);
+    let hits: Vec<String> = result.staves[1].drum_voices().unwrap()[0]
+        .events
+        .iter()
+        .filter_map(|e| match e {
+            DrumEvent::Hit(h) => Some(h.name.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(hits, vec!["sd", "sd", "hh"]);
+    assert!(result.summary().staves.iter().all(|s| s.bars == 3));
+
+    let strudel = StrudelGenerator::generate_multi_with_options(
+        &result.staves,
+        &result.tempo,
+        &GeneratorOptions { emit_prologue: true, ..Default::default() },
+    );
+    assert!(strudel.contains("const nbars = 3;"), "{strudel}");
+}
+
+#[test]
+fn test_loop_markers() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      c'1 | d'1 |
+      % @strudel-of-lilypond@ loop start
+      e'1 | f'1
+      % @strudel-of-lilypond@ loop end
+      | g'1
+    }
+    \new DrumStaff { \drummode { bd1 | bd1 | sn1 | sn1 | hh1 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let names: Vec<char> = result.notes().iter().map(|n| n.name).collect();
+    assert_eq!(names, vec!['e', 'f']);
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[sd@4]\n[sd@4]`"), "{strudel}");
+    assert!(!strudel.contains("LOOP"), "{strudel}");
+}
+
+#[test]
+fn test_click_staff() {
+    let input = r#"
+\tempo 4 = 90
+\score { << \new Staff { \time 3/4 c'4 d' e' | f'2. } >> }
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert_eq!(result.time, TimeSignature { count: 3, unit: 4 });
+
+    let click = result.click_staff();
+    assert_eq!(click.name.as_deref(), Some("click"));
+    let strudel = StrudelGenerator::generate_staff(&click, &result.tempo);
+    assert!(strudel.contains("[cb rim rim]@0.75\n[cb rim rim]@0.75`"), "{strudel}");
+
+    let plain = LilyPondParser::new().parse("\\tempo 4 = 90\n{ c'1 }").unwrap();
+    assert_eq!(plain.time, TimeSignature::default());
+}
+
+#[test]
+fn test_time_signature_changes() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4 d' e' f' | \time 7/8 g'4 a' b'4. | \repeat volta 2 { c''8 b' a' g' f' e' d' } | \time 4/4 c'1 }
+    \new DrumStaff { \drummode { bd1 | \time 7/8 sn2.. | sn2.. | sn2.. | \time 4/4 bd1 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let strudel = StrudelGenerator::generate_multi_with_options(
+        &result.staves,
+        &result.tempo,
+        &GeneratorOptions { emit_prologue: true, ..Default::default() },
+    );
+    assert!(strudel.contains("[c4 d4 e4 f4]\n[g4 a4 b4@1.5]@0.875\n[[[c5@0.5 b4@0.5 a4@0.5 g4@0.5 f4@0.5 e4@0.5 d4@0.5]@0.875]!2]@1.75\n[c4@4]`"), "{strudel}");
+    assert!(strudel.contains("[bd@4]\n[sd@3.5]@0.875\n[sd@3.5]@0.875\n[sd@3.5]@0.875\n[bd@4]`"), "{strudel}");
+    // 2 bars of 4/4 and 3 of 7/8
+    assert!(strudel.contains("const nbars = 4.625;"), "{strudel}");
+
+    let click = StrudelGenerator::generate_staff(&result.click_staff(), &result.tempo);
+    assert!(click.contains("[cb rim rim rim]\n[cb@0.5 rim@0.5 rim@0.5 rim@0.5 rim@0.5 rim@0.5 rim@0.5]@0.875"), "{click}");
+}
+
+#[test]
+fn test_manual_beams_group_notes() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c''8[ d'' e'' f''] g''4 a''8.[ b''16] | <c' e'>8[ r d'4] f'2 }
+    \new DrumStaff { \drummode { hh8[ hh hh hh] sn2 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[[c5 d5 e5 f5]@2 g5 [a5@0.75 b5@0.25]]"), "{strudel}");
+    assert!(strudel.contains("[[[c4,e4]@0.5 ~@0.5 d4]@2 f4@2]"), "{strudel}");
+    assert!(strudel.contains("[[hh hh hh hh]@2 sd@2]"), "{strudel}");
+}
+
+#[test]
+fn test_percussion_mapping_on_pitched_staff() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ percussion c'=bd e'=sd g'=hh
+      <c' g'>4 g' <e' g'> g' | c'4 c' e' b'
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert!(matches!(result.staves[0].kind, StaffKind::Drums));
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.starts_with("$: stack("), "{strudel}");
+    assert!(strudel.contains("[bd hh sd hh]\n[bd bd sd ~]"), "{strudel}");
+    assert!(strudel.contains("[hh ~ hh ~]\n[~ ~ ~ ~]"), "{strudel}");
+    assert_eq!(result.warnings.len(), 1);
+    assert!(result.warnings[0].message.contains("`b4`"), "{:?}", result.warnings);
+}
+
+#[test]
+fn test_sample_magic_comment() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ sample casio
+      c'4 e' g' c''
+    }
+    \new Staff {
+      % @strudel-of-lilypond@ sample breaks n
+      d'4 <e' g'> d' f'
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert_eq!(result.staves[0].sample, Some(Sample { name: "casio".to_string(), slices: false }));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("note(`\n[c4 e4 g4 c5]`)\n  .s(\"casio\")"), "{strudel}");
+    // Sample numbers count the semitones above the lowest note (d')
+    assert!(strudel.contains("n(`\n[0 [2,5] 0 3]`)\n  .s(\"breaks\")"), "{strudel}");
+    assert!(!strudel.contains("piano"), "{strudel}");
+}
+
+#[test]
+fn test_multi_measure_rests() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      \set Score.skipBars = ##t
+      \compressFullBarRests
+      c'1 | R1*2 | \time 3/4 \compressMMRests { R2.*2 } | e'2.
+    }
+    \new DrumStaff { \drummode { bd1 | R1*2 | \time 3/4 R4*6 | sn2. } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    assert!(result.summary().staves.iter().all(|s| s.bars == 6));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@4]\n[~ ~ ~ ~]\n[~ ~ ~ ~]\n[~ ~ ~]@0.75\n[~ ~ ~]@0.75\n[e4@3]@0.75"), "{strudel}");
+    assert!(strudel.contains("[bd@4]\n[~ ~ ~ ~]\n[~ ~ ~ ~]\n[~ ~ ~]@0.75\n[~ ~ ~]@0.75\n[sd@3]@0.75"), "{strudel}");
+}
+
+#[test]
+fn test_drum_voice_length_mismatch() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff = "kit" <<
+      \new DrumVoice { \drummode { bd4 sn bd sn | bd4 sn bd sn | bd4 sn bd sn } }
+      \new DrumVoice { \drummode { hh8 hh hh hh hh hh hh hh | hh8 hh hh hh hh hh hh hh } }
+    >>
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert_eq!(result.warnings.len(), 1, "{:?}", result.warnings);
+    assert_eq!(
+        result.warnings[0].message,
+        "kit: voice 2 has 2 bars but voice 1 has 3; it will loop early (pad it with rests)"
+    );
+
+    let mut staff = result.staves[0].clone();
+    assert_eq!(staff.voice_bars(), vec![3, 2]);
+    staff.pad_short_voices();
+    assert_eq!(staff.voice_bars(), vec![3, 3]);
+    let strudel = StrudelGenerator::generate_staff(&staff, &result.tempo);
+    assert!(strudel.contains("[hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5 hh@0.5]\n[~ ~ ~ ~]`"), "{strudel}");
+}
+
+#[test]
+fn test_pad_short_voices_option() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff = "kit" <<
+      \new DrumVoice { \drummode { bd4 sn bd sn | bd4 sn bd sn | bd4 sn bd sn } }
+      \new DrumVoice { \drummode { hh4 hh hh hh } }
+    >>
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let staff = &result.staves[0];
+
+    let plain = StrudelGenerator::generate_staff(staff, &result.tempo);
+    assert!(!plain.contains("[~ ~ ~ ~]"), "{plain}");
+
+    let options = GeneratorOptions { pad_short_voices: true, ..Default::default() };
+    let padded = StrudelGenerator::generate_staff_with_options(staff, &result.tempo, &options);
+    assert!(padded.contains("[hh hh hh hh]\n[~ ~ ~ ~]\n[~ ~ ~ ~]`"), "{padded}");
+    // Voices already as long as the longest one are left alone
+    assert_eq!(padded.matches("[~ ~ ~ ~]").count(), 2, "{padded}");
+}
+
+#[test]
+fn test_rubato_map() {
+    let input = r#"
+\tempo 4 = 120
+% @strudel-of-lilypond@ rubato 3-4=0.8 6=0.5 x=2
+\new Staff { c'1 | d'1 | e'1 | f'1 | g'1 | a'1 }
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert_eq!(
+        result.rubato,
+        vec![
+            Rubato { first_bar: 3, last_bar: 4, factor: "0.8".to_string() },
+            Rubato { first_bar: 6, last_bar: 6, factor: "0.5".to_string() },
+        ]
+    );
+    assert_eq!(result.warnings.len(), 1, "{:?}", result.warnings);
+    assert!(result.warnings[0].message.contains("`x=2` in rubato map"));
+
+    let plain = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(plain.contains(".cpm(tempo/4/nbars)"), "{plain}");
+
+    let options = GeneratorOptions { rubato: result.rubato.clone(), ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains(r#".cpm("[1@2 0.8@2 1 0.5]".mul(tempo/4/nbars))"#), "{strudel}");
+}
+
+#[test]
+fn test_grace_notes_steal_time() {
+    let input = r#"
+\tempo 4 = 120
+\new Staff { c'4 \grace d'8 e'4 f'2 | \acciaccatura g'16 a'4 b'2. | c''2 \grace { d''16 e''16 } f''2 }
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let graces: Vec<bool> = result.notes().iter().map(|n| n.grace).collect();
+    assert_eq!(graces, vec![false, true, false, false, true, false, false, false, true, true, false]);
+
+    let staff = &result.staves[0];
+    let strudel = StrudelGenerator::generate_staff(staff, &result.tempo);
+    assert!(strudel.contains("[c4 d4@0.5 e4@0.5 f4@2]"), "{strudel}");
+    assert!(strudel.contains("[g4@0.25 a4@0.75 b4@3]"), "{strudel}");
+    assert!(strudel.contains("[c5@2 d5@0.25 e5@0.25 f5@1.5]"), "{strudel}");
+
+    // Taking the time from the note before keeps the ornamented note on its beat; a grace
+    // note starting a bar still borrows from the ornamented note
+    let options = GeneratorOptions { grace_timing: GraceTiming::Previous, ..Default::default() };
+    let strudel = StrudelGenerator::generate_staff_with_options(staff, &result.tempo, &options);
+    assert!(strudel.contains("[c4@0.5 d4@0.5 e4 f4@2]"), "{strudel}");
+    assert!(strudel.contains("[g4@0.25 a4@0.75 b4@3]"), "{strudel}");
+    assert!(strudel.contains("[c5@1.5 d5@0.25 e5@0.25 f5@2]"), "{strudel}");
+}
+
+#[test]
+fn test_slurs_emit_clip_pattern() {
+    let input = r#"
+\tempo 4 = 120
+\new Staff { c'4( d' e') f' | g'8( a' b'4) r2 }
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let slurs: Vec<Option<Slur>> = result.notes().iter().map(|n| n.slur).collect();
+    assert_eq!(slurs[..4], [Some(Slur::Start), None, Some(Slur::End), None]);
+
+    let strudel = StrudelGenerator::generate_staff(&result.staves[0], &result.tempo);
+    assert!(strudel.contains(".clip(`\n[1 1 1 0.9]\n[1@0.5 1@0.5 1 ~ ~]`)"), "{strudel}");
+
+    // Without slurs, no clip pattern
+    let result = LilyPondParser::new().parse("\\tempo 4 = 120\n\\new Staff { c'4 d' e' f' }").unwrap();
+    let strudel = StrudelGenerator::generate_staff(&result.staves[0], &result.tempo);
+    assert!(!strudel.contains(".clip("), "{strudel}");
+}
+
+#[test]
+fn test_tremolo_expands_into_strokes() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4:16 <c' e'>4:8 d'2 | e'4.:16 f'8 g'2 }
+    \new DrumStaff { \drummode { sn4:32 sn sn2:16 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4@0.25 c4@0.25 c4@0.25 c4@0.25 [c4,e4]@0.5 [c4,e4]@0.5 d4@2]"), "{strudel}");
+    // The duration of the next note is the written one
+    assert!(strudel.contains("[e4@0.25 e4@0.25 e4@0.25 e4@0.25 e4@0.25 e4@0.25 f4@0.5 g4@2]"), "{strudel}");
+    assert!(strudel.contains(&format!("[{} sd {}]", ["sd@0.125"; 8].join(" "), ["sd@0.25"; 8].join(" "))), "{strudel}");
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+}
+
+#[test]
+fn test_sticking_label_pattern() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff { \drummode { sn8^"R" sn^"L" sn^"R" sn^"R" sn4^"L" bd | sn8^"R" sn^"L" sn2. } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let StaffContent::Drums(voices) = &result.staves[0].content else { panic!("expected drums") };
+    let DrumEvent::Hit(hit) = &voices[0].events[0] else { panic!("expected a hit") };
+    assert_eq!(hit.sticking.as_deref(), Some("R"));
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+
+    let strudel = StrudelGenerator::generate_staff(&result.staves[0], &result.tempo);
+    assert!(strudel.contains(".label(`\n[R@0.5 L@0.5 R@0.5 R@0.5 L ~]\n[R@0.5 L@0.5 ~ ~ ~]`)"), "{strudel}");
+}
+
+#[test]
+fn test_skip_inline_scheme_and_overrides() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      c'4 #(define e 1) d' \once \override Beam.positions = #'(2 . 3) e'
+      \override NoteHead.color = #(rgb-color 1 0 0) f' |
+      \tweak color #red g'2 \set Staff.midiInstrument = #"acoustic grand" a'4 \revert NoteHead.color b'
+    }
+    \new DrumStaff { \drummode { \override NoteHead.style = #'cross bd4 sn #(define e 1) bd sn } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[c4 d4 e4 f4]\n[g4@2 a4 b4]"), "{strudel}");
+    assert!(strudel.contains("[bd sd bd sd]"), "{strudel}");
+}
+
+#[test]
+fn test_tuplet_over_variable_reference() {
+    let input = r#"
+\tempo 4 = 120
+riff = { c'8 d' e' }
+\score {
+  <<
+    \new Staff {
+      \tuplet 3/2 \riff \tuplet 3/2 { \riff } \times 2/3 { f'8 g' a' } b'4 |
+      \repeat volta 2 { \tuplet 3/2 4 { c'8 d' e' f' g' a' } c''2 }
+    }
+    \new DrumStaff { \drummode { \tuplet 3/2 { bd8 sn sn } bd4 bd2 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let third = Length::new(1, 3);
+    let lengths: Vec<Length> = result.notes().iter().map(|n| n.quarters()).collect();
+    assert_eq!(lengths[..10], [third, third, third, third, third, third, third, third, third, Length::new(1, 1)]);
+    assert_eq!(lengths[10..17], [third, third, third, third, third, third, Length::new(2, 1)]);
+
+    let options = GeneratorOptions { weight_style: WeightStyle::Fraction, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[bd@1/3 sd@1/3 sd@1/3 bd bd@2]"), "{strudel}");
+    assert!(strudel.contains("]]!2"), "{strudel}");
+}
+
+#[test]
+fn test_warnings_panel_in_html() {
+    let input = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4 d' e' f' | g'4 a' b'4-\foo c'' }
+    \new DrumStaff { \drummode { bd4 snn4 bd sn } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(input).unwrap();
+    let bars: Vec<Option<u32>> = result.warnings.iter().map(|w| w.bar).collect();
+    assert_eq!(bars, vec![Some(2), Some(1)], "{:?}", result.warnings);
+    assert_eq!(result.warnings[1].to_string(), "warning: bar 1: ignored `snn4`: unknown drum name");
+
+    let options = GeneratorOptions { warnings: result.warnings.clone(), ..Default::default() };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "Test", &options);
+    assert!(html.contains("<summary>Conversion notes (2)</summary>"), "{html}");
+    assert!(html.contains("<li>bar 1: ignored `snn4`: unknown drum name</li>"), "{html}");
+
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "Test", &GeneratorOptions::default());
+    assert!(!html.contains("conversion-notes"), "{html}");
+}
+
+#[test]
+fn test_stamp_round_trip_and_staleness() {
+    let stamp = Stamp::new(&["\\tempo 4 = 120 { c'4 }"], "--midi");
+    assert_eq!(stamp.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(stamp.hash.len(), 16);
+
+    let html = stamp.apply("<!DOCTYPE html>\n<html></html>", "html");
+    assert!(html.starts_with("<!DOCTYPE html>\n<!-- strudel-of-lilypond-stamp version="), "{html}");
+    assert_eq!(Stamp::read(&html), Some(stamp.clone()));
+    assert_eq!(Stamp::read(&stamp.apply("\\version \"2.24.4\"", "ly")), Some(stamp.clone()));
+    assert_eq!(Stamp::read(&stamp.apply("const tempo = 120;", "str")), Some(stamp.clone()));
+    assert_eq!(Stamp::read("<html></html>"), None);
+
+    assert_eq!(stamp.staleness(&stamp), None);
+    let edited = Stamp::new(&["\\tempo 4 = 120 { d'4 }"], "--midi");
+    assert_eq!(stamp.staleness(&edited).as_deref(), Some("inputs changed"));
+    // Moving text from one input to the next is a change too
+    assert_ne!(Stamp::new(&["ab", "c"], "").hash, Stamp::new(&["a", "bc"], "").hash);
+    let other_options = Stamp::new(&["\\tempo 4 = 120 { c'4 }"], "--panels");
+    assert_eq!(stamp.staleness(&other_options).as_deref(), Some("generated with options `--midi`"));
+
+    // Dashes cannot end the HTML comment early
+    let tricky = Stamp::new(&[""], "--title a-->b --css c:\\x\\-- ---");
+    let html = tricky.apply("<html></html>", "html");
+    assert!(html.starts_with("<!-- strudel-of-lilypond-stamp ") && html.matches("--").count() == 2, "{html}");
+    assert!(html.contains("options=-\\-title a-\\->b -\\-css c:\\\\x\\\\-\\- -\\-\\- -->"), "{html}");
+    assert_eq!(Stamp::read(&html), Some(tricky.clone()));
+    assert_eq!(Stamp::read(&tricky.apply("", "ly")), Some(tricky));
+}
+
+#[test]
+fn test_stamp_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let page = dir.path().join("song.html").display().to_string();
+    let cache_path = dir.path().join(StampCache::FILE_NAME);
+    let stamp = Stamp::new(&["\\tempo 4 = 120 { c'4 }"], "--midi");
+
+    let mut cache = StampCache::load(&cache_path);
+    assert!(!cache.is_fresh("song.html", &stamp));
+    cache.record("song.html", &stamp, vec![page.clone()]);
+    // Recorded, but the page is not written yet
+    assert!(!cache.is_fresh("song.html", &stamp));
+    std::fs::write(&page, "").unwrap();
+    assert!(cache.is_fresh("song.html", &stamp));
+
+    cache.save(&cache_path).unwrap();
+    let cache = StampCache::load(&cache_path);
+    assert!(cache.is_fresh("song.html", &stamp));
+    // An edited include, other options or another version make it stale
+    assert!(!cache.is_fresh("song.html", &Stamp::new(&["\\tempo 4 = 120 { d'4 }"], "--midi")));
+    assert!(!cache.is_fresh("song.html", &Stamp::new(&["\\tempo 4 = 120 { c'4 }"], "")));
+    assert!(!cache.is_fresh("song.html", &Stamp { version: "0.0.1".to_string(), ..stamp }));
+}
+
+#[test]
+fn test_length_arithmetic() {
+    assert_eq!(Length::new(6, 8), Length { num: 3, den: 4 });
+    // A zero denominator counts as 1, a zero length stays zero
+    assert_eq!(Length::new(6, 0), Length { num: 6, den: 1 });
+    assert_eq!(Length::new(0, 0), Length { num: 0, den: 1 });
+
+    // Terms beyond `u32` are reduced before they are checked
+    let fine = Length::new(1, 65535 * 65535);
+    assert_eq!(fine.checked_plus(fine), Some(Length::new(2, 65535 * 65535)));
+    assert_eq!(fine.checked_times(Length::new(65535, 1)), Some(Length::new(1, 65535)));
+    // Too fine or too long: `None`, or rounded
+    let other = Length::new(1, 65521 * 65519);
+    assert_eq!(fine.checked_plus(other), None);
+    let error = fine.plus(other).as_f64() - (fine.as_f64() + other.as_f64());
+    assert!(error.abs() <= 0.5 / u32::MAX as f64, "{error}");
+    assert_eq!(fine.checked_times(fine), None);
+    assert_eq!(fine.times(fine), Length::new(0, 1));
+    assert_eq!(Length::new(u32::MAX, 1).times(Length::new(2, 1)), Length::new(u32::MAX, 1));
+    assert_eq!(Length::of_duration(u32::MAX, 8).den, u32::MAX);
+    assert_eq!(Length::new(7, 4).offset_in(Length::new(1, 2)), Length::new(1, 4));
+}
+
+#[test]
+fn test_parse_lossy_never_fails() {
+    let parser = LilyPondParser::new();
+
+    // Missing tempo: a default one, and the music is kept
+    let (result, warnings) = parser.parse_lossy("\\new Staff { c'4 d'4 }");
+    assert_eq!((result.tempo.beat_unit, result.tempo.bpm), (4, 120));
+    assert_eq!(result.notes().len(), 2);
+    assert_eq!(warnings[0].message, "no \\tempo, using 4 = 120");
+    assert!(result.warnings.is_empty());
+
+    // Garbage and truncated input give an empty score with a warning
+    let messages = |warnings: &[Warning]| warnings.iter().map(|w| w.message.clone()).collect::<Vec<_>>();
+    let (result, warnings) = parser.parse_lossy("\\tempo 4 = 120 \\repeat unfold { c4");
+    assert!(result.staves.is_empty());
+    assert_eq!(messages(&warnings), vec!["No '}' found"]);
+    let (result, warnings) = parser.parse_lossy("\u{0}%{");
+    assert!(result.staves.is_empty());
+    assert_eq!(messages(&warnings), vec!["no \\tempo, using 4 = 120", "No '}' found"]);
+
+    // A staff that does not parse is kept as an error, the others are converted, and the
+    // lines of the errors are those of the input
+    let code = "\\score {\n  <<\n    \\new Staff { c'4 d' }\n    \\new Staff { \\repeat volta x { c4 } }\n  >>\n}";
+    let (result, warnings) = parser.parse_lossy(code);
+    assert_eq!(result.staves.len(), 2);
+    assert_eq!(result.notes().len(), 2);
+    assert_eq!(result.staves[1].error.as_deref(), Some("Line 4: \\repeat volta expects a repeat count, found `x`"));
+    assert_eq!(
+        messages(&warnings),
+        vec![
+            "no \\tempo, using 4 = 120",
+            "Staff 2 could not be converted: Line 4: \\repeat volta expects a repeat count, found `x`"
+        ]
+    );
+    let (result, warnings) = parser.parse_lossy("\\tempo 4 = 120 { \\tuplet #( c4 \\times }");
+    assert_eq!((result.staves.len(), result.notes().len()), (1, 0));
+    assert!(warnings.is_empty(), "{warnings:?}");
+
+    // Nested tuplets too fine for a `Length` are rounded with a warning, not an overflow
+    let (result, warnings) = parser.parse_lossy("\\tempo 4 = 120 { \\tuplet 65535/1 { \\tuplet 65535/1 { c4 d4 } } }");
+    let lengths: Vec<Length> = result.notes().iter().map(|n| n.quarters()).collect();
+    assert_eq!(lengths, vec![Length::new(1, 65535 * 65535); 2]);
+    assert!(warnings.is_empty(), "{warnings:?}");
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &GeneratorOptions::default());
+    assert!(strudel.contains("[c3@1/4294836225 d3@1/4294836225]"), "{strudel}");
+
+    let (result, warnings) = parser.parse_lossy("\\tempo 4 = 120 { \\times 4294967295/1 { \\times 4294967295/1 { c4 } } | c4 }");
+    assert_eq!(messages(&warnings), vec!["`c4` is too short to be timed exactly in its tuplets, it is rounded"]);
+    assert_eq!(warnings[0].bar, Some(1));
+    assert_eq!(result.notes()[0].quarters(), Length::new(u32::MAX, 1));
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &GeneratorOptions::default());
+    assert!(strudel.contains("c3"), "{strudel}");
+
+    let (_, warnings) = parser.parse_lossy("\\tempo 4 = 120 { \\tuplet 65521/1 { c4 } \\tuplet 65519/1 { d4 } \\tuplet 65537/3 { e4 f4 } | c1 }");
+    assert_eq!(messages(&warnings), vec!["the notes of the bar are too short to add up exactly, their timing is rounded"]);
+
+    // Scores too large to play are refused without being unfolded
+    let code = "\\tempo 4 = 120 \\new Staff { \\repeat unfold 1000 { \\repeat unfold 1000 { \\repeat unfold 1000 { c4 } } } }";
+    let (result, warnings) = parser.parse_lossy(code);
+    assert!(result.staves.is_empty());
+    assert_eq!(warnings[0].message, "the score unfolds to more than 1000000 events");
+
+    let (result, warnings) = parser.parse_lossy("\\tempo 4 = 120 \\new Staff { R1*99999999 c4 }");
+    assert_eq!(result.notes().len(), 1);
+    assert_eq!(warnings[0].message, "ignored `R1*99999999`: a rest of more than 10000 bars");
+}
+
+#[test]
+fn test_include_source_map() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("drums.ly"), "% drums\nbd4 sn4\n\\repeat volta x { bd4 }\n").unwrap();
+
+    let code = "\\tempo 4 = 120\n\\include \"drums.ly\"\nc'4\n";
+    let main = dir.path().join("song.ly");
+    let (expanded, map) = expand_includes_with_map(code, dir.path(), &main).unwrap();
+    assert_eq!(expanded, expand_includes(code, dir.path()).unwrap());
+
+    let drums = dir.path().join("drums.ly");
+    assert_eq!(map.locate_line(1), Some((main.as_path(), 1)));
+    // The included lines, then the end of the line of the `\include`
+    assert_eq!(map.locate_line(2), Some((drums.as_path(), 1)));
+    assert_eq!(map.locate_line(4), Some((drums.as_path(), 3)));
+    assert_eq!(map.locate_line(5), Some((main.as_path(), 2)));
+    assert_eq!(map.locate_line(6), Some((main.as_path(), 3)));
+    assert_eq!(map.locate(expanded.find("c'4").unwrap()), Some((main.as_path(), 3)));
+    assert_eq!(map.locate(expanded.find("sn4").unwrap()), Some((drums.as_path(), 2)));
+
+    let error = LilyPondParser::new().parse(&expanded).unwrap_err();
+    assert_eq!(
+        map.locate_error(&error),
+        format!("{}:3: \\repeat volta expects a repeat count, found `x`", drums.display())
+    );
+}
+
+#[test]
+fn test_relative_chords_keep_their_voicing() {
+    let parser = LilyPondParser::new();
+    let code = r#"\tempo 4 = 120
+melody = \relative c' { <e c g>2 d4 <g, b d'> | c,1 }
+\score { << \new Staff { \melody } \new Staff \relative c'' { a4 f' \relative { c' } e } >> }
+"#;
+    let result = parser.parse(code).unwrap();
+    let options = GeneratorOptions::default();
+    let strudel = StrudelGenerator::generate_staff_with_options(&result.staves[0], &result.tempo, &options);
+    // Each chord note follows the previous one, the note after a chord its first one
+    assert!(strudel.contains("[[e4,c4,g3]@2 d4 [g3,b3,d5]]\n[c3@4]"), "{strudel}");
+    let strudel = StrudelGenerator::generate_staff_with_options(&result.staves[1], &result.tempo, &options);
+    // A nested \relative without a pitch starts from f
+    assert!(strudel.contains("a4 f5 c4 e5"), "{strudel}");
+}
+
+#[test]
+fn test_octave_offset_option() {
+    let parser = LilyPondParser::new();
+    let result = parser.parse("\\tempo 4 = 120\n{ c'4 ees''4 <c e g>2 }").unwrap();
+
+    let options = GeneratorOptions { octave_offset: -1, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[c3 eb4 [c2,e2,g2]@2]"), "{strudel}");
+
+    let options = GeneratorOptions { octave_offset: -1, midi_numbers: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[48 63 [36,40,43]@2]"), "{strudel}");
+}
+
+#[test]
+fn test_max_line_width_wraps_inside_groups() {
+    let parser = LilyPondParser::new();
+    let code = "\\tempo 4 = 120\n{ c'16[ d' e' f' g' a' b' c''] \\tuplet 3/2 { c''8 d'' e'' } <c' e' g'>4 | c'1 }";
+    let result = parser.parse(code).unwrap();
+    let staff = &result.staves[0];
+
+    let unwrapped = StrudelGenerator::generate_staff_with_options(staff, &result.tempo, &GeneratorOptions::default());
+    let options = GeneratorOptions { max_line_width: Some(24), ..Default::default() };
+    let wrapped = StrudelGenerator::generate_staff_with_options(staff, &result.tempo, &options);
+    assert!(wrapped.contains(
+        "note(`\n[\n  [\n    c4 d4 e4 f4 g4 a4 b4\n    c5\n  ]@2\n  c5@1/3 d5@1/3 e5@1/3\n  [c4,e4,g4]\n]\n[c4@4]`)"
+    ), "{wrapped}");
+    assert!(wrapped.lines().all(|line| line.len() <= 24), "{wrapped}");
+    // Only whitespace changes
+    let squeeze = |s: &str| s.split_whitespace().collect::<String>();
+    assert_eq!(squeeze(&wrapped), squeeze(&unwrapped));
+}
+
+#[test]
+fn test_check_corpus_reports_mismatches() {
+    use crate::corpus::{check_corpus, MismatchKind};
+
+    let dir = tempfile::tempdir().unwrap();
+    let score = |note: &str| format!("\\tempo 4 = 120\n{{ {note}4 d'4 }}");
+    let snapshot = |code: &str| {
+        let result = LilyPondParser::new().parse(code).unwrap();
+        let options = GeneratorOptions { emit_prologue: true, ..Default::default() };
+        let source = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+        Stamp::new(&[code], "--also-str").apply(&format!("{source}\n"), "str")
+    };
+    std::fs::write(dir.path().join("a.ly"), score("c'")).unwrap();
+    std::fs::write(dir.path().join("a.str"), snapshot(&score("c'"))).unwrap();
+    std::fs::write(dir.path().join("b.ly"), score("e'")).unwrap();
+    std::fs::write(dir.path().join("b.str"), snapshot(&score("c'"))).unwrap();
+    std::fs::write(dir.path().join("c.ly"), score("c'")).unwrap();
+    std::fs::write(dir.path().join("d.ly"), "{ c'4 }").unwrap();
+    std::fs::write(dir.path().join("d.str"), "").unwrap();
+
+    let mismatches = check_corpus(dir.path(), &GeneratorOptions::default()).unwrap();
+    let kinds: Vec<(String, MismatchKind)> = mismatches
+        .iter()
+        .map(|m| (m.source.file_name().unwrap().to_string_lossy().to_string(), m.kind.clone()))
+        .collect();
+    assert_eq!(kinds.len(), 3, "{kinds:?}");
+    assert_eq!(
+        kinds[0],
+        ("b.ly".to_string(), MismatchKind::Differs { line: 5, expected: "[c4 d4]`)".to_string(), actual: "[e4 d4]`)".to_string() })
+    );
+    assert_eq!(kinds[1], ("c.ly".to_string(), MismatchKind::MissingSnapshot));
+    assert!(matches!(&kinds[2].1, MismatchKind::Error(e) if e.starts_with("Missing tempo")), "{kinds:?}");
+    assert!(mismatches[0].to_string().ends_with("b.ly, line 5:\n  expected: [c4 d4]`)\n  actual:   [e4 d4]`)"));
+}
+
+#[test]
+fn test_lilypond_source_panel_in_html() {
+    let input = "\\tempo 4 = 120\n\\score { \\new Staff { c'4 <e' g'>4 d'2 } }\n";
+    let result = LilyPondParser::new().parse(input).unwrap();
+
+    let html = StrudelGenerator::generate_html(&result.staves, &result.tempo, "Test");
+    assert!(!html.contains("lilypond-source"));
+
+    let options = GeneratorOptions { lilypond_source: Some(input.to_string()), ..Default::default() };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "Test", &options);
+    assert!(html.contains("<details class=\"lilypond-source\">\n    <summary>LilyPond source</summary>"), "{html}");
+    assert!(html.contains("<pre>\\tempo 4 = 120\n\\score { \\new Staff { c'4 &lt;e' g'&gt;4 d'2 } }</pre>"), "{html}");
+    // Below the player
+    assert!(html.find("</strudel-repl>").unwrap() < html.find("<details class=\"lilypond-source\">").unwrap());
+}
+
+#[test]
+fn test_drum_voice_accents() {
+    let code = r#"
+\tempo 4 = 100
+\score {
+  <<
+    \new DrumStaff {
+      <<
+        \new DrumVoice {
+          % @strudel-of-lilypond@ accents <1 .6 .8 .6>
+          \drummode { hh4 hh hh hh | hh4 hh hh hh }
+        }
+        \new DrumVoice {
+          % @strudel-of-lilypond@ gain <0.5 1>
+          % @strudel-of-lilypond@ accents 1 .7
+          \drummode { bd4 sn bd sn | bd4 sn bd sn }
+        }
+      >>
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let voices = result.staves[0].drum_voices().unwrap();
+    assert_eq!(voices[0].gain.as_deref(), Some("\"1 .6 .8 .6\".fast(nbars)"));
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains(".gain(\"1 .6 .8 .6\".fast(nbars))"), "{strudel}");
+    assert!(strudel.contains(".gain(\"1 .7\".fast(nbars).mul(\"<0.5 1>\"))"), "{strudel}");
+}
+
+#[test]
+fn test_choke_hats_option() {
+    let code = r#"
+\tempo 4 = 100
+\score {
+  <<
+    \new DrumStaff { \drummode { bd8 hh hho hh sn8 hh hho hhc } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let plain = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(!plain.contains(".cut("));
+
+    let options = GeneratorOptions { choke_hats: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("sound(`\n[bd@0.5 ~@0.5 ~@0.5 ~@0.5 sd@0.5 ~@0.5 ~@0.5 ~@0.5]`)"), "{strudel}");
+    assert!(strudel.contains("sound(`\n[~@0.5 hh@0.5 oh@0.5 hh@0.5 ~@0.5 hh@0.5 oh@0.5 hh@0.5]`)\n  .cut(1)"), "{strudel}");
+
+    // Voices without open hats are left alone
+    let result = LilyPondParser::new().parse(&code.replace("hho", "hh")).unwrap();
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(!strudel.contains(".cut(") && !strudel.contains("stack("), "{strudel}");
+}
+
+#[test]
+fn test_instrument_range_warnings() {
+    let code = r#"
+\tempo 4 = 100
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ range violin
+      \relative c'' { g4 a b c | d,,4 e <f a> g }
+    }
+    \new Staff {
+      % @strudel-of-lilypond@ range c' c''
+      { c'4 d' e' f' | g'4 a' b' d'' }
+    }
+    \new Staff {
+      % @strudel-of-lilypond@ range kazoo
+      { c'1 }
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let warnings: Vec<String> = result.warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(
+        warnings,
+        vec![
+            "warning: bar 2: d3 is below the violin range (g3 to g7)",
+            "warning: bar 2: e3 is below the violin range (g3 to g7)",
+            "warning: bar 2: f3 is below the violin range (g3 to g7)",
+            "warning: bar 2: d5 is above the given range (c4 to c5)",
+            "warning: unknown instrument range `kazoo` (expected violin, viola, cello, bass, flute, clarinet, trumpet, trombone, guitar)",
+        ]
+    );
+}
+
+#[test]
+fn test_bar_minimap_in_html() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ comment intro
+      c'4 d' e' f' |
+      \time 2/4
+      g'2 |
+      % @strudel-of-lilypond@ comment verse
+      a'4 <c'' e''>4
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let html = StrudelGenerator::generate_html(&result.staves, &result.tempo, "Test");
+    assert!(!html.contains("bar-minimap"));
+
+    let options = GeneratorOptions { bar_minimap: true, ..Default::default() };
+    let html = StrudelGenerator::generate_html_with_options(&result.staves, &result.tempo, "Test", &options);
+    assert!(html.contains("<summary>Bars (3)</summary>"), "{html}");
+    // A 4/4 bar lasts 2 seconds at 120, a 2/4 bar 1 second
+    assert!(html.contains(r#"<li id="bar-1" title="[c4 d4 e4 f4]"><span class="bar-number">1</span> <span class="bar-time">0:00</span> intro</li>"#), "{html}");
+    assert!(html.contains(r#"<span class="bar-number">2</span> <span class="bar-time">0:02</span></li>"#), "{html}");
+    assert!(html.contains(r#"<li id="bar-3" title="[a4 [c5,e5]]@0.5"><span class="bar-number">3</span> <span class="bar-time">0:03</span> verse</li>"#), "{html}");
+}
+
+#[test]
+fn test_repeat_style() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { \repeat volta 2 { c'4 d' e' f' } }
+    \new Staff {
+      % @strudel-of-lilypond@ repeats compressed
+      \repeat volta 2 { g'1 }
+    }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    assert_eq!(result.staves[1].repeat_style, Some(RepeatStyle::Compressed));
+    let compressed = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(compressed.contains("[[c4 d4 e4 f4]]!2"), "{compressed}");
+
+    // The staff setting its own style keeps it
+    let options = GeneratorOptions { repeat_style: RepeatStyle::Unrolled, ..Default::default() };
+    let unrolled = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(unrolled.contains("[c4 d4 e4 f4]\n[c4 d4 e4 f4]"), "{unrolled}");
+    assert!(unrolled.contains("[[g4@4]]!2"), "{unrolled}");
+}
+
+#[test]
+fn test_double_quote_style() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4 d' e' f' | g'1 }
+    \new DrumStaff { \drummode { bd4 sn4^"R" bd4 sn4^"L" } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let options = GeneratorOptions { quote_style: QuoteStyle::Double, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("note(\"[c4 d4 e4 f4] [g4@4]\")"), "{strudel}");
+    assert!(strudel.contains("sound(\"[bd sd bd sd]\")"), "{strudel}");
+    assert!(strudel.contains(".label(\"[~ R ~ L]\")"), "{strudel}");
+    assert!(!strudel.contains('`'), "{strudel}");
+    assert_eq!("double".parse::<QuoteStyle>(), Ok(QuoteStyle::Double));
+}
+
+#[test]
+fn test_drum_map_option() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff { \drummode { bd4 sn4 hh4 sn4 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    // By LilyPond name or by the sound it plays
+    for from in ["sn", "sd"] {
+        let options = GeneratorOptions {
+            drum_map: BTreeMap::from([(from.to_string(), "cp".to_string()), ("hh".to_string(), "rim".to_string())]),
+            ..Default::default()
+        };
+        let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+        assert!(strudel.contains("[bd cp rim cp]"), "{strudel}");
+    }
+    // The parsed score is left as it is
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[bd sd hh sd]"), "{strudel}");
+}
+
+#[test]
+fn test_key_signature() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff \relative c'' { \key g \major g4 f e d | \key bes \major c1 }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let key = KeySignature { tonic: "g".to_string(), mode: "major".to_string() };
+    assert_eq!(result.staves[0].key, Some(key));
+    // The tonics are not notes, and `f` stays F natural as in LilyPond
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("[g4 f4 e4 d4]\n[c4@4]"), "{strudel}");
+    assert!(result.summary().to_string().contains(", key g major,"));
+}
+
+#[test]
+fn test_lenient_staff_errors() {
+    let code = r#"
+\tempo 4 = 120
+riff = { \repeat volta x { e'4 f' g' a' } }
+\score {
+  <<
+    \new Staff { c'4 d' e' f' }
+    \new Staff \with { instrumentName = "Bass" } { \repeat unfold many { c4 } }
+    \new Staff { \riff }
+    \new DrumStaff { \drummode { bd4 sn bd sn } }
+  >>
+}
+"#;
+    let err = LilyPondParser::new().parse(code).unwrap_err();
+    assert_eq!(err, "Line 3: \\repeat volta expects a repeat count, found `x`");
+
+    let result = LilyPondParser::new().lenient().parse(code).unwrap();
+    assert_eq!(result.staves.len(), 4);
+    assert_eq!(result.staves[1].error.as_deref(), Some("Line 7: \\repeat unfold expects a repeat count, found `many`"));
+    let messages: Vec<&str> = result.warnings.iter().map(|w| w.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Bass could not be converted: Line 7: \\repeat unfold expects a repeat count, found `many`",
+            "Staff 3 could not be converted: In variable riff: Line 1: \\repeat volta expects a repeat count, found `x`",
+        ]
+    );
+
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    assert!(strudel.contains("$: note(`\n[c4 d4 e4 f4]`)"), "{strudel}");
+    assert!(strudel.contains("\n\n// Not converted: Line 7: \\repeat unfold expects a repeat count, found `many`\n\n"), "{strudel}");
+    assert!(strudel.contains("$: sound(`\n[bd sd bd sd]`)"), "{strudel}");
+}
+
+#[test]
+fn test_dynamics_velocity() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4\p d' e'\< f' | g'1\f }
+    \new DrumStaff { \drummode { bd4\ff sn4 bd4\sfz sn4 } }
+    \new Staff { c'4 d' e' f' }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    // Piano, a crescendo from e' to the forte
+    assert!(strudel.contains("  .velocity(`\n[0.5 0.5 0.5 0.65]\n[0.8@4]`)"), "{strudel}");
+    // An accent only plays one hit louder
+    assert!(strudel.contains("$: sound(`\n[bd sd bd sd]`)\n.velocity(`\n[0.87 0.87 0.93 0.87]`)"), "{strudel}");
+    // Nothing without dynamics
+    assert_eq!(strudel.matches(".velocity(").count(), 2, "{strudel}");
+}
+
+#[test]
+fn test_generate_single_staff() {
+    let code = r#"
+\tempo 4 = 90
+\score {
+  <<
+    \new Staff {
+      % @strudel-of-lilypond@ gain 0.8
+      c'4 d' e' f'
+    }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 sn4 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let options = GeneratorOptions { emit_prologue: true, ..Default::default() };
+    let tracks: Vec<String> = result
+        .staves
+        .iter()
+        .map(|staff| format!("$: {}", StrudelGenerator::generate_staff_with_options(staff, &result.tempo, &options)))
+        .collect();
+    let laid_out = format!("{}\n\n{}", StrudelGenerator::generate_prologue(&result.staves, &result.tempo, &options), tracks.join("\n\n"));
+    assert_eq!(laid_out, StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options));
+    assert!(tracks[0].contains(".gain(0.8)"), "{}", tracks[0]);
+}
+
+#[test]
+fn test_articulations() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'4-. d'-- e'-> f'-! | <c' e'>1-_ }
+    \new DrumStaff { \drummode { bd4 sn4-> bd4 sn4-^ } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    // Staccato, tenuto, staccatissimo and portato set the length, other notes slightly detached
+    assert!(strudel.contains("  .clip(`\n[0.5 1 0.9 0.25]\n[0.75@4]`)"), "{strudel}");
+    // Accents louder than the mezzo-forte around them, marcato even more
+    assert!(strudel.contains("  .velocity(`\n[0.7 0.7 0.85 0.7]\n[0.7@4]`)"), "{strudel}");
+    assert!(strudel.contains("$: sound(`\n[bd sd bd sd]`)\n.velocity(`\n[0.7 0.85 0.7 0.95]`)"), "{strudel}");
+}
+
+#[test]
+fn test_rests_on_beats() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c'8 r2 c'8 d'4 | r2. c'4 | \time 6/8 c'8 r2 r8 }
+    \new DrumStaff { \drummode { bd8 r4. sn4 r4 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let strudel = StrudelGenerator::generate_multi(&result.staves, &result.tempo);
+    // A half rest after an eighth ends half a beat into the third beat
+    assert!(strudel.contains("[c4@0.5 ~@0.5 ~ ~@0.5 c4@0.5 d4]"), "{strudel}");
+    // Rests on the beat stay one `~` per quarter
+    assert!(strudel.contains("[~ ~ ~ c4]"), "{strudel}");
+    // Beats of a dotted quarter in 6/8
+    assert!(strudel.contains("[c4@0.5 ~ ~ ~@0.5]@0.75"), "{strudel}");
+    assert!(strudel.contains("[bd@0.5 ~@0.5 ~ sd ~]"), "{strudel}");
+}
+
+#[test]
+fn test_chord_names() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { <c' e' g' b'>2 <a c' e'> | \repeat volta 2 { <e' g' c''>2 <d' f' a' c''> | } <c' d' fis'>1 }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let options = GeneratorOptions { chord_names: true, ..Default::default() };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(strudel.contains("[[c4,e4,g4,b4]@2 [a3,c4,e4]@2] // Cmaj7 Am\n"), "{strudel}");
+    // Inversions name their bass, the repeat closes on the next line
+    assert!(strudel.contains("[[[e4,g4,c5]@2 [d4,f4,a4,c5]@2] // C/E Dm7\n]!2\n"), "{strudel}");
+    // No symbol for chords of unknown quality
+    assert!(strudel.contains("\n[[c4,d4,f#4]@4]`)"), "{strudel}");
+
+    // Double-quoted patterns are on one line, where a comment would end the pattern
+    let options = GeneratorOptions { quote_style: QuoteStyle::Double, ..options };
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &options);
+    assert!(!strudel.contains("//"), "{strudel}");
+}
+
+#[test]
+fn test_partial_pickup() {
+    let code = r#"
+\tempo 4 = 120
+\time 3/4
+\score {
+  <<
+    \new Staff { \partial 4 g4 | c'2. | d'2. }
+    \new DrumStaff { \drummode { \partial 8 \acciaccatura sn16 sn8 bd4 sn4 bd4 | bd4 sn4 bd4 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    let strudel = StrudelGenerator::generate_multi_with_options(
+        &result.staves,
+        &result.tempo,
+        &GeneratorOptions { emit_prologue: true, ..Default::default() },
+    );
+    // The pickup weighs what it holds, the bars after it the meter of the score
+    assert!(strudel.contains("const nbars = 1.75;"), "{strudel}");
+    assert!(strudel.contains("[g3]@0.25\n[c4@3]@0.75\n[d4@3]@0.75"), "{strudel}");
+    // Without a bar check the pickup is closed after its length; the flam takes its time from the hit
+    assert!(strudel.contains("[sd@0.25 sd@0.25]@0.125\n[bd sd bd]@0.75\n[bd sd bd]@0.75"), "{strudel}");
+}
+
+#[test]
+fn test_cycle_info() {
+    let code = r#"
+\tempo 4 = 90
+% @strudel-of-lilypond@ rubato 3=0.5
+\score {
+  <<
+    \new Staff { \time 3/4 c'2. | d'2. | \repeat volta 2 { e'2. | } }
+    \new DrumStaff { \drummode { bd4 sn4 bd4 | bd4 sn4 bd4 } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    let options = GeneratorOptions { rubato: result.rubato.clone(), ..Default::default() };
+    let info = StrudelGenerator::cycle_info(&result.staves, &result.tempo, &options);
+    assert_eq!(info.bars, vec![4, 2]);
+    assert_eq!(info.nbars, Length::new(3, 1));
+    // 90 quarters a minute, 12 quarters a cycle
+    assert_eq!(info.cpm, 7.5);
+    assert_eq!(info.seconds, 8.0);
+    assert_eq!(info.cpm_expression, "\"[1@2 0.5 1]\".mul(tempo/4/nbars)");
+    assert_eq!(result.summary().seconds, info.seconds);
+}
+
+#[test]
+fn test_alternative_endings() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { \repeat volta 3 { c'1 | } \alternative { { d'1 | } { e'1 } } f'1 }
+    \new DrumStaff { \drummode { \repeat volta 2 { bd4 sn4 bd4 sn4 | } \alternative { { bd1 | } { sn1 | } } } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    let strudel = StrudelGenerator::generate_multi_with_options(
+        &result.staves,
+        &result.tempo,
+        &GeneratorOptions { emit_prologue: true, ..Default::default() },
+    );
+    // Three passes, two endings: the first ending closes the first two passes
+    assert!(strudel.contains("note(`\n[[[c4@4]\n[d4@4]]!2]@4\n[c4@4]\n[e4@4]\n[f4@4]`)"), "{strudel}");
+    assert!(strudel.contains("const nbars = 7;"), "{strudel}");
+    assert!(strudel.contains("sound(`\n[bd sd bd sd]\n[bd@4]\n[bd sd bd sd]\n[sd@4]`)"), "{strudel}");
+
+    // The endings stay in the events, after the repeat they close
+    let events = result.staves[0].events().unwrap();
+    let endings = events.iter().filter(|e| matches!(e, PitchedEvent::AlternativeStart)).count();
+    assert_eq!(endings, 2);
+    assert!(matches!(events[3], PitchedEvent::RepeatEnd));
+    assert!(matches!(events[4], PitchedEvent::AlternativeStart));
+    assert_eq!(result.unfolded_events(), 31);
+
+    // Unrolled, every pass is written out in playing order
+    let options = GeneratorOptions { repeat_style: RepeatStyle::Unrolled, ..Default::default() };
+    let unrolled = StrudelGenerator::generate_staff_with_options(&result.staves[0], &result.tempo, &options);
+    assert!(unrolled.contains("note(`\n[c4@4]\n[d4@4]\n[c4@4]\n[d4@4]\n[c4@4]\n[e4@4]\n[f4@4]`)"), "{unrolled}");
+
+    let err = LilyPondParser::new()
+        .parse("\\tempo 4 = 120 \\score { \\new Staff { \\repeat volta 2 { c'1 } \\alternative d'1 } }")
+        .unwrap_err();
+    assert!(err.contains("\\alternative expects its endings in `{ ... }`"), "{err}");
+}
+
+#[test]
+fn test_parser_options() {
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new Staff { c e'2 | g,1 }
+    \new DrumStaff { \drummode { bd sn bd4 sn | } }
+  >>
+}
+"#;
+    let options = ParserOptions { default_octave: 4, default_duration: 8 };
+    let result = LilyPondParser::with_options(options).parse(code).unwrap();
+    let notes: Vec<(i32, u32)> = result.notes().iter().map(|n| (n.midi, n.duration)).collect();
+    // `c` is middle C, one octave up from the default
+    assert_eq!(notes, vec![(60, 8), (76, 2), (55, 1)]);
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &GeneratorOptions::default());
+    assert!(strudel.contains("[bd@0.5 sd@0.5 bd sd]"), "{strudel}");
+
+    // A `\relative` without a reference pitch starts from the unmarked f of that octave too
+    let relative = "\\tempo 4 = 120 \\relative { c4 d b | c'1 }";
+    let midi = |options: ParserOptions| -> Vec<i32> {
+        LilyPondParser::with_options(options).parse(relative).unwrap().notes().iter().map(|n| n.midi).collect()
+    };
+    assert_eq!(midi(ParserOptions::default()), vec![48, 50, 47, 60]);
+    assert_eq!(midi(ParserOptions { default_octave: 4, ..Default::default() }), vec![60, 62, 59, 72]);
+
+    let options = ParserOptions { default_duration: 3, ..Default::default() };
+    let err = LilyPondParser::with_options(options).parse(code).unwrap_err();
+    assert_eq!(err, "Default duration 3 is not a note value (1, 2, 4, ... 128)");
+}
+
+#[test]
+fn test_parser_shared_between_threads() {
+    let parser = LilyPondParser::new();
+    let inputs = [
+        "\\tempo 4 = 120 \\relative c' { c4 d e f | g1?? }",
+        "\\tempo 4 = 90 { c'4 d'4 | e'1 -\\bar }",
+    ];
+    let alone: Vec<ParseResult> = inputs.iter().map(|input| parser.parse(input).unwrap()).collect();
+    // Each call keeps its own bars, warnings and `\\relative` pitches
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let parser = &parser;
+                scope.spawn(move || (i % 2, parser.parse(inputs[i % 2]).unwrap()))
+            })
+            .collect();
+        for handle in handles {
+            let (index, result) = handle.join().unwrap();
+            let pitches = |result: &ParseResult| result.notes().iter().map(|n| (n.midi, n.duration)).collect::<Vec<_>>();
+            assert_eq!(pitches(&result), pitches(&alone[index]));
+            assert_eq!(result.warnings, alone[index].warnings);
+        }
+    });
+    assert!(!alone[0].warnings.is_empty() && alone[0].warnings[0].bar == Some(2), "{:?}", alone[0].warnings);
+}
+
+
+#[test]
+fn test_drum_staff_reads_shared_commands() {
+    // Drum voices go through the same token loop as pitched staves: `\key` is skipped there too
+    let code = r#"
+\tempo 4 = 120
+\score {
+  <<
+    \new DrumStaff { \drummode { \key g \major bd4 sn4 \tuplet 3/2 { hh8 hh hh } bd4 | } }
+  >>
+}
+"#;
+    let result = LilyPondParser::new().parse(code).unwrap();
+    assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+    let strudel = StrudelGenerator::generate_multi_with_options(&result.staves, &result.tempo, &GeneratorOptions::default());
+    assert!(strudel.contains("[bd sd hh@1/3 hh@1/3 hh@1/3 bd]"), "{strudel}");
+}